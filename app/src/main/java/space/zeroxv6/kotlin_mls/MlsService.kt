@@ -3,9 +3,15 @@ package space.zeroxv6.kotlin_mls
 import android.content.Context
 import kotlinx.coroutines.Dispatchers
 import kotlinx.coroutines.withContext
+import uniffi.android_openmls.AddMemberResult
+import uniffi.android_openmls.CommitOutcome
+import uniffi.android_openmls.DecryptedMessage
+import uniffi.android_openmls.JoinedGroup
 import uniffi.android_openmls.MlsClient
 import uniffi.android_openmls.MlsException
 import uniffi.android_openmls.MemberInfo
+import uniffi.android_openmls.SaveReport
+import uniffi.android_openmls.StorageBackend
 
 /**
  * Kotlin wrapper for OpenMLS providing end-to-end encrypted group messaging
@@ -19,18 +25,33 @@ import uniffi.android_openmls.MemberInfo
  *
  * ## Thread safety
  * All public methods are suspending and dispatched on [Dispatchers.IO].
- * The underlying Rust layer uses a Mutex internally.
+ * The underlying Rust layer uses a read-write lock internally, so queries
+ * can run concurrently while mutating calls are serialized.
  *
- * @param context  Android context (used only for [Context.getFilesDir]).
- * @param storageName  Unique storage folder name; allows multiple users on
- *                     the same device (useful for testing).
+ * ## Storage
+ * By default state is kept in files under [Context.getFilesDir].  To keep
+ * it elsewhere (SQLite, EncryptedSharedPreferences, ...), implement
+ * [StorageBackend] and pass it to the secondary constructor.
  */
-class MlsService(context: Context, storageName: String = "mls_storage") {
+class MlsService private constructor(createClient: () -> MlsClient) {
 
-    private val client: MlsClient by lazy {
+    /**
+     * @param context  Android context (used only for [Context.getFilesDir]).
+     * @param storageName  Unique storage folder name; allows multiple users
+     *                     on the same device (useful for testing).
+     */
+    constructor(context: Context, storageName: String = "mls_storage") : this({
         val dbPath = context.filesDir.absolutePath + "/" + storageName
         MlsClient(dbPath)
-    }
+    })
+
+    /**
+     * @param backend  Host-provided key-value storage that all state
+     *                 (identity, key store, group list) is persisted to.
+     */
+    constructor(backend: StorageBackend) : this({ MlsClient.newWithBackend(backend) })
+
+    private val client: MlsClient by lazy(createClient)
 
     // ── Identity ───────────────────────────────────────────────────────
 
@@ -66,6 +87,20 @@ class MlsService(context: Context, storageName: String = "mls_storage") {
         }
     }
 
+    /**
+     * Like [generateKeyPackage], but wraps the key package in an MLSMessage,
+     * the form other RFC 9420 implementations publish and expect.
+     *
+     * @return Hex-encoded MLSMessage carrying the key package.
+     */
+    suspend fun generateKeyPackageWrapped(): String = withContext(Dispatchers.IO) {
+        try {
+            client.generateKeyPackageWrapped()
+        } catch (e: MlsException) {
+            throw MlsServiceException("Failed to generate key package", e)
+        }
+    }
+
     /**
      * Returns `true` if [createIdentity] has been called (or an identity
      * was restored from disk).
@@ -107,6 +142,47 @@ class MlsService(context: Context, storageName: String = "mls_storage") {
             }
         }
 
+    /**
+     * Like [addMember], but leaves the commit unmerged so the group does not
+     * advance its epoch before the commit is delivered.
+     *
+     * Send the commit to the existing members, then call [confirmCommit] and
+     * send the welcome to the new member.  If delivery fails, call
+     * [abortCommit] and discard the welcome.
+     */
+    suspend fun addMemberDeferred(groupId: String, keyPackageHex: String): AddMemberResult =
+        withContext(Dispatchers.IO) {
+            try {
+                client.addMemberDeferred(groupId, keyPackageHex)
+            } catch (e: MlsException) {
+                throw MlsServiceException("Failed to add member to group $groupId", e)
+            }
+        }
+
+    /**
+     * Merges the commit left pending by [addMemberDeferred], advancing the
+     * group to the next epoch.
+     */
+    suspend fun confirmCommit(groupId: String) = withContext(Dispatchers.IO) {
+        try {
+            client.confirmCommit(groupId)
+        } catch (e: MlsException) {
+            throw MlsServiceException("Failed to confirm commit for group $groupId", e)
+        }
+    }
+
+    /**
+     * Discards the commit left pending by [addMemberDeferred]; the group
+     * stays at its current epoch.
+     */
+    suspend fun abortCommit(groupId: String) = withContext(Dispatchers.IO) {
+        try {
+            client.abortCommit(groupId)
+        } catch (e: MlsException) {
+            throw MlsServiceException("Failed to abort commit for group $groupId", e)
+        }
+    }
+
     /**
      * Removes a member from a group by their leaf index.
      *
@@ -139,6 +215,20 @@ class MlsService(context: Context, storageName: String = "mls_storage") {
         }
     }
 
+    /**
+     * Moves this member's leaf in one group to a new signature key pair,
+     * e.g. for periodic rotation. Other groups keep their current key.
+     *
+     * @return JSON: `{"commit":"<hex>"}` — broadcast to all other members.
+     */
+    suspend fun rotateGroupSignatureKey(groupId: String): String = withContext(Dispatchers.IO) {
+        try {
+            client.rotateGroupSignatureKey(groupId)
+        } catch (e: MlsException) {
+            throw MlsServiceException("Failed to rotate the signature key in group $groupId", e)
+        }
+    }
+
     /**
      * Processes a Welcome message to join a group.
      *
@@ -155,16 +245,58 @@ class MlsService(context: Context, storageName: String = "mls_storage") {
         }
     }
 
+    /**
+     * Like [processWelcome], but also returns the joined group's epoch and
+     * members, so the conversation can be rendered without further calls.
+     *
+     * @param welcomeHex  Hex-encoded Welcome from the group admin.
+     * @return The group ID, epoch and member list.
+     */
+    suspend fun processWelcomeFull(welcomeHex: String): JoinedGroup = withContext(Dispatchers.IO) {
+        try {
+            val joined = client.processWelcomeFull(welcomeHex)
+            client.saveState()
+            joined
+        } catch (e: MlsException) {
+            throw MlsServiceException("Failed to process Welcome message", e)
+        }
+    }
+
+    /**
+     * Rejoins a group whose local state was lost, by external commit.
+     *
+     * @param groupInfoHex    GroupInfo exported by a current member.
+     * @param ratchetTreeHex  The group's ratchet tree, unless the GroupInfo
+     *   carries it.
+     * @return JSON: `{"group_id":"<hex>","commit":"<hex>"}`; send the commit
+     *   to all members via [processCommit].
+     */
+    suspend fun rejoin(groupInfoHex: String, ratchetTreeHex: String? = null): String =
+        withContext(Dispatchers.IO) {
+            try {
+                val result = client.rejoin(groupInfoHex, ratchetTreeHex)
+                client.saveState()
+                result
+            } catch (e: MlsException) {
+                throw MlsServiceException("Failed to rejoin group", e)
+            }
+        }
+
     /**
      * Processes a Commit from another member (e.g. add/remove/update).
      *
      * All existing members **must** process commits to stay in sync.
+     *
+     * @return [CommitOutcome.Merged] with the members the commit added and
+     *   removed, or [CommitOutcome.RemovedSelf] if the commit removed us; the
+     *   group is then gone and further calls on it fail with `NoLongerMember`.
      */
-    suspend fun processCommit(groupId: String, commitHex: String): Unit =
+    suspend fun processCommit(groupId: String, commitHex: String): CommitOutcome =
         withContext(Dispatchers.IO) {
             try {
-                client.processCommit(groupId, commitHex)
+                val outcome = client.processCommit(groupId, commitHex)
                 client.saveState()
+                outcome
             } catch (e: MlsException) {
                 throw MlsServiceException("Failed to process commit for group $groupId", e)
             }
@@ -206,6 +338,37 @@ class MlsService(context: Context, storageName: String = "mls_storage") {
             }
         }
 
+    /**
+     * Encrypts a binary payload (e.g. protobuf or CBOR) for the group.
+     * Any bytes are carried unchanged, including an empty payload.
+     *
+     * @return Hex-encoded ciphertext to broadcast to **all** group members.
+     */
+    suspend fun encryptBytes(groupId: String, plaintext: ByteArray): String =
+        withContext(Dispatchers.IO) {
+            try {
+                client.encryptMessageBytes(groupId, plaintext)
+            } catch (e: MlsException) {
+                throw MlsServiceException("Failed to encrypt message for group $groupId", e)
+            }
+        }
+
+    /**
+     * Decrypts an application message from the group without assuming it
+     * is text.  [DecryptedMessage.asUtf8] is null for payloads that are not
+     * valid UTF-8.
+     */
+    suspend fun decryptBytes(groupId: String, ciphertextHex: String): DecryptedMessage =
+        withContext(Dispatchers.IO) {
+            try {
+                val result = client.decryptMessageWithSender(groupId, ciphertextHex)
+                client.saveState()
+                result
+            } catch (e: MlsException) {
+                throw MlsServiceException("Failed to decrypt message for group $groupId", e)
+            }
+        }
+
     // ── Group queries ──────────────────────────────────────────────────
 
     /**
@@ -254,8 +417,10 @@ class MlsService(context: Context, storageName: String = "mls_storage") {
      *
      * State is also auto-saved after [processWelcome], [processCommit],
      * and [decrypt].
+     *
+     * @return which groups were saved, skipped (we were removed) or failed.
      */
-    suspend fun save(): Unit = withContext(Dispatchers.IO) {
+    suspend fun save(): SaveReport = withContext(Dispatchers.IO) {
         try {
             client.saveState()
         } catch (e: MlsException) {
@@ -0,0 +1,325 @@
+//! Seeded OpenMLS provider for reproducible test transcripts.
+//!
+//! **NOT FOR PRODUCTION.**  Every secret this provider produces (group IDs,
+//! signature keys, HPKE ephemerals, leaf secrets) is derived from the seed
+//! handed to `MlsClient::new_for_testing()`, so anyone who knows the seed
+//! knows the keys.  The module only exists when the `deterministic-tests`
+//! cargo feature is enabled, and that feature must never be turned on for
+//! release builds.
+//!
+//! Without a seed the provider behaves exactly like `OpenMlsRustCrypto`.
+
+use std::sync::RwLock;
+
+use ed25519_dalek::SigningKey;
+use hpke_rs::hpke_types;
+use hpke_rs::rustcrypto::HpkeRustCrypto;
+use hpke_rs::{Hpke, HpkePublicKey};
+use openmls::prelude::*;
+use openmls_rust_crypto::{MemoryStorage, RandError, RustCrypto};
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// Drop-in replacement for `OpenMlsRustCrypto` with an optional seeded DRBG.
+#[derive(Default)]
+pub(crate) struct DeterministicProvider {
+    crypto: DeterministicCrypto,
+    key_store: MemoryStorage,
+}
+
+impl DeterministicProvider {
+    /// A provider whose randomness is fully determined by `seed`.
+    pub(crate) fn seeded(seed: &[u8]) -> Result<Self, CryptoError> {
+        let crypto = RustCrypto::default();
+        // Hash so that seeds of any length map onto a full ChaCha key.
+        let key: [u8; 32] = crypto
+            .hash(HashType::Sha2_256, seed)?
+            .try_into()
+            .map_err(|_| CryptoError::InvalidLength)?;
+
+        Ok(Self {
+            crypto: DeterministicCrypto {
+                inner: crypto,
+                rng: Some(RwLock::new(ChaCha20Rng::from_seed(key))),
+            },
+            key_store: MemoryStorage::default(),
+        })
+    }
+
+    pub(crate) fn is_seeded(&self) -> bool {
+        self.crypto.rng.is_some()
+    }
+}
+
+impl OpenMlsProvider for DeterministicProvider {
+    type CryptoProvider = DeterministicCrypto;
+    type RandProvider = DeterministicCrypto;
+    type StorageProvider = MemoryStorage;
+
+    fn storage(&self) -> &Self::StorageProvider {
+        &self.key_store
+    }
+
+    fn crypto(&self) -> &Self::CryptoProvider {
+        &self.crypto
+    }
+
+    fn rand(&self) -> &Self::RandProvider {
+        &self.crypto
+    }
+}
+
+/// RustCrypto primitives.  When seeded, every operation that would consume
+/// randomness draws from the DRBG instead.
+#[derive(Default)]
+pub(crate) struct DeterministicCrypto {
+    inner: RustCrypto,
+    rng: Option<RwLock<ChaCha20Rng>>,
+}
+
+impl DeterministicCrypto {
+    fn fill(rng: &RwLock<ChaCha20Rng>, out: &mut [u8]) -> Result<(), RandError> {
+        rng.write()
+            .map_err(|_| RandError::LockPoisoned)?
+            .try_fill_bytes(out)
+            .map_err(|_| RandError::NotEnoughRandomness)
+    }
+
+    /// A one-shot HPKE instance whose encapsulation randomness comes from
+    /// the DRBG.
+    fn seeded_hpke(
+        &self,
+        rng: &RwLock<ChaCha20Rng>,
+        config: HpkeConfig,
+    ) -> Result<Hpke<HpkeRustCrypto>, CryptoError> {
+        let kem = match config.0 {
+            HpkeKemType::DhKemP256 => hpke_types::KemAlgorithm::DhKemP256,
+            HpkeKemType::DhKemP384 => hpke_types::KemAlgorithm::DhKemP384,
+            HpkeKemType::DhKemP521 => hpke_types::KemAlgorithm::DhKemP521,
+            HpkeKemType::DhKem25519 => hpke_types::KemAlgorithm::DhKem25519,
+            HpkeKemType::DhKem448 => hpke_types::KemAlgorithm::DhKem448,
+            HpkeKemType::XWingKemDraft6 => return Err(CryptoError::UnsupportedCiphersuite),
+        };
+        let kdf = match config.1 {
+            HpkeKdfType::HkdfSha256 => hpke_types::KdfAlgorithm::HkdfSha256,
+            HpkeKdfType::HkdfSha384 => hpke_types::KdfAlgorithm::HkdfSha384,
+            HpkeKdfType::HkdfSha512 => hpke_types::KdfAlgorithm::HkdfSha512,
+        };
+        let aead = match config.2 {
+            HpkeAeadType::AesGcm128 => hpke_types::AeadAlgorithm::Aes128Gcm,
+            HpkeAeadType::AesGcm256 => hpke_types::AeadAlgorithm::Aes256Gcm,
+            HpkeAeadType::ChaCha20Poly1305 => hpke_types::AeadAlgorithm::ChaCha20Poly1305,
+            HpkeAeadType::Export => hpke_types::AeadAlgorithm::HpkeExport,
+        };
+
+        let mut hpke = Hpke::<HpkeRustCrypto>::new(hpke_rs::Mode::Base, kem, kdf, aead);
+        let mut ephemeral = vec![0u8; kem.private_key_len()];
+        Self::fill(rng, &mut ephemeral).map_err(|_| CryptoError::InsufficientRandomness)?;
+        hpke.seed(&ephemeral)
+            .map_err(|_| CryptoError::InsufficientRandomness)?;
+        Ok(hpke)
+    }
+}
+
+impl OpenMlsRand for DeterministicCrypto {
+    type Error = RandError;
+
+    fn random_array<const N: usize>(&self) -> Result<[u8; N], Self::Error> {
+        match &self.rng {
+            Some(rng) => {
+                let mut out = [0u8; N];
+                Self::fill(rng, &mut out)?;
+                Ok(out)
+            }
+            None => self.inner.random_array(),
+        }
+    }
+
+    fn random_vec(&self, len: usize) -> Result<Vec<u8>, Self::Error> {
+        match &self.rng {
+            Some(rng) => {
+                let mut out = vec![0u8; len];
+                Self::fill(rng, &mut out)?;
+                Ok(out)
+            }
+            None => self.inner.random_vec(len),
+        }
+    }
+}
+
+impl OpenMlsCrypto for DeterministicCrypto {
+    fn supports(&self, ciphersuite: Ciphersuite) -> Result<(), CryptoError> {
+        self.inner.supports(ciphersuite)
+    }
+
+    fn supported_ciphersuites(&self) -> Vec<Ciphersuite> {
+        self.inner.supported_ciphersuites()
+    }
+
+    fn hkdf_extract(
+        &self,
+        hash_type: HashType,
+        salt: &[u8],
+        ikm: &[u8],
+    ) -> Result<SecretVLBytes, CryptoError> {
+        self.inner.hkdf_extract(hash_type, salt, ikm)
+    }
+
+    fn hmac(
+        &self,
+        hash_type: HashType,
+        key: &[u8],
+        message: &[u8],
+    ) -> Result<SecretVLBytes, CryptoError> {
+        self.inner.hmac(hash_type, key, message)
+    }
+
+    fn hkdf_expand(
+        &self,
+        hash_type: HashType,
+        prk: &[u8],
+        info: &[u8],
+        okm_len: usize,
+    ) -> Result<SecretVLBytes, CryptoError> {
+        self.inner.hkdf_expand(hash_type, prk, info, okm_len)
+    }
+
+    fn hash(&self, hash_type: HashType, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.inner.hash(hash_type, data)
+    }
+
+    fn aead_encrypt(
+        &self,
+        alg: AeadType,
+        key: &[u8],
+        data: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.inner.aead_encrypt(alg, key, data, nonce, aad)
+    }
+
+    fn aead_decrypt(
+        &self,
+        alg: AeadType,
+        key: &[u8],
+        ct_tag: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.inner.aead_decrypt(alg, key, ct_tag, nonce, aad)
+    }
+
+    fn signature_key_gen(&self, alg: SignatureScheme) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+        let Some(rng) = &self.rng else {
+            return self.inner.signature_key_gen(alg);
+        };
+        if alg != SignatureScheme::ED25519 {
+            return Err(CryptoError::UnsupportedSignatureScheme);
+        }
+        let mut secret = [0u8; 32];
+        Self::fill(rng, &mut secret).map_err(|_| CryptoError::InsufficientRandomness)?;
+        let sk = SigningKey::from_bytes(&secret);
+        Ok((sk.to_bytes().into(), sk.verifying_key().to_bytes().into()))
+    }
+
+    fn verify_signature(
+        &self,
+        alg: SignatureScheme,
+        data: &[u8],
+        pk: &[u8],
+        signature: &[u8],
+    ) -> Result<(), CryptoError> {
+        self.inner.verify_signature(alg, data, pk, signature)
+    }
+
+    fn sign(&self, alg: SignatureScheme, data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.inner.sign(alg, data, key)
+    }
+
+    fn hpke_seal(
+        &self,
+        config: HpkeConfig,
+        pk_r: &[u8],
+        info: &[u8],
+        aad: &[u8],
+        ptxt: &[u8],
+    ) -> Result<HpkeCiphertext, CryptoError> {
+        let Some(rng) = &self.rng else {
+            return self.inner.hpke_seal(config, pk_r, info, aad, ptxt);
+        };
+        let (kem_output, ciphertext) = self
+            .seeded_hpke(rng, config)?
+            .seal(&HpkePublicKey::from(pk_r), info, aad, ptxt, None, None, None)
+            .map_err(|_| CryptoError::CryptoLibraryError)?;
+        Ok(HpkeCiphertext {
+            kem_output: kem_output.into(),
+            ciphertext: ciphertext.into(),
+        })
+    }
+
+    fn hpke_open(
+        &self,
+        config: HpkeConfig,
+        input: &HpkeCiphertext,
+        sk_r: &[u8],
+        info: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.inner.hpke_open(config, input, sk_r, info, aad)
+    }
+
+    fn hpke_setup_sender_and_export(
+        &self,
+        config: HpkeConfig,
+        pk_r: &[u8],
+        info: &[u8],
+        exporter_context: &[u8],
+        exporter_length: usize,
+    ) -> Result<(KemOutput, ExporterSecret), CryptoError> {
+        let Some(rng) = &self.rng else {
+            return self.inner.hpke_setup_sender_and_export(
+                config,
+                pk_r,
+                info,
+                exporter_context,
+                exporter_length,
+            );
+        };
+        let (kem_output, context) = self
+            .seeded_hpke(rng, config)?
+            .setup_sender(&HpkePublicKey::from(pk_r), info, None, None, None)
+            .map_err(|_| CryptoError::SenderSetupError)?;
+        let exported = context
+            .export(exporter_context, exporter_length)
+            .map_err(|_| CryptoError::ExporterError)?;
+        Ok((kem_output, exported.into()))
+    }
+
+    fn hpke_setup_receiver_and_export(
+        &self,
+        config: HpkeConfig,
+        enc: &[u8],
+        sk_r: &[u8],
+        info: &[u8],
+        exporter_context: &[u8],
+        exporter_length: usize,
+    ) -> Result<ExporterSecret, CryptoError> {
+        self.inner.hpke_setup_receiver_and_export(
+            config,
+            enc,
+            sk_r,
+            info,
+            exporter_context,
+            exporter_length,
+        )
+    }
+
+    fn derive_hpke_keypair(
+        &self,
+        config: HpkeConfig,
+        ikm: &[u8],
+    ) -> Result<HpkeKeyPair, CryptoError> {
+        self.inner.derive_hpke_keypair(config, ikm)
+    }
+}
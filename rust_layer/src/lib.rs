@@ -1,17 +1,94 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::path::PathBuf;
-use std::fs::{self, File};
+use std::fs;
 
 use openmls::prelude::*;
-use openmls::prelude::tls_codec::{Serialize as TlsSerialize, Deserialize as TlsDeserialize};
-use openmls_rust_crypto::OpenMlsRustCrypto;
+use openmls::prelude::tls_codec::{Serialize as TlsSerialize, Deserialize as TlsDeserialize, Size as TlsSize, VLBytes};
 use openmls_basic_credential::SignatureKeyPair;
+use openmls_traits::storage::StorageProvider;
 use serde::{Serialize, Deserialize};
+use zeroize::{Zeroize, Zeroizing};
+use openmls::framing::errors::{MessageDecryptionError, SecretTreeError};
+use openmls::schedule::errors::PskError;
+use openmls::schedule::{ExternalPsk, PreSharedKeyId, Psk};
 use openmls::treesync::LeafNodeParameters;
 
+#[cfg(feature = "deterministic-tests")]
+mod deterministic;
+
+#[cfg(test)]
+mod tests;
+
+/// The OpenMLS provider backing every client.  Test builds swap in a
+/// provider whose randomness can be seeded.
+#[cfg(not(feature = "deterministic-tests"))]
+type MlsProvider = openmls_rust_crypto::OpenMlsRustCrypto;
+#[cfg(feature = "deterministic-tests")]
+type MlsProvider = deterministic::DeterministicProvider;
+
 const CIPHERSUITE: Ciphersuite = Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519;
 
+/// Private-use group context extension carrying application metadata
+/// (group name, avatar hash, ...).  Advertised in all our leaf nodes.
+const GROUP_METADATA_EXTENSION_TYPE: u16 = 0xff01;
+
+/// Private-use group context extension announcing the group's successor
+/// (see `propose_reinit()`).  An application-level migration, not an MLS
+/// ReInit, which OpenMLS can neither propose nor commit: only clients of
+/// this library act on it.  Its content is laid out like a ReInit
+/// proposal.  Advertised in all our leaf nodes.
+const MIGRATION_EXTENSION_TYPE: u16 = 0xff02;
+
+/// Private-use group context extension, with no content, marking groups
+/// whose application messages carry a payload envelope (see
+/// `GroupConfig::payload_envelope`).  Required of every member, so only
+/// clients that parse the envelope can join.  Advertised in all our leaf
+/// nodes.
+const PAYLOAD_ENVELOPE_EXTENSION_TYPE: u16 = 0xff03;
+
+/// Key packages expiring within this many seconds (a week) are replaced by
+/// `refresh_key_packages()`, leaving time to upload the new ones.
+const KEY_PACKAGE_REFRESH_MARGIN: u64 = 7 * 24 * 60 * 60;
+
+/// How many message digests to remember per group for duplicate detection,
+/// unless set with `new_with_replay_cache()`.
+const MESSAGE_CACHE_SIZE: usize = 256;
+
+/// Exporter label of `EpochDebugInfo::exporter_secret`.
+#[cfg(feature = "debug-secrets")]
+const EPOCH_DEBUG_LABEL: &str = "kotlin-mls debug";
+
+/// Exporter label of the per-epoch key of `encrypt_ephemeral()` messages.
+const EPHEMERAL_LABEL: &str = "kotlin-mls ephemeral";
+
+/// Domain separation labels for `get_group_fingerprint()` and
+/// `get_member_fingerprint()`.
+const FINGERPRINT_GROUP_LABEL: &[u8] = b"kotlin-mls group fingerprint";
+const FINGERPRINT_MEMBER_LABEL: &[u8] = b"kotlin-mls member fingerprint";
+
+/// Domain separation label of the epoch authenticator digest in
+/// `diagnostics()`.
+const DIAGNOSTICS_LABEL: &[u8] = b"kotlin-mls diagnostics";
+
+/// Domain separation label of `encrypt_signed_message()` signatures.
+const SIGNED_MESSAGE_LABEL: &[u8] = b"kotlin-mls signed message";
+
+/// Upper bound of the per-joiner part of a Welcome (key package ref,
+/// HPKE KEM output, and the encrypted joiner and path secrets), in bytes.
+const WELCOME_SECRETS_SIZE: u64 = 190;
+
+/// Size in bytes of a ratchet tree parent node: encryption key, parent
+/// hash and a few unmerged leaves.
+const PARENT_NODE_SIZE: u64 = 90;
+
+/// Default `InputLimits`, in decoded bytes.
+const MAX_HANDSHAKE_INPUT: u64 = 1024 * 1024;
+const MAX_APPLICATION_INPUT: u64 = 10 * 1024 * 1024;
+
+/// Most key packages `create_key_packages()` mints in one call.
+const MAX_KEY_PACKAGE_BATCH: u32 = 100;
+
 #[derive(Debug, thiserror::Error, uniffi::Error)]
 pub enum MlsError {
     #[error("Generic MLS error: {msg}")]
@@ -26,9 +103,122 @@ pub enum MlsError {
     SerializationError { msg: String },
     #[error("Identity not initialized. Call create_identity() first.")]
     IdentityNotInitialized { msg: String },
+    #[error("Duplicate message for group {group_id}: already processed")]
+    DuplicateMessage { group_id: String },
+    #[error("Own message reflected back for group {group_id}")]
+    OwnMessageReflected { group_id: String },
+    #[error("Stored state generation {found} is older than the high-water mark {expected_at_least}")]
+    StateRollbackDetected { found: u64, expected_at_least: u64 },
+    #[error("Group {group_id} requires a self_update() before sending")]
+    SelfUpdateRequired { group_id: String },
+    #[error("Unsupported capabilities: {msg}")]
+    UnsupportedCapabilities { msg: String },
+    #[error("Group is frozen: {reason}")]
+    GroupFrozen { reason: String },
+    #[error("Unknown pre-shared key: {psk_id}")]
+    UnknownPsk { psk_id: String },
+    #[error("Wrong passphrase: {msg}")]
+    WrongPassphrase { msg: String },
+    #[error("No members with identities: {identities:?}")]
+    UnknownMembers { identities: Vec<String> },
+    #[error("Cannot commit our own removal from group {group_id}; another member must remove us")]
+    CannotRemoveSelf { group_id: String },
+    #[error("Message is for past epoch {got}; group is at epoch {expected}")]
+    StaleMessage { expected: u64, got: u64 },
+    #[error("Message is for future epoch {got}; group is at epoch {expected}")]
+    FutureEpoch { expected: u64, got: u64 },
+    #[error("No longer a member of group {group_id}")]
+    NoLongerMember { group_id: String },
+    #[error("Key package belongs to a member of group {group_id} (leaf {leaf_index})")]
+    AlreadyMember { group_id: String, leaf_index: u32 },
+    #[error("Unsupported backup format version {version}")]
+    UnsupportedBackupVersion { version: u32 },
+    #[error("Group {group_id} has an unconfirmed commit of ours; abort_commit() it first")]
+    PendingCommitConflict { group_id: String },
+    #[error("{subject} is missing required capabilities: extensions {missing_extensions:?}, proposals {missing_proposals:?}, credentials {missing_credentials:?}")]
+    CapabilitiesMismatch {
+        subject: String,
+        missing_extensions: Vec<u16>,
+        missing_proposals: Vec<u16>,
+        missing_credentials: Vec<u16>,
+    },
+    #[error("Batch failed at message {index}: {message}")]
+    BatchFailed {
+        index: u32,
+        processed: Vec<IncomingMessage>,
+        /// The failing message's error variant, e.g. "InputTooLarge".
+        error_kind: String,
+        message: String,
+    },
+    #[error("Key package uses ciphersuite 0x{key_package:04x} but the group uses 0x{group:04x}")]
+    CiphersuiteMismatch { group: u16, key_package: u16 },
+    #[error("Welcome is for key packages {key_package_refs:?}, none of which this client holds")]
+    NoMatchingKeyPackage { key_package_refs: Vec<String> },
+    #[error("Key package expired at {not_after}")]
+    KeyPackageExpired { not_after: u64 },
+    #[error("Key package is not valid before {not_before}")]
+    KeyPackageNotYetValid { not_before: u64 },
+    #[error("Group {group_id} is unknown; the message was queued until we join it")]
+    QueuedForUnknownGroup { group_id: String },
+    #[error("Proposal for group {group_id} is from an external sender the group does not authorize")]
+    UnauthorizedExternalSender { group_id: String },
+    #[error("Commit for group {group_id} references proposals we have not received: {proposal_refs:?}")]
+    MissingProposal { group_id: String, proposal_refs: Vec<String> },
+    #[error("Commit rejected by policy: {reason}")]
+    CommitRejectedByPolicy { reason: String },
+    #[error("Invalid signature: {detail}")]
+    InvalidSignature { detail: String },
+    #[error("Invalid key package: {detail}")]
+    InvalidKeyPackage { detail: String },
+    #[error("Unknown member: {detail}")]
+    UnknownMember { detail: String },
+    #[error("Decryption failed: {detail}")]
+    DecryptionFailed { detail: String },
+    #[error("Protocol version mismatch: we speak {ours}, the message uses {theirs}")]
+    ProtocolVersionMismatch { ours: String, theirs: String },
+    #[error("A commit transaction is open for group {group_id}")]
+    CommitTransactionOpen { group_id: String },
+    #[error("Identity mismatch: expected {expected}, key package has {actual}")]
+    IdentityMismatch { expected: String, actual: String },
+    #[error("Message from epoch {message_epoch} is older than the oldest retained epoch {oldest_retained}")]
+    EpochTooOld { message_epoch: u64, oldest_retained: u64 },
+    #[error("Unknown identity: {name}")]
+    UnknownIdentity { name: String },
+    #[error("Input of {actual} bytes exceeds the limit of {limit}")]
+    InputTooLarge { limit: u64, actual: u64 },
+    #[error("Group {group_id} was reinitialized as group {new_group_id}")]
+    GroupReinitialized { group_id: String, new_group_id: String },
+    #[error("Ciphersuite 0x{ciphersuite:04x} is not supported")]
+    UnsupportedCiphersuite { ciphersuite: u16 },
+    #[error("Key package signature key is already added in this commit")]
+    DuplicateSignatureKey { group_id: String },
+    #[error("Identity {identity} is already a member or added in this commit")]
+    DuplicateIdentity { identity: String },
+    #[error("Group {group_id} is out of sync; rejoin it with a new Welcome")]
+    GroupDesynced { group_id: String },
+    #[error("Join is for epoch {bundle_epoch}, but the group is at epoch {current_epoch}; join from a fresh invite")]
+    InviteBundleStale { bundle_epoch: u64, current_epoch: u64 },
+    #[error("{structure} of {consumed} bytes is followed by {remaining} trailing bytes")]
+    TrailingBytes { structure: String, consumed: u64, remaining: u64 },
+    #[error("Group {group_id} is capped at {max_members} members")]
+    GroupFull { group_id: String, max_members: u32 },
+    #[error("Already a member of group {group_id}, at epoch {current_epoch}; mark_group_desynced() it first to replace it")]
+    GroupAlreadyJoined { group_id: String, current_epoch: u64 },
+    #[error("Message of group {group_id} is from epoch {message_epoch}, but the group is at epoch {current_epoch}")]
+    EpochMismatch { group_id: String, message_epoch: u64, current_epoch: u64 },
+    #[error("Unsupported state format version {version}; saved by a newer version of this library")]
+    UnsupportedStateVersion { version: u32 },
+    #[error("Removed from group {group_id} by a commit")]
+    SelfRemoved { group_id: String },
 }
 
 impl MlsError {
+    /// The variant's name, e.g. "InputTooLarge".
+    fn kind_name(&self) -> String {
+        let debug = format!("{:?}", self);
+        debug.split(|c: char| !c.is_alphanumeric()).next().unwrap_or_default().to_string()
+    }
+
     fn generic(msg: impl Into<String>) -> Self {
         MlsError::Generic { msg: msg.into() }
     }
@@ -51,6 +241,20 @@ impl MlsError {
             msg: "Internal lock was poisoned by a previous panic.".into(),
         }
     }
+
+    fn no_commit_txn() -> Self {
+        MlsError::Generic {
+            msg: "No commit transaction is open; call begin_commit() first.".into(),
+        }
+    }
+}
+
+/// A `StorageBackend` implementation threw something other than an
+/// `MlsError`.
+impl From<uniffi::UnexpectedUniFFICallbackError> for MlsError {
+    fn from(e: uniffi::UnexpectedUniFFICallbackError) -> Self {
+        MlsError::io(format!("Storage backend failed: {}", e.reason))
+    }
 }
 
 // ── Persistence types ──────────────────────────────────────────────────────
@@ -60,666 +264,11877 @@ struct PersistedIdentity {
     name: String,
     /// The full SignatureKeyPair serialized via serde
     signer_json: String,
+    #[serde(default)]
+    capabilities: ClientCapabilities,
+    #[serde(default)]
+    application_id: Option<Vec<u8>>,
+}
+
+/// Leaf node capabilities advertised by this client, as raw codepoints.
+/// Empty lists mean "use the OpenMLS defaults".
+#[derive(Clone, Debug, Default, Serialize, Deserialize, uniffi::Record)]
+pub struct ClientCapabilities {
+    /// Ciphersuites; ours is always included.
+    pub ciphersuites: Vec<u16>,
+    /// Non-default extension types, e.g. private-use group context
+    /// extensions.  The metadata extension is always included.
+    pub extensions: Vec<u16>,
+    /// Non-default proposal types.
+    #[serde(default)]
+    pub proposals: Vec<u16>,
+    /// Credential types; `Basic` is always included.
+    #[serde(default)]
+    pub credentials: Vec<u16>,
+}
+
+impl ClientCapabilities {
+    fn to_leaf_capabilities(&self) -> Result<Capabilities, MlsError> {
+        let ciphersuites = if self.ciphersuites.is_empty() {
+            None
+        } else {
+            let mut suites = self
+                .ciphersuites
+                .iter()
+                .map(|&cs| {
+                    Ciphersuite::try_from(cs).map_err(|_| MlsError::UnsupportedCapabilities {
+                        msg: format!("Unknown ciphersuite 0x{:04x}", cs),
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            // Our own key packages must always advertise the suite we use.
+            if !suites.contains(&CIPHERSUITE) {
+                suites.push(CIPHERSUITE);
+            }
+            Some(suites)
+        };
+        let mut extensions: Vec<ExtensionType> =
+            self.extensions.iter().map(|&e| ExtensionType::from(e)).collect();
+        // Every member must support our private extensions before they can
+        // be set in a group context.
+        for private in [
+            GROUP_METADATA_EXTENSION_TYPE,
+            MIGRATION_EXTENSION_TYPE,
+            PAYLOAD_ENVELOPE_EXTENSION_TYPE,
+        ] {
+            let private = ExtensionType::from(private);
+            if !extensions.contains(&private) {
+                extensions.push(private);
+            }
+        }
+
+        let proposals: Vec<ProposalType> =
+            self.proposals.iter().map(|&p| ProposalType::from(p)).collect();
+        let credentials = if self.credentials.is_empty() {
+            None
+        } else {
+            let mut types: Vec<CredentialType> =
+                self.credentials.iter().map(|&c| CredentialType::from(c)).collect();
+            // Our own credentials are always basic ones.
+            if !types.contains(&CredentialType::Basic) {
+                types.push(CredentialType::Basic);
+            }
+            Some(types)
+        };
+
+        Ok(Capabilities::new(
+            None,
+            ciphersuites.as_deref(),
+            Some(&extensions),
+            Some(&proposals),
+            credentials.as_deref(),
+        ))
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct PersistedGroupMeta {
     group_id: String,
     epoch: u64,
+    /// `None` for groups saved before timestamps were recorded.
+    #[serde(default)]
+    times: Option<GroupTimes>,
+    /// `None` for groups saved before settings were recorded.
+    #[serde(default)]
+    settings: Option<GroupSettings>,
+}
+
+/// The parts of a group's join configuration OpenMLS has no getter for,
+/// recorded when we create or join the group and kept up to date when we
+/// change them, and the past epochs whose messages we accept.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+struct GroupSettings {
+    use_ratchet_tree_extension: bool,
+    /// Past epochs whose application messages we accept (see
+    /// `set_max_past_epochs()`).
+    max_past_epochs: usize,
+    /// Past epochs OpenMLS keeps the secrets of.  It fixes the number when
+    /// the group is created or joined, so `max_past_epochs` can refuse
+    /// retained epochs but not retain more.
+    stored_past_epochs: usize,
+    /// The epoch we created or joined the group in, or the one
+    /// `purge_past_epochs()` was last called in.
+    first_accepted_epoch: u64,
+}
+
+/// The settings of groups saved before they were recorded: those of a
+/// group joined from a Welcome without past epochs.
+impl Default for GroupSettings {
+    fn default() -> Self {
+        Self {
+            use_ratchet_tree_extension: true,
+            max_past_epochs: 0,
+            stored_past_epochs: 0,
+            first_accepted_epoch: 0,
+        }
+    }
+}
+
+impl GroupSettings {
+    /// The settings of a group just created or joined with these options.
+    fn new(group: &MlsGroup, use_ratchet_tree_extension: bool, max_past_epochs: usize) -> Self {
+        Self {
+            use_ratchet_tree_extension,
+            max_past_epochs,
+            stored_past_epochs: max_past_epochs,
+            first_accepted_epoch: group.epoch().as_u64(),
+        }
+    }
+
+    /// The oldest epoch whose application messages we accept.
+    fn oldest_retained_epoch(&self, group: &MlsGroup) -> u64 {
+        let epoch = group.epoch().as_u64();
+        let retained = self.max_past_epochs.min(self.stored_past_epochs) as u64;
+        epoch
+            .saturating_sub(retained)
+            .max(self.first_accepted_epoch)
+            .min(epoch)
+    }
+}
+
+/// When a group was created or joined, and when a commit last changed it,
+/// in seconds since the Unix epoch (see `get_group_info()`).  Application
+/// metadata only; not part of the group state.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct GroupTimes {
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl GroupTimes {
+    fn now() -> Self {
+        let now = unix_time();
+        Self {
+            created_at: now,
+            updated_at: now,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct PersistedState {
+    /// Format version (see `STATE_VERSION`); 0 for files saved before it
+    /// was recorded.
+    #[serde(default)]
+    version: u32,
     identity: Option<PersistedIdentity>,
     groups: Vec<PersistedGroupMeta>,
+    /// Monotonic counter bumped on every save; guards against rollback.
+    #[serde(default)]
+    generation: u64,
+    /// Groups that must self-update before sending (after a forced rollback).
+    #[serde(default)]
+    pending_self_updates: Vec<String>,
+    /// Frozen groups and the reason they were frozen.
+    #[serde(default)]
+    frozen_groups: HashMap<String, String>,
+    /// Hex IDs of external PSKs registered with `store_external_psk()`.
+    #[serde(default)]
+    external_psks: Vec<String>,
+    /// Hex references of regular key packages we have added to groups.
+    #[serde(default)]
+    used_key_packages: Vec<String>,
+    /// Groups we have been removed from.
+    #[serde(default)]
+    removed_groups: Vec<String>,
+    /// Per group, the generation our next application message gets.
+    #[serde(default)]
+    send_generations: HashMap<String, MessageGeneration>,
+    /// Per group and hex sender identity, the latest generation received.
+    #[serde(default)]
+    seen_generations: HashMap<String, HashMap<String, MessageGeneration>>,
+    /// Per group, the sequence number of our next framed message.
+    #[serde(default)]
+    send_sequences: HashMap<String, u64>,
+    /// Reinitialized groups and the group replacing each.
+    #[serde(default)]
+    reinits: HashMap<String, MigrationTarget>,
+    /// Identities registered with `add_identity()` besides the default
+    /// one, by the name they were registered under.
+    #[serde(default)]
+    identities: HashMap<String, PersistedIdentity>,
+    /// Groups belonging to one of `identities`, and its name.
+    #[serde(default)]
+    group_identities: HashMap<String, String>,
+    /// Groups with a signature key of their own, and the key serialized
+    /// as in `PersistedIdentity::signer_json`.
+    #[serde(default)]
+    group_signers: HashMap<String, String>,
+    /// Groups with a credential identity of their own, and the identity.
+    #[serde(default)]
+    group_credentials: HashMap<String, String>,
+    /// Groups marked out of sync with `mark_group_desynced()`.
+    #[serde(default)]
+    desynced_groups: Vec<String>,
+    /// Per group, the member cap set with `set_max_members()`.
+    #[serde(default)]
+    max_members: HashMap<String, u32>,
+    /// Per group, add commits not yet acknowledged with `ack_commit()`.
+    #[serde(default)]
+    commit_journal: HashMap<String, Vec<UnackedCommit>>,
+    /// Sequence number of the last add commit we created.
+    #[serde(default)]
+    commit_sequence: u64,
+    /// Per group, when we last sent, received or processed a message.
+    #[serde(default)]
+    group_activity: HashMap<String, u64>,
+    /// Hex SHA-256 digests of the Welcomes we joined from, and the group
+    /// each joined.
+    #[serde(default)]
+    joined_welcomes: HashMap<String, String>,
+}
+
+impl PersistedState {
+    /// Upgrades a state saved by an older version of this library to
+    /// `STATE_VERSION`, one version at a time.  Fails with
+    /// `UnsupportedStateVersion` for states saved by a newer version,
+    /// whose fields we might silently drop.
+    fn migrate(mut self) -> Result<Self, MlsError> {
+        if self.version > STATE_VERSION {
+            return Err(MlsError::UnsupportedStateVersion { version: self.version });
+        }
+        if self.version == 0 {
+            // Unversioned states differ only in fields that default when
+            // missing.
+            self.version = 1;
+        }
+        Ok(self)
+    }
+}
+
+/// The successor group a migration commit announced (see
+/// `MIGRATION_EXTENSION_TYPE`).
+#[derive(Clone, Serialize, Deserialize)]
+struct MigrationTarget {
+    /// Hex-encoded group ID.
+    group_id: String,
+    ciphersuite: u16,
+    /// TLS-encoded group context extensions.
+    extensions: Vec<u8>,
+}
+
+/// An `export_group()` blob: the group's key store entries plus this
+/// client's bookkeeping for it.
+#[derive(Serialize, Deserialize)]
+struct GroupBackup {
+    /// Hex-encoded group ID.
+    group_id: String,
+    /// Base64 key → base64 value, as in `openmls_store.json`.
+    store: HashMap<String, String>,
+    #[serde(default)]
+    send_generation: Option<MessageGeneration>,
+    #[serde(default)]
+    send_sequence: Option<u64>,
+    #[serde(default)]
+    self_update_required: bool,
+    #[serde(default)]
+    times: Option<GroupTimes>,
+    #[serde(default)]
+    settings: Option<GroupSettings>,
+}
+
+/// Plaintext of an `export_state()` blob: `state.json` plus the key store,
+/// in their on-disk formats.
+#[derive(Serialize, Deserialize)]
+struct StateBackup {
+    state: PersistedState,
+    /// Base64 key → base64 value, as in `openmls_store.json`.
+    store: HashMap<String, String>,
 }
 
 // ── Member info returned to Kotlin ─────────────────────────────────────────
 
-#[derive(uniffi::Record)]
+#[derive(Debug, uniffi::Record)]
 pub struct MemberInfo {
     pub index: u32,
     pub identity: Vec<u8>,
+    pub signature_key: Vec<u8>,
+    /// Contents of the member's application_id leaf extension, if any.
+    pub application_id: Option<Vec<u8>>,
+    /// Leaf node extensions of types this client does not know, e.g. from
+    /// a newer client.
+    pub unknown_extensions: Vec<RawExtension>,
 }
 
-// ── Internal state (behind Mutex) ──────────────────────────────────────────
-
-struct MlsClientState {
-    groups: HashMap<String, MlsGroup>,
-    crypto: OpenMlsRustCrypto,
-    /// `None` until `create_identity()` is called.
-    signer: Option<SignatureKeyPair>,
-    /// `None` until `create_identity()` is called.
-    credential: Option<CredentialWithKey>,
-    identity_name: Option<String>,
+/// Who invited us into which group, as read from a Welcome by
+/// `stage_welcome()` before joining.
+#[derive(Debug, uniffi::Record)]
+pub struct WelcomePreview {
+    pub group_id: String,
+    /// Leaf index of the member who added us and signed the Welcome.
+    pub inviter_index: u32,
+    /// Credential identity of that member.
+    pub inviter_identity: Vec<u8>,
+    /// The epoch we would join at.
+    pub epoch: u64,
+    /// IANA codepoint of the group's ciphersuite.
+    pub ciphersuite: u16,
+    /// Number of members, us included.
+    pub member_count: u32,
+    /// The group's metadata, as `get_group_metadata()` would return it.
+    pub metadata: Option<Vec<u8>>,
 }
 
-// ── Public API ─────────────────────────────────────────────────────────────
+/// Outcome of `save_state()`, per group.  A group that fails does not
+/// prevent the others from being saved.
+#[derive(uniffi::Record)]
+pub struct SaveReport {
+    /// Groups written to disk and verified to be restorable.
+    pub saved_groups: Vec<String>,
+    /// Groups we have been removed from.  They are not saved and will not
+    /// be restored.
+    pub removed_groups: Vec<String>,
+    /// Groups that could not be saved, with the reason.
+    pub failed_groups: Vec<GroupSaveFailure>,
+}
 
-#[derive(uniffi::Object)]
-pub struct MlsClient {
-    state: Arc<Mutex<MlsClientState>>,
-    storage_path: PathBuf,
+#[derive(uniffi::Record)]
+pub struct GroupSaveFailure {
+    pub group_id: String,
+    pub reason: String,
 }
 
-#[uniffi::export]
-impl MlsClient {
-    // ── Constructor ────────────────────────────────────────────────────
+/// The messages of an add-member commit created by `add_member_prepare`.
+#[derive(uniffi::Record)]
+pub struct AddMemberResult {
+    /// Encoded commit, for the existing members.
+    pub commit: String,
+    /// Encoded welcome, for the new member once the commit is confirmed.
+    pub welcome: String,
+    /// Whether we already added someone with this (regular) key package.
+    /// Its owner will most likely fail to join; ask them for a fresh one.
+    pub key_package_reused: bool,
+    /// Serialized sizes in bytes, before encoding.
+    pub commit_size: u64,
+    pub welcome_size: u64,
+    /// Size of the GroupInfo for the new epoch, 0 if none was created.
+    pub group_info_size: u64,
+    /// Whether the welcome lacks the ratchet tree (see
+    /// `add_member_with_welcome_limit()`).  The new member then needs the
+    /// tree from `export_ratchet_tree()`, taken after the commit is merged.
+    pub ratchet_tree_omitted: bool,
+    /// Whether the new member's identity is already in the group, let
+    /// through by `DuplicateIdentityPolicy::Flag`.
+    pub duplicate_identity: bool,
+    /// Hex-encoded hash of the commit, identifying the commit and welcome
+    /// pair until `ack_commit()` (see `get_unacked_commits()`).
+    pub commit_ref: String,
+    /// Hex-encoded ref of the new member's key package (comma-separated
+    /// refs, for `update_membership()` with several).
+    pub key_package_ref: String,
+    /// The epoch the commit creates.
+    pub epoch: u64,
+    /// Local sequence number, increasing with every add commit we create.
+    pub sequence: u64,
+}
 
-    #[uniffi::constructor]
-    pub fn new(storage_path: String) -> Self {
-        let crypto = OpenMlsRustCrypto::default();
+/// A commit and welcome pair from an add, kept until the application
+/// acknowledges its delivery with `ack_commit()`.
+#[derive(Clone, Serialize, Deserialize, uniffi::Record)]
+pub struct UnackedCommit {
+    pub commit_ref: String,
+    pub key_package_ref: String,
+    pub epoch: u64,
+    pub sequence: u64,
+    /// Encoded commit and welcome, as `add_member()` returned them.
+    pub commit: String,
+    pub welcome: String,
+}
 
-        let client = Self {
-            state: Arc::new(Mutex::new(MlsClientState {
-                groups: HashMap::new(),
-                crypto,
-                signer: None,
-                credential: None,
-                identity_name: None,
-            })),
-            storage_path: PathBuf::from(storage_path),
-        };
+/// What adding a member whose credential identity is already in the group
+/// (or added in the same commit) does.  Set with
+/// `set_duplicate_identity_policy()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, uniffi::Enum)]
+pub enum DuplicateIdentityPolicy {
+    /// Add them and report it (`AddMemberResult.duplicate_identity`, the
+    /// result of `txn_add()`), e.g. for one user on several devices.
+    #[default]
+    Flag,
+    /// Fail with `DuplicateIdentity`.
+    Reject,
+}
 
-        // Attempt to restore a previously-persisted identity.
-        let _ = client.load_state();
+/// What to do with bytes left over after an incoming key package or
+/// message has been parsed, e.g. junk appended by a faulty relay.  Set
+/// with `set_deserialization_mode()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, uniffi::Enum)]
+pub enum DeserializationMode {
+    /// Ignore them, logging a warning and counting the structure in
+    /// `MetricsSnapshot.trailing_bytes_ignored`.
+    #[default]
+    Permissive,
+    /// Fail with `TrailingBytes`.
+    Strict,
+}
 
-        client
-    }
+/// An identity registered by `create_identity_with_key_package()`.
+#[derive(uniffi::Record)]
+pub struct CreatedIdentity {
+    /// The identity's name, to pass as `identity` to other methods.
+    pub identity_id: String,
+    /// Encoded key package for the identity.
+    pub key_package: String,
+}
 
-    // ── Identity management ────────────────────────────────────────────
+/// The messages of a commit created by `finish_commit()`.
+#[derive(uniffi::Record)]
+pub struct CommitBundle {
+    /// Encoded commit, for the existing members.
+    pub commit: String,
+    /// Encoded welcome, for the added members; `None` if nobody was added.
+    pub welcome: Option<String>,
+}
 
-    /// Creates (or recreates) this client's cryptographic identity.
-    ///
-    /// **Must** be called before any group operations.
-    /// Returns the identity name on success.
-    pub fn create_identity(&self, name: String) -> Result<String, MlsError> {
-        let mut state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
+/// A group's state as `get_group_diagnostics()` reports it.  Members in
+/// the same epoch agree on every field except `own_leaf_index`.
+#[derive(Debug, uniffi::Record)]
+pub struct GroupDiagnostics {
+    pub epoch: u64,
+    /// Hex tree hash of the ratchet tree.
+    pub tree_hash: String,
+    /// Hex confirmed transcript hash.
+    pub confirmed_transcript_hash: String,
+    /// Leaves in the tree, blank or not.
+    pub leaf_count: u32,
+    /// Leaves left blank by removals.
+    pub blank_leaf_count: u32,
+    pub own_leaf_index: u32,
+    /// IANA codepoint of the group's ciphersuite.
+    pub ciphersuite: u16,
+    /// Types of the group context extensions, in context order.
+    pub extension_types: Vec<u16>,
+    /// The group's MLS protocol version, e.g. `"mls10"`.
+    pub protocol_version: String,
+    /// The oldest epoch whose application messages can still be decrypted;
+    /// `epoch` itself if no past epoch is retained (see
+    /// `set_max_past_epochs()`).
+    pub oldest_retained_epoch: u64,
+}
 
-        let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm())
-            .map_err(|e| MlsError::crypto(format!("Failed to create signature keys: {:?}", e)))?;
+/// A short code for comparing group state out of band, e.g. on a "verify
+/// this chat" screen.
+#[derive(uniffi::Record)]
+pub struct Fingerprint {
+    /// 30 decimal digits in six groups of five, e.g. `"12345 67890 ..."`.
+    pub code: String,
+    /// The epoch the code belongs to.  Every commit changes it, so two codes
+    /// are only comparable if computed in the same epoch; warn if the
+    /// group has moved on since the code was shown.
+    pub epoch: u64,
+}
 
-        let credential = CredentialWithKey {
-            credential: Credential::new(CredentialType::Basic, name.clone().into_bytes()),
-            signature_key: signer.to_public_vec().into(),
-        };
+/// A commit created in one of several groups at once.
+#[derive(uniffi::Record)]
+pub struct GroupCommit {
+    pub group_id: String,
+    /// Encoded commit, to broadcast to the group's other members.
+    pub commit: String,
+}
 
-        // Store the key pair in the crypto provider so that OpenMLS can find
-        // the private key when processing Welcome messages later.
-        signer
-            .store(state.crypto.storage())
-            .map_err(|e| MlsError::crypto(format!("Failed to store signer: {:?}", e)))?;
+/// The outcome of `rotate_all_groups()` in one group.
+#[derive(Debug, uniffi::Record)]
+pub struct GroupRotation {
+    pub group_id: String,
+    /// Encoded self-update commit, already merged; broadcast it to the
+    /// group's other members.  `None` if the group was skipped.
+    pub commit: Option<String>,
+    /// Why the group was skipped, e.g. a pending commit of ours.
+    pub skipped_reason: Option<String>,
+}
 
-        state.signer = Some(signer);
-        state.credential = Some(credential);
-        state.identity_name = Some(name.clone());
+/// Settings for `create_group_with_config()`.
+#[derive(uniffi::Record)]
+pub struct GroupConfig {
+    /// Embed the ratchet tree in Welcome messages.  Without it Welcomes
+    /// are smaller, but joiners also need the tree from
+    /// `export_ratchet_tree()` (see `process_welcome_with_ratchet_tree()`).
+    pub use_ratchet_tree_extension: bool,
+    /// Send handshake messages as `PublicMessage`, as with
+    /// `set_wire_format_policy()`.
+    pub wire_format_public: bool,
+    /// Block size application messages are padded to, as with
+    /// `set_padding_size()`; 0 disables padding.
+    pub padding_size: u32,
+    /// Non-members (e.g. the delivery service) allowed to send proposals
+    /// to the group, listed in its external_senders extension.  A
+    /// sender's position in the list is its index in their proposals.
+    pub external_senders: Vec<ExternalSenderKey>,
+    /// How many past epochs' secrets to keep for decrypting late
+    /// application messages, as with `set_max_past_epochs()`.
+    pub max_past_epochs: u32,
+    /// Wrap application messages, inside their encryption, in an envelope
+    /// carrying the payload kind, the sender's generation and the header
+    /// of framed messages (see `send_control_message()`,
+    /// `DecryptedMessage`).  Every member must support it, as clients of
+    /// this library do; others cannot be added.  Without it payloads are
+    /// sent as they are, for any MLS client to read, but only chat can be
+    /// sent and received messages carry no generation or header.
+    pub payload_envelope: bool,
+}
 
-        // Persist identity to disk so it survives restarts.
-        drop(state);
-        let _ = self.persist_state();
+/// Largest inputs accepted, in bytes once decoded (see
+/// `set_input_limits()`).  Larger ones fail with `InputTooLarge` before
+/// they are decoded.
+#[derive(Clone, Copy, Debug, uniffi::Record)]
+pub struct InputLimits {
+    pub key_package: u64,
+    /// Also applies to proposals.
+    pub commit: u64,
+    /// Also applies to GroupInfos and ratchet trees.
+    pub welcome: u64,
+    /// Also applies to messages of unknown type, as passed to
+    /// `process_message()`.
+    pub application_message: u64,
+}
 
-        Ok(name)
+impl Default for InputLimits {
+    fn default() -> Self {
+        Self {
+            key_package: MAX_HANDSHAKE_INPUT,
+            commit: MAX_HANDSHAKE_INPUT,
+            welcome: MAX_HANDSHAKE_INPUT,
+            application_message: MAX_APPLICATION_INPUT,
+        }
     }
+}
 
-    /// Generates a fresh key package for the current identity.
-    ///
-    /// Key packages are single-use; call this each time you need to be
-    /// added to a new group.  Returns a hex-encoded key package.
-    pub fn generate_key_package(&self) -> Result<String, MlsError> {
-        let state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
-
-        let signer = state.signer.as_ref().ok_or_else(MlsError::no_identity)?;
-        let credential = state.credential.as_ref().ok_or_else(MlsError::no_identity)?;
+/// A non-member allowed to send proposals to a group.
+#[derive(uniffi::Record)]
+pub struct ExternalSenderKey {
+    /// Basic credential identity.
+    pub identity: Vec<u8>,
+    /// Signature public key their proposals are verified with.
+    pub signature_key: Vec<u8>,
+}
 
-        let kp = KeyPackage::builder()
-            .build(CIPHERSUITE, &state.crypto, signer, credential.clone())
-            .map_err(|e| MlsError::crypto(format!("Failed to build key package: {:?}", e)))?;
+/// A key package from `generate_key_packages()`.
+#[derive(uniffi::Record)]
+pub struct GeneratedKeyPackage {
+    /// The encoded key package, to publish.
+    pub key_package: String,
+    /// Hex-encoded KeyPackageRef, the hash by which the delivery service
+    /// and Welcomes refer to it.
+    pub key_package_ref: String,
+}
 
-        let bytes = kp
-            .key_package()
-            .tls_serialize_detached()
-            .map_err(|e| MlsError::serialization(format!("Failed to serialize key package: {:?}", e)))?;
+/// One of our key packages whose private key material is still stored, as
+/// listed by `list_stored_key_packages()`.
+#[derive(uniffi::Record)]
+pub struct KeyPackageRefInfo {
+    /// Hex-encoded KeyPackageRef.
+    pub key_package_ref: String,
+    /// Credential identity of the key package's leaf.
+    pub identity: Vec<u8>,
+    pub ciphersuite: u16,
+    /// End of the key package's lifetime, in seconds since the Unix epoch.
+    pub not_after: u64,
+    pub last_resort: bool,
+}
 
-        Ok(hex::encode(bytes))
-    }
+/// When one of our stored key packages expires, as listed by
+/// `key_package_expirations()`.
+#[derive(Debug, uniffi::Record)]
+pub struct KeyPackageExpiry {
+    /// Hex-encoded KeyPackageRef.
+    pub key_package_ref: String,
+    /// Credential identity of the key package's leaf.
+    pub identity: Vec<u8>,
+    /// End of the key package's lifetime, in seconds since the Unix epoch.
+    pub not_after: u64,
+    /// Whether the lifetime has ended: peers refuse the key package.
+    pub expired: bool,
+    /// Whether `refresh_key_packages()` would replace it: it is expired or
+    /// expires within `KEY_PACKAGE_REFRESH_MARGIN`.
+    pub needs_refresh: bool,
+}
 
-    /// Returns whether an identity has been created.
-    pub fn has_identity(&self) -> bool {
-        self.state
-            .lock()
-            .map(|s| s.signer.is_some())
-            .unwrap_or(false)
-    }
+/// The group replacing a reinitialized one (see `propose_reinit()`).
+#[derive(uniffi::Record)]
+pub struct ReInitInfo {
+    /// Hex-encoded ID of the new group.
+    pub new_group_id: String,
+    /// The new group's ciphersuite (IANA codepoint).
+    pub ciphersuite: u16,
+}
 
-    // ── Group lifecycle ────────────────────────────────────────────────
+/// Key schedule outputs of a group's current epoch, hex-encoded, to find
+/// where another MLS implementation diverges from ours (see
+/// `export_epoch_debug_info()`).  None of them decrypts messages.
+#[derive(uniffi::Record)]
+pub struct EpochDebugInfo {
+    pub epoch: u64,
+    /// Exporter secret for the label `EPOCH_DEBUG_LABEL`, with an empty
+    /// context, 32 bytes long.
+    pub exporter_secret: String,
+    pub epoch_authenticator: String,
+    /// TLS-encoded, as in the commit that created the epoch.  A MAC under
+    /// the epoch's confirmation key, which OpenMLS does not expose: equal
+    /// tags over equal transcript hashes mean equal confirmation keys.
+    pub confirmation_tag: String,
+    pub tree_hash: String,
+    pub confirmed_transcript_hash: String,
+}
 
-    /// Creates a new MLS group.  Returns the hex-encoded group ID.
-    pub fn create_group(&self, _group_id: String) -> Result<String, MlsError> {
-        let mut state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
+/// A group in the saved state, as `list_restorable_groups()` reports it.
+#[derive(uniffi::Record)]
+pub struct SavedGroup {
+    pub group_id: String,
+    /// The group's epoch when it was saved.
+    pub epoch: u64,
+    /// Whether the group's private state is saved and loads.  If not, the
+    /// group needs a rejoin to work after a restart.
+    pub restorable: bool,
+    /// Why the group cannot be restored; `None` if it can.
+    pub reason: Option<String>,
+}
 
-        let signer = state.signer.as_ref().ok_or_else(MlsError::no_identity)?;
-        let credential = state.credential.as_ref().ok_or_else(MlsError::no_identity)?;
+/// A group joined by external commit with `join_from_invite_bundle()`.
+#[derive(uniffi::Record)]
+pub struct ExternalJoinResult {
+    pub group_id: String,
+    /// Encoded commit, for all members (via `process_commit`).
+    pub commit: String,
+    /// The epoch the commit creates, which we are now in.
+    pub epoch: u64,
+}
 
-        let config = MlsGroupCreateConfig::builder()
-            .use_ratchet_tree_extension(true)
-            .build();
+/// A group just joined with `process_welcome_full()`.
+#[derive(uniffi::Record)]
+pub struct JoinedGroup {
+    pub group_id: String,
+    /// Hex-encoded ref of the key package the Welcome consumed, so the
+    /// delivery service can drop it from its pool.
+    pub key_package_ref: String,
+    /// Leaf index of the member who added us and signed the Welcome.
+    pub inviter_index: u32,
+    /// Credential identity of that member, e.g. to show who added us.
+    pub inviter_identity: Vec<u8>,
+    /// Epoch and members as of the Welcome, before queued messages.
+    pub epoch: u64,
+    pub members: Vec<MemberInfo>,
+    /// IANA codepoint of the group's ciphersuite.
+    pub ciphersuite: u16,
+    pub own_leaf_index: u32,
+    /// The group's metadata, as `get_group_metadata()` returns it.
+    pub metadata: Option<Vec<u8>>,
+    /// Results of the messages queued for the group before we joined it,
+    /// in arrival order (see `set_pending_queue_limits()`).
+    pub queued: Vec<IncomingMessage>,
+}
 
-        let group = MlsGroup::new(&state.crypto, signer, &config, credential.clone())
-            .map_err(|e| MlsError::generic(format!("Failed to create group: {:?}", e)))?;
+/// How our epoch of a group compares to one observed elsewhere, e.g. on
+/// the delivery service (see `check_epoch()`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum EpochStatus {
+    InSync,
+    /// We are `by` epochs behind: commits were not delivered to us.
+    Behind { by: u64 },
+    /// We are `by` epochs ahead of the observed epoch.
+    Ahead { by: u64 },
+}
 
-        let gid = hex::encode(group.group_id().as_slice());
-        state.groups.insert(gid.clone(), group);
-        Ok(gid)
-    }
+/// What another member needs to re-add us to a group we fell out of sync
+/// with (see `request_rejoin_info()`).
+#[derive(uniffi::Record)]
+pub struct RejoinInfo {
+    pub group_id: String,
+    /// Our last known epoch of the group.
+    pub epoch: u64,
+    /// A fresh key package of the identity we are a member as.
+    pub key_package: String,
+}
 
-    /// Adds a member to an existing group.
-    ///
-    /// Returns JSON: `{"commit":"<hex>","welcome":"<hex>"}`.
-    /// * Send the **commit** to all *existing* members (via `process_commit`).
-    /// * Send the **welcome** to the *new* member (via `process_welcome`).
-    pub fn add_member(
-        &self,
-        group_id: String,
-        new_member_key_package_hex: String,
-    ) -> Result<String, MlsError> {
-        let mut state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
+/// Whether we are still a member of a group (see `list_groups_detailed()`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum MembershipStatus {
+    Active,
+    /// Another member removed us; the group state is gone.
+    Removed,
+    /// Marked out of sync with `mark_group_desynced()`.
+    Desynced,
+}
 
-        let kp_bytes = hex::decode(&new_member_key_package_hex)
-            .map_err(|e| MlsError::serialization(format!("Invalid hex: {:?}", e)))?;
+/// One group, as `list_groups_detailed()` reports it.
+#[derive(Clone, Debug, uniffi::Record)]
+pub struct GroupSummary {
+    pub group_id: String,
+    /// `None` for groups we were removed from.
+    pub epoch: Option<u64>,
+    /// 0 for groups we were removed from.
+    pub member_count: u32,
+    pub status: MembershipStatus,
+    /// `None` for groups we were removed from.
+    pub ciphersuite: Option<u16>,
+    /// When we last encrypted, decrypted or processed a message for the
+    /// group or, before that, when a commit last changed it, in seconds
+    /// since the Unix epoch.
+    pub last_activity: Option<u64>,
+    /// Whether the group is frozen (see `freeze_group()`); always false
+    /// for groups we were removed from.
+    pub frozen: bool,
+}
 
-        let kp_in = KeyPackageIn::tls_deserialize(&mut kp_bytes.as_slice())
-            .map_err(|e| MlsError::serialization(format!("Invalid key package: {:?}", e)))?;
+/// Narrows and orders `list_groups_detailed()`.
+#[derive(Clone, Debug, uniffi::Record)]
+pub struct GroupFilter {
+    /// Only groups with this status.
+    pub status: Option<MembershipStatus>,
+    /// Only groups with at least this many members.
+    pub min_members: Option<u32>,
+    /// Most recently active first, instead of by group ID.
+    pub newest_first: bool,
+}
 
-        // Destructure so the borrow-checker can see independent borrows.
-        let MlsClientState {
-            groups,
-            crypto,
-            signer,
-            ..
-        } = &mut *state;
+/// What a staged (processed but not yet merged) commit would change.
+#[derive(uniffi::Record)]
+pub struct StagedCommitDescription {
+    /// Credential identity of the member who sent the commit (the joiner,
+    /// for an external commit).
+    pub sender_identity: Vec<u8>,
+    /// Credential identities of members the commit adds.
+    pub added_identities: Vec<Vec<u8>>,
+    /// Credential identities of members the commit removes.
+    pub removed_identities: Vec<Vec<u8>>,
+    /// Whether the commit removes this client from the group.
+    pub self_removed: bool,
+    /// Number of update proposals the commit applies.
+    pub update_count: u32,
+    /// Hex IDs of the external PSKs the commit injects.
+    pub psk_ids: Vec<String>,
+    /// The group context extensions after the commit, if it changes them.
+    pub new_extensions: Option<Vec<RawExtension>>,
+}
 
-        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+/// A proposal queued in a group but not yet committed.
+#[derive(Debug, uniffi::Record)]
+pub struct ProposalInfo {
+    /// Proposal type, e.g. `"Add"`, `"Remove"`, `"Update"`.
+    pub proposal_type: String,
+    /// Leaf index of the member being removed or updated.
+    pub leaf_index: Option<u32>,
+    /// Credential identity of the affected member (the joiner for adds).
+    pub identity: Option<Vec<u8>>,
+    /// Leaf index of the proposer, if it is a group member.
+    pub sender_index: Option<u32>,
+    /// Index of the proposer in the group's external senders (see
+    /// `GroupConfig.external_senders`), if it is one.
+    pub external_sender_index: Option<u32>,
+    /// Hex proposal reference, as listed by `MissingProposal`.
+    pub proposal_ref: String,
+}
 
-        let key_package = kp_in
-            .validate(crypto.crypto(), ProtocolVersion::default())
-            .map_err(|e| MlsError::crypto(format!("Key package validation failed: {:?}", e)))?;
+/// Why queued proposals cannot be committed together as they are, as
+/// reported by `validate_pending_proposals()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum ProposalConflictKind {
+    /// The same member is removed more than once.
+    DuplicateRemove,
+    /// A member is both updated and removed.
+    UpdateOfRemovedMember,
+    /// We are removed: a member cannot commit its own removal.
+    RemovesCommitter,
+    /// The same key package signature key is added more than once.
+    DuplicateAdd,
+    /// The joiner's signature key is already a member's.
+    AlreadyMember,
+    /// More than one GroupContextExtensions proposal is queued.
+    MultipleGroupContextExtensions,
+    /// A ReInit proposal is queued alongside other proposals.
+    ReInitNotAlone,
+    /// A PSK proposal references a PSK not registered with
+    /// `store_external_psk()`.
+    UnknownPsk,
+    /// The adds would take the group past its member cap (see
+    /// `set_max_members()`).
+    GroupFull,
+    /// OpenMLS refuses to build the commit; see the reason.
+    Rejected,
+}
 
-        let group = groups
-            .get_mut(&group_id)
-            .ok_or_else(|| MlsError::GroupNotFound {
-                group_id: group_id.clone(),
-            })?;
+/// A conflict among a group's queued proposals.
+#[derive(Debug, uniffi::Record)]
+pub struct ProposalConflict {
+    pub kind: ProposalConflictKind,
+    /// Human-readable explanation, e.g. naming the leaf concerned.
+    pub reason: String,
+    /// Hex references of the proposals involved (see `ProposalInfo`).
+    pub proposal_refs: Vec<String>,
+}
 
-        let (commit, welcome, _gi) = group
-            .add_members(crypto, signer, &[key_package])
-            .map_err(|e| MlsError::generic(format!("Failed to add member: {:?}", e)))?;
+/// The result of `validate_pending_proposals()`.
+#[derive(Debug, uniffi::Record)]
+pub struct ProposalValidation {
+    /// Number of proposals queued in the group.
+    pub proposal_count: u32,
+    /// Whether `commit_pending_proposals()` would succeed.
+    pub committable: bool,
+    /// Conflicts found among the queued proposals.
+    pub conflicts: Vec<ProposalConflict>,
+}
 
-        group
-            .merge_pending_commit(crypto)
-            .map_err(|e| MlsError::generic(format!("Failed to merge pending commit: {:?}", e)))?;
+/// An extension as its raw codepoint and TLS-encoded body.
+#[derive(Clone, Debug, uniffi::Record)]
+pub struct RawExtension {
+    pub extension_type: u16,
+    pub data: Vec<u8>,
+}
 
-        let commit_hex = hex::encode(
-            commit
-                .tls_serialize_detached()
-                .map_err(|e| MlsError::serialization(format!("{:?}", e)))?,
-        );
-        let welcome_hex = hex::encode(
-            welcome
-                .tls_serialize_detached()
-                .map_err(|e| MlsError::serialization(format!("{:?}", e)))?,
-        );
+impl RawExtension {
+    /// Parses the extension as OpenMLS would on the wire, so that known
+    /// types (required capabilities, external senders, ...) are validated.
+    fn into_extension(self) -> Result<Extension, MlsError> {
+        let mut bytes = self.extension_type.to_be_bytes().to_vec();
+        VLBytes::new(self.data)
+            .tls_serialize(&mut bytes)
+            .map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+        Extension::tls_deserialize_exact(&bytes).map_err(|e| {
+            MlsError::serialization(format!(
+                "Invalid extension 0x{:04x}: {:?}",
+                self.extension_type, e
+            ))
+        })
+    }
 
-        Ok(format!(
-            r#"{{"commit":"{}","welcome":"{}"}}"#,
-            commit_hex, welcome_hex
-        ))
+    fn from_extension(extension: &Extension) -> Result<Self, MlsError> {
+        let bytes = extension
+            .tls_serialize_detached()
+            .map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+        let mut slice = bytes.as_slice();
+        let extension_type = u16::tls_deserialize(&mut slice)
+            .map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+        let data = VLBytes::tls_deserialize(&mut slice)
+            .map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+        Ok(Self {
+            extension_type,
+            data: data.into(),
+        })
     }
+}
 
-    /// Removes a member from the group by leaf index.
-    ///
-    /// Use `get_members()` to discover leaf indices.
-    /// Returns JSON: `{"commit":"<hex>"}`.
-    /// Broadcast the commit to all remaining members.
-    pub fn remove_member(
-        &self,
-        group_id: String,
-        member_index: u32,
-    ) -> Result<String, MlsError> {
-        let mut state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
+// ── Group events pushed to Kotlin ──────────────────────────────────────────
+
+/// Notified of group changes, whether initiated locally or by another
+/// member.  Callbacks run on the calling thread after the client has
+/// released its lock, so they may call back into the client.
+#[uniffi::export(callback_interface)]
+pub trait MlsEventListener: Send + Sync {
+    fn on_member_added(&self, group_id: String, identity: Vec<u8>);
+    fn on_member_removed(&self, group_id: String, identity: Vec<u8>, was_self: bool);
+    fn on_epoch_changed(&self, group_id: String, epoch: u64);
+    fn on_group_joined(&self, group_id: String);
+    /// A message queued for a group we have not joined yet was dropped
+    /// because the queue was full.
+    fn on_pending_message_evicted(&self, group_id: String);
+}
 
-        let MlsClientState {
-            groups,
-            crypto,
-            signer,
-            ..
-        } = &mut *state;
+/// Application rules MLS knows nothing about, such as "only admins may
+/// remove members" (see `set_commit_policy()`).  Called while the client
+/// is locked, so it must not call back into the client; everything it
+/// needs is in the description.
+#[uniffi::export(callback_interface)]
+pub trait CommitPolicy: Send + Sync {
+    fn evaluate(&self, description: StagedCommitDescription) -> PolicyDecision;
+}
+
+/// A `CommitPolicy` verdict.
+#[derive(Debug, uniffi::Enum)]
+pub enum PolicyDecision {
+    Allow,
+    /// The commit is not applied; `reason` is passed on in
+    /// `CommitRejectedByPolicy`.
+    Reject { reason: String },
+}
+
+/// The policy registered with `set_commit_policy()`.
+#[derive(Clone)]
+struct CommitPolicyHook {
+    policy: Arc<dyn CommitPolicy>,
+    /// Whether our own commits are checked too.
+    check_own_commits: bool,
+}
+
+impl CommitPolicyHook {
+    fn check(&self, group_id: &str, description: StagedCommitDescription) -> Result<(), MlsError> {
+        match self.policy.evaluate(description) {
+            PolicyDecision::Allow => Ok(()),
+            PolicyDecision::Reject { reason } => {
+                log::info!("Commit policy rejected a commit in group {}: {}", group_id, reason);
+                Err(MlsError::CommitRejectedByPolicy { reason })
+            }
+        }
+    }
+}
+
+/// Members added and removed by a commit.  Both lists are empty for
+/// commits that only update keys or the group context.
+#[derive(Debug, uniffi::Record)]
+pub struct MembershipDiff {
+    /// The new members, as they appear after the commit.
+    pub added: Vec<MemberInfo>,
+    /// The departed members, as they appeared before the commit.
+    pub removed: Vec<MemberInfo>,
+}
+
+/// The outcome of `process_commit()`.
+#[derive(Debug, uniffi::Enum)]
+pub enum CommitOutcome {
+    /// The commit was merged; `epoch` is the group's new epoch and `diff`
+    /// the members it added and removed.
+    Merged { epoch: u64, diff: MembershipDiff },
+    /// The commit removed us.  The group's state has been deleted and any
+    /// further use of it fails with `NoLongerMember`.
+    RemovedSelf,
+    /// The message was a proposal, stored for a later commit.
+    ProposalStored { proposal: ProposalInfo },
+    /// Our own commit or proposal, echoed back by the delivery service.
+    OwnMessage,
+    /// The group is unknown; the message was queued until we join it (see
+    /// `set_pending_queue_limits()`).
+    QueuedForUnknownGroup,
+    /// The commit was merged and reinitialized the group (see
+    /// `propose_reinit()`): `new_group_id` with `ciphersuite` replaces it.
+    /// Publish a key package for that suite for the committer's
+    /// `complete_reinit()`.
+    ReinitRequired {
+        epoch: u64,
+        new_group_id: String,
+        ciphersuite: u16,
+    },
+}
+
+/// One merged commit in a group's membership audit log (see
+/// `get_audit_log()`).  Members of the group log the same `epoch`,
+/// `committer`, `added`, `removed` and `commit_hash` for a commit; only
+/// `timestamp`, and so the hashes chaining the entries, differ.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, uniffi::Record)]
+pub struct AuditEntry {
+    pub group_id: String,
+    /// The epoch the commit started.
+    pub epoch: u64,
+    /// Credential identity of the member who sent the commit.
+    pub committer: Vec<u8>,
+    /// Credential identities of the members the commit added.
+    pub added: Vec<Vec<u8>>,
+    /// Credential identities of the members the commit removed.
+    pub removed: Vec<Vec<u8>>,
+    /// Hex confirmed transcript hash of `epoch`, which covers the signed
+    /// commit.
+    pub commit_hash: String,
+    /// When we merged the commit, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// `entry_hash` of the previous entry; empty for the first one.
+    pub previous_hash: String,
+    /// Hex SHA-256 of `previous_hash` and the other fields.
+    pub entry_hash: String,
+}
+
+/// The outcome of processing one incoming message.
+#[derive(Debug, uniffi::Enum)]
+pub enum IncomingMessage {
+    /// A decrypted application message and its authenticated additional
+    /// data (empty if none).
+    /// The other fields are as in `DecryptedMessage`.
+    Application {
+        plaintext: Vec<u8>,
+        aad: Vec<u8>,
+        sender_index: u32,
+        sender_identity: Vec<u8>,
+        generation: Option<MessageGeneration>,
+        header: Option<MessageHeader>,
+        payload_kind: PayloadKind,
+        verified: bool,
+    },
+    /// A commit, already merged.  `epoch` is the group's new epoch.
+    Commit { epoch: u64 },
+    /// A commit removing us, sent by the member with credential identity
+    /// `removed_by`.  The group's state has been deleted and any further
+    /// use of it fails with `NoLongerMember`.
+    Removed { removed_by: Vec<u8> },
+    /// A proposal, stored for a later commit (see
+    /// `commit_pending_proposals()`).
+    Proposal { proposal: ProposalInfo },
+    /// An application-defined proposal (see `send_custom_proposal()`),
+    /// stored for a later commit like any other proposal.
+    CustomProposal {
+        proposal_type: u16,
+        payload: Vec<u8>,
+        sender_identity: Vec<u8>,
+    },
+    /// A message that was already processed; safe to drop.
+    Duplicate,
+    /// A message we sent ourselves, reflected back; safe to drop.
+    OwnMessage,
+    /// The group is unknown; the message was queued until we join it (see
+    /// `set_pending_queue_limits()`).
+    QueuedForUnknownGroup,
+}
+
+impl IncomingMessage {
+    /// Unwraps an application message, turning anything else into the
+    /// error `decrypt_message()` reports for it.
+    fn into_decrypted(self, group_id: String) -> Result<DecryptedMessage, MlsError> {
+        match self {
+            IncomingMessage::Application {
+                plaintext,
+                aad,
+                sender_index,
+                sender_identity,
+                generation,
+                header,
+                payload_kind,
+                verified,
+            } => Ok(DecryptedMessage {
+                as_utf8: std::str::from_utf8(&plaintext).ok().map(str::to_owned),
+                plaintext,
+                aad,
+                sender_index,
+                sender_identity,
+                generation,
+                header,
+                payload_kind,
+                verified,
+            }),
+            IncomingMessage::Commit { .. } => Err(MlsError::generic(
+                "Received a Commit, not an application message. \
+                 The commit has been merged. Group epoch advanced.",
+            )),
+            IncomingMessage::Removed { .. } => Err(MlsError::SelfRemoved { group_id }),
+            IncomingMessage::Proposal { .. } | IncomingMessage::CustomProposal { .. } => Err(MlsError::generic(
+                "Received a Proposal, not an application message. \
+                 The proposal has been stored.",
+            )),
+            IncomingMessage::Duplicate => Err(MlsError::DuplicateMessage { group_id }),
+            IncomingMessage::OwnMessage => Err(MlsError::OwnMessageReflected { group_id }),
+            IncomingMessage::QueuedForUnknownGroup => Err(MlsError::QueuedForUnknownGroup { group_id }),
+        }
+    }
+}
+
+/// The kind of an MLS message, as reported by `inspect_message()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum MessageKind {
+    Application,
+    Proposal,
+    Commit,
+    Welcome,
+    GroupInfo,
+    KeyPackage,
+}
+
+/// The cleartext header of an MLS message, read without processing it.
+#[derive(Debug, uniffi::Record)]
+pub struct MessageMetadata {
+    pub kind: MessageKind,
+    /// The group the message belongs to; `None` for welcomes and key
+    /// packages.
+    pub group_id: Option<String>,
+    /// The epoch the message was sent in; `None` for welcomes and key
+    /// packages.
+    pub epoch: Option<u64>,
+    /// Whether the message is a PrivateMessage (encrypted content).
+    pub encrypted: bool,
+}
+
+/// The wire format of an incoming blob, as reported by
+/// `classify_message()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum MessageClass {
+    Welcome,
+    KeyPackage,
+    /// A signed handshake (or application) message; pass to
+    /// `process_commit()` or `process_message()`.
+    PublicMessage,
+    /// An encrypted message; pass to `decrypt_message()` or
+    /// `process_message()`.
+    PrivateMessage,
+    GroupInfo,
+}
+
+/// The contents of a (validated) key package.
+#[derive(uniffi::Record)]
+pub struct KeyPackageInfo {
+    /// IANA codepoint of the key package's ciphersuite.
+    pub ciphersuite: u16,
+    /// Credential identity of the key package's owner.
+    pub identity: Vec<u8>,
+    /// Start of the validity period, in seconds since the Unix epoch.
+    pub not_before: u64,
+    /// End of the validity period, in seconds since the Unix epoch.
+    pub not_after: u64,
+    /// The owner's signature public key.
+    pub signature_key: Vec<u8>,
+    /// Key package extensions of types this client does not know.
+    pub unknown_extensions: Vec<RawExtension>,
+    /// Extensions of the key package's leaf node of types this client does
+    /// not know.  They stay in the leaf once the owner is added (see
+    /// `MemberInfo.unknown_extensions`).
+    pub unknown_leaf_extensions: Vec<RawExtension>,
+    /// The key package's MLS protocol version, e.g. `"mls10"`.
+    pub protocol_version: String,
+}
+
+/// A decrypted application message.
+#[derive(uniffi::Record)]
+pub struct DecryptedMessage {
+    pub plaintext: Vec<u8>,
+    /// `plaintext` as a string, or `None` if it is not valid UTF-8 (e.g. a
+    /// protobuf or CBOR payload).
+    pub as_utf8: Option<String>,
+    /// Authenticated additional data bound to the message by its sender.
+    pub aad: Vec<u8>,
+    /// Leaf index of the sender.
+    pub sender_index: u32,
+    /// Credential identity of the sender.
+    pub sender_identity: Vec<u8>,
+    /// The message's place in its sender's stream, in groups with payload
+    /// envelopes (see `GroupConfig::payload_envelope`); it travels
+    /// encrypted with the payload.  `None` in other groups.
+    pub generation: Option<MessageGeneration>,
+    /// The application-layer header of a framed message (see
+    /// `new_with_message_framing()`); `None` if the sender did not frame
+    /// the message or the group has no payload envelopes.
+    pub header: Option<MessageHeader>,
+    /// What the payload is: chat, or a control message sent with
+    /// `send_control_message()`.
+    pub payload_kind: PayloadKind,
+    /// Whether OpenMLS authenticated the message as coming from the member
+    /// at `sender_index`: it decrypted under that member's key schedule
+    /// secrets and its signature verified with the member's leaf key.
+    /// Messages failing either check are rejected (`DecryptionFailed`,
+    /// `InvalidSignature`) rather than returned, so this is always set.
+    pub verified: bool,
+}
+
+/// A message from `decrypt_ephemeral()`.
+#[derive(Debug, uniffi::Record)]
+pub struct EphemeralMessage {
+    /// Leaf index of the sender, whose leaf signature key signed the
+    /// message.
+    pub sender_leaf_index: u32,
+    /// Credential identity of the sender.
+    pub sender_identity: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+/// What an application payload carries: chat, or a control message sent
+/// with `send_control_message()` that apps keep out of the conversation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum PayloadKind {
+    /// An ordinary message.
+    Chat,
+    ReadReceipt,
+    Typing,
+    Reaction,
+}
+
+impl PayloadKind {
+    /// The kind byte of the payload envelope.
+    fn tag(self) -> u8 {
+        match self {
+            PayloadKind::Chat => 0,
+            PayloadKind::ReadReceipt => 1,
+            PayloadKind::Typing => 2,
+            PayloadKind::Reaction => 3,
+        }
+    }
+
+    /// The kind with envelope kind byte `tag`, if we know it.
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(PayloadKind::Chat),
+            1 => Some(PayloadKind::ReadReceipt),
+            2 => Some(PayloadKind::Typing),
+            3 => Some(PayloadKind::Reaction),
+            _ => None,
+        }
+    }
+}
+
+/// Sender timestamp and sequence number of a framed application message.
+/// Unlike generations, sequence numbers keep counting across epochs, so
+/// they order a sender's messages on their own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Record)]
+pub struct MessageHeader {
+    /// The sender's clock when the message was sent, in milliseconds since
+    /// the Unix epoch.  Not authenticated against anything but the sender.
+    pub timestamp_ms: u64,
+    /// Counts up from 0 per sender and group.
+    pub sequence: u64,
+}
+
+/// An encrypted application message, as returned by
+/// `encrypt_message_detailed()`.
+#[derive(Debug, uniffi::Record)]
+pub struct EncryptedMessage {
+    /// The encoded ciphertext.
+    pub ciphertext: String,
+    /// The epoch the message is encrypted in: receivers need that epoch's
+    /// secrets to decrypt it.
+    pub epoch: u64,
+    /// The message's place in our stream within the epoch, as receivers
+    /// get it in `DecryptedMessage.generation` in groups with payload
+    /// envelopes.
+    pub generation: u32,
+}
+
+/// An application message with a detached signature of its plaintext, as
+/// returned by `encrypt_signed_message()`.  Everything but `ciphertext`
+/// is needed to check the signature with `verify_signed_message()`.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct SignedMessage {
+    /// The encoded ciphertext, sent to the group as usual.
+    pub ciphertext: String,
+    /// The group and epoch the signature binds the plaintext to.
+    pub group_id: String,
+    pub epoch: u64,
+    /// IANA codepoint of the signature scheme.
+    pub signature_scheme: u16,
+    /// The sender's signature public key.
+    pub signature_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// The place of an application message in its sender's stream.
+/// Generations count up from 0 in each epoch, so `(epoch, generation)`
+/// orders a sender's messages: a skipped generation means a lost message.
+/// OpenMLS keeps its secret tree's generation to itself, so senders count
+/// their messages the same way and send the count in the payload envelope.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, uniffi::Record)]
+pub struct MessageGeneration {
+    pub epoch: u64,
+    pub generation: u32,
+}
+
+/// Counters reported by `get_metrics()`, accumulated since the client was
+/// created or `reset_metrics()` was last called.  Kept in memory only.
+#[derive(Debug, Default, uniffi::Record)]
+pub struct MetricsSnapshot {
+    /// Whether the library was built with the `metrics` feature.  Without
+    /// it nothing is counted and the other fields are empty.
+    pub enabled: bool,
+    /// One entry per instrumented operation, calls or not.
+    pub operations: Vec<OperationMetrics>,
+    /// Failed calls of any operation, by `MlsError` variant name (e.g.
+    /// `"GroupNotFound"`).
+    pub failures: HashMap<String, u64>,
+    /// Application traffic per group, sorted by group ID.  Groups that
+    /// neither sent nor received an application message are not listed.
+    pub groups: Vec<GroupTraffic>,
+    /// Inputs accepted despite trailing bytes, by the structure parsed
+    /// (e.g. `"KeyPackage"`); see `DeserializationMode::Permissive`.
+    pub trailing_bytes_ignored: HashMap<String, u64>,
+}
+
+/// Calls and latency of one operation.
+#[derive(Debug, uniffi::Record)]
+pub struct OperationMetrics {
+    /// The public method counted, e.g. `"encrypt_message"` (which also
+    /// counts `encrypt_message_bytes()`).
+    pub operation: String,
+    pub calls: u64,
+    /// Calls that returned an error; included in `calls`.
+    pub failures: u64,
+    /// Time spent in all calls together, in microseconds.
+    pub total_micros: u64,
+    pub max_micros: u64,
+    /// Calls by latency: entry `i` counts calls that took less than
+    /// `10^(i + 2)` microseconds (100 µs, 1 ms, ... 1 s) and more than the
+    /// bound of entry `i - 1`.  The last entry counts everything slower.
+    pub latency_histogram: Vec<u64>,
+}
+
+/// Application messages of one group, with their plaintext sizes.
+#[derive(Debug, Default, Clone, uniffi::Record)]
+pub struct GroupTraffic {
+    pub group_id: String,
+    pub messages_encrypted: u64,
+    pub bytes_encrypted: u64,
+    pub messages_decrypted: u64,
+    pub bytes_decrypted: u64,
+}
+
+/// An event recorded while the state lock is held, delivered once released.
+enum MlsEvent {
+    MemberAdded { group_id: String, identity: Vec<u8> },
+    MemberRemoved { group_id: String, identity: Vec<u8>, was_self: bool },
+    EpochChanged { group_id: String, epoch: u64 },
+    GroupJoined { group_id: String },
+    PendingMessageEvicted { group_id: String },
+    /// A merged commit, appended to the group's audit log instead of
+    /// being delivered (see `append_audit_entries()`).
+    CommitMerged { entry: AuditEntry },
+}
+
+impl MlsEvent {
+    fn dispatch(self, listener: &dyn MlsEventListener) {
+        match self {
+            MlsEvent::MemberAdded { group_id, identity } => {
+                listener.on_member_added(group_id, identity)
+            }
+            MlsEvent::MemberRemoved {
+                group_id,
+                identity,
+                was_self,
+            } => listener.on_member_removed(group_id, identity, was_self),
+            MlsEvent::EpochChanged { group_id, epoch } => listener.on_epoch_changed(group_id, epoch),
+            MlsEvent::GroupJoined { group_id } => listener.on_group_joined(group_id),
+            MlsEvent::PendingMessageEvicted { group_id } => listener.on_pending_message_evicted(group_id),
+            MlsEvent::CommitMerged { .. } => {}
+        }
+    }
+
+    fn log(&self) {
+        match self {
+            MlsEvent::MemberAdded { group_id, .. } => log::info!("Member added to group {}", group_id),
+            MlsEvent::MemberRemoved {
+                group_id,
+                was_self: true,
+                ..
+            } => log::info!("Removed from group {}", group_id),
+            MlsEvent::MemberRemoved { group_id, .. } => log::info!("Member removed from group {}", group_id),
+            MlsEvent::EpochChanged { group_id, epoch } => {
+                log::info!("Commit merged in group {}, now at epoch {}", group_id, epoch)
+            }
+            MlsEvent::GroupJoined { group_id } => log::info!("Joined group {} from a Welcome", group_id),
+            MlsEvent::PendingMessageEvicted { group_id } => {
+                log::warn!("Pending message queue for unknown group {} is full; dropped the oldest", group_id)
+            }
+            MlsEvent::CommitMerged { entry } => {
+                log::debug!("Logging commit of epoch {} in group {} for audit", entry.epoch, entry.group_id)
+            }
+        }
+    }
+}
+
+/// Logs an event and queues it for the listener.
+fn record_event(events: &mut Vec<MlsEvent>, event: MlsEvent) {
+    event.log();
+    events.push(event);
+}
+
+// ── Logging ────────────────────────────────────────────────────────────────
+
+/// Severity of a log record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+/// Receives the library's log records, e.g. to forward them to logcat.
+/// Records never contain key material or message contents.
+///
+/// Records may be emitted while the client holds its lock, so `log()` must
+/// not call back into any `MlsClient`.
+#[uniffi::export(callback_interface)]
+pub trait MlsLogger: Send + Sync {
+    fn log(&self, level: LogLevel, target: String, message: String);
+}
+
+/// The logger registered with `set_logger()`; shared by all clients since
+/// the `log` facade is process-wide.
+static LOGGER: RwLock<Option<Box<dyn MlsLogger>>> = RwLock::new(None);
+
+/// Forwards records from the `log` facade to `LOGGER`.
+struct LogBridge;
+
+impl log::Log for LogBridge {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        if let Ok(logger) = LOGGER.read() {
+            if let Some(logger) = logger.as_ref() {
+                logger.log(
+                    record.level().into(),
+                    record.target().to_string(),
+                    record.args().to_string(),
+                );
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOG_BRIDGE: LogBridge = LogBridge;
+
+// ── Storage backends ───────────────────────────────────────────────────────
+
+/// Key of the state file (identity, group list, generation).
+const STATE_KEY: &str = "state.json";
+
+/// Version of the `state.json` format this library writes.  Bump it, and
+/// add a step to `PersistedState::migrate()`, when a change would misread
+/// older states.
+const STATE_VERSION: u32 = 1;
+/// Key of the OpenMLS key store (group state, key package bundles, secrets).
+const KEY_STORE_KEY: &str = "openmls_store.json";
+/// Prefix of the keys holding the key store entries of one group each
+/// (`group_<hex ID>.json`, in the format of `KEY_STORE_KEY`), so that saves
+/// rewrite only the groups that changed.  `KEY_STORE_KEY` keeps the entries
+/// of no group (key packages, signature keys, leaf encryption keys).
+const GROUP_STORE_PREFIX: &str = "group_";
+/// Prefix of the keys holding the membership audit log of one group each
+/// (`audit_<hex ID>.json`, a JSON array of `AuditEntry`).  Written as
+/// commits are merged, whatever the autosave setting, and kept after the
+/// group is left or deleted.
+const AUDIT_LOG_PREFIX: &str = "audit_";
+/// Prefix under which `purge_orphaned_state()` moves stored files that
+/// cannot be loaded: a `.corrupt` subfolder of the storage directory.
+const CORRUPT_PREFIX: &str = ".corrupt/";
+/// Key of the rollback high-water mark.  Backends should keep it apart from
+/// the other keys, so that restoring an old copy of those does not also
+/// roll it back.
+const HIGH_WATER_MARK_KEY: &str = "high_water_mark";
+
+/// Key-value storage for everything the client persists, so that apps can
+/// keep secrets in Android Keystore-backed storage instead of plain files.
+/// Values are opaque bytes (encrypted if the client has a passphrase).
+#[uniffi::export(callback_interface)]
+pub trait StorageBackend: Send + Sync {
+    /// The value stored under `key`, or `None` if there is none.
+    fn get(&self, key: String) -> Result<Option<Vec<u8>>, MlsError>;
+    /// Stores `value` under `key`, replacing any previous value.
+    fn put(&self, key: String, value: Vec<u8>) -> Result<(), MlsError>;
+    /// Removes `key`.  Removing a missing key is not an error.
+    fn delete(&self, key: String) -> Result<(), MlsError>;
+    /// All stored keys starting with `prefix`.
+    fn list(&self, prefix: String) -> Result<Vec<String>, MlsError>;
+}
+
+/// Suffix of the file `FileStorageBackend::put()` writes before renaming it
+/// into place.
+const TEMP_FILE_SUFFIX: &str = ".tmp";
+
+/// The default backend used by `MlsClient::new()`: one file per key in a
+/// directory.  The high-water mark is kept in `<dir>.hwm`, *next to* the
+/// directory.
+struct FileStorageBackend {
+    dir: PathBuf,
+}
+
+impl FileStorageBackend {
+    fn path(&self, key: &str) -> PathBuf {
+        if key == HIGH_WATER_MARK_KEY {
+            let mut path = self.dir.clone().into_os_string();
+            path.push(".hwm");
+            PathBuf::from(path)
+        } else {
+            self.dir.join(key)
+        }
+    }
+}
+
+impl StorageBackend for FileStorageBackend {
+    fn get(&self, key: String) -> Result<Option<Vec<u8>>, MlsError> {
+        match fs::read(self.path(&key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(MlsError::io(format!("Failed to read {}: {:?}", key, e))),
+        }
+    }
+
+    fn put(&self, key: String, value: Vec<u8>) -> Result<(), MlsError> {
+        let path = self.path(&key);
+        // Keys under `CORRUPT_PREFIX` live in a subfolder.
+        let dir = path.parent().unwrap_or(&self.dir);
+        fs::create_dir_all(dir)
+            .map_err(|e| MlsError::io(format!("Failed to create directory: {:?}", e)))?;
+
+        // Write the new value aside and move it into place, so that a crash
+        // leaves either the old value or the new one, never a torn file.
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(TEMP_FILE_SUFFIX);
+        let temp = path.with_file_name(temp_name);
+        let written = (|| -> std::io::Result<()> {
+            use std::io::Write;
+            let mut file = fs::File::create(&temp)?;
+            file.write_all(&value)?;
+            file.sync_all()?;
+            fs::rename(&temp, &path)?;
+            // Make the rename durable too, where directories can be synced.
+            let _ = fs::File::open(dir).and_then(|dir| dir.sync_all());
+            Ok(())
+        })();
+        if written.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        written.map_err(|e| MlsError::io(format!("Failed to write {}: {:?}", key, e)))
+    }
+
+    fn delete(&self, key: String) -> Result<(), MlsError> {
+        match fs::remove_file(self.path(&key)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(MlsError::io(format!("Failed to delete {}: {:?}", key, e))),
+        }
+    }
+
+    fn list(&self, prefix: String) -> Result<Vec<String>, MlsError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(MlsError::io(format!("Failed to list directory: {:?}", e))),
+        };
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| MlsError::io(format!("Failed to list directory: {:?}", e)))?;
+            if let Some(name) = entry.file_name().to_str() {
+                if name.starts_with(&prefix) && !name.ends_with(TEMP_FILE_SUFFIX) {
+                    keys.push(name.to_string());
+                }
+            }
+        }
+        if HIGH_WATER_MARK_KEY.starts_with(&prefix) && self.path(HIGH_WATER_MARK_KEY).exists() {
+            keys.push(HIGH_WATER_MARK_KEY.to_string());
+        }
+        Ok(keys)
+    }
+}
+
+/// The backend of `MlsClient::new_ephemeral()`: stores nothing and never
+/// touches the filesystem.
+struct NullStorageBackend;
+
+impl StorageBackend for NullStorageBackend {
+    fn get(&self, _key: String) -> Result<Option<Vec<u8>>, MlsError> {
+        Ok(None)
+    }
+
+    fn put(&self, _key: String, _value: Vec<u8>) -> Result<(), MlsError> {
+        Ok(())
+    }
+
+    fn delete(&self, _key: String) -> Result<(), MlsError> {
+        Ok(())
+    }
+
+    fn list(&self, _prefix: String) -> Result<Vec<String>, MlsError> {
+        Ok(Vec::new())
+    }
+}
+
+/// The backend of `MlsClient::new_in_memory()`: a map that lives and dies
+/// with the client.  Values are wiped when it is dropped.
+#[derive(Default)]
+struct MemoryStorageBackend {
+    values: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl StorageBackend for MemoryStorageBackend {
+    fn get(&self, key: String) -> Result<Option<Vec<u8>>, MlsError> {
+        let values = self.values.lock().map_err(|_| MlsError::lock_poisoned())?;
+        Ok(values.get(&key).cloned())
+    }
+
+    fn put(&self, key: String, value: Vec<u8>) -> Result<(), MlsError> {
+        let mut values = self.values.lock().map_err(|_| MlsError::lock_poisoned())?;
+        if let Some(mut old) = values.insert(key, value) {
+            old.zeroize();
+        }
+        Ok(())
+    }
+
+    fn delete(&self, key: String) -> Result<(), MlsError> {
+        let mut values = self.values.lock().map_err(|_| MlsError::lock_poisoned())?;
+        if let Some(mut old) = values.remove(&key) {
+            old.zeroize();
+        }
+        Ok(())
+    }
+
+    fn list(&self, prefix: String) -> Result<Vec<String>, MlsError> {
+        let values = self.values.lock().map_err(|_| MlsError::lock_poisoned())?;
+        Ok(values.keys().filter(|key| key.starts_with(&prefix)).cloned().collect())
+    }
+}
+
+impl Drop for MemoryStorageBackend {
+    fn drop(&mut self) {
+        if let Ok(mut values) = self.values.lock() {
+            values.values_mut().for_each(|value| value.zeroize());
+        }
+    }
+}
+
+// ── Text encodings for message blobs ───────────────────────────────────────
+
+/// How binary blobs (key packages, commits, welcomes, proposals and
+/// ciphertexts) are encoded in the strings exchanged with Kotlin.  Set with
+/// `set_default_encoding()`; group IDs and PSK IDs are always hex.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, uniffi::Enum)]
+pub enum Encoding {
+    #[default]
+    Hex,
+    /// Standard alphabet, padded.
+    Base64,
+    /// URL-safe alphabet, unpadded.  Suitable for URLs and QR codes.
+    Base64Url,
+}
+
+impl Encoding {
+    fn encode(self, bytes: &[u8]) -> String {
+        use base64::Engine;
+        match self {
+            Encoding::Hex => hex::encode(bytes),
+            Encoding::Base64 => base64::prelude::BASE64_STANDARD.encode(bytes),
+            Encoding::Base64Url => base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(bytes),
+        }
+    }
+
+    /// Like `decode()`, but fails with `InputTooLarge`, without decoding,
+    /// if `text` would decode to more than `limit` bytes.
+    fn decode_limited(self, text: &str, limit: u64) -> Result<Vec<u8>, MlsError> {
+        let decoded_len = match self {
+            Encoding::Hex => text.len() / 2,
+            Encoding::Base64 | Encoding::Base64Url => text.trim_end_matches('=').len() * 3 / 4,
+        };
+        check_input_size(decoded_len, limit)?;
+        self.decode(text)
+    }
+
+    /// Decodes `text`.  Base64 input is accepted with or without padding.
+    fn decode(self, text: &str) -> Result<Vec<u8>, MlsError> {
+        self.decode_unlogged(text)
+            .inspect_err(|e| log::warn!("Failed to decode {:?} input: {}", self, e))
+    }
+
+    fn decode_unlogged(self, text: &str) -> Result<Vec<u8>, MlsError> {
+        use base64::alphabet;
+        use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+        use base64::{DecodeError, Engine};
+
+        let alphabet = match self {
+            Encoding::Hex => {
+                return hex::decode(text).map_err(|e| {
+                    MlsError::serialization(match e {
+                        hex::FromHexError::InvalidHexCharacter { c, index } => {
+                            format!("Invalid hex character {:?} at position {}", c, index)
+                        }
+                        hex::FromHexError::OddLength => {
+                            format!("Invalid hex: odd length {}", text.len())
+                        }
+                        other => format!("Invalid hex: {}", other),
+                    })
+                });
+            }
+            Encoding::Base64 => &alphabet::STANDARD,
+            Encoding::Base64Url => &alphabet::URL_SAFE,
+        };
+
+        let config =
+            GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent);
+        GeneralPurpose::new(alphabet, config)
+            .decode(text)
+            .map_err(|e| {
+                MlsError::serialization(match e {
+                    DecodeError::InvalidByte(index, byte) => format!(
+                        "Invalid base64 character {:?} at position {}",
+                        byte as char, index
+                    ),
+                    DecodeError::InvalidLastSymbol(index, byte) => format!(
+                        "Invalid trailing base64 character {:?} at position {}",
+                        byte as char, index
+                    ),
+                    DecodeError::InvalidLength(len) => {
+                        format!("Invalid base64: truncated input of {} characters", len)
+                    }
+                    DecodeError::InvalidPadding => "Invalid base64 padding".to_string(),
+                })
+            })
+    }
+}
+
+// ── Message digest cache ───────────────────────────────────────────────────
+
+/// Bounded FIFO of message digests, used to recognise replayed ciphertexts
+/// and our own messages echoed back by the delivery service.
+#[derive(Default)]
+struct MessageCache {
+    digests: VecDeque<Vec<u8>>,
+    /// Latest epoch passed to `start_epoch()`.
+    epoch: u64,
+}
+
+impl MessageCache {
+    fn contains(&self, digest: &[u8]) -> bool {
+        self.digests.iter().any(|d| d == digest)
+    }
+
+    fn insert(&mut self, digest: Vec<u8>, capacity: usize) {
+        if capacity == 0 || self.contains(&digest) {
+            return;
+        }
+        while self.digests.len() >= capacity {
+            self.digests.pop_front();
+        }
+        self.digests.push_back(digest);
+    }
+
+    /// Forgets the digests of earlier epochs once a message of a later
+    /// epoch arrives.  Replays from those epochs then fail in OpenMLS,
+    /// which has deleted the keys they need or marked them as used.
+    fn start_epoch(&mut self, epoch: u64) {
+        if epoch > self.epoch {
+            self.digests.clear();
+            self.epoch = epoch;
+        }
+    }
+}
+
+// ── Internal state (behind RwLock) ─────────────────────────────────────────
+
+struct MlsClientState {
+    groups: HashMap<String, MlsGroup>,
+    crypto: MlsProvider,
+    /// `None` until `create_identity()` is called.
+    signer: Option<SigningKeyPair>,
+    /// `None` until `create_identity()` is called.
+    credential: Option<CredentialWithKey>,
+    identity_name: Option<String>,
+    /// Capabilities advertised in our key packages and leaf nodes.
+    capabilities: ClientCapabilities,
+    /// Carried in the application_id extension of our leaf nodes.
+    application_id: Option<Vec<u8>>,
+    /// Digests of messages successfully processed, per group, for the
+    /// current epoch.
+    seen_messages: HashMap<String, MessageCache>,
+    /// How many digests `seen_messages` keeps per group; 0 turns replay
+    /// detection off.  Set with `new_with_replay_cache()`.
+    message_cache_size: usize,
+    /// Digests of messages we produced, per group.
+    sent_messages: HashMap<String, MessageCache>,
+    /// Generation of the last state written to (or read from) disk.
+    generation: u64,
+    /// Set when the on-disk state is older than the high-water mark.
+    /// Saving is refused until `force_accept_rollback()` is called.
+    rollback: Option<(u64, u64)>,
+    /// Set when the on-disk state could not be decrypted.  Saving is refused
+    /// so that a wrong passphrase cannot overwrite it.
+    storage_locked: bool,
+    /// Format version of an on-disk state saved by a newer version of this
+    /// library.  Saving is refused so that it cannot be overwritten with
+    /// an older format.
+    newer_state_version: Option<u32>,
+    /// Groups restored from a rolled-back state; sends are blocked until
+    /// each one has performed a self-update.
+    pending_self_updates: HashSet<String>,
+    /// Groups frozen for maintenance, with the reason.  Frozen groups still
+    /// process incoming messages but refuse to send or commit.
+    frozen_groups: HashMap<String, String>,
+    /// Groups marked out of sync with `mark_group_desynced()`.  Sending
+    /// fails with `GroupDesynced` until a Welcome replaces the group.
+    desynced_groups: HashSet<String>,
+    /// Per group, the most members our commits may leave it with (see
+    /// `set_max_members()`).  Groups not listed are uncapped.
+    max_members: HashMap<String, u32>,
+    /// Hex SHA-256 digests of the Welcomes we joined from, and the group
+    /// each joined, to recognise a Welcome processed twice once its key
+    /// package is gone.  Another Welcome for the same key package is not
+    /// a replay: its group cannot be told without the key package.
+    joined_welcomes: HashMap<String, String>,
+    /// Whether the last Welcome we joined with consumed a last-resort key
+    /// package, which OpenMLS keeps for reuse (see `was_last_resort_used()`).
+    last_join_last_resort: bool,
+    /// Hex IDs of external PSKs registered with the crypto provider.  The
+    /// secrets themselves live in the OpenMLS key store.
+    external_psks: HashSet<String>,
+    /// Hex references of the regular (not last-resort) key packages we
+    /// have added to groups, to spot a key package being handed out twice.
+    used_key_packages: HashSet<String>,
+    /// Groups another member removed us from.  Their state is deleted and
+    /// using them fails with `NoLongerMember`.
+    removed_groups: HashSet<String>,
+    /// Per group, the epoch and generation of our next application message.
+    send_generations: HashMap<String, MessageGeneration>,
+    /// Per group and hex sender identity, the latest application message
+    /// generation received (see `get_last_seen_generation()`).
+    seen_generations: HashMap<String, HashMap<String, MessageGeneration>>,
+    /// Whether application messages carry a `MessageHeader` (see
+    /// `new_with_message_framing()`).
+    message_framing: bool,
+    /// Per group, the sequence number of our next framed message.
+    send_sequences: HashMap<String, u64>,
+    /// Raw messages for groups we have not joined yet, oldest first.  Kept
+    /// in memory only.
+    pending_messages: HashMap<String, VecDeque<Vec<u8>>>,
+    /// Per-group limits of `pending_messages`; 0 messages disables queueing.
+    pending_max_messages: usize,
+    pending_max_bytes: usize,
+    /// Commits processed by `stage_commit()` awaiting merge or rejection,
+    /// with their sender's identity.
+    staged_commits: HashMap<String, (StagedCommit, Vec<u8>)>,
+    /// Encoded Welcomes previewed by `stage_welcome()` awaiting
+    /// `commit_welcome()` or `decline_welcome()`, by group ID.
+    staged_welcomes: HashMap<String, String>,
+    /// Events not yet delivered to the listener (see `EventFlush`).
+    pending_events: Vec<MlsEvent>,
+    /// Encoding of message blobs passed to and returned from the API.
+    encoding: Encoding,
+    /// Block size application messages are padded to; 0 disables padding.
+    padding_size: usize,
+    /// Past epochs whose secrets groups created or joined later retain.
+    max_past_epochs: usize,
+    /// Groups dropped from memory by `archive_group()` or the resident
+    /// limit.  Their state stays in the key store and is reloaded on first
+    /// access.
+    archived_groups: HashSet<String>,
+    /// Resident groups, least recently used first.
+    resident_order: VecDeque<String>,
+    /// How many groups to keep in memory; 0 means no limit.
+    max_resident_groups: usize,
+    /// Whether calls save the state when they leave it dirty (see
+    /// `set_autosave()`).
+    autosave: bool,
+    /// Groups changed since the last save.
+    dirty_groups: HashSet<String>,
+    /// Reinitialized groups and the group replacing each.
+    reinits: HashMap<String, MigrationTarget>,
+    /// Whether key packages were generated since the last save.
+    key_packages_dirty: bool,
+    /// Consulted before merging commits (see `set_commit_policy()`).
+    commit_policy: Option<CommitPolicyHook>,
+    /// The changes collected since `begin_commit()`.  Kept in memory only.
+    commit_txn: Option<CommitTxn>,
+    /// Largest inputs accepted (see `set_input_limits()`).
+    input_limits: InputLimits,
+    /// See `set_duplicate_identity_policy()`.
+    duplicate_identity_policy: DuplicateIdentityPolicy,
+    /// See `set_deserialization_mode()`.
+    deserialization_mode: DeserializationMode,
+    /// Creation and last-change times of groups, resident or archived.
+    group_times: HashMap<String, GroupTimes>,
+    /// Settings of groups, resident or archived (see `group_settings()`).
+    group_settings: HashMap<String, GroupSettings>,
+    /// Per group, add commits not yet acknowledged, oldest first.
+    commit_journal: HashMap<String, Vec<UnackedCommit>>,
+    /// Sequence number of the last add commit we created.
+    commit_sequence: u64,
+    /// Per group, when we last encrypted, decrypted or processed a message
+    /// for it, in seconds since the Unix epoch.  Kept for groups we were
+    /// removed from.
+    group_activity: HashMap<String, u64>,
+    /// Groups whose key store entries are saved under their own key (see
+    /// `GROUP_STORE_PREFIX`).  Any other group is written by the next save.
+    saved_group_files: HashSet<String>,
+    /// Epochs of archived groups, so saves need not load them.
+    archived_epochs: HashMap<String, u64>,
+    /// Identities registered with `add_identity()` besides the default
+    /// one, by name.
+    identities: HashMap<String, Identity>,
+    /// Groups belonging to one of `identities`, and its name.  Groups not
+    /// listed belong to the default identity.
+    group_identities: HashMap<String, String>,
+    /// The entry of `identities` currently swapped into `signer`,
+    /// `credential` and `identity_name` (see `select_identity()`).
+    active_identity: Option<String>,
+    /// Groups moved to a signature key of their own by
+    /// `rotate_group_signature_key()`, and the key.  The identity the
+    /// group belongs to signs with it in that group only.
+    group_signers: HashMap<String, SigningKeyPair>,
+    /// The group whose entry of `group_signers` is currently swapped into
+    /// `signer` (see `select_group()`).
+    active_group_signer: Option<String>,
+    /// Groups moved to a credential identity of their own by
+    /// `update_credential()`, and the identity.  The identity the group
+    /// belongs to keeps its name everywhere else.
+    group_credentials: HashMap<String, String>,
+    /// The group whose entry of `group_credentials` is currently in
+    /// `credential`, and the credential it replaced (see `select_group()`).
+    displaced_credential: Option<(String, CredentialWithKey)>,
+    /// Application traffic per group (see `get_metrics()`).  Counted here
+    /// rather than in `MlsClient::metrics` as the lock is held anyway.
+    #[cfg(feature = "metrics")]
+    traffic: HashMap<String, GroupTraffic>,
+    /// Inputs accepted despite trailing bytes, by structure (see
+    /// `DeserializationMode::Permissive`).
+    #[cfg(feature = "metrics")]
+    trailing_bytes_ignored: HashMap<String, u64>,
+}
+
+/// The key pair and credential of an identity other than the default one.
+/// The fields mirror those of `MlsClientState` so they can be swapped in.
+struct Identity {
+    signer: Option<SigningKeyPair>,
+    credential: Option<CredentialWithKey>,
+    identity_name: Option<String>,
+}
+
+/// A signature key pair whose private key is wiped when it is dropped,
+/// unlike `openmls_basic_credential::SignatureKeyPair`, which leaves it in
+/// freed memory.  Serialized the same way, so saved states and key store
+/// entries written as either read back as either.
+#[derive(Clone, Serialize, Deserialize)]
+struct SigningKeyPair {
+    private: Vec<u8>,
+    public: Vec<u8>,
+    signature_scheme: SignatureScheme,
+}
+
+impl Drop for SigningKeyPair {
+    fn drop(&mut self) {
+        self.private.zeroize();
+    }
+}
+
+impl openmls_traits::signatures::Signer for SigningKeyPair {
+    fn sign(&self, payload: &[u8]) -> Result<Vec<u8>, openmls_traits::signatures::SignerError> {
+        use openmls_traits::crypto::OpenMlsCrypto;
+        openmls_rust_crypto::RustCrypto::default()
+            .sign(self.signature_scheme, payload, &self.private)
+            .map_err(|_| openmls_traits::signatures::SignerError::SigningError)
+    }
+
+    fn signature_scheme(&self) -> SignatureScheme {
+        self.signature_scheme
+    }
+}
+
+impl openmls_traits::storage::Entity<{ openmls_traits::storage::CURRENT_VERSION }> for SigningKeyPair {}
+impl openmls_traits::storage::traits::SignatureKeyPair<{ openmls_traits::storage::CURRENT_VERSION }>
+    for SigningKeyPair
+{
+}
+
+impl SigningKeyPair {
+    fn from_raw(signature_scheme: SignatureScheme, private: Vec<u8>, public: Vec<u8>) -> Self {
+        Self {
+            private,
+            public,
+            signature_scheme,
+        }
+    }
+
+    fn public(&self) -> &[u8] {
+        &self.public
+    }
+
+    fn to_public_vec(&self) -> Vec<u8> {
+        self.public.clone()
+    }
+
+    fn signature_scheme(&self) -> SignatureScheme {
+        self.signature_scheme
+    }
+
+    /// Registers the key pair in the key store under the ID
+    /// `SignatureKeyPair::store()` uses.
+    fn store<T: StorageProvider<{ openmls_traits::storage::CURRENT_VERSION }>>(&self, store: &T) -> Result<(), T::Error> {
+        let id = SignatureKeyPair::from_raw(self.signature_scheme, Vec::new(), self.public.clone()).id();
+        store.write_signature_key_pair(&id, self)
+    }
+}
+
+/// Membership changes collected for a single commit (see `begin_commit()`).
+struct CommitTxn {
+    group_id: String,
+    adds: Vec<KeyPackage>,
+    /// Identities to remove; resolved to leaves when the commit is built.
+    removes: Vec<String>,
+    self_update: bool,
+}
+
+/// Signers wipe themselves; this wipes the key store, which holds the
+/// group secrets and copies of the signers, so that dropping the client
+/// leaves no key material in freed memory.
+impl Drop for MlsClientState {
+    fn drop(&mut self) {
+        if let Ok(mut values) = self.crypto.storage().values.write() {
+            values.values_mut().for_each(|value| value.zeroize());
+        }
+    }
+}
+
+impl MlsClientState {
+    fn new(crypto: MlsProvider) -> Self {
+        Self {
+            groups: HashMap::new(),
+            crypto,
+            signer: None,
+            credential: None,
+            identity_name: None,
+            capabilities: ClientCapabilities::default(),
+            application_id: None,
+            seen_messages: HashMap::new(),
+            sent_messages: HashMap::new(),
+            generation: 0,
+            rollback: None,
+            storage_locked: false,
+            newer_state_version: None,
+            pending_self_updates: HashSet::new(),
+            frozen_groups: HashMap::new(),
+            desynced_groups: HashSet::new(),
+            max_members: HashMap::new(),
+            joined_welcomes: HashMap::new(),
+            last_join_last_resort: false,
+            external_psks: HashSet::new(),
+            used_key_packages: HashSet::new(),
+            removed_groups: HashSet::new(),
+            send_generations: HashMap::new(),
+            seen_generations: HashMap::new(),
+            message_framing: false,
+            message_cache_size: MESSAGE_CACHE_SIZE,
+            send_sequences: HashMap::new(),
+            pending_messages: HashMap::new(),
+            pending_max_messages: 0,
+            pending_max_bytes: 0,
+            staged_commits: HashMap::new(),
+            staged_welcomes: HashMap::new(),
+            pending_events: Vec::new(),
+            encoding: Encoding::default(),
+            padding_size: 0,
+            max_past_epochs: 0,
+            archived_groups: HashSet::new(),
+            resident_order: VecDeque::new(),
+            max_resident_groups: 0,
+            autosave: true,
+            dirty_groups: HashSet::new(),
+            key_packages_dirty: false,
+            reinits: HashMap::new(),
+            commit_policy: None,
+            commit_txn: None,
+            input_limits: InputLimits::default(),
+            duplicate_identity_policy: DuplicateIdentityPolicy::default(),
+            deserialization_mode: DeserializationMode::default(),
+            group_times: HashMap::new(),
+            group_settings: HashMap::new(),
+            commit_journal: HashMap::new(),
+            commit_sequence: 0,
+            group_activity: HashMap::new(),
+            saved_group_files: HashSet::new(),
+            archived_epochs: HashMap::new(),
+            identities: HashMap::new(),
+            group_identities: HashMap::new(),
+            active_identity: None,
+            group_signers: HashMap::new(),
+            active_group_signer: None,
+            group_credentials: HashMap::new(),
+            displaced_credential: None,
+            #[cfg(feature = "metrics")]
+            traffic: HashMap::new(),
+            #[cfg(feature = "metrics")]
+            trailing_bytes_ignored: HashMap::new(),
+        }
+    }
+
+    /// Makes `group_id` resident, loading it from the key store if it was
+    /// archived, and marks it as most recently used.  Unknown groups are
+    /// left for the caller to report.
+    fn ensure_resident(&mut self, group_id: &str) -> Result<(), MlsError> {
+        if self.removed_groups.contains(group_id) {
+            return Err(MlsError::NoLongerMember {
+                group_id: group_id.to_string(),
+            });
+        }
+        if self.groups.contains_key(group_id) {
+            self.resident_order.retain(|g| g != group_id);
+            self.resident_order.push_back(group_id.to_string());
+            return Ok(());
+        }
+        if !self.archived_groups.contains(group_id) {
+            return Ok(());
+        }
+
+        let group = self.load_group(group_id)?;
+        self.archived_groups.remove(group_id);
+        self.archived_epochs.remove(group_id);
+        self.insert_group(group_id.to_string(), group);
+        Ok(())
+    }
+
+    /// Loads a group's current state from the key store.
+    fn load_group(&self, group_id: &str) -> Result<MlsGroup, MlsError> {
+        let gid = GroupId::from_slice(&Encoding::Hex.decode(group_id)?);
+        MlsGroup::load(self.crypto.storage(), &gid)
+            .map_err(|e| MlsError::io(format!("Failed to load group {}: {:?}", group_id, e)))?
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.to_string(),
+            })
+    }
+
+    /// Deletes all local state of a group, resident or archived.
+    fn discard_group(&mut self, group_id: &str) {
+        let group = match self.groups.remove(group_id) {
+            Some(group) => Some(group),
+            None if self.archived_groups.remove(group_id) => self.load_group(group_id).ok(),
+            None => None,
+        };
+        if let Some(mut group) = group {
+            if let Err(e) = group.delete(self.crypto.storage()) {
+                log::warn!("Failed to delete state of group {}: {:?}", group_id, e);
+            }
+        }
+        self.resident_order.retain(|g| g != group_id);
+        self.seen_messages.remove(group_id);
+        self.sent_messages.remove(group_id);
+        self.pending_self_updates.remove(group_id);
+        self.frozen_groups.remove(group_id);
+        self.desynced_groups.remove(group_id);
+        self.max_members.remove(group_id);
+        self.joined_welcomes.retain(|_, joined| joined != group_id);
+        self.staged_commits.remove(group_id);
+        self.pending_messages.remove(group_id);
+        self.send_generations.remove(group_id);
+        self.seen_generations.remove(group_id);
+        self.send_sequences.remove(group_id);
+        self.reinits.remove(group_id);
+        self.group_identities.remove(group_id);
+        let selected = self.displaced_credential.as_ref().map(|(id, _)| id.as_str());
+        if self.active_group_signer.as_deref() == Some(group_id) || selected == Some(group_id) {
+            self.restore_group_key();
+        }
+        self.drop_group_signer(group_id);
+        self.group_credentials.remove(group_id);
+        self.group_times.remove(group_id);
+        self.group_settings.remove(group_id);
+        self.commit_journal.remove(group_id);
+        self.group_activity.remove(group_id);
+        self.archived_epochs.remove(group_id);
+        if self.commit_txn.as_ref().is_some_and(|txn| txn.group_id == group_id) {
+            self.commit_txn = None;
+        }
+    }
+
+    #[cfg(feature = "debug-secrets")]
+    fn epoch_debug_info(&self, group_id: &str) -> Result<EpochDebugInfo, MlsError> {
+        let group = self.groups.get(group_id).ok_or_else(|| MlsError::GroupNotFound {
+            group_id: group_id.to_string(),
+        })?;
+        let exporter_secret = Zeroizing::new(
+            group
+                .export_secret(self.crypto.crypto(), EPOCH_DEBUG_LABEL, &[], 32)
+                .map_err(|e| MlsError::crypto(format!("Failed to export secret: {:?}", e)))?,
+        );
+        let confirmation_tag = group
+            .confirmation_tag()
+            .tls_serialize_detached()
+            .map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+        let context = group_context(&self.crypto, self.group_signer(group_id)?, group)?;
+        Ok(EpochDebugInfo {
+            epoch: group.epoch().as_u64(),
+            exporter_secret: hex::encode(&*exporter_secret),
+            epoch_authenticator: hex::encode(group.epoch_authenticator().as_slice()),
+            confirmation_tag: hex::encode(confirmation_tag),
+            tree_hash: hex::encode(context.tree_hash()),
+            confirmed_transcript_hash: hex::encode(context.confirmed_transcript_hash()),
+        })
+    }
+
+    /// Zeroes the key store values of a group (those whose key contains its
+    /// serialized ID) in place, so that deleting them drops no secrets.
+    fn zeroize_group_values(&self, group_id: &str) -> Result<(), MlsError> {
+        let gid = GroupId::from_slice(&Encoding::Hex.decode(group_id)?);
+        let is_group_key = group_key_matcher(&gid)?;
+        let mut values = self
+            .crypto
+            .storage()
+            .values
+            .write()
+            .map_err(|_| MlsError::lock_poisoned())?;
+        for (key, value) in values.iter_mut() {
+            if is_group_key(key) {
+                value.zeroize();
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes a group, resident or archived, accept messages of its last
+    /// `max_past_epochs` epochs from now on (see `GroupSettings`).
+    fn set_group_max_past_epochs(&mut self, group_id: &str, max_past_epochs: usize) -> Result<(), MlsError> {
+        let mut settings = self.group_settings(group_id);
+        settings.max_past_epochs = max_past_epochs;
+        match self.groups.get_mut(group_id) {
+            Some(group) => set_group_config(group, &self.crypto, settings)?,
+            None => set_group_config(&mut self.load_group(group_id)?, &self.crypto, settings)?,
+        }
+        self.group_settings.insert(group_id.to_string(), settings);
+        Ok(())
+    }
+
+    /// The key store entries of a group, base64-encoded as in
+    /// `store_values()`.
+    fn group_store_values(&self, group_id: &str) -> Result<HashMap<String, String>, MlsError> {
+        use base64::Engine;
+        let gid = GroupId::from_slice(&Encoding::Hex.decode(group_id)?);
+        let is_group_key = group_key_matcher(&gid)?;
+        let values = self
+            .crypto
+            .storage()
+            .values
+            .read()
+            .map_err(|_| MlsError::lock_poisoned())?;
+        Ok(values
+            .iter()
+            .filter(|(k, _)| is_group_key(k))
+            .map(|(k, v)| {
+                (
+                    base64::prelude::BASE64_STANDARD.encode(k),
+                    base64::prelude::BASE64_STANDARD.encode(v),
+                )
+            })
+            .collect())
+    }
+
+    /// Sets the `updated_at` time of the groups whose epoch changed.
+    fn note_epoch_changes(&mut self, events: &[MlsEvent]) {
+        let now = unix_time();
+        for event in events {
+            if let MlsEvent::EpochChanged { group_id, .. } = event {
+                if let Some(times) = self.group_times.get_mut(group_id) {
+                    times.updated_at = now;
+                }
+            }
+        }
+    }
+
+    /// Adds a group to the resident set, archiving the least recently used
+    /// ones beyond the limit.
+    /// The recorded settings of a group, the defaults for groups saved
+    /// before they were recorded.
+    fn group_settings(&self, group_id: &str) -> GroupSettings {
+        self.group_settings.get(group_id).copied().unwrap_or_default()
+    }
+
+    fn insert_group(&mut self, group_id: String, group: MlsGroup) {
+        // Re-added to a group we were once removed from.
+        self.removed_groups.remove(&group_id);
+        self.groups.insert(group_id.clone(), group);
+        self.dirty_groups.insert(group_id.clone());
+        self.resident_order.retain(|g| *g != group_id);
+        self.resident_order.push_back(group_id);
+        self.evict_excess_groups();
+    }
+
+    /// Fails with `GroupAlreadyJoined` if we hold `group_id`, resident or
+    /// archived, unless it is marked out of sync: joining or creating it
+    /// again would throw away its ratchet state.
+    fn ensure_not_joined(&self, group_id: &str) -> Result<(), MlsError> {
+        if self.desynced_groups.contains(group_id) {
+            return Ok(());
+        }
+        let current_epoch = match self.groups.get(group_id) {
+            Some(group) => group.epoch().as_u64(),
+            None if self.archived_groups.contains(group_id) => {
+                self.archived_epochs.get(group_id).copied().unwrap_or_default()
+            }
+            None => return Ok(()),
+        };
+        Err(MlsError::GroupAlreadyJoined {
+            group_id: group_id.to_string(),
+            current_epoch,
+        })
+    }
+
+    fn evict_excess_groups(&mut self) {
+        if self.max_resident_groups == 0 {
+            return;
+        }
+        while self.groups.len() > self.max_resident_groups {
+            let Some(oldest) = self.resident_order.pop_front() else {
+                break;
+            };
+            self.archive_group(&oldest);
+        }
+    }
+
+    /// Drops a group from memory.  OpenMLS writes every change through to
+    /// the key store, so nothing needs flushing first.
+    fn archive_group(&mut self, group_id: &str) {
+        if let Some(group) = self.groups.remove(group_id) {
+            self.archived_epochs.insert(group_id.to_string(), group.epoch().as_u64());
+            self.archived_groups.insert(group_id.to_string());
+        }
+        self.resident_order.retain(|g| g != group_id);
+    }
+
+    /// The contents of `state.json` for the given generation, plus which
+    /// groups made it in.  Only groups for which `verify` holds are checked
+    /// to load back from the key store and listed as saved; the others
+    /// are taken to be unchanged since they were last saved.
+    fn persisted_state(
+        &self,
+        generation: u64,
+        verify: &dyn Fn(&str) -> bool,
+    ) -> Result<(PersistedState, SaveReport), MlsError> {
+        let identity = self.persisted_identity(&self.signer, &self.identity_name)?;
+        let mut identities = HashMap::new();
+        for (name, identity) in &self.identities {
+            if let Some(persisted) = self.persisted_identity(&identity.signer, &identity.identity_name)? {
+                identities.insert(name.clone(), persisted);
+            }
+        }
+        let mut group_signers = HashMap::new();
+        for (group_id, signer) in &self.group_signers {
+            let signer_json = serde_json::to_string(signer)
+                .map_err(|e| MlsError::serialization(format!("Failed to serialize signer: {:?}", e)))?;
+            group_signers.insert(group_id.clone(), signer_json);
+        }
+
+        let mut report = SaveReport {
+            saved_groups: Vec::new(),
+            removed_groups: Vec::new(),
+            failed_groups: Vec::new(),
+        };
+        let mut groups = Vec::new();
+        for id in &self.archived_groups {
+            if let Some(&epoch) = self.archived_epochs.get(id).filter(|_| !verify(id)) {
+                groups.push(PersistedGroupMeta {
+                    group_id: id.clone(),
+                    epoch,
+                    times: self.group_times.get(id).copied(),
+                    settings: self.group_settings.get(id).copied(),
+                });
+                continue;
+            }
+            match self.load_group(id) {
+                Ok(group) => {
+                    groups.push(PersistedGroupMeta {
+                        group_id: id.clone(),
+                        epoch: group.epoch().as_u64(),
+                        times: self.group_times.get(id).copied(),
+                        settings: self.group_settings.get(id).copied(),
+                    });
+                    report.saved_groups.push(id.clone());
+                }
+                Err(e) => report.failed_groups.push(GroupSaveFailure {
+                    group_id: id.clone(),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        for (id, group) in &self.groups {
+            if !group.is_active() || group.own_leaf().is_none() {
+                report.removed_groups.push(id.clone());
+                continue;
+            }
+            if !verify(id) {
+                groups.push(PersistedGroupMeta {
+                    group_id: id.clone(),
+                    epoch: group.epoch().as_u64(),
+                    times: self.group_times.get(id).copied(),
+                    settings: self.group_settings.get(id).copied(),
+                });
+                continue;
+            }
+            // The group's state lives in the key store; check that it can
+            // actually be loaded back from there.
+            match MlsGroup::load(self.crypto.storage(), group.group_id()) {
+                Ok(Some(_)) => {
+                    groups.push(PersistedGroupMeta {
+                        group_id: id.clone(),
+                        epoch: group.epoch().as_u64(),
+                        times: self.group_times.get(id).copied(),
+                        settings: self.group_settings.get(id).copied(),
+                    });
+                    report.saved_groups.push(id.clone());
+                }
+                Ok(None) => report.failed_groups.push(GroupSaveFailure {
+                    group_id: id.clone(),
+                    reason: "Group is missing from the key store".into(),
+                }),
+                Err(e) => report.failed_groups.push(GroupSaveFailure {
+                    group_id: id.clone(),
+                    reason: format!("Failed to read group from the key store: {:?}", e),
+                }),
+            }
+        }
+
+        let persisted = PersistedState {
+            version: STATE_VERSION,
+            identity,
+            groups,
+            generation,
+            pending_self_updates: self.pending_self_updates.iter().cloned().collect(),
+            frozen_groups: self.frozen_groups.clone(),
+            external_psks: self.external_psks.iter().cloned().collect(),
+            used_key_packages: self.used_key_packages.iter().cloned().collect(),
+            removed_groups: self.removed_groups.iter().cloned().collect(),
+            send_generations: self.send_generations.clone(),
+            seen_generations: self.seen_generations.clone(),
+            send_sequences: self.send_sequences.clone(),
+            reinits: self.reinits.clone(),
+            identities,
+            group_identities: self.group_identities.clone(),
+            group_signers,
+            group_credentials: self.group_credentials.clone(),
+            desynced_groups: self.desynced_groups.iter().cloned().collect(),
+            max_members: self.max_members.clone(),
+            commit_journal: self.commit_journal.clone(),
+            commit_sequence: self.commit_sequence,
+            group_activity: self.group_activity.clone(),
+            joined_welcomes: self.joined_welcomes.clone(),
+        };
+        Ok((persisted, report))
+    }
+
+    fn persisted_identity(
+        &self,
+        signer: &Option<SigningKeyPair>,
+        name: &Option<String>,
+    ) -> Result<Option<PersistedIdentity>, MlsError> {
+        let (Some(signer), Some(name)) = (signer, name) else {
+            return Ok(None);
+        };
+        let signer_json = serde_json::to_string(signer)
+            .map_err(|e| MlsError::serialization(format!("Failed to serialize signer: {:?}", e)))?;
+        Ok(Some(PersistedIdentity {
+            name: name.clone(),
+            signer_json,
+            capabilities: self.capabilities.clone(),
+            application_id: self.application_id.clone(),
+        }))
+    }
+
+    /// The key store, base64-encoded as in `openmls_store.json`, split into
+    /// the entries of no group and those of each group in `groups`.
+    /// Entries of other groups are left out.
+    #[allow(clippy::type_complexity)]
+    fn group_split_store_values(
+        &self,
+        groups: &HashSet<String>,
+    ) -> Result<(HashMap<String, String>, HashMap<String, HashMap<String, String>>), MlsError> {
+        use base64::Engine;
+        let mut known = HashMap::new();
+        for id in self.groups.keys().chain(&self.archived_groups) {
+            let gid = GroupId::from_slice(&Encoding::Hex.decode(id)?);
+            let serialized = serde_json::to_vec(&gid).map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+            known.insert(serialized, id.clone());
+        }
+
+        let values = self
+            .crypto
+            .storage()
+            .values
+            .read()
+            .map_err(|_| MlsError::lock_poisoned())?;
+        let mut shared = HashMap::new();
+        let mut by_group: HashMap<String, HashMap<String, String>> = HashMap::new();
+        for (key, value) in values.iter() {
+            let entries = match key_group(key, &known) {
+                None => &mut shared,
+                Some(id) if groups.contains(id) => by_group.entry(id.clone()).or_default(),
+                Some(_) => continue,
+            };
+            entries.insert(
+                base64::prelude::BASE64_STANDARD.encode(key),
+                base64::prelude::BASE64_STANDARD.encode(value),
+            );
+        }
+        Ok((shared, by_group))
+    }
+
+    /// The key store, base64-encoded as in `openmls_store.json`.
+    fn store_values(&self) -> Result<HashMap<String, String>, MlsError> {
+        use base64::Engine;
+        let values = self
+            .crypto
+            .storage()
+            .values
+            .read()
+            .map_err(|_| MlsError::lock_poisoned())?;
+        Ok(values
+            .iter()
+            .map(|(k, v)| {
+                (
+                    base64::prelude::BASE64_STANDARD.encode(k),
+                    base64::prelude::BASE64_STANDARD.encode(v),
+                )
+            })
+            .collect())
+    }
+
+    /// Lifetime for the leaf nodes we create.  Seeded test clients pin it so
+    /// that transcripts don't depend on the wall clock.
+    fn leaf_lifetime(&self) -> Lifetime {
+        #[cfg(feature = "deterministic-tests")]
+        if self.crypto.is_seeded() {
+            return Lifetime::init(0, u64::MAX);
+        }
+        Lifetime::default()
+    }
+
+    /// Fails with `GroupReinitialized`, `GroupDesynced` or `GroupFrozen` if
+    /// nothing may be sent in the group.
+    fn ensure_not_frozen(&self, group_id: &str) -> Result<(), MlsError> {
+        if let Some(target) = self.reinits.get(group_id) {
+            return Err(MlsError::GroupReinitialized {
+                group_id: group_id.to_string(),
+                new_group_id: target.group_id.clone(),
+            });
+        }
+        if self.desynced_groups.contains(group_id) {
+            return Err(MlsError::GroupDesynced {
+                group_id: group_id.to_string(),
+            });
+        }
+        match self.frozen_groups.get(group_id) {
+            Some(reason) => Err(MlsError::GroupFrozen {
+                reason: reason.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the maintenance step `f` with `group_id` frozen for `reason`,
+    /// lifting the freeze again afterwards, also when `f` fails.  A freeze
+    /// already in place, or one `f` sets itself, is left as it is.
+    fn frozen_for<T>(
+        &mut self,
+        group_id: &str,
+        reason: &str,
+        f: impl FnOnce(&mut Self) -> Result<T, MlsError>,
+    ) -> Result<T, MlsError> {
+        let placed = !self.frozen_groups.contains_key(group_id);
+        if placed {
+            self.frozen_groups.insert(group_id.to_string(), reason.to_string());
+        }
+        let result = f(self);
+        if placed && self.frozen_groups.get(group_id).is_some_and(|r| r == reason) {
+            self.frozen_groups.remove(group_id);
+        }
+        result
+    }
+
+    /// Like `ensure_not_frozen()`, and also fails with
+    /// `CommitTransactionOpen` while `begin_commit()` has a transaction
+    /// open for the group.
+    fn ensure_can_commit(&self, group_id: &str) -> Result<(), MlsError> {
+        self.ensure_not_frozen(group_id)?;
+        match &self.commit_txn {
+            Some(txn) if txn.group_id == group_id => Err(MlsError::CommitTransactionOpen {
+                group_id: group_id.to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The group of the open commit transaction.
+    fn commit_txn_group(&self) -> Result<String, MlsError> {
+        self.commit_txn
+            .as_ref()
+            .map(|txn| txn.group_id.clone())
+            .ok_or_else(MlsError::no_commit_txn)
+    }
+
+    /// Swaps the identity registered as `name` into `signer`, `credential`
+    /// and `identity_name`, so that everything signed until
+    /// `restore_identity()` is signed as it.
+    fn select_identity(&mut self, name: &str) -> Result<(), MlsError> {
+        self.restore_identity();
+        let identity = self
+            .identities
+            .get_mut(name)
+            .ok_or_else(|| MlsError::UnknownIdentity { name: name.to_string() })?;
+        std::mem::swap(&mut self.signer, &mut identity.signer);
+        std::mem::swap(&mut self.credential, &mut identity.credential);
+        std::mem::swap(&mut self.identity_name, &mut identity.identity_name);
+        self.active_identity = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Selects the identity `group_id` belongs to, with the group's own
+    /// signature key (see `rotate_group_signature_key()`) and credential
+    /// identity (see `update_credential()`) if it has them.
+    fn select_group(&mut self, group_id: &str) -> Result<(), MlsError> {
+        match self.group_identities.get(group_id).cloned() {
+            Some(name) => self.select_identity(&name)?,
+            None => self.restore_identity(),
+        }
+        if let (Some(own), Some(signer)) = (self.group_signers.get_mut(group_id), self.signer.as_mut()) {
+            std::mem::swap(own, signer);
+            self.active_group_signer = Some(group_id.to_string());
+        }
+        if let (Some(name), Some(signer)) = (self.group_credentials.get(group_id), self.signer.as_ref()) {
+            let own = CredentialWithKey {
+                credential: Credential::new(CredentialType::Basic, name.clone().into_bytes()),
+                signature_key: signer.to_public_vec().into(),
+            };
+            if let Some(displaced) = self.credential.replace(own) {
+                self.displaced_credential = Some((group_id.to_string(), displaced));
+            }
+        }
+        Ok(())
+    }
+
+    /// Swaps the default identity back in after `select_identity()` or
+    /// `select_group()`.
+    fn restore_identity(&mut self) {
+        self.restore_group_key();
+        let Some(name) = self.active_identity.take() else {
+            return;
+        };
+        if let Some(identity) = self.identities.get_mut(&name) {
+            std::mem::swap(&mut self.signer, &mut identity.signer);
+            std::mem::swap(&mut self.credential, &mut identity.credential);
+            std::mem::swap(&mut self.identity_name, &mut identity.identity_name);
+        }
+    }
+
+    /// Swaps the key and credential of the identity back in after
+    /// `select_group()` swapped in the group's own.
+    fn restore_group_key(&mut self) {
+        if let Some((_, credential)) = self.displaced_credential.take() {
+            self.credential = Some(credential);
+        }
+        if let Some(group_id) = self.active_group_signer.take() {
+            if let (Some(own), Some(signer)) = (self.group_signers.get_mut(&group_id), self.signer.as_mut()) {
+                std::mem::swap(own, signer);
+            }
+        }
+    }
+
+    /// The key pair `group_id` is signed for with, outside
+    /// `select_group()`: its own if it has one, otherwise that of the
+    /// identity it belongs to.
+    fn group_signer(&self, group_id: &str) -> Result<&SigningKeyPair, MlsError> {
+        if let Some(signer) = self.group_signers.get(group_id) {
+            return Ok(signer);
+        }
+        let signer = match self.group_identities.get(group_id) {
+            Some(name) => self
+                .identities
+                .get(name)
+                .ok_or_else(|| MlsError::UnknownIdentity { name: name.clone() })?
+                .signer
+                .as_ref(),
+            None => self.signer.as_ref(),
+        };
+        signer.ok_or_else(MlsError::no_identity)
+    }
+
+    /// The name of the identity added with `add_identity()` whose
+    /// signature key is `key`, or `None` if it is not one of theirs.
+    fn extra_identity_with_key(&self, key: &[u8]) -> Option<String> {
+        self.identities
+            .iter()
+            .find(|(_, identity)| identity.signer.as_ref().is_some_and(|s| s.public() == key))
+            .map(|(name, _)| name.clone())
+    }
+
+    /// Creates a group with the given ID (random if `None`), ciphersuite,
+    /// context extensions and configuration, and returns its hex-encoded ID.
+    /// The group belongs to the selected identity.
+    fn create_group(
+        &mut self,
+        group_id: Option<GroupId>,
+        ciphersuite: Ciphersuite,
+        mut extensions: Extensions<GroupContext>,
+        group_config: &GroupConfig,
+    ) -> Result<String, MlsError> {
+        let signer = self.signer.as_ref().ok_or_else(MlsError::no_identity)?;
+        let credential = self.credential.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        // Our leaf must advertise the group's own suite.
+        let mut capabilities = self.capabilities.clone();
+        let suite = u16::from(ciphersuite);
+        if !capabilities.ciphersuites.is_empty() && !capabilities.ciphersuites.contains(&suite) {
+            capabilities.ciphersuites.push(suite);
+        }
+
+        if !group_config.external_senders.is_empty() {
+            let senders = group_config
+                .external_senders
+                .iter()
+                .map(|s| {
+                    ExternalSender::new(
+                        s.signature_key.clone().into(),
+                        BasicCredential::new(s.identity.clone()).into(),
+                    )
+                })
+                .collect();
+            extensions
+                .add_or_replace(Extension::ExternalSenders(senders))
+                .map_err(|e| MlsError::generic(format!("Invalid external senders: {:?}", e)))?;
+        }
+        if group_config.payload_envelope {
+            extensions
+                .add_or_replace(Extension::Unknown(
+                    PAYLOAD_ENVELOPE_EXTENSION_TYPE,
+                    UnknownExtension(Vec::new()),
+                ))
+                .map_err(|e| MlsError::generic(format!("Invalid payload envelope extension: {:?}", e)))?;
+            require_custom_extensions(&mut extensions)?;
+        }
+
+        let config = MlsGroupCreateConfig::builder()
+            .ciphersuite(ciphersuite)
+            .use_ratchet_tree_extension(group_config.use_ratchet_tree_extension)
+            .wire_format_policy(wire_format_policy(group_config.wire_format_public))
+            .capabilities(capabilities.to_leaf_capabilities()?)
+            .lifetime(self.leaf_lifetime())
+            .padding_size(group_config.padding_size as usize)
+            .max_past_epochs(group_config.max_past_epochs as usize)
+            .with_group_context_extensions(extensions)
+            .with_leaf_node_extensions(self.leaf_node_extensions()?)
+            .map_err(|e| MlsError::generic(format!("Invalid leaf node extensions: {:?}", e)))?
+            .build();
+
+        if let Some(group_id) = &group_id {
+            self.ensure_not_joined(&hex::encode(group_id.as_slice()))?;
+        }
+        let group = match group_id {
+            Some(group_id) => MlsGroup::new_with_group_id(&self.crypto, signer, &config, group_id, credential.clone()),
+            None => MlsGroup::new(&self.crypto, signer, &config, credential.clone()),
+        }
+        .map_err(|e| MlsError::generic(format!("Failed to create group: {:?}", e)))?;
+
+        let gid = hex::encode(group.group_id().as_slice());
+        log::info!("Created group {} with ciphersuite 0x{:04x}", gid, suite);
+        let settings = GroupSettings::new(
+            &group,
+            group_config.use_ratchet_tree_extension,
+            group_config.max_past_epochs as usize,
+        );
+        self.insert_group(gid.clone(), group);
+        self.group_times.insert(gid.clone(), GroupTimes::now());
+        self.group_settings.insert(gid.clone(), settings);
+        if let Some(name) = &self.active_identity {
+            self.group_identities.insert(gid.clone(), name.clone());
+        }
+        Ok(gid)
+    }
+
+    /// The configuration of groups created without one: ratchet tree in
+    /// Welcomes, ciphertext-only handshakes and the client's padding and
+    /// past epoch retention.
+    fn default_group_config(&self) -> GroupConfig {
+        GroupConfig {
+            use_ratchet_tree_extension: true,
+            wire_format_public: false,
+            padding_size: self.padding_size as u32,
+            external_senders: Vec::new(),
+            max_past_epochs: self.max_past_epochs as u32,
+            payload_envelope: false,
+        }
+    }
+
+    /// Resolves a ciphersuite codepoint, checking that the provider
+    /// implements it and that our identity key can sign for it.
+    fn usable_ciphersuite(&self, ciphersuite: u16) -> Result<Ciphersuite, MlsError> {
+        let suite = Ciphersuite::try_from(ciphersuite).map_err(|_| MlsError::UnsupportedCapabilities {
+            msg: format!("Unknown ciphersuite 0x{:04x}", ciphersuite),
+        })?;
+        if self.crypto.crypto().supports(suite).is_err() {
+            return Err(MlsError::UnsupportedCapabilities {
+                msg: format!("Ciphersuite {:?} is not supported by the crypto provider", suite),
+            });
+        }
+        if suite.signature_algorithm() != CIPHERSUITE.signature_algorithm() {
+            return Err(MlsError::UnsupportedCapabilities {
+                msg: format!(
+                    "Ciphersuite {:?} needs {:?} signatures; our identity uses {:?}",
+                    suite,
+                    suite.signature_algorithm(),
+                    CIPHERSUITE.signature_algorithm()
+                ),
+            });
+        }
+        Ok(suite)
+    }
+
+    /// Builds and stores a key package bundle for `credential`, valid for
+    /// `lifetime`, returning the encoded key package, as an MLSMessage if
+    /// `wrapped`, and its ref.  OpenMLS keeps the private keys of last-resort packages
+    /// when a Welcome consumes them.
+    fn encoded_key_package(
+        &self,
+        ciphersuite: Ciphersuite,
+        credential: CredentialWithKey,
+        lifetime: Lifetime,
+        last_resort: bool,
+        wrapped: bool,
+    ) -> Result<GeneratedKeyPackage, MlsError> {
+        let signer = self.signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        // Adders reject key package extensions the leaf does not advertise.
+        let mut capabilities = self.capabilities.clone();
+        if last_resort {
+            capabilities.extensions.push(u16::from(ExtensionType::LastResort));
+        }
+        let suite = u16::from(ciphersuite);
+        if !capabilities.ciphersuites.is_empty() && !capabilities.ciphersuites.contains(&suite) {
+            capabilities.ciphersuites.push(suite);
+        }
+
+        let mut builder = KeyPackage::builder()
+            .key_package_lifetime(lifetime)
+            .leaf_node_capabilities(capabilities.to_leaf_capabilities()?)
+            .leaf_node_extensions(self.leaf_node_extensions()?);
+        if last_resort {
+            builder = builder.mark_as_last_resort();
+        }
+        let kp = builder
+            .build(ciphersuite, &self.crypto, signer, credential)
+            .map_err(|e| MlsError::crypto(format!("Failed to build key package: {:?}", e)))?;
+
+        let key_package = kp.key_package().clone();
+        let bytes = if wrapped {
+            MlsMessageOut::from(key_package).tls_serialize_detached()
+        } else {
+            key_package.tls_serialize_detached()
+        }
+        .map_err(|e| MlsError::serialization(format!("Failed to serialize key package: {:?}", e)))?;
+        let hash_ref = kp
+            .key_package()
+            .hash_ref(self.crypto.crypto())
+            .map_err(|e| MlsError::crypto(format!("Failed to hash key package: {:?}", e)))?;
+
+        Ok(GeneratedKeyPackage {
+            key_package: self.encoding.encode(&bytes),
+            key_package_ref: hex::encode(hash_ref.as_slice()),
+        })
+    }
+
+    /// Extensions for our own leaf nodes: the application id, if set.
+    fn leaf_node_extensions(&self) -> Result<Extensions<LeafNode>, MlsError> {
+        let Some(application_id) = &self.application_id else {
+            return Ok(Extensions::empty());
+        };
+        Extensions::single(Extension::ApplicationId(ApplicationIdExtension::new(application_id)))
+            .map_err(|e| MlsError::generic(format!("Invalid leaf node extensions: {:?}", e)))
+    }
+
+    /// Parses an encoded Welcome.  Returns it with the hex SHA-256 digest
+    /// of its bytes.
+    fn decode_welcome(&mut self, welcome_encoded: &str) -> Result<(Welcome, String), MlsError> {
+        let bytes = self.encoding.decode_limited(welcome_encoded, self.input_limits.welcome)?;
+        check_protocol_version(&bytes)?;
+        let welcome_digest = hex::encode(
+            self.crypto
+                .crypto()
+                .hash(HashType::Sha2_256, &bytes)
+                .map_err(|e| MlsError::crypto(format!("Failed to hash Welcome: {:?}", e)))?,
+        );
+
+        let mut rest = bytes.as_slice();
+        let mls_msg = MlsMessageIn::tls_deserialize(&mut rest)
+            .inspect_err(|e| log::warn!("Invalid Welcome message: {:?}", e))
+            .map_err(|e| MlsError::serialization(format!("Invalid MLS message: {:?}", e)))?;
+        self.check_trailing_bytes("Welcome MLSMessage", bytes.len(), rest.len())?;
+
+        match mls_msg.extract() {
+            MlsMessageBodyIn::Welcome(welcome) => Ok((welcome, welcome_digest)),
+            _ => Err(MlsError::generic("Expected a Welcome message")),
+        }
+    }
+
+    fn welcome_join_config(&self) -> MlsGroupJoinConfig {
+        MlsGroupJoinConfig::builder()
+            .use_ratchet_tree_extension(true)
+            .padding_size(self.padding_size)
+            .max_past_epochs(self.max_past_epochs)
+            .build()
+    }
+
+    /// Reads who invited us into which group from an encoded Welcome,
+    /// without joining.  The Welcome is staged against a scratch copy of
+    /// the key store, so the key package it is for is not consumed.
+    fn preview_welcome(&mut self, welcome_encoded: &str) -> Result<WelcomePreview, MlsError> {
+        let (welcome, welcome_digest) = self.decode_welcome(welcome_encoded)?;
+        let key_package_refs: Vec<String> = welcome
+            .secrets()
+            .iter()
+            .map(|secrets| hex::encode(secrets.new_member().as_slice()))
+            .collect();
+
+        let scratch = MlsProvider::default();
+        {
+            let values = self.crypto.storage().values.read().map_err(|_| MlsError::lock_poisoned())?;
+            let mut copy = scratch.storage().values.write().map_err(|_| MlsError::lock_poisoned())?;
+            copy.extend(values.iter().map(|(key, value)| (key.clone(), value.clone())));
+        }
+        let staged = match StagedWelcome::build_from_welcome(&scratch, &self.welcome_join_config(), welcome)
+            .and_then(|builder| builder.build())
+        {
+            Ok(staged) => staged,
+            Err(WelcomeError::NoMatchingKeyPackage) => {
+                // The same Welcome again, after joining with it.
+                if let Some(group_id) = self.joined_welcomes.get(&welcome_digest) {
+                    self.ensure_not_joined(group_id)?;
+                }
+                return Err(MlsError::NoMatchingKeyPackage { key_package_refs });
+            }
+            Err(e) => return Err(MlsError::generic(format!("Failed to stage welcome: {:?}", e))),
+        };
+
+        let group_id = hex::encode(staged.group_context().group_id().as_slice());
+        self.ensure_not_joined(&group_id)?;
+        let inviter = staged
+            .welcome_sender()
+            .map_err(|e| MlsError::generic(format!("Welcome signer not in the group: {:?}", e)))?;
+        Ok(WelcomePreview {
+            inviter_index: staged.welcome_sender_index().u32(),
+            inviter_identity: inviter.credential().serialized_content().to_vec(),
+            epoch: staged.group_context().epoch().as_u64(),
+            ciphersuite: u16::from(staged.group_context().ciphersuite()),
+            member_count: staged.members().count() as u32,
+            metadata: staged
+                .group_context()
+                .extensions()
+                .unknown(GROUP_METADATA_EXTENSION_TYPE)
+                .map(|e| e.0.clone()),
+            group_id,
+        })
+    }
+
+    /// Joins the group an encoded Welcome invites us to, with the group's
+    /// ratchet tree if the Welcome does not carry it.  Returns the group
+    /// ID, the hex-encoded ref of the key package it consumed and the leaf
+    /// index of the member who invited us.
+    fn join_group(
+        &mut self,
+        welcome_encoded: &str,
+        ratchet_tree_encoded: Option<&str>,
+    ) -> Result<(String, String, u32), MlsError> {
+        let (welcome, welcome_digest) = self.decode_welcome(welcome_encoded)?;
+        let join_config = self.welcome_join_config();
+
+        // Staging deletes the consumed key package's private key material
+        // from the key store, unless it is a last-resort key package.
+        let key_package_refs: Vec<String> = welcome
+            .secrets()
+            .iter()
+            .map(|secrets| hex::encode(secrets.new_member().as_slice()))
+            .collect();
+        // OpenMLS uses the first secret whose key package we hold.
+        let mut consumed = None;
+        for secrets in welcome.secrets() {
+            let bundle: Option<KeyPackageBundle> = self
+                .crypto
+                .storage()
+                .key_package(&secrets.new_member())
+                .map_err(|e| MlsError::io(format!("Failed to read key package: {:?}", e)))?;
+            if let Some(bundle) = bundle {
+                consumed = Some((hex::encode(secrets.new_member().as_slice()), bundle.key_package().last_resort()));
+                break;
+            }
+        }
+        let Some((consumed, last_resort)) = consumed else {
+            // The same Welcome again, e.g. retried after a timeout.
+            if let Some(group_id) = self.joined_welcomes.get(&welcome_digest) {
+                self.ensure_not_joined(group_id)?;
+            }
+            return Err(MlsError::NoMatchingKeyPackage { key_package_refs });
+        };
+        let ratchet_tree = ratchet_tree_encoded
+            .map(|encoded| self.decode_ratchet_tree(encoded))
+            .transpose()?;
+        let staging_error = |e| match e {
+            WelcomeError::NoMatchingKeyPackage => MlsError::NoMatchingKeyPackage {
+                key_package_refs: key_package_refs.clone(),
+            },
+            e => MlsError::generic(format!("Failed to stage welcome: {:?}", e)),
+        };
+        let mut builder =
+            StagedWelcome::build_from_welcome(&self.crypto, &join_config, welcome).map_err(staging_error)?;
+        // A Welcome back into a group we marked out of sync replaces it;
+        // for any other existing group, joining fails.
+        let desynced = hex::encode(
+            builder
+                .processed_welcome()
+                .unverified_group_info()
+                .group_id()
+                .as_slice(),
+        );
+        self.ensure_not_joined(&desynced)?;
+        let replaces = self.desynced_groups.contains(&desynced);
+        if replaces {
+            builder = builder.replace_old_group();
+        }
+        if let Some(ratchet_tree) = ratchet_tree {
+            builder = builder.with_ratchet_tree(ratchet_tree);
+        }
+        let staged = builder.build().map_err(staging_error)?;
+        if replaces {
+            self.discard_group(&desynced);
+        }
+        let inviter_index = staged.welcome_sender_index().u32();
+
+        let group = staged
+            .into_group(&self.crypto)
+            .map_err(|e| MlsError::generic(format!("Failed to join group: {:?}", e)))?;
+
+        // The Welcome was for a key package of whichever identity our leaf
+        // carries the key of.
+        let identity = group
+            .own_leaf_node()
+            .and_then(|leaf| self.extra_identity_with_key(leaf.signature_key().as_slice()));
+        let gid = hex::encode(group.group_id().as_slice());
+        // Joined with `welcome_join_config()`.
+        let settings = GroupSettings::new(&group, true, self.max_past_epochs);
+        self.insert_group(gid.clone(), group);
+        self.group_times.insert(gid.clone(), GroupTimes::now());
+        self.group_settings.insert(gid.clone(), settings);
+        self.joined_welcomes.insert(welcome_digest, gid.clone());
+        self.last_join_last_resort = last_resort;
+        if let Some(name) = identity {
+            self.group_identities.insert(gid.clone(), name);
+        }
+        record_event(
+            &mut self.pending_events,
+            MlsEvent::GroupJoined {
+                group_id: gid.clone(),
+            },
+        );
+        Ok((gid, consumed, inviter_index))
+    }
+
+    /// Joins from an encoded Welcome, as `join_group()` does, and describes
+    /// the joined group for `process_welcome_full()`.
+    fn join_group_full(&mut self, welcome_encoded: &str) -> Result<JoinedGroup, MlsError> {
+        let (group_id, key_package_ref, inviter_index) = self.join_group(welcome_encoded, None)?;
+        let group = self
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let epoch = group.epoch().as_u64();
+        let members = member_infos(group)?;
+        let inviter_identity = group
+            .member(LeafNodeIndex::new(inviter_index))
+            .map(|c| c.serialized_content().to_vec())
+            .unwrap_or_default();
+        let ciphersuite = u16::from(group.ciphersuite());
+        let own_leaf_index = group.own_leaf_index().u32();
+        let metadata = group
+            .extensions()
+            .unknown(GROUP_METADATA_EXTENSION_TYPE)
+            .map(|e| e.0.clone());
+        let queued = self.drain_pending(&group_id);
+
+        Ok(JoinedGroup {
+            group_id,
+            key_package_ref,
+            inviter_index,
+            inviter_identity,
+            epoch,
+            members,
+            ciphersuite,
+            own_leaf_index,
+            metadata,
+            queued,
+        })
+    }
+
+    fn decode_ratchet_tree(&self, encoded: &str) -> Result<RatchetTreeIn, MlsError> {
+        let bytes = self.encoding.decode_limited(encoded, self.input_limits.welcome)?;
+        RatchetTreeIn::tls_deserialize_exact(bytes)
+            .map_err(|e| MlsError::serialization(format!("Invalid ratchet tree: {:?}", e)))
+    }
+
+    /// Queues a protocol message for a group we have not joined yet, if
+    /// queueing is enabled, evicting the oldest ones beyond the limits.
+    /// Returns whether the message was queued.
+    fn queue_if_unknown(&mut self, group_id: &str, bytes: &[u8]) -> bool {
+        if self.pending_max_messages == 0 || self.groups.contains_key(group_id) {
+            return false;
+        }
+        let is_protocol_message = MlsMessageIn::tls_deserialize_exact(bytes)
+            .is_ok_and(|m| m.try_into_protocol_message().is_ok());
+        if !is_protocol_message {
+            return false;
+        }
+
+        let queue = self.pending_messages.entry(group_id.to_string()).or_default();
+        queue.push_back(bytes.to_vec());
+        let mut total: usize = queue.iter().map(Vec::len).sum();
+        while queue.len() > self.pending_max_messages || (self.pending_max_bytes > 0 && total > self.pending_max_bytes) {
+            let Some(evicted) = queue.pop_front() else {
+                break;
+            };
+            total -= evicted.len();
+            record_event(
+                &mut self.pending_events,
+                MlsEvent::PendingMessageEvicted {
+                    group_id: group_id.to_string(),
+                },
+            );
+        }
+        true
+    }
+
+    /// Processes the messages queued for a group we just joined, in
+    /// arrival order.  Messages that fail, e.g. because they were sent
+    /// before the epoch we joined in, are dropped with a warning.
+    fn drain_pending(&mut self, group_id: &str) -> Vec<IncomingMessage> {
+        let Some(queue) = self.pending_messages.remove(group_id) else {
+            return Vec::new();
+        };
+        log::info!("Processing {} messages queued for group {}", queue.len(), group_id);
+
+        let mut results = Vec::with_capacity(queue.len());
+        for bytes in queue {
+            match self.process_incoming(group_id, &bytes) {
+                Ok(result) => {
+                    let removed = matches!(result, IncomingMessage::Removed { .. });
+                    results.push(result);
+                    if removed {
+                        break;
+                    }
+                }
+                Err(e) => log::warn!("Dropping queued message for group {}: {}", group_id, e),
+            }
+        }
+        results
+    }
+
+    /// Joins a group by external commit against a serialized GroupInfo
+    /// message and, if it lacks one, ratchet tree.  With `replace`, any
+    /// local state we still hold for the group is discarded; without, it
+    /// fails with `GroupAlreadyJoined` (see `ensure_not_joined()`).
+    /// Returns the group ID and the encoded commit.
+    fn rejoin_group(
+        &mut self,
+        group_info_bytes: &[u8],
+        ratchet_tree_bytes: Option<&[u8]>,
+        identity: Option<String>,
+        replace: bool,
+    ) -> Result<(String, String), MlsError> {
+        let group_info = match MlsMessageIn::tls_deserialize_exact(group_info_bytes)
+            .map_err(|e| MlsError::serialization(format!("Invalid MLS message: {:?}", e)))?
+            .extract()
+        {
+            MlsMessageBodyIn::GroupInfo(info) => info,
+            _ => return Err(MlsError::generic("Expected a GroupInfo message")),
+        };
+        // Unless told otherwise, rejoin as the identity we were in the group
+        // as.  The caller restores the default identity afterwards.
+        let gid = hex::encode(group_info.group_id().as_slice());
+        if !replace {
+            self.ensure_not_joined(&gid)?;
+        }
+        let identity = identity
+            .or_else(|| self.group_identities.get(&gid).cloned())
+            .filter(|name| self.identity_name.as_ref() != Some(name));
+        if let Some(name) = identity {
+            self.select_identity(&name)?;
+        }
+        let credential = self.credential.clone().ok_or_else(MlsError::no_identity)?;
+
+        // The new state is stored under the same group ID, so the stale
+        // state has to go first.
+        if self.groups.contains_key(&gid) || self.archived_groups.contains(&gid) {
+            log::info!("Discarding stale state of group {} to rejoin it", gid);
+            self.discard_group(&gid);
+        }
+
+        let ratchet_tree = ratchet_tree_bytes
+            .map(|bytes| {
+                RatchetTreeIn::tls_deserialize_exact(bytes)
+                    .map_err(|e| MlsError::serialization(format!("Invalid ratchet tree: {:?}", e)))
+            })
+            .transpose()?;
+
+        let join_config = MlsGroupJoinConfig::builder()
+            .use_ratchet_tree_extension(true)
+            .padding_size(self.padding_size)
+            .max_past_epochs(self.max_past_epochs)
+            .build();
+        let mut builder = MlsGroup::external_commit_builder().with_config(join_config);
+        if let Some(ratchet_tree) = ratchet_tree {
+            builder = builder.with_ratchet_tree(ratchet_tree);
+        }
+
+        // OpenMLS removes any leaf with our signature key, i.e. the one we
+        // lost the state of.
+        let signer = self.signer.as_ref().ok_or_else(MlsError::no_identity)?;
+        let leaf_params = LeafNodeParameters::builder()
+            .with_credential_with_key(credential.clone())
+            .with_capabilities(self.capabilities.to_leaf_capabilities()?)
+            .with_extensions(self.leaf_node_extensions()?)
+            .build();
+        let (group, bundle) = builder
+            .build_group(&self.crypto, group_info, credential)
+            .map_err(|e| MlsError::generic(format!("Failed to join by external commit: {:?}", e)))?
+            .leaf_node_parameters(leaf_params)
+            .load_psks(self.crypto.storage())
+            .map_err(|e| MlsError::generic(format!("Failed to load PSKs: {:?}", e)))?
+            .build(self.crypto.rand(), self.crypto.crypto(), signer, |_| true)
+            .map_err(|e| MlsError::generic(format!("Failed to create external commit: {:?}", e)))?
+            .finalize(&self.crypto)
+            .map_err(|e| MlsError::generic(format!("Failed to join by external commit: {:?}", e)))?;
+
+        let commit = bundle
+            .into_commit()
+            .tls_serialize_detached()
+            .map_err(|e| MlsError::serialization(format!("Failed to serialize commit: {:?}", e)))?;
+
+        let settings = GroupSettings::new(&group, true, self.max_past_epochs);
+        self.insert_group(gid.clone(), group);
+        self.group_times.insert(gid.clone(), GroupTimes::now());
+        self.group_settings.insert(gid.clone(), settings);
+        if let Some(name) = &self.active_identity {
+            self.group_identities.insert(gid.clone(), name.clone());
+        }
+        record_event(
+            &mut self.pending_events,
+            MlsEvent::GroupJoined {
+                group_id: gid.clone(),
+            },
+        );
+        Ok((gid, self.encoding.encode(&commit)))
+    }
+
+    /// Decodes and validates a key package on its own.  Besides decoding
+    /// errors, fails with `UnsupportedCiphersuite` if the provider does not
+    /// implement its ciphersuite, `KeyPackageExpired` or
+    /// `KeyPackageNotYetValid` if it is outside its lifetime,
+    /// `InvalidSignature` if its or its leaf's signature does not verify,
+    /// and `InvalidKeyPackage` for any other violation.
+    fn validated_key_package(&self, key_package_encoded: &str) -> Result<KeyPackage, MlsError> {
+        let kp_bytes = self
+            .encoding
+            .decode_limited(key_package_encoded, self.input_limits.key_package)?;
+
+        // An unknown codepoint would only surface as a decoding error.
+        if let Some(ciphersuite) = key_package_ciphersuite(&kp_bytes) {
+            let supported = Ciphersuite::try_from(ciphersuite)
+                .is_ok_and(|suite| self.crypto.crypto().supports(suite).is_ok());
+            if !supported {
+                return Err(MlsError::UnsupportedCiphersuite { ciphersuite });
+            }
+        }
+
+        let kp_in = parse_key_package(&kp_bytes)?;
+        check_key_package_lifetime(&kp_in)?;
+        kp_in
+            .validate(self.crypto.crypto(), ProtocolVersion::default())
+            .map_err(|e| match e {
+                KeyPackageVerifyError::InvalidSignature | KeyPackageVerifyError::InvalidLeafNodeSignature => {
+                    MlsError::InvalidSignature { detail: format!("{:?}", e) }
+                }
+                e => MlsError::InvalidKeyPackage { detail: format!("{:?}", e) },
+            })
+    }
+
+    /// Decodes and validates the key package of a member to add to the
+    /// group: its lifetime, ciphersuite and capabilities must fit, and its
+    /// signature key must not be a member's already.
+    fn new_member_key_package(&mut self, group_id: &str, key_package_encoded: &str) -> Result<KeyPackage, MlsError> {
+        let kp_bytes = self
+            .encoding
+            .decode_limited(key_package_encoded, self.input_limits.key_package)?;
+
+        let (kp_in, remaining) = parse_key_package_prefix(&kp_bytes)
+            .inspect_err(|e| log::warn!("Invalid key package for group {}: {}", group_id, e))?;
+        self.check_trailing_bytes("KeyPackage", kp_bytes.len(), remaining)?;
+        check_key_package_lifetime(&kp_in)?;
+
+        let key_package = kp_in
+            .validate(self.crypto.crypto(), ProtocolVersion::default())
+            .map_err(|e| MlsError::InvalidKeyPackage { detail: format!("{:?}", e) })?;
+
+        let group = self.groups.get(group_id).ok_or_else(|| MlsError::GroupNotFound {
+            group_id: group_id.to_string(),
+        })?;
+
+        if key_package.ciphersuite() != group.ciphersuite() {
+            return Err(MlsError::CiphersuiteMismatch {
+                group: u16::from(group.ciphersuite()),
+                key_package: u16::from(key_package.ciphersuite()),
+            });
+        }
+
+        let signature_key = key_package.leaf_node().signature_key().as_slice();
+        if let Some(existing) = group.members().find(|m| m.signature_key == signature_key) {
+            return Err(MlsError::AlreadyMember {
+                group_id: group_id.to_string(),
+                leaf_index: existing.index.u32(),
+            });
+        }
+
+        check_required_capabilities(group, &key_package)?;
+        Ok(key_package)
+    }
+
+    /// Creates (but does not merge) a commit adding the member whose key
+    /// package is given, returning the encoded commit and welcome.  With
+    /// `expected_identity`, fails with `IdentityMismatch` unless the key
+    /// package's credential carries exactly that identity.
+    fn prepare_add_member(
+        &mut self,
+        group_id: &str,
+        key_package_encoded: &str,
+        expected_identity: Option<&[u8]>,
+        omit_ratchet_tree: bool,
+    ) -> Result<AddMemberResult, MlsError> {
+        self.ensure_can_commit(group_id)?;
+        let key_package = self.new_member_key_package(group_id, key_package_encoded)?;
+        let duplicate_identity = self.check_duplicate_identity(group_id, &key_package, &[], &[])?;
+
+        if let Some(expected) = expected_identity {
+            let actual = key_package.leaf_node().credential().serialized_content();
+            if actual != expected {
+                return Err(MlsError::IdentityMismatch {
+                    expected: String::from_utf8_lossy(expected).into_owned(),
+                    actual: String::from_utf8_lossy(actual).into_owned(),
+                });
+            }
+        }
+
+        self.prepare_membership_commit(
+            group_id,
+            vec![key_package],
+            Vec::new(),
+            duplicate_identity,
+            omit_ratchet_tree,
+        )
+    }
+
+    /// Creates (but does not merge) a commit adding the members of the
+    /// validated `key_packages` and removing the members at `removals`,
+    /// and journals it until `ack_commit()`.  With several key packages,
+    /// `key_package_ref` lists their refs separated by commas.
+    fn prepare_membership_commit(
+        &mut self,
+        group_id: &str,
+        key_packages: Vec<KeyPackage>,
+        removals: Vec<LeafNodeIndex>,
+        duplicate_identity: bool,
+        omit_ratchet_tree: bool,
+    ) -> Result<AddMemberResult, MlsError> {
+        let group = self.groups.get(group_id).ok_or_else(|| MlsError::GroupNotFound {
+            group_id: group_id.to_string(),
+        })?;
+        check_member_cap(
+            group_id,
+            group,
+            self.max_members.get(group_id).copied(),
+            key_packages.len(),
+            removals.len(),
+        )?;
+
+        // Destructure so the borrow-checker can see independent borrows.
+        let MlsClientState {
+            groups,
+            crypto,
+            signer,
+            encoding,
+            used_key_packages,
+            sent_messages,
+            message_cache_size,
+            commit_policy,
+            commit_journal,
+            commit_sequence,
+            ..
+        } = self;
+
+        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        let mut key_package_refs = Vec::new();
+        let mut key_package_reused = false;
+        for key_package in &key_packages {
+            let key_package_ref = hex::encode(
+                key_package
+                    .hash_ref(crypto.crypto())
+                    .map_err(|e| MlsError::crypto(format!("Failed to hash key package: {:?}", e)))?
+                    .as_slice(),
+            );
+            // Last-resort packages are meant to be shared; regular ones are not.
+            if !key_package.last_resort() && used_key_packages.contains(&key_package_ref) {
+                key_package_reused = true;
+            }
+            key_package_refs.push((key_package_ref, key_package.last_resort()));
+        }
+
+        let group = groups
+            .get_mut(group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.to_string(),
+            })?;
+
+        let (commit, welcome, group_info) = if omit_ratchet_tree || !removals.is_empty() {
+            let builder = group
+                .commit_builder()
+                .propose_removals(removals)
+                .propose_adds(key_packages)
+                .force_self_update(true)
+                .load_psks(crypto.storage())
+                .map_err(|e| MlsError::generic(format!("Failed to load PSKs: {:?}", e)))?;
+            // Otherwise the group's configuration decides, as in `add_members()`.
+            let builder = if omit_ratchet_tree {
+                builder.use_ratchet_tree_extension(false)
+            } else {
+                builder
+            };
+            let (commit, welcome, group_info) = builder
+                .build(crypto.rand(), crypto.crypto(), signer, |_| true)
+                .map_err(|e| MlsError::generic(format!("Failed to add member: {:?}", e)))?
+                .stage_commit(crypto)
+                .map_err(|e| MlsError::generic(format!("Failed to add member: {:?}", e)))?
+                .into_messages();
+            let welcome = welcome.ok_or_else(|| MlsError::generic("Commit has no welcome"))?;
+            (commit, welcome, group_info)
+        } else {
+            let (commit, welcome, group_info) = group
+                .add_members(crypto, signer, &key_packages)
+                .map_err(|e| MlsError::generic(format!("Failed to add member: {:?}", e)))?;
+            (commit, welcome, group_info.map(MlsMessageOut::from))
+        };
+        check_own_commit(group_id, group, crypto, commit_policy.as_ref())?;
+
+        used_key_packages.extend(
+            key_package_refs
+                .iter()
+                .filter(|(_, last_resort)| !last_resort)
+                .map(|(key_package_ref, _)| key_package_ref.clone()),
+        );
+        let key_package_ref = key_package_refs
+            .into_iter()
+            .map(|(key_package_ref, _)| key_package_ref)
+            .collect::<Vec<_>>()
+            .join(",");
+        let commit_bytes = commit
+            .tls_serialize_detached()
+            .map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+        let commit_ref = crypto
+            .crypto()
+            .hash(group.ciphersuite().hash_algorithm(), &commit_bytes)
+            .map_err(|e| MlsError::crypto(format!("Failed to hash commit: {:?}", e)))?;
+        *commit_sequence += 1;
+
+        let result = AddMemberResult {
+            commit: encode_sent(&commit, group_id, crypto, *encoding, sent_messages, *message_cache_size)?,
+            welcome: encoding.encode(
+                &welcome
+                    .tls_serialize_detached()
+                    .map_err(|e| MlsError::serialization(format!("{:?}", e)))?,
+            ),
+            key_package_reused,
+            commit_size: commit.tls_serialized_len() as u64,
+            welcome_size: welcome.tls_serialized_len() as u64,
+            group_info_size: group_info.map_or(0, |gi| gi.tls_serialized_len() as u64),
+            ratchet_tree_omitted: omit_ratchet_tree,
+            duplicate_identity,
+            commit_ref: hex::encode(commit_ref),
+            key_package_ref,
+            epoch: group.epoch().as_u64() + 1,
+            sequence: *commit_sequence,
+        };
+        commit_journal
+            .entry(group_id.to_string())
+            .or_default()
+            .push(UnackedCommit {
+                commit_ref: result.commit_ref.clone(),
+                key_package_ref: result.key_package_ref.clone(),
+                epoch: result.epoch,
+                sequence: result.sequence,
+                commit: result.commit.clone(),
+                welcome: result.welcome.clone(),
+            });
+        Ok(result)
+    }
+
+    /// Checks whether the identity of `key_package`, a member to add to
+    /// `group_id`, is already held by a member (other than those in
+    /// `removed`, leaving in the same commit) or by one of `batch`, the
+    /// key packages added in the same commit.  Fails with
+    /// `DuplicateIdentity` if it is and the policy says so; otherwise
+    /// returns whether it is.
+    fn check_duplicate_identity(
+        &self,
+        group_id: &str,
+        key_package: &KeyPackage,
+        batch: &[KeyPackage],
+        removed: &[String],
+    ) -> Result<bool, MlsError> {
+        let group = self.groups.get(group_id).ok_or_else(|| MlsError::GroupNotFound {
+            group_id: group_id.to_string(),
+        })?;
+        let identity = key_package.leaf_node().credential().serialized_content();
+        let duplicate = group
+            .members()
+            .map(|m| m.credential.serialized_content().to_vec())
+            .filter(|member| !removed.iter().any(|r| r.as_bytes() == member.as_slice()))
+            .any(|member| member == identity)
+            || batch
+                .iter()
+                .any(|kp| kp.leaf_node().credential().serialized_content() == identity);
+        if !duplicate {
+            return Ok(false);
+        }
+
+        let identity = String::from_utf8_lossy(identity).into_owned();
+        match self.duplicate_identity_policy {
+            DuplicateIdentityPolicy::Reject => Err(MlsError::DuplicateIdentity { identity }),
+            DuplicateIdentityPolicy::Flag => {
+                log::warn!("Adding {} to group {}, which already has that identity", identity, group_id);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Creates and merges a commit adding the member whose key package is
+    /// given (see `prepare_add_member()`).
+    fn add_member(
+        &mut self,
+        group_id: &str,
+        key_package_encoded: &str,
+        expected_identity: Option<&[u8]>,
+        omit_ratchet_tree: bool,
+    ) -> Result<AddMemberResult, MlsError> {
+        let prepared =
+            self.prepare_add_member(group_id, key_package_encoded, expected_identity, omit_ratchet_tree)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            pending_events,
+            commit_journal,
+            ..
+        } = self;
+        let group = groups
+            .get_mut(group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.to_string(),
+            })?;
+        // `prepare_add_member()` already ran it past the commit policy.
+        if let Err(e) = merge_own_commit(group_id, group, crypto, None, pending_events) {
+            forget_unmerged_commit(commit_journal, group_id, &prepared.commit_ref);
+            return Err(e);
+        }
+        Ok(prepared)
+    }
+
+    /// Creates and merges one commit adding the members whose key packages
+    /// are given and removing those at `remove_leaf_indices`.
+    fn update_membership(
+        &mut self,
+        group_id: &str,
+        add_key_packages: &[String],
+        remove_leaf_indices: &[u32],
+    ) -> Result<AddMemberResult, MlsError> {
+        self.ensure_can_commit(group_id)?;
+        if add_key_packages.is_empty() {
+            return Err(MlsError::generic(
+                "No key packages to add; use remove_member() to only remove members",
+            ));
+        }
+
+        let group = self.groups.get(group_id).ok_or_else(|| MlsError::GroupNotFound {
+            group_id: group_id.to_string(),
+        })?;
+        let mut removals = Vec::new();
+        let mut removed_identities = Vec::new();
+        for &index in remove_leaf_indices {
+            let leaf = LeafNodeIndex::new(index);
+            let member = group.member_at(leaf).ok_or_else(|| MlsError::UnknownMember {
+                detail: format!("No member at leaf {}", index),
+            })?;
+            if leaf == group.own_leaf_index() {
+                return Err(MlsError::CannotRemoveSelf {
+                    group_id: group_id.to_string(),
+                });
+            }
+            if !removals.contains(&leaf) {
+                removals.push(leaf);
+                removed_identities.push(String::from_utf8_lossy(member.credential.serialized_content()).into_owned());
+            }
+        }
+
+        let mut key_packages: Vec<KeyPackage> = Vec::new();
+        let mut duplicate_identity = false;
+        for encoded in add_key_packages {
+            let key_package = self.new_member_key_package(group_id, encoded)?;
+            let signature_key = key_package.leaf_node().signature_key();
+            if key_packages.iter().any(|kp| kp.leaf_node().signature_key() == signature_key) {
+                return Err(MlsError::DuplicateSignatureKey {
+                    group_id: group_id.to_string(),
+                });
+            }
+            duplicate_identity |=
+                self.check_duplicate_identity(group_id, &key_package, &key_packages, &removed_identities)?;
+            key_packages.push(key_package);
+        }
+
+        let prepared = self.prepare_membership_commit(group_id, key_packages, removals, duplicate_identity, false)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            pending_events,
+            commit_journal,
+            ..
+        } = self;
+        let group = groups
+            .get_mut(group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.to_string(),
+            })?;
+        // Already run past the commit policy when prepared.
+        if let Err(e) = merge_own_commit(group_id, group, crypto, None, pending_events) {
+            forget_unmerged_commit(commit_journal, group_id, &prepared.commit_ref);
+            return Err(e);
+        }
+        Ok(prepared)
+    }
+
+    /// Decodes and processes an incoming handshake message (commit or
+    /// proposal) for the group, without merging anything.  Fails with
+    /// `OwnMessageReflected` for a message we created ourselves.
+    fn process_handshake(
+        &mut self,
+        group_id: &str,
+        commit_hex: &str,
+    ) -> Result<ProcessedMessage, MlsError> {
+        let bytes = self.encoding.decode_limited(commit_hex, self.input_limits.commit)?;
+
+        let mut rest = bytes.as_slice();
+        let mls_msg = MlsMessageIn::tls_deserialize(&mut rest)
+            .inspect_err(|e| log::warn!("Invalid handshake message for group {}: {:?}", group_id, e))
+            .map_err(|e| MlsError::serialization(format!("Invalid MLS message: {:?}", e)))?;
+        self.check_trailing_bytes("handshake MLSMessage", bytes.len(), rest.len())?;
+
+        let protocol_msg = mls_msg
+            .try_into_protocol_message()
+            .map_err(|e| MlsError::serialization(format!("Not a protocol message: {:?}", e)))?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            external_psks,
+            sent_messages,
+            ..
+        } = self;
+
+        let group = groups
+            .get_mut(group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.to_string(),
+            })?;
+
+        // Our own commit or proposal, echoed back by the delivery service.
+        let digest = message_digest(crypto, &bytes)?;
+        if sent_messages
+            .get(group_id)
+            .is_some_and(|c| c.contains(&digest))
+        {
+            return Err(MlsError::OwnMessageReflected {
+                group_id: group_id.to_string(),
+            });
+        }
+
+        // Decrypting a commit consumes its ratchet secret, so a commit that
+        // fails for lack of a PSK could never be retried.  Check up front.
+        // Likewise refuse while our own commit awaits confirmation: only one
+        // of the two can win the epoch, and the caller must decide which.
+        if protocol_msg.content_type() == ContentType::Commit {
+            if group.pending_commit().is_some() {
+                return Err(MlsError::PendingCommitConflict {
+                    group_id: group_id.to_string(),
+                });
+            }
+            check_pending_psks(group, external_psks)?;
+            check_committed_proposals(group_id, group, crypto, &protocol_msg)?;
+        }
+
+        let message_epoch = protocol_msg.epoch();
+        let external = from_external_sender(&protocol_msg);
+        let external_commit = is_external_commit(&protocol_msg);
+        let processed = group
+            .process_message(crypto, protocol_msg)
+            .map_err(|e| match e {
+                ProcessMessageError::InvalidCommit(StageCommitError::PskError(
+                    PskError::KeyNotFound,
+                )) => missing_psk_error(group, external_psks),
+                e => epoch_mismatch_error(group, message_epoch, None, &e)
+                    .map(|error| stale_join_error(external_commit, error))
+                    .or_else(|| external_sender_error(group_id, external, &e))
+                    .or_else(|| validation_error(&e))
+                    .unwrap_or_else(|| MlsError::generic(format!("Failed to process commit: {:?}", e))),
+            })?;
+
+        self.group_activity.insert(group_id.to_string(), unix_time());
+        Ok(processed)
+    }
+
+    /// Merges `staged`, sent by `sender_identity`, returning the members it
+    /// added and removed, or `None` if it removed us and the group has been
+    /// deleted.  Fails with `CommitRejectedByPolicy`, leaving the group
+    /// untouched, if the commit policy rejects it.
+    fn merge_commit(
+        &mut self,
+        group_id: &str,
+        staged: StagedCommit,
+        sender_identity: Vec<u8>,
+    ) -> Result<Option<MembershipDiff>, MlsError> {
+        let MlsClientState {
+            groups,
+            crypto,
+            commit_policy,
+            ..
+        } = self;
+
+        let group = groups
+            .get_mut(group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.to_string(),
+            })?;
+
+        if let Some(hook) = commit_policy {
+            hook.check(group_id, describe_staged_commit(group, &staged, sender_identity.clone()))?;
+        }
+
+        let self_removed = staged.self_removed();
+        let events = membership_events(group_id, group, &staged);
+        let audit = commit_merged_event(group_id, &staged, sender_identity, &events);
+        let removed_leaves: HashSet<u32> = staged
+            .remove_proposals()
+            .map(|p| p.remove_proposal().removed().u32())
+            .collect();
+        let before = member_infos(group)?;
+        group
+            .merge_staged_commit(crypto, staged)
+            .map_err(|e| MlsError::generic(format!("Failed to merge commit: {:?}", e)))?;
+        let reinit = match group.extensions().unknown(MIGRATION_EXTENSION_TYPE) {
+            Some(announcement) if !self_removed && !self.reinits.contains_key(group_id) => {
+                Some(migration_target(&announcement.0)?)
+            }
+            _ => None,
+        };
+
+        // Adds may reuse leaves freed by removes in the same commit, so a
+        // leaf is new if it was empty or removed before the commit.
+        let diff = if self_removed {
+            None
+        } else {
+            let (removed, kept): (Vec<_>, Vec<_>) =
+                before.into_iter().partition(|m| removed_leaves.contains(&m.index));
+            let kept: HashSet<u32> = kept.iter().map(|m| m.index).collect();
+            let added = member_infos(group)?
+                .into_iter()
+                .filter(|m| !kept.contains(&m.index))
+                .collect();
+            Some(MembershipDiff { added, removed })
+        };
+
+        for event in events {
+            record_event(&mut self.pending_events, event);
+        }
+        record_event(&mut self.pending_events, audit);
+        if !self_removed {
+            record_event(
+                &mut self.pending_events,
+                MlsEvent::EpochChanged {
+                    group_id: group_id.to_string(),
+                    epoch: group.epoch().as_u64(),
+                },
+            );
+        }
+
+        if self_removed {
+            self.discard_group(group_id);
+            self.removed_groups.insert(group_id.to_string());
+            self.group_activity.insert(group_id.to_string(), unix_time());
+        }
+        if let Some(target) = reinit {
+            self.record_reinit(group_id, target);
+        }
+
+        Ok(diff)
+    }
+
+    /// Encrypts an application message of `kind` with `aad` as its
+    /// authenticated additional data, returning the serialized ciphertext
+    /// and its place in our stream.
+    fn encrypt(
+        &mut self,
+        group_id: &str,
+        kind: PayloadKind,
+        plaintext: &[u8],
+        aad: Vec<u8>,
+    ) -> Result<(Vec<u8>, MessageGeneration), MlsError> {
+        self.ensure_not_frozen(group_id)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            signer,
+            sent_messages,
+            message_cache_size,
+            pending_self_updates,
+            send_generations,
+            message_framing,
+            send_sequences,
+            ..
+        } = self;
+        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        if pending_self_updates.contains(group_id) {
+            return Err(MlsError::SelfUpdateRequired {
+                group_id: group_id.to_string(),
+            });
+        }
+
+        let group = groups
+            .get_mut(group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.to_string(),
+            })?;
+
+        let enveloped = uses_payload_envelope(group);
+        if !enveloped && kind != PayloadKind::Chat {
+            return Err(MlsError::generic(format!(
+                "Group {} has no payload envelopes to carry {:?} messages",
+                group_id, kind
+            )));
+        }
+
+        // Generations restart with each epoch.
+        let epoch = group.epoch().as_u64();
+        let next = send_generations
+            .entry(group_id.to_string())
+            .or_insert(MessageGeneration { epoch, generation: 0 });
+        if next.epoch != epoch {
+            *next = MessageGeneration { epoch, generation: 0 };
+        }
+
+        let header = (enveloped && *message_framing).then(|| MessageHeader {
+            timestamp_ms: unix_time_ms(),
+            sequence: send_sequences.get(group_id).copied().unwrap_or(0),
+        });
+        let envelope;
+        let payload = if enveloped {
+            envelope = PayloadEnvelope {
+                generation: next.generation,
+                kind,
+                header,
+                payload: plaintext,
+            }
+            .seal();
+            envelope.as_slice()
+        } else {
+            plaintext
+        };
+
+        // OpenMLS only resets the AAD after a successful encryption; clear
+        // it on failure so it cannot leak into the next commit.
+        group.set_aad(aad);
+        let msg = group
+            .create_message(crypto, signer, payload)
+            .map_err(|e| {
+                group.set_aad(Vec::new());
+                MlsError::generic(format!("Encryption failed: {:?}", e))
+            })?;
+        let sent = *next;
+        next.generation = next.generation.saturating_add(1);
+        if let Some(header) = header {
+            send_sequences.insert(group_id.to_string(), header.sequence + 1);
+        }
+
+        let bytes = msg
+            .tls_serialize_detached()
+            .map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+
+        let digest = message_digest(crypto, &bytes)?;
+        sent_messages.entry(group_id.to_string()).or_default().insert(digest, *message_cache_size);
+        self.group_activity.insert(group_id.to_string(), unix_time());
+
+        #[cfg(feature = "metrics")]
+        self.count_traffic(group_id, true, plaintext.len());
+        Ok((bytes, sent))
+    }
+
+    /// Counts an application message sent (`encrypted`) or received in
+    /// `group_id`, with the size of its plaintext.
+    #[cfg(feature = "metrics")]
+    fn count_traffic(&mut self, group_id: &str, encrypted: bool, len: usize) {
+        let traffic = self
+            .traffic
+            .entry(group_id.to_string())
+            .or_insert_with(|| GroupTraffic {
+                group_id: group_id.to_string(),
+                ..GroupTraffic::default()
+            });
+        if encrypted {
+            traffic.messages_encrypted += 1;
+            traffic.bytes_encrypted += len as u64;
+        } else {
+            traffic.messages_decrypted += 1;
+            traffic.bytes_decrypted += len as u64;
+        }
+    }
+
+    /// Applies the deserialization mode to the `remaining` bytes left
+    /// after parsing `structure` from `total` bytes.
+    fn check_trailing_bytes(&mut self, structure: &str, total: usize, remaining: usize) -> Result<(), MlsError> {
+        if remaining == 0 {
+            return Ok(());
+        }
+        let consumed = (total - remaining) as u64;
+        match self.deserialization_mode {
+            DeserializationMode::Strict => Err(MlsError::TrailingBytes {
+                structure: structure.to_string(),
+                consumed,
+                remaining: remaining as u64,
+            }),
+            DeserializationMode::Permissive => {
+                log::warn!(
+                    "Ignoring {} trailing bytes after {} of {} bytes",
+                    remaining,
+                    structure,
+                    consumed
+                );
+                #[cfg(feature = "metrics")]
+                {
+                    *self.trailing_bytes_ignored.entry(structure.to_string()).or_default() += 1;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Decodes a message of a `process_messages()` batch, which may be a
+    /// handshake or an application message, checking it against the input
+    /// limit for its content type.
+    fn decode_batch_message(&self, encoded: &str) -> Result<Vec<u8>, MlsError> {
+        let limits = &self.input_limits;
+        let bytes = self
+            .encoding
+            .decode_limited(encoded, limits.commit.max(limits.application_message))?;
+        let handshake = MlsMessageIn::tls_deserialize(&mut bytes.as_slice())
+            .ok()
+            .and_then(|message| message.try_into_protocol_message().ok())
+            .is_some_and(|message| message.is_handshake_message());
+        let limit = if handshake {
+            limits.commit
+        } else {
+            limits.application_message
+        };
+        check_input_size(bytes.len(), limit)?;
+        Ok(bytes)
+    }
+
+    /// Processes one message of any content type, merging commits and
+    /// storing proposals.  Duplicates and our own reflected messages are
+    /// reported as such rather than as errors.
+    fn process_incoming(
+        &mut self,
+        group_id: &str,
+        bytes: &[u8],
+    ) -> Result<IncomingMessage, MlsError> {
+        if self.queue_if_unknown(group_id, bytes) {
+            return Ok(IncomingMessage::QueuedForUnknownGroup);
+        }
+
+        let mut rest = bytes;
+        let mls_msg = MlsMessageIn::tls_deserialize(&mut rest)
+            .inspect_err(|e| log::warn!("Invalid message for group {}: {:?}", group_id, e))
+            .map_err(|e| MlsError::serialization(format!("Invalid MLS message: {:?}", e)))?;
+        self.check_trailing_bytes("MLSMessage", bytes.len(), rest.len())?;
+
+        let protocol_msg = mls_msg
+            .try_into_protocol_message()
+            .map_err(|e| MlsError::serialization(format!("Not a protocol message: {:?}", e)))?;
+
+        let settings = self.group_settings(group_id);
+        let MlsClientState {
+            groups,
+            crypto,
+            seen_messages,
+            sent_messages,
+            message_cache_size,
+            ..
+        } = self;
+
+        let group = groups
+            .get_mut(group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.to_string(),
+            })?;
+
+        let digest = message_digest(crypto, bytes)?;
+        if sent_messages
+            .get(group_id)
+            .is_some_and(|c| c.contains(&digest))
+        {
+            return Ok(IncomingMessage::OwnMessage);
+        }
+        if seen_messages
+            .get(group_id)
+            .is_some_and(|c| c.contains(&digest))
+        {
+            return Ok(IncomingMessage::Duplicate);
+        }
+        if protocol_msg.content_type() == ContentType::Commit {
+            check_committed_proposals(group_id, group, crypto, &protocol_msg)?;
+        }
+
+        let message_epoch = protocol_msg.epoch();
+        let external = from_external_sender(&protocol_msg);
+        let external_commit = is_external_commit(&protocol_msg);
+        let application = protocol_msg.content_type() == ContentType::Application;
+        let enveloped = uses_payload_envelope(group);
+        // OpenMLS may still hold the secrets of epochs we no longer accept
+        // messages of (see `GroupSettings`).
+        let oldest_retained = settings.oldest_retained_epoch(group);
+        if application && message_epoch.as_u64() < oldest_retained {
+            return Err(MlsError::EpochTooOld {
+                message_epoch: message_epoch.as_u64(),
+                oldest_retained,
+            });
+        }
+        let processed = match group.process_message(crypto, protocol_msg) {
+            Ok(processed) => processed,
+            Err(ProcessMessageError::ValidationError(ValidationError::CannotDecryptOwnMessage)) => {
+                return Ok(IncomingMessage::OwnMessage);
+            }
+            Err(ProcessMessageError::ValidationError(ValidationError::UnableToDecrypt(
+                MessageDecryptionError::SecretTreeError(SecretTreeError::SecretReuseError),
+            ))) => return Ok(IncomingMessage::Duplicate),
+            Err(ProcessMessageError::ValidationError(ValidationError::UnableToDecrypt(
+                MessageDecryptionError::AeadError,
+            ))) => {
+                return Err(MlsError::DecryptionFailed {
+                    detail: "ciphertext or authenticated data was tampered with".into(),
+                })
+            }
+            Err(e) => {
+                let oldest_retained = application.then_some(oldest_retained);
+                return Err(epoch_mismatch_error(group, message_epoch, oldest_retained, &e)
+                    .map(|error| stale_join_error(external_commit, error))
+                    .or_else(|| external_sender_error(group_id, external, &e))
+                    .or_else(|| validation_error(&e))
+                    .unwrap_or_else(|| MlsError::generic(format!("Decryption failed: {:?}", e))))
+            }
+        };
+
+        let seen = seen_messages.entry(group_id.to_string()).or_default();
+        seen.start_epoch(message_epoch.as_u64());
+        seen.insert(digest, *message_cache_size);
+        self.group_activity.insert(group_id.to_string(), unix_time());
+
+        let epoch = processed.epoch().as_u64();
+        let sender_index = match processed.sender() {
+            Sender::Member(leaf) => Some(leaf.u32()),
+            _ => None,
+        };
+        let sender_credential = processed.credential().serialized_content().to_vec();
+        let aad = processed.aad().to_vec();
+        match processed.into_content() {
+            ProcessedMessageContent::ApplicationMessage(app) => {
+                let sender_index =
+                    sender_index.ok_or_else(|| MlsError::generic("Application message from a non-member"))?;
+                let decrypted = Zeroizing::new(app.into_bytes());
+                let (plaintext, payload_kind, generation, header) = if enveloped {
+                    let envelope = PayloadEnvelope::open(&decrypted)?;
+                    let generation = MessageGeneration {
+                        epoch,
+                        generation: envelope.generation,
+                    };
+                    let last = self
+                        .seen_generations
+                        .entry(group_id.to_string())
+                        .or_default()
+                        .entry(hex::encode(&sender_credential))
+                        .or_insert(generation);
+                    *last = (*last).max(generation);
+                    (envelope.payload.to_vec(), envelope.kind, Some(generation), envelope.header)
+                } else {
+                    (decrypted.to_vec(), PayloadKind::Chat, None, None)
+                };
+                #[cfg(feature = "metrics")]
+                self.count_traffic(group_id, false, plaintext.len());
+                Ok(IncomingMessage::Application {
+                    plaintext,
+                    aad,
+                    sender_index,
+                    sender_identity: sender_credential,
+                    generation,
+                    header,
+                    payload_kind,
+                    // `process_message()` checked the AEAD tag and the
+                    // sender's signature, or it would have failed.
+                    verified: true,
+                })
+            }
+            ProcessedMessageContent::StagedCommitMessage(staged) => {
+                // Auto-merge so the group doesn't get stuck.
+                let epoch = staged.group_context().epoch().as_u64();
+                match self.merge_commit(group_id, *staged, sender_credential.clone())? {
+                    Some(_) => Ok(IncomingMessage::Commit { epoch }),
+                    None => Ok(IncomingMessage::Removed {
+                        removed_by: sender_credential,
+                    }),
+                }
+            }
+            ProcessedMessageContent::ProposalMessage(proposal) => {
+                let custom = match proposal.proposal() {
+                    Proposal::Custom(custom) => Some(IncomingMessage::CustomProposal {
+                        proposal_type: custom.proposal_type(),
+                        payload: custom.payload().to_vec(),
+                        sender_identity: sender_identity(group, proposal.sender()),
+                    }),
+                    _ => None,
+                };
+                let info = self.store_proposal(group_id, *proposal)?;
+                Ok(custom.unwrap_or(IncomingMessage::Proposal { proposal: info }))
+            }
+            _ => Err(MlsError::generic("Unknown MLS message type received.")),
+        }
+    }
+
+    /// Queues a proposal received from another member for a later commit.
+    fn store_proposal(&mut self, group_id: &str, proposal: QueuedProposal) -> Result<ProposalInfo, MlsError> {
+        let MlsClientState { groups, crypto, .. } = self;
+        let group = groups
+            .get_mut(group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.to_string(),
+            })?;
+        let info = proposal_info(group, &proposal);
+        group
+            .store_pending_proposal(crypto.storage(), proposal)
+            .map_err(|e| MlsError::io(format!("Failed to store proposal: {:?}", e)))?;
+        Ok(info)
+    }
+
+    /// Commits an update of our own leaf node and merges it locally.
+    /// Returns JSON: `{"commit":"<encoded>"}`.
+    fn commit_self_update(
+        &mut self,
+        group_id: &str,
+        params: LeafNodeParameters,
+    ) -> Result<String, MlsError> {
+        let commit_encoded = self.self_update_commit(group_id, params)?;
+        Ok(format!(r#"{{"commit":"{}"}}"#, commit_encoded))
+    }
+
+    /// Like `commit_self_update()`, but returns the bare encoded commit.
+    fn self_update_commit(&mut self, group_id: &str, params: LeafNodeParameters) -> Result<String, MlsError> {
+        let MlsClientState {
+            groups,
+            crypto,
+            commit_policy,
+            signer,
+            pending_self_updates,
+            pending_events,
+            encoding,
+            sent_messages,
+            message_cache_size,
+            ..
+        } = self;
+        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        let group = groups
+            .get_mut(group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.to_string(),
+            })?;
+
+        let bundle = group
+            .self_update(crypto, signer, params)
+            .map_err(|e| MlsError::generic(format!("Failed to self-update: {:?}", e)))?;
+
+        let commit = bundle.into_commit();
+
+        merge_own_commit(group_id, group, crypto, commit_policy.as_ref(), pending_events)?;
+
+        pending_self_updates.remove(group_id);
+
+        encode_sent(&commit, group_id, crypto, *encoding, sent_messages, *message_cache_size)
+    }
+
+    /// Announces a successor to the group with `ciphersuite` and the
+    /// group's current context extensions, committing it as a context
+    /// extension, and freezes the group.  Returns the encoded commit and
+    /// the successor's hex-encoded ID.
+    fn commit_migration(&mut self, group_id: &str, ciphersuite: Ciphersuite) -> Result<(String, String), MlsError> {
+        let MlsClientState {
+            groups,
+            crypto,
+            commit_policy,
+            signer,
+            pending_events,
+            encoding,
+            sent_messages,
+            message_cache_size,
+            ..
+        } = self;
+        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        let group = groups
+            .get_mut(group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.to_string(),
+            })?;
+
+        // struct { group_id; version; cipher_suite; extensions } ReInit;
+        let mut announcement = Vec::new();
+        for field in [
+            GroupId::random(crypto.rand()).tls_serialize_detached(),
+            ProtocolVersion::default().tls_serialize_detached(),
+            ciphersuite.tls_serialize_detached(),
+            group.extensions().tls_serialize_detached(),
+        ] {
+            announcement.extend(field.map_err(|e| MlsError::serialization(format!("{:?}", e)))?);
+        }
+        let target = migration_target(&announcement)?;
+
+        let mut extensions = group.extensions().clone();
+        extensions
+            .add_or_replace(Extension::Unknown(
+                MIGRATION_EXTENSION_TYPE,
+                UnknownExtension(announcement),
+            ))
+            .map_err(|e| MlsError::generic(format!("Invalid migration extension: {:?}", e)))?;
+        require_custom_extensions(&mut extensions)?;
+
+        let (commit, _welcome, _gi) = group
+            .update_group_context_extensions(crypto, extensions, signer)
+            .map_err(|e| MlsError::generic(format!("Failed to reinitialize group: {:?}", e)))?;
+
+        merge_own_commit(group_id, group, crypto, commit_policy.as_ref(), pending_events)?;
+
+        let commit_encoded = encode_sent(&commit, group_id, crypto, *encoding, sent_messages, *message_cache_size)?;
+        let new_group_id = target.group_id.clone();
+        self.record_reinit(group_id, target);
+
+        Ok((commit_encoded, new_group_id))
+    }
+
+    /// Records that a merged commit reinitialized `group_id`, freezing it:
+    /// no further messages may be sent in it.
+    fn record_reinit(&mut self, group_id: &str, target: MigrationTarget) {
+        log::info!("Group {} reinitialized as {}", group_id, target.group_id);
+        self.frozen_groups.insert(
+            group_id.to_string(),
+            format!("Reinitialized as group {}", target.group_id),
+        );
+        self.reinits.insert(group_id.to_string(), target);
+    }
+
+    /// Creates the successor of reinitialized `group_id` and adds the
+    /// members' `key_packages`, which must be for the successor's
+    /// ciphersuite and match the group's other members by identity.
+    /// Returns the successor's ID and the encoded Welcome.
+    fn complete_reinit(&mut self, group_id: &str, key_packages: &[String]) -> Result<(String, String), MlsError> {
+        let target = self
+            .reinits
+            .get(group_id)
+            .cloned()
+            .ok_or_else(|| MlsError::generic(format!("Group {} has not been reinitialized", group_id)))?;
+        if self.groups.contains_key(&target.group_id) || self.archived_groups.contains(&target.group_id) {
+            return Err(MlsError::generic(format!("Group {} already exists", target.group_id)));
+        }
+
+        let old = self
+            .groups
+            .get(group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.to_string(),
+            })?;
+        let own_index = old.own_leaf_index();
+        let mut expected: Vec<Vec<u8>> = old
+            .members()
+            .filter(|m| m.index != own_index)
+            .map(|m| m.credential.serialized_content().to_vec())
+            .collect();
+        let config = GroupConfig {
+            use_ratchet_tree_extension: self.group_settings(group_id).use_ratchet_tree_extension,
+            wire_format_public: old.configuration().wire_format_policy() == MIXED_PLAINTEXT_WIRE_FORMAT_POLICY,
+            padding_size: old.configuration().padding_size() as u32,
+            // Already among the carried-over extensions.
+            external_senders: Vec::new(),
+            max_past_epochs: self.group_settings(group_id).max_past_epochs as u32,
+            payload_envelope: false,
+        };
+
+        let suite = self.usable_ciphersuite(target.ciphersuite)?;
+        let mut validated = Vec::with_capacity(key_packages.len());
+        for encoded in key_packages {
+            let kp_in = parse_key_package(
+                &self
+                    .encoding
+                    .decode_limited(encoded, self.input_limits.key_package)?,
+            )?;
+            check_key_package_lifetime(&kp_in)?;
+            let key_package = kp_in
+                .validate(self.crypto.crypto(), ProtocolVersion::default())
+                .map_err(|e| MlsError::InvalidKeyPackage { detail: format!("{:?}", e) })?;
+            if key_package.ciphersuite() != suite {
+                return Err(MlsError::CiphersuiteMismatch {
+                    group: u16::from(suite),
+                    key_package: u16::from(key_package.ciphersuite()),
+                });
+            }
+            validated.push(key_package);
+        }
+
+        let mut offered: Vec<Vec<u8>> = validated
+            .iter()
+            .map(|kp| kp.leaf_node().credential().serialized_content().to_vec())
+            .collect();
+        expected.sort();
+        offered.sort();
+        if offered != expected {
+            return Err(MlsError::generic(format!(
+                "Key packages do not match the members of group {}: expected identities {:?}, got {:?}",
+                group_id,
+                expected.iter().map(hex::encode).collect::<Vec<_>>(),
+                offered.iter().map(hex::encode).collect::<Vec<_>>(),
+            )));
+        }
+
+        let extensions = Extensions::<GroupContext>::tls_deserialize_exact(&target.extensions)
+            .map_err(|e| MlsError::serialization(format!("Invalid ReInit extensions: {:?}", e)))?;
+        let new_group_id = hex::decode(&target.group_id)
+            .map_err(|e| MlsError::serialization(format!("Invalid group ID: {:?}", e)))?;
+        let new_group_id = self.create_group(Some(GroupId::from_slice(&new_group_id)), suite, extensions, &config)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            signer,
+            pending_events,
+            encoding,
+            ..
+        } = self;
+        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+        let group = groups
+            .get_mut(&new_group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: new_group_id.clone(),
+            })?;
+        for key_package in &validated {
+            check_required_capabilities(group, key_package)?;
+        }
+
+        let (_commit, welcome, _gi) = group
+            .add_members(crypto, signer, &validated)
+            .map_err(|e| MlsError::generic(format!("Failed to add members: {:?}", e)))?;
+        // Re-adds the old group's members; the policy approved the reinit.
+        merge_own_commit(&new_group_id, group, crypto, None, pending_events)?;
+
+        let welcome_encoded = encoding.encode(
+            &welcome
+                .tls_serialize_detached()
+                .map_err(|e| MlsError::serialization(format!("{:?}", e)))?,
+        );
+        Ok((new_group_id, welcome_encoded))
+    }
+
+    /// Replaces our signature key pair, committing a leaf update carrying
+    /// the new key in every group we are still in.
+    fn rotate_signature_key(&mut self) -> Result<Vec<GroupCommit>, MlsError> {
+        let name = self.identity_name.clone().ok_or_else(MlsError::no_identity)?;
+        let scheme = self
+            .signer
+            .as_ref()
+            .ok_or_else(MlsError::no_identity)?
+            .signature_scheme();
+
+        // Check every group first: a group left on the old key could no
+        // longer be signed for.
+        let mut group_ids: Vec<String> = self
+            .groups
+            .keys()
+            .chain(self.archived_groups.iter())
+            .filter(|id| !self.group_identities.contains_key(*id))
+            .cloned()
+            .collect();
+        group_ids.sort();
+        let mut targets = Vec::new();
+        for group_id in group_ids {
+            self.ensure_resident(&group_id)?;
+            let Some(group) = self.groups.get(&group_id) else {
+                continue;
+            };
+            if !group.is_active() {
+                continue;
+            }
+            self.ensure_can_commit(&group_id)?;
+            if group.pending_commit().is_some() {
+                return Err(MlsError::PendingCommitConflict { group_id });
+            }
+            targets.push(group_id);
+        }
+
+        let (private, public) = self
+            .crypto
+            .crypto()
+            .signature_key_gen(scheme)
+            .map_err(|e| MlsError::crypto(format!("Failed to create signature keys: {:?}", e)))?;
+        let new_signer = SigningKeyPair::from_raw(scheme, private, public);
+        new_signer
+            .store(self.crypto.storage())
+            .map_err(|e| MlsError::crypto(format!("Failed to store signer: {:?}", e)))?;
+        let credential = CredentialWithKey {
+            credential: Credential::new(CredentialType::Basic, name.into_bytes()),
+            signature_key: new_signer.to_public_vec().into(),
+        };
+
+        let mut commits = Vec::new();
+        let result = targets.iter().try_for_each(|group_id| {
+            // A group with a credential identity of its own keeps it.
+            let commit = self.select_group(group_id).and_then(|()| {
+                self.frozen_for(group_id, "Signature key rotation in progress", |state| {
+                    let credential = state.credential_for_key(&new_signer)?;
+                    state.new_signer_commit(group_id, &new_signer, &credential)
+                })
+            });
+            self.restore_identity();
+            let commit = commit?;
+            // A group with a key of its own is on the identity's key again.
+            self.drop_group_signer(group_id);
+            commits.push(GroupCommit {
+                group_id: group_id.clone(),
+                commit,
+            });
+            Ok(())
+        });
+
+        // Groups already updated only accept the new key.
+        if result.is_ok() || !commits.is_empty() {
+            if let Some(old_signer) = self.signer.replace(new_signer) {
+                let _ = SignatureKeyPair::delete(self.crypto.storage(), old_signer.public(), scheme);
+            }
+            self.credential = Some(credential);
+        } else {
+            let _ = SignatureKeyPair::delete(self.crypto.storage(), new_signer.public(), scheme);
+        }
+
+        result.map(|()| commits)
+    }
+
+    /// Moves `group_id` alone to a new signature key pair, committing a
+    /// leaf update carrying it.  The key is kept in `group_signers`; the
+    /// identity's other groups keep their key.  Returns the encoded commit.
+    fn rotate_group_signature_key(&mut self, group_id: &str) -> Result<String, MlsError> {
+        self.ensure_resident(group_id)?;
+        let group = self.groups.get(group_id).ok_or_else(|| MlsError::GroupNotFound {
+            group_id: group_id.to_string(),
+        })?;
+        if !group.is_active() {
+            return Err(MlsError::NoLongerMember {
+                group_id: group_id.to_string(),
+            });
+        }
+        if group.pending_commit().is_some() {
+            return Err(MlsError::PendingCommitConflict {
+                group_id: group_id.to_string(),
+            });
+        }
+        self.ensure_can_commit(group_id)?;
+
+        let result = self.select_group(group_id).and_then(|()| self.new_group_key_commit(group_id));
+        self.restore_identity();
+        let (commit, new_signer) = result?;
+
+        // A key an earlier rotation gave this group is no longer used.
+        self.drop_group_signer(group_id);
+        self.group_signers.insert(group_id.to_string(), new_signer);
+        Ok(commit)
+    }
+
+    /// The selected credential (see `select_group()`) moved to the key of
+    /// `signer`.
+    fn credential_for_key(&self, signer: &SigningKeyPair) -> Result<CredentialWithKey, MlsError> {
+        let credential = self.credential.as_ref().ok_or_else(MlsError::no_identity)?;
+        Ok(CredentialWithKey {
+            credential: credential.credential.clone(),
+            signature_key: signer.to_public_vec().into(),
+        })
+    }
+
+    /// Commits a leaf update moving `group_id` from the selected key to a
+    /// new one with the same credential identity, returning the commit and
+    /// the new key.
+    fn new_group_key_commit(&mut self, group_id: &str) -> Result<(String, SigningKeyPair), MlsError> {
+        let scheme = self.signer.as_ref().ok_or_else(MlsError::no_identity)?.signature_scheme();
+        let (private, public) = self
+            .crypto
+            .crypto()
+            .signature_key_gen(scheme)
+            .map_err(|e| MlsError::crypto(format!("Failed to create signature keys: {:?}", e)))?;
+        let new_signer = SigningKeyPair::from_raw(scheme, private, public);
+        new_signer
+            .store(self.crypto.storage())
+            .map_err(|e| MlsError::crypto(format!("Failed to store signer: {:?}", e)))?;
+        let credential = self.credential_for_key(&new_signer)?;
+
+        match self.frozen_for(group_id, "Signature key rotation in progress", |state| {
+            state.new_signer_commit(group_id, &new_signer, &credential)
+        }) {
+            Ok(commit) => Ok((commit, new_signer)),
+            Err(e) => {
+                let _ = SignatureKeyPair::delete(self.crypto.storage(), new_signer.public(), scheme);
+                Err(e)
+            }
+        }
+    }
+
+    /// Commits and merges a leaf update moving the group from our current
+    /// signature key to `new_signer`, returning the encoded commit.
+    fn new_signer_commit(
+        &mut self,
+        group_id: &str,
+        new_signer: &SigningKeyPair,
+        credential: &CredentialWithKey,
+    ) -> Result<String, MlsError> {
+        self.ensure_resident(group_id)?;
+        let MlsClientState {
+            groups,
+            crypto,
+            signer,
+            pending_self_updates,
+            pending_events,
+            encoding,
+            sent_messages,
+            message_cache_size,
+            ..
+        } = self;
+        let old_signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        let group = groups
+            .get_mut(group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.to_string(),
+            })?;
+
+        let new_signer = NewSignerBundle {
+            signer: new_signer,
+            credential_with_key: credential.clone(),
+        };
+        let bundle = group
+            .self_update_with_new_signer(crypto, old_signer, new_signer, LeafNodeParameters::default())
+            .map_err(|e| MlsError::generic(format!("Failed to self-update: {:?}", e)))?;
+
+        let commit = bundle.into_commit();
+
+        // Not subject to the commit policy: rejecting one group midway
+        // would leave the others on a key the rest could not use.
+        merge_own_commit(group_id, group, crypto, None, pending_events)?;
+
+        pending_self_updates.remove(group_id);
+
+        encode_sent(&commit, group_id, crypto, *encoding, sent_messages, *message_cache_size)
+    }
+
+    /// Forgets the key `group_id` has of its own, if any, deleting it from
+    /// the key store unless something else still signs with it.
+    fn drop_group_signer(&mut self, group_id: &str) {
+        let Some(signer) = self.group_signers.remove(group_id) else {
+            return;
+        };
+        let key = signer.public();
+        let in_use = self.signer.as_ref().is_some_and(|s| s.public() == key)
+            || self.group_signers.values().any(|s| s.public() == key)
+            || self.extra_identity_with_key(key).is_some();
+        if !in_use {
+            let _ = SignatureKeyPair::delete(self.crypto.storage(), key, signer.signature_scheme());
+        }
+    }
+
+    /// Why a self-update cannot be committed in `group_id` now, if it
+    /// cannot (see `rotate_all_groups()`).
+    fn rotation_blocker(&mut self, group_id: &str) -> Option<String> {
+        if let Err(e) = self.ensure_resident(group_id) {
+            return Some(e.to_string());
+        }
+        let group = self.groups.get(group_id)?;
+        if !group.is_active() {
+            return Some("No longer a member".to_string());
+        }
+        if group.pending_commit().is_some() {
+            return Some(
+                MlsError::PendingCommitConflict {
+                    group_id: group_id.to_string(),
+                }
+                .to_string(),
+            );
+        }
+        self.ensure_can_commit(group_id).err().map(|e| e.to_string())
+    }
+
+    /// Commits a self-update in every group we are in, with a new signature
+    /// key pair for the default identity if `rotate_signature_key`.
+    fn rotate_all_groups(&mut self, rotate_signature_key: bool) -> Result<Vec<GroupRotation>, MlsError> {
+        let skipped = |group_id: String, reason: String| GroupRotation {
+            group_id,
+            commit: None,
+            skipped_reason: Some(reason),
+        };
+        let mut group_ids: Vec<String> = self.groups.keys().chain(self.archived_groups.iter()).cloned().collect();
+        group_ids.sort();
+        let mut removed: Vec<String> = self.removed_groups.iter().cloned().collect();
+        removed.sort();
+        let mut rotations: Vec<GroupRotation> = removed
+            .into_iter()
+            .map(|group_id| skipped(group_id, "No longer a member".to_string()))
+            .collect();
+
+        let new_key = if rotate_signature_key {
+            let name = self.identity_name.clone().ok_or_else(MlsError::no_identity)?;
+            let scheme = self.signer.as_ref().ok_or_else(MlsError::no_identity)?.signature_scheme();
+            let (private, public) = self
+                .crypto
+                .crypto()
+                .signature_key_gen(scheme)
+                .map_err(|e| MlsError::crypto(format!("Failed to create signature keys: {:?}", e)))?;
+            let signer = SigningKeyPair::from_raw(scheme, private, public);
+            signer
+                .store(self.crypto.storage())
+                .map_err(|e| MlsError::crypto(format!("Failed to store signer: {:?}", e)))?;
+            let credential = CredentialWithKey {
+                credential: Credential::new(CredentialType::Basic, name.into_bytes()),
+                signature_key: signer.to_public_vec().into(),
+            };
+            Some((signer, credential))
+        } else {
+            None
+        };
+
+        // Groups of the default identity that stay on its current key.
+        let mut left_behind = Vec::new();
+        for group_id in group_ids {
+            let default_identity = !self.group_identities.contains_key(&group_id);
+            // Groups with a key of their own are not on the current key.
+            let on_current_key = default_identity && !self.group_signers.contains_key(&group_id);
+            if let Some(reason) = self.rotation_blocker(&group_id) {
+                let member = self.groups.get(&group_id).is_some_and(|g| g.is_active());
+                if new_key.is_some() && on_current_key && member {
+                    left_behind.push(group_id.clone());
+                }
+                rotations.push(skipped(group_id, reason));
+                continue;
+            }
+
+            let result = self.select_group(&group_id).and_then(|()| {
+                self.frozen_for(&group_id, "Group rotation in progress", |state| match &new_key {
+                    Some((signer, _)) if default_identity => {
+                        let credential = state.credential_for_key(signer)?;
+                        state.new_signer_commit(&group_id, signer, &credential)
+                    }
+                    _ => state.self_update_commit(&group_id, LeafNodeParameters::default()),
+                })
+            });
+            self.restore_identity();
+            match result {
+                Ok(commit) => {
+                    // Groups left behind by an earlier rotation move to the
+                    // new key along with the others.
+                    if new_key.is_some() && default_identity {
+                        self.drop_group_signer(&group_id);
+                    }
+                    rotations.push(GroupRotation {
+                        group_id,
+                        commit: Some(commit),
+                        skipped_reason: None,
+                    });
+                }
+                Err(e) => {
+                    if new_key.is_some() && on_current_key {
+                        left_behind.push(group_id.clone());
+                    }
+                    rotations.push(skipped(group_id, e.to_string()));
+                }
+            }
+        }
+
+        if let Some((new_signer, credential)) = new_key {
+            let old_signer = self.signer.replace(new_signer);
+            self.credential = Some(credential);
+            if let Some(old_signer) = old_signer {
+                // Keep signing for the groups left behind with the old key,
+                // as a key of their own.
+                for group_id in &left_behind {
+                    self.group_signers.insert(group_id.clone(), old_signer.clone());
+                }
+                for rotation in rotations.iter_mut().filter(|r| left_behind.contains(&r.group_id)) {
+                    if let Some(reason) = &mut rotation.skipped_reason {
+                        reason.push_str("; still signed with the old key");
+                    }
+                }
+                if left_behind.is_empty() {
+                    let scheme = old_signer.signature_scheme();
+                    let _ = SignatureKeyPair::delete(self.crypto.storage(), old_signer.public(), scheme);
+                }
+            }
+        }
+
+        rotations.sort_by(|a, b| a.group_id.cmp(&b.group_id));
+        Ok(rotations)
+    }
+}
+
+// ── Public API ─────────────────────────────────────────────────────────────
+
+/// An MLS client: one storage location, its identities and groups.
+///
+/// Safe to call from any number of threads at once (e.g. coroutines on
+/// `Dispatchers.IO`): all state sits behind one lock, held for the length
+/// of a call, so calls on the same client are serialized and never see
+/// each other's partial changes.  Event listeners run after the lock is
+/// released and may call back into the client.  See `handle()` to share
+/// one client between owners that close it independently.
+#[derive(uniffi::Object)]
+pub struct MlsClient {
+    state: Arc<RwLock<MlsClientState>>,
+    storage: Box<dyn StorageBackend>,
+    /// Set by `new_encrypted()`: every stored value is encrypted.
+    storage_key: Option<StorageKey>,
+    /// Set by `new_ephemeral()`: state is never saved.
+    ephemeral: bool,
+    /// Kept outside `state` so that callbacks can re-enter the client.
+    listener: Mutex<Option<Arc<dyn MlsEventListener>>>,
+    /// Call counters, updated without taking the state lock.
+    metrics: Metrics,
+}
+
+#[uniffi::export]
+impl MlsClient {
+    // ── Constructor ────────────────────────────────────────────────────
+
+    #[uniffi::constructor]
+    pub fn new(storage_path: String) -> Self {
+        Self::with_provider(Self::file_storage(storage_path), MlsProvider::default(), None)
+    }
+
+    /// Like `new()`, but with `message_framing` set every application
+    /// message we send in groups with payload envelopes (see
+    /// `GroupConfig::payload_envelope`) carries a header with our timestamp
+    /// and a sequence number, which receivers get in
+    /// `DecryptedMessage.header` (see `decrypt_message_with_sender()`).
+    /// Without it, or in other groups, messages are sent with no header.
+    /// Received headers are reported whatever the setting.
+    ///
+    /// The header travels in the envelope, encrypted, ahead of the
+    /// application's bytes and marked by a flag rather than a magic prefix,
+    /// so payloads are received exactly as they were sent whatever their
+    /// first bytes are.
+    #[uniffi::constructor]
+    pub fn new_with_message_framing(storage_path: String, message_framing: bool) -> Self {
+        let client = Self::new(storage_path);
+        if let Ok(mut state) = client.state.write() {
+            state.message_framing = message_framing;
+        }
+        client
+    }
+
+    /// Like `new()`, but remembers the last `cache_size` messages of each
+    /// group, instead of 256, to fail a replayed one with
+    /// `DuplicateMessage` (or report it as `IncomingMessage::Duplicate`).
+    /// The cache only covers the group's current epoch: it is cleared when
+    /// messages of the next epoch arrive.  0 turns the cache off, leaving
+    /// replays to OpenMLS: application messages whose keys were already
+    /// used are still reported as duplicates, but a replayed commit fails
+    /// with `StaleMessage` and a replayed proposal is stored again.
+    #[uniffi::constructor]
+    pub fn new_with_replay_cache(storage_path: String, cache_size: u32) -> Self {
+        let client = Self::new(storage_path);
+        if let Ok(mut state) = client.state.write() {
+            state.message_cache_size = cache_size as usize;
+        }
+        client
+    }
+
+    /// Like `new()`, but every group, restored or created or joined later,
+    /// keeps the secrets of its last `max_past_epochs` epochs, so that an
+    /// application message sent just before a commit still decrypts when
+    /// it arrives after it.  Same as calling `set_max_past_epochs()` right
+    /// after `new()`.
+    #[uniffi::constructor]
+    pub fn new_with_max_past_epochs(storage_path: String, max_past_epochs: u32) -> Result<Self, MlsError> {
+        let client = Self::new(storage_path);
+        client.set_max_past_epochs(max_past_epochs)?;
+        Ok(client)
+    }
+
+    /// Creates a client that keeps everything in memory and never touches
+    /// the filesystem, for tests and sessions that must leave no trace.
+    /// Groups work exactly as with `new()`, but `save_state()` saves
+    /// nothing (its report lists no groups) and `load_state()` finds no
+    /// stored state.  Use `export_state()` to keep anything beyond the
+    /// client's lifetime.
+    #[uniffi::constructor]
+    pub fn new_ephemeral() -> Self {
+        let mut client = Self::with_provider(Box::new(NullStorageBackend), MlsProvider::default(), None);
+        client.ephemeral = true;
+        client
+    }
+
+    /// Creates a client whose storage is an in-memory map instead of a
+    /// directory, e.g. for unit tests without a writable filesystem or
+    /// incognito sessions.  Unlike `new_ephemeral()`, `save_state()` and
+    /// `load_state()` work as with `new()`, against the map; nothing ever
+    /// reaches the disk, and dropping the client wipes the map along with
+    /// the key store.
+    #[uniffi::constructor]
+    pub fn new_in_memory() -> Self {
+        Self::with_provider(Box::<MemoryStorageBackend>::default(), MlsProvider::default(), None)
+    }
+
+    /// Like `new()`, but persists through `backend` instead of files (e.g.
+    /// Android Keystore-backed storage).  Previously saved state in the
+    /// backend is restored.
+    #[uniffi::constructor]
+    pub fn new_with_backend(backend: Box<dyn StorageBackend>) -> Self {
+        Self::with_provider(backend, MlsProvider::default(), None)
+    }
+
+    /// Like `new()`, but every file written under `storage_path` is
+    /// encrypted with a key derived from `passphrase`, and decrypted
+    /// transparently on load.
+    ///
+    /// If the stored state cannot be decrypted, the client starts empty and
+    /// `load_state()` fails with `WrongPassphrase`.
+    #[uniffi::constructor]
+    pub fn new_encrypted(storage_path: String, passphrase: String) -> Self {
+        Self::with_provider(Self::file_storage(storage_path), MlsProvider::default(), Some(passphrase))
+    }
+
+    /// Creates a client at `storage_path` from an `export_backup()` blob,
+    /// saving the restored state there.  Use a fresh storage path.
+    ///
+    /// A wrong passphrase fails with `CryptoError`, a truncated blob with
+    /// `SerializationError` and a blob from a newer release with
+    /// `UnsupportedBackupVersion`.
+    #[uniffi::constructor]
+    pub fn import_backup(
+        storage_path: String,
+        blob: Vec<u8>,
+        passphrase: String,
+    ) -> Result<Self, MlsError> {
+        let sealed = unwrap_backup(&blob)?;
+        let json = open_with_passphrase(&passphrase, sealed)?;
+        let backup: StateBackup = serde_json::from_slice(&json)
+            .map_err(|e| MlsError::serialization(format!("Corrupt backup: {}", json_error(&e))))?;
+
+        let client = Self::with_provider(Self::file_storage(storage_path), MlsProvider::default(), None);
+        client.restore_backup(backup)?;
+        Ok(client)
+    }
+
+    /// Registers the listener notified of membership and epoch changes,
+    /// replacing any previous one.
+    pub fn set_event_listener(&self, listener: Box<dyn MlsEventListener>) {
+        if let Ok(mut slot) = self.listener.lock() {
+            *slot = Some(Arc::from(listener));
+        }
+    }
+
+    /// Registers `policy`, replacing any previous one.  Every commit from
+    /// another member is described to it before being merged; a rejected
+    /// commit leaves the group untouched and fails with
+    /// `CommitRejectedByPolicy`.  With `check_own_commits`, the commits
+    /// this client creates are checked too and, if rejected, discarded
+    /// before they are returned.  Signature key rotation is never checked.
+    pub fn set_commit_policy(&self, policy: Box<dyn CommitPolicy>, check_own_commits: bool) {
+        if let Ok(mut state) = self.state.write() {
+            state.commit_policy = Some(CommitPolicyHook {
+                policy: Arc::from(policy),
+                check_own_commits,
+            });
+        }
+    }
+
+    /// Removes the policy registered with `set_commit_policy()`.
+    pub fn clear_commit_policy(&self) {
+        if let Ok(mut state) = self.state.write() {
+            state.commit_policy = None;
+        }
+    }
+
+    /// Routes log records at info level and above (from this library and
+    /// OpenMLS) to `logger`, replacing any previous one.  The logger is
+    /// process-wide: it receives records from every client.
+    ///
+    /// Nothing is logged until a logger is set.  Records carry group IDs
+    /// and counts, so release builds may prefer not to set one, or to
+    /// remove it with `clear_logger()`.
+    pub fn set_logger(&self, logger: Box<dyn MlsLogger>) {
+        if let Ok(mut slot) = LOGGER.write() {
+            *slot = Some(logger);
+        }
+        // Fails if the host already installed a `log` logger; records then
+        // keep going there.
+        if log::set_logger(&LOG_BRIDGE).is_ok() {
+            log::set_max_level(log::LevelFilter::Info);
+        }
+    }
+
+    /// Removes the logger set with `set_logger()`; records are dropped
+    /// from then on, for every client.
+    pub fn clear_logger(&self) {
+        if let Ok(mut slot) = LOGGER.write() {
+            *slot = None;
+        }
+    }
+
+    /// The MLS protocol version this client speaks, e.g. `"mls10"`.
+    pub fn get_protocol_version(&self) -> String {
+        protocol_version_name(ProtocolVersion::default())
+    }
+
+    /// Selects the MLS protocol version to speak, by its codepoint (1 for
+    /// MLS 1.0).  OpenMLS currently implements MLS 1.0 only, so any other
+    /// version falls back to it.  Returns the version in effect, as
+    /// `get_protocol_version()` reports it.
+    pub fn set_protocol_version(&self, version: u16) -> String {
+        let requested = ProtocolVersion::from(version);
+        if requested != ProtocolVersion::default() {
+            log::warn!(
+                "Protocol version {} is not supported; staying on {}",
+                protocol_version_name(requested),
+                self.get_protocol_version()
+            );
+        }
+        self.get_protocol_version()
+    }
+
+    /// Selects the encoding of key packages, commits, welcomes, proposals
+    /// and ciphertexts, both returned and accepted, for all subsequent
+    /// calls.  Defaults to `Encoding::Hex`.
+    pub fn set_default_encoding(&self, encoding: Encoding) {
+        if let Ok(mut state) = self.state.write() {
+            state.encoding = encoding;
+        }
+    }
+
+    /// The encoding selected with `set_default_encoding()`.
+    pub fn default_encoding(&self) -> Encoding {
+        self.state
+            .read()
+            .map(|s| s.encoding)
+            .unwrap_or_default()
+    }
+
+    /// Another reference to this client, sharing all of its state.  Each
+    /// reference is released on its own (e.g. by Kotlin's `close()`), and
+    /// the client lives until the last one is, so components can hold
+    /// their own handle without coordinating who closes it.
+    pub fn handle(self: Arc<Self>) -> Arc<Self> {
+        self
+    }
+
+    // ── Identity management ────────────────────────────────────────────
+
+    /// Creates (or recreates) this client's cryptographic identity.
+    ///
+    /// **Must** be called before any group operations.
+    /// Returns the identity name on success.
+    ///
+    /// This is the default identity, used wherever no other is chosen;
+    /// register more with `add_identity()`.
+    pub fn create_identity(&self, name: String) -> Result<String, MlsError> {
+        self.create_identity_with_capabilities(name, ClientCapabilities::default())
+    }
+
+    /// Registers another identity, e.g. a second account, with its own
+    /// signature key pair and a credential carrying `name`.  Pass `name` as
+    /// the `identity` of `create_group()`, `generate_key_package()` and
+    /// `rejoin()` to act as it; groups remember which identity they were
+    /// created or joined with and sign with it from then on.  Welcomes are
+    /// matched to the identity whose key package they consume.
+    ///
+    /// The identity shares the capabilities and application id of the
+    /// default one.  If there is no default identity yet, this creates it
+    /// instead, like `create_identity()`.  Fails if `name` is already
+    /// registered.  Returns `name`.
+    pub fn add_identity(&self, name: String) -> Result<String, MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        if state.signer.is_none() {
+            drop(state);
+            return self.create_identity(name);
+        }
+        if state.identity_name.as_ref() == Some(&name) || state.identities.contains_key(&name) {
+            return Err(MlsError::generic(format!("Identity {} already exists", name)));
+        }
+
+        let scheme = CIPHERSUITE.signature_algorithm();
+        let (private, public) = state
+            .crypto
+            .crypto()
+            .signature_key_gen(scheme)
+            .map_err(|e| MlsError::crypto(format!("Failed to create signature keys: {:?}", e)))?;
+        let signer = SigningKeyPair::from_raw(scheme, private, public);
+        signer
+            .store(state.crypto.storage())
+            .map_err(|e| MlsError::crypto(format!("Failed to store signer: {:?}", e)))?;
+
+        let credential = CredentialWithKey {
+            credential: Credential::new(CredentialType::Basic, name.clone().into_bytes()),
+            signature_key: signer.to_public_vec().into(),
+        };
+        state.identities.insert(
+            name.clone(),
+            Identity {
+                signer: Some(signer),
+                credential: Some(credential),
+                identity_name: Some(name.clone()),
+            },
+        );
+
+        drop(state);
+        let _ = self.persist_state();
+        Ok(name)
+    }
+
+    /// Registers an identity like `add_identity()` (so the first one becomes
+    /// the default identity) and generates a key package for it, for
+    /// callers that hand one out right away.  Like every identity, its key
+    /// pair is kept in the key store, so a Welcome for the key package is
+    /// joined as it with `process_welcome()`.
+    pub fn create_identity_with_key_package(&self, name: String) -> Result<CreatedIdentity, MlsError> {
+        let identity_id = self.add_identity(name)?;
+        let key_package = self.generate_key_package(Some(identity_id.clone()))?;
+        Ok(CreatedIdentity {
+            identity_id,
+            key_package,
+        })
+    }
+
+    /// Names of this client's identities: the default one first, then those
+    /// registered with `add_identity()`, sorted.
+    pub fn list_identities(&self) -> Vec<String> {
+        let Ok(state) = self.state.read() else {
+            return Vec::new();
+        };
+        let mut names: Vec<String> = state.identities.keys().cloned().collect();
+        names.sort();
+        state.identity_name.iter().cloned().chain(names).collect()
+    }
+
+    /// Like `create_identity()`, but advertises the given capabilities (raw
+    /// IANA codepoints) in every key package and leaf node this client
+    /// produces.  Groups whose `RequiredCapabilities` list types we do not
+    /// advertise will refuse to add us.
+    pub fn create_identity_with_capabilities(
+        &self,
+        name: String,
+        capabilities: ClientCapabilities,
+    ) -> Result<String, MlsError> {
+        self.init_identity(name, capabilities, None)
+    }
+
+    /// Like `create_identity()`, but also places `application_id` (e.g. a
+    /// backend account UUID) in the application_id extension of every key
+    /// package and leaf node this client produces.  Unlike the credential
+    /// identity, it stays the same across `update_credential()` renames and
+    /// is reported as `MemberInfo.application_id`.
+    pub fn create_identity_with_application_id(
+        &self,
+        name: String,
+        application_id: Vec<u8>,
+    ) -> Result<String, MlsError> {
+        self.init_identity(name, ClientCapabilities::default(), Some(application_id))
+    }
+
+    /// Replaces (or with `None`, clears) the application id carried by key
+    /// packages and groups created from now on.  Leaf nodes in existing
+    /// groups keep their old application id.
+    pub fn set_application_id(&self, application_id: Option<Vec<u8>>) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        if state.signer.is_none() {
+            return Err(MlsError::no_identity());
+        }
+        state.application_id = application_id;
+
+        drop(state);
+        let _ = self.persist_state();
+        Ok(())
+    }
+
+    /// Returns the application id set with
+    /// `create_identity_with_application_id()` or `set_application_id()`.
+    pub fn get_application_id(&self) -> Result<Option<Vec<u8>>, MlsError> {
+        let state = self.state.read().map_err(|_| MlsError::lock_poisoned())?;
+        Ok(state.application_id.clone())
+    }
+
+    /// Replaces the capabilities advertised in key packages generated from
+    /// now on.  Leaf nodes in existing groups keep their old capabilities.
+    pub fn set_capabilities(&self, capabilities: ClientCapabilities) -> Result<(), MlsError> {
+        capabilities.to_leaf_capabilities()?;
+
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        if state.signer.is_none() {
+            return Err(MlsError::no_identity());
+        }
+        state.capabilities = capabilities;
+
+        drop(state);
+        let _ = self.persist_state();
+        Ok(())
+    }
+
+    /// Returns the capabilities advertised in our key packages.
+    pub fn get_capabilities(&self) -> Result<ClientCapabilities, MlsError> {
+        let state = self.state.read().map_err(|_| MlsError::lock_poisoned())?;
+        Ok(state.capabilities.clone())
+    }
+
+    /// Generates a fresh key package for `identity` (see `add_identity()`),
+    /// or the default identity if `None`.
+    ///
+    /// Key packages are single-use; call this each time you need to be
+    /// added to a new group.  Returns the encoded key package.
+    #[uniffi::method(default(identity = None))]
+    pub fn generate_key_package(&self, identity: Option<String>) -> Result<String, MlsError> {
+        self.timed(Operation::GenerateKeyPackage, || {
+            let _flush = self.flush_on_return();
+            let mut state = self.write_as(identity.as_deref())?;
+            state.key_packages_dirty = true;
+
+            let credential = state.credential.clone().ok_or_else(MlsError::no_identity)?;
+            state
+                .encoded_key_package(CIPHERSUITE, credential, state.leaf_lifetime(), false, false)
+                .map(|kp| kp.key_package)
+        })
+    }
+
+    /// Like `generate_key_package()`, but wraps the key package in an
+    /// MLSMessage (wire format `mls_key_package`), as RFC 9420 delivery
+    /// services and other MLS implementations expect.  Every method taking
+    /// a key package accepts either form.
+    pub fn generate_key_package_wrapped(&self) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.key_packages_dirty = true;
+
+        let credential = state.credential.clone().ok_or_else(MlsError::no_identity)?;
+        state
+            .encoded_key_package(CIPHERSUITE, credential, state.leaf_lifetime(), false, true)
+            .map(|kp| kp.key_package)
+    }
+
+    /// Like `generate_key_package()`, but for `ciphersuite` (IANA
+    /// codepoint), e.g. to be added to the successor of a reinitialized
+    /// group (see `get_reinit()`).  See `supported_ciphersuites()`.
+    pub fn generate_key_package_for_ciphersuite(&self, ciphersuite: u16) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.key_packages_dirty = true;
+
+        let suite = state.usable_ciphersuite(ciphersuite)?;
+        let credential = state.credential.clone().ok_or_else(MlsError::no_identity)?;
+        state
+            .encoded_key_package(suite, credential, state.leaf_lifetime(), false, false)
+            .map(|kp| kp.key_package)
+    }
+
+    /// Generates `count` key packages for the current identity, valid from
+    /// `not_before` to `not_after` (seconds since the Unix epoch), e.g. to
+    /// top up the delivery service's pool.  Returns the encoded key
+    /// packages with their refs, which the delivery service reports back
+    /// once one is consumed (see `delete_key_package_by_ref()`).
+    ///
+    /// Peers refuse key packages outside their lifetime, and some reject
+    /// lifetimes much longer than OpenMLS's default of about three months.
+    ///
+    /// Like `generate_key_package()`, generates them for `identity` if
+    /// given.
+    #[uniffi::method(default(identity = None))]
+    pub fn generate_key_packages(
+        &self,
+        count: u32,
+        not_before: u64,
+        not_after: u64,
+        identity: Option<String>,
+    ) -> Result<Vec<GeneratedKeyPackage>, MlsError> {
+        let _flush = self.flush_on_return();
+        if not_before >= not_after {
+            return Err(MlsError::generic(format!(
+                "Key package lifetime must end after it starts ({} >= {})",
+                not_before, not_after
+            )));
+        }
+        let mut state = self.write_as(identity.as_deref())?;
+        state.key_packages_dirty = true;
+
+        let credential = state.credential.clone().ok_or_else(MlsError::no_identity)?;
+        let lifetime = Lifetime::init(not_before, not_after);
+        (0..count)
+            .map(|_| state.encoded_key_package(CIPHERSUITE, credential.clone(), lifetime, false, false))
+            .collect()
+    }
+
+    /// Generates `count` single-use key packages for the identity `name`
+    /// (the default identity or one from `add_identity()`), e.g. to upload
+    /// to a directory so several people can add us while we are offline.
+    /// Each is consumed by the first Welcome that uses it, independently of
+    /// the others.  Returns the encoded key packages.
+    ///
+    /// Fails with `UnknownIdentity` if there is no identity `name`, and
+    /// with `InputTooLarge` if `count` exceeds 100.
+    pub fn create_key_packages(&self, name: String, count: u32) -> Result<Vec<String>, MlsError> {
+        self.timed(Operation::GenerateKeyPackage, || {
+            let _flush = self.flush_on_return();
+            if count > MAX_KEY_PACKAGE_BATCH {
+                return Err(MlsError::InputTooLarge {
+                    limit: MAX_KEY_PACKAGE_BATCH.into(),
+                    actual: count.into(),
+                });
+            }
+            let mut state = self.write_as(Some(&name))?;
+            state.key_packages_dirty = true;
+
+            let credential = state.credential.clone().ok_or_else(MlsError::no_identity)?;
+            let lifetime = state.leaf_lifetime();
+            (0..count)
+                .map(|_| {
+                    state
+                        .encoded_key_package(CIPHERSUITE, credential.clone(), lifetime, false, false)
+                        .map(|kp| kp.key_package)
+                })
+                .collect()
+        })
+    }
+
+    /// Generates a last-resort key package for the identity `name` (the
+    /// default identity or one from `add_identity()`).
+    ///
+    /// Unlike `generate_key_package()`, the private key material is kept
+    /// when a Welcome consumes it, so the same package can be used by any
+    /// number of adders.  Publish it as a fallback for when the server's
+    /// pool of regular key packages runs out.  Returns the encoded key
+    /// package.
+    ///
+    /// This costs forward secrecy: until the package is replaced, anyone
+    /// who later obtains its private init key can decrypt every Welcome
+    /// sent to it and so read those groups' first epochs.  Rotate it
+    /// regularly and prefer regular key packages whenever available.
+    ///
+    /// Fails with `UnknownIdentity` if there is no identity `name`.
+    pub fn generate_last_resort_key_package(&self, name: String) -> Result<String, MlsError> {
+        self.timed(Operation::GenerateKeyPackage, || {
+            let _flush = self.flush_on_return();
+            let mut state = self.write_as(Some(&name))?;
+            state.key_packages_dirty = true;
+
+            let credential = state.credential.clone().ok_or_else(MlsError::no_identity)?;
+            state
+                .encoded_key_package(CIPHERSUITE, credential, state.leaf_lifetime(), true, false)
+                .map(|kp| kp.key_package)
+        })
+    }
+
+    /// Decodes and validates a key package (e.g. a peer's, before creating
+    /// a group to talk to them) and returns its contents.
+    ///
+    /// Extensions of unknown types, e.g. from newer clients, are accepted
+    /// as long as the leaf lists them in its capabilities, and reported in
+    /// `unknown_extensions`.  OpenMLS still rejects extensions with GREASE
+    /// codepoints (0x0A0A, 0x1A1A, ...) here; in capabilities they are fine.
+    ///
+    /// Fails like `validate_key_package()`.
+    pub fn get_key_package_info(&self, key_package_hex: String) -> Result<KeyPackageInfo, MlsError> {
+        let state = self.state.read().map_err(|_| MlsError::lock_poisoned())?;
+        let key_package = state.validated_key_package(&key_package_hex)?;
+
+        let leaf = key_package.leaf_node();
+        Ok(KeyPackageInfo {
+            ciphersuite: u16::from(key_package.ciphersuite()),
+            identity: leaf.credential().serialized_content().to_vec(),
+            not_before: key_package.life_time().not_before(),
+            not_after: key_package.life_time().not_after(),
+            signature_key: leaf.signature_key().as_slice().to_vec(),
+            unknown_extensions: unknown_extensions(key_package.extensions().iter())?,
+            unknown_leaf_extensions: unknown_extensions(leaf.extensions().iter())?,
+            // Validation only accepts key packages of our version.
+            protocol_version: protocol_version_name(ProtocolVersion::default()),
+        })
+    }
+
+    /// Checks a key package without using it, e.g. for a directory server
+    /// vetting uploads before storing them, and returns its contents like
+    /// `get_key_package_info()`.  Nothing is stored.
+    ///
+    /// Fails with `UnsupportedCiphersuite` if this client cannot handle
+    /// the key package's ciphersuite, `KeyPackageExpired` or
+    /// `KeyPackageNotYetValid` if it is outside its lifetime,
+    /// `InvalidSignature` if its signature or its leaf's does not verify,
+    /// `InvalidKeyPackage` if it is otherwise invalid (e.g. its init and
+    /// encryption keys are equal), and `Serialization` if it cannot be
+    /// decoded.
+    pub fn validate_key_package(&self, key_package_hex: String) -> Result<KeyPackageInfo, MlsError> {
+        self.get_key_package_info(key_package_hex)
+    }
+
+    /// Deletes the private key material of one of our key packages from the
+    /// key store, e.g. once the delivery service reports it as expired or
+    /// withdrawn, or to retire a last-resort key package.
+    ///
+    /// Regular key packages consumed by `process_welcome()` are deleted
+    /// automatically; last-resort ones stay usable until deleted here.
+    /// Deleting a key package that is no longer stored is a no-op; expired
+    /// key packages fail validation, so remove those with
+    /// `prune_expired_key_packages()`.
+    pub fn delete_key_package(&self, key_package_hex: String) -> Result<(), MlsError> {
+        let state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+
+        let kp_bytes = state
+            .encoding
+            .decode_limited(&key_package_hex, state.input_limits.key_package)?;
+        let kp_in = parse_key_package(&kp_bytes)?;
+        let key_package = kp_in
+            .validate(state.crypto.crypto(), ProtocolVersion::default())
+            .map_err(|e| MlsError::InvalidKeyPackage { detail: format!("{:?}", e) })?;
+        let hash_ref = key_package
+            .hash_ref(state.crypto.crypto())
+            .map_err(|e| MlsError::crypto(format!("Failed to hash key package: {:?}", e)))?;
+
+        state
+            .crypto
+            .storage()
+            .delete_key_package(&hash_ref)
+            .map_err(|e| MlsError::io(format!("Failed to delete key package: {:?}", e)))?;
+
+        drop(state);
+        let _ = self.persist_state();
+        Ok(())
+    }
+
+    /// Like `delete_key_package()`, but by hex-encoded KeyPackageRef, e.g.
+    /// when the delivery service reports the package with that ref as
+    /// consumed.  Deleting a ref that is not stored is a no-op.
+    pub fn delete_key_package_by_ref(&self, key_package_ref: String) -> Result<(), MlsError> {
+        let state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+
+        let bytes = hex::decode(&key_package_ref)
+            .map_err(|e| MlsError::serialization(format!("Invalid key package ref: {:?}", e)))?;
+        // A KeyPackageRef is TLS-encoded as variable-length bytes.
+        let hash_ref = VLBytes::from(bytes)
+            .tls_serialize_detached()
+            .ok()
+            .and_then(|encoded| KeyPackageRef::tls_deserialize_exact(encoded).ok())
+            .ok_or_else(|| MlsError::serialization("Invalid key package ref"))?;
+
+        state
+            .crypto
+            .storage()
+            .delete_key_package(&hash_ref)
+            .map_err(|e| MlsError::io(format!("Failed to delete key package: {:?}", e)))?;
+
+        drop(state);
+        let _ = self.persist_state();
+        Ok(())
+    }
+
+    /// Lists the key packages whose private key material we still hold:
+    /// those generated and not yet consumed or deleted, plus last-resort
+    /// ones.  Compare with the delivery service's pool to keep both in
+    /// sync.
+    pub fn list_stored_key_packages(&self) -> Result<Vec<KeyPackageRefInfo>, MlsError> {
+        let state = self.state.read().map_err(|_| MlsError::lock_poisoned())?;
+
+        let bundles = stored_key_packages(&state.crypto)?;
+        bundles
+            .iter()
+            .map(|bundle| {
+                let key_package = bundle.key_package();
+                let hash_ref = key_package
+                    .hash_ref(state.crypto.crypto())
+                    .map_err(|e| MlsError::crypto(format!("Failed to hash key package: {:?}", e)))?;
+                Ok(KeyPackageRefInfo {
+                    key_package_ref: hex::encode(hash_ref.as_slice()),
+                    identity: key_package.leaf_node().credential().serialized_content().to_vec(),
+                    ciphersuite: u16::from(key_package.ciphersuite()),
+                    not_after: key_package.life_time().not_after(),
+                    last_resort: key_package.last_resort(),
+                })
+            })
+            .collect()
+    }
+
+    /// Deletes the private key material of all our key packages whose
+    /// lifetime has ended, returning how many were deleted.  Peers refuse
+    /// expired key packages, so nothing can use them any more.
+    pub fn prune_expired_key_packages(&self) -> Result<u32, MlsError> {
+        let state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+
+        let storage = state.crypto.storage();
+        let now = unix_time();
+        let expired: Vec<KeyPackageBundle> = stored_key_packages(&state.crypto)?
+            .into_iter()
+            .filter(|bundle| bundle.key_package().life_time().not_after() <= now)
+            .collect();
+        for bundle in &expired {
+            let hash_ref = bundle
+                .key_package()
+                .hash_ref(state.crypto.crypto())
+                .map_err(|e| MlsError::crypto(format!("Failed to hash key package: {:?}", e)))?;
+            storage
+                .delete_key_package(&hash_ref)
+                .map_err(|e| MlsError::io(format!("Failed to delete key package: {:?}", e)))?;
+        }
+        log::info!("Pruned {} expired key packages", expired.len());
+
+        drop(state);
+        let _ = self.persist_state();
+        Ok(expired.len() as u32)
+    }
+
+    /// Lists when each of our stored key packages (see
+    /// `list_stored_key_packages()`) expires, soonest first, so that the
+    /// delivery service's pool can be topped up in time.  See
+    /// `refresh_key_packages()`.
+    pub fn key_package_expirations(&self) -> Result<Vec<KeyPackageExpiry>, MlsError> {
+        let now = unix_time();
+        let mut expirations: Vec<KeyPackageExpiry> = self
+            .list_stored_key_packages()?
+            .into_iter()
+            .map(|info| KeyPackageExpiry {
+                key_package_ref: info.key_package_ref,
+                identity: info.identity,
+                not_after: info.not_after,
+                expired: info.not_after <= now,
+                needs_refresh: info.not_after <= now.saturating_add(KEY_PACKAGE_REFRESH_MARGIN),
+            })
+            .collect();
+        expirations.sort_by_key(|expiry| expiry.not_after);
+        Ok(expirations)
+    }
+
+    /// Replaces each stored key package that has expired or expires within
+    /// a week with a fresh one for the same identity, ciphersuite and
+    /// last-resort flag, valid for OpenMLS's default lifetime.  Returns
+    /// the encoded new key packages, to upload to the delivery service.
+    ///
+    /// The replaced packages are deleted, so withdraw them from the
+    /// delivery service along with the upload: a Welcome for one fails
+    /// with `NoMatchingKeyPackage`.  Packages of a signature key we no
+    /// longer hold cannot be replaced; expired ones are deleted all the
+    /// same.
+    pub fn refresh_key_packages(&self) -> Result<Vec<String>, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_as(None)?;
+
+        let deadline = unix_time().saturating_add(KEY_PACKAGE_REFRESH_MARGIN);
+        let now = unix_time();
+        let stale: Vec<KeyPackageBundle> = stored_key_packages(&state.crypto)?
+            .into_iter()
+            .filter(|bundle| bundle.key_package().life_time().not_after() <= deadline)
+            .collect();
+
+        let mut fresh = Vec::new();
+        for bundle in &stale {
+            let key_package = bundle.key_package();
+            let leaf = key_package.leaf_node();
+            let signature_key = leaf.signature_key().as_slice();
+            let owner = if state.signer.as_ref().is_some_and(|s| s.public() == signature_key) {
+                Some(None)
+            } else {
+                state.extra_identity_with_key(signature_key).map(Some)
+            };
+            let hash_ref = key_package
+                .hash_ref(state.crypto.crypto())
+                .map_err(|e| MlsError::crypto(format!("Failed to hash key package: {:?}", e)))?;
+
+            match owner {
+                Some(name) => {
+                    if let Some(name) = &name {
+                        state.select_identity(name)?;
+                    }
+                    let credential = CredentialWithKey {
+                        credential: leaf.credential().clone(),
+                        signature_key: signature_key.into(),
+                    };
+                    let generated = state.encoded_key_package(
+                        key_package.ciphersuite(),
+                        credential,
+                        state.leaf_lifetime(),
+                        key_package.last_resort(),
+                        false,
+                    );
+                    state.restore_identity();
+                    fresh.push(generated?.key_package);
+                }
+                None if key_package.life_time().not_after() > now => {
+                    log::warn!("Cannot refresh key package {}: its signature key is gone", hex::encode(hash_ref.as_slice()));
+                    continue;
+                }
+                None => {}
+            }
+            state
+                .crypto
+                .storage()
+                .delete_key_package(&hash_ref)
+                .map_err(|e| MlsError::io(format!("Failed to delete key package: {:?}", e)))?;
+        }
+        if !stale.is_empty() {
+            state.key_packages_dirty = true;
+            log::info!("Refreshed {} of {} expiring key packages", fresh.len(), stale.len());
+        }
+        Ok(fresh)
+    }
+
+    /// Returns whether an identity has been created.
+    pub fn has_identity(&self) -> bool {
+        self.state
+            .read()
+            .map(|s| s.signer.is_some())
+            .unwrap_or(false)
+    }
+
+    // ── Group lifecycle ────────────────────────────────────────────────
+
+    /// Creates a new MLS group.  Returns the hex-encoded group ID.
+    ///
+    /// The group belongs to `identity` (see `add_identity()`), or to the
+    /// default identity if `None`.
+    #[uniffi::method(default(identity = None))]
+    pub fn create_group(&self, _group_id: String, identity: Option<String>) -> Result<String, MlsError> {
+        self.timed(Operation::CreateGroup, || {
+            let _flush = self.flush_on_return();
+            let mut state = self.write_as(identity.as_deref())?;
+            let config = state.default_group_config();
+            state.create_group(None, CIPHERSUITE, Extensions::empty(), &config)
+        })
+    }
+
+    /// Like `create_group()`, but returns the new group's summary, as
+    /// `list_groups_detailed()` would report it, e.g. to render the
+    /// conversation right away.
+    #[uniffi::method(default(identity = None))]
+    pub fn create_group_full(&self, _group_id: String, identity: Option<String>) -> Result<GroupSummary, MlsError> {
+        self.timed(Operation::CreateGroup, || {
+            let _flush = self.flush_on_return();
+            let mut state = self.write_as(identity.as_deref())?;
+            let config = state.default_group_config();
+            let group_id = state.create_group(None, CIPHERSUITE, Extensions::empty(), &config)?;
+            let group = state
+                .groups
+                .get(&group_id)
+                .ok_or_else(|| MlsError::GroupNotFound {
+                    group_id: group_id.clone(),
+                })?;
+            Ok(GroupSummary {
+                epoch: Some(group.epoch().as_u64()),
+                member_count: group.members().count() as u32,
+                status: MembershipStatus::Active,
+                ciphersuite: Some(u16::from(group.ciphersuite())),
+                last_activity: state.group_times.get(&group_id).map(|t| t.updated_at),
+                frozen: state.frozen_groups.contains_key(&group_id),
+                group_id,
+            })
+        })
+    }
+
+    /// Like `create_group()`, but with the given Welcome, wire format,
+    /// padding and external sender settings instead of the defaults
+    /// (ratchet tree included, ciphertext-only handshakes, the client's
+    /// padding size, no external senders).  A later
+    /// `set_wire_format_policy()` or `set_padding_size()` still applies.
+    ///
+    /// Proposals from external senders are stored like members' ones and
+    /// listed by `get_pending_proposals()`, for any member to commit with
+    /// `commit_pending_proposals()`.  Proposals from senders not in the
+    /// list fail with `UnauthorizedExternalSender`.
+    ///
+    /// Returns the hex-encoded group ID.
+    #[uniffi::method(default(identity = None))]
+    pub fn create_group_with_config(
+        &self,
+        _group_id: String,
+        config: GroupConfig,
+        identity: Option<String>,
+    ) -> Result<String, MlsError> {
+        self.timed(Operation::CreateGroup, || {
+            let _flush = self.flush_on_return();
+            let mut state = self.write_as(identity.as_deref())?;
+            state.create_group(None, CIPHERSUITE, Extensions::empty(), &config)
+        })
+    }
+
+    /// Creates a new MLS group using the given ciphersuite (IANA
+    /// codepoint), e.g. the one read from a peer's key package with
+    /// `get_key_package_info()`.  See `supported_ciphersuites()` for the
+    /// suites this client can use.
+    ///
+    /// Returns the hex-encoded group ID.
+    #[uniffi::method(default(identity = None))]
+    pub fn create_group_with_ciphersuite(
+        &self,
+        _group_id: String,
+        ciphersuite: u16,
+        identity: Option<String>,
+    ) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_as(identity.as_deref())?;
+        let suite = state.usable_ciphersuite(ciphersuite)?;
+        let config = state.default_group_config();
+        state.create_group(None, suite, Extensions::empty(), &config)
+    }
+
+    /// Lists the ciphersuites (IANA codepoints) this client can create
+    /// groups with: those the crypto provider implements that use our
+    /// identity's signature scheme.  Exactly the codepoints
+    /// `create_group_with_ciphersuite()` accepts.
+    pub fn supported_ciphersuites(&self) -> Vec<u16> {
+        self.state
+            .read()
+            .map(|s| {
+                s.crypto
+                    .crypto()
+                    .supported_ciphersuites()
+                    .into_iter()
+                    .map(u16::from)
+                    .filter(|&cs| s.usable_ciphersuite(cs).is_ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Lists the MLS protocol versions (codepoints, 1 for MLS 1.0) this
+    /// build can speak.  OpenMLS implements MLS 1.0 only.
+    pub fn supported_protocol_versions(&self) -> Vec<u16> {
+        let version = match ProtocolVersion::default() {
+            ProtocolVersion::Mls10 => 1,
+            ProtocolVersion::Other(v) => v,
+        };
+        vec![version]
+    }
+
+    /// Creates a new MLS group whose `RequiredCapabilities` extension lists
+    /// the given extension, proposal and credential types (raw codepoints).
+    /// `add_member()` then rejects joiners whose key package does not
+    /// support all of them with `CapabilitiesMismatch`.  Fails the same
+    /// way if our own capabilities do not satisfy them (see
+    /// `create_identity_with_capabilities()`).
+    ///
+    /// Returns the hex-encoded group ID.
+    #[uniffi::method(default(identity = None))]
+    pub fn create_group_with_required_capabilities(
+        &self,
+        _group_id: String,
+        extensions: Vec<u16>,
+        proposals: Vec<u16>,
+        credentials: Vec<u16>,
+        identity: Option<String>,
+    ) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let extension_types: Vec<ExtensionType> =
+            extensions.into_iter().map(ExtensionType::from).collect();
+        let proposal_types: Vec<ProposalType> =
+            proposals.into_iter().map(ProposalType::from).collect();
+        let credential_types: Vec<CredentialType> =
+            credentials.into_iter().map(CredentialType::from).collect();
+        let required = RequiredCapabilitiesExtension::new(
+            &extension_types,
+            &proposal_types,
+            &credential_types,
+        );
+
+        let extensions = Extensions::single(Extension::RequiredCapabilities(required.clone()))
+            .map_err(|e| MlsError::generic(format!("Invalid required capabilities: {:?}", e)))?;
+
+        let mut state = self.write_as(identity.as_deref())?;
+        check_capabilities(&required, &state.capabilities.to_leaf_capabilities()?, "Own leaf")?;
+        let config = state.default_group_config();
+        state.create_group(None, CIPHERSUITE, extensions, &config)
+    }
+
+    /// Adds a member to an existing group.
+    ///
+    /// Returns JSON:
+    /// `{"commit":"<encoded>","welcome":"<encoded>","key_package_reused":false,"duplicate_identity":false,"commit_ref":"<hex>"}`.
+    /// * Send the **commit** to all *existing* members (via `process_commit`).
+    /// * Send the **welcome** to the *new* member (via `process_welcome`).
+    ///
+    /// `key_package_reused` is set if we already added someone with this
+    /// key package (and it is not a last-resort one): its owner has most
+    /// likely discarded the private key, so the join will probably fail.
+    ///
+    /// `duplicate_identity` is set if the new member's identity is already
+    /// in the group, with a different signature key (see
+    /// `set_duplicate_identity_policy()`).
+    ///
+    /// `commit_ref` identifies the commit and welcome in the journal of
+    /// undelivered adds until `ack_commit()` (see `get_unacked_commits()`).
+    ///
+    /// Fails with `AlreadyMember` if the key package's signature key is
+    /// already in the group (e.g. the same key package submitted twice).
+    ///
+    /// The commit is merged before this returns, so we are in the next
+    /// epoch even if the commit never reaches the other members.  Where
+    /// delivery can fail, use `add_member_deferred()` and merge with
+    /// `confirm_commit()` only once the commit is accepted.  If merging
+    /// fails, the group is left in its previous epoch with no pending
+    /// commit, and the commit must not be sent.
+    pub fn add_member(
+        &self,
+        group_id: String,
+        new_member_key_package_hex: String,
+    ) -> Result<String, MlsError> {
+        self.timed(Operation::AddMember, || {
+            let _flush = self.flush_on_return();
+            let mut state = self.write_group(&group_id)?;
+            let prepared = state.add_member(&group_id, &new_member_key_package_hex, None, false)?;
+
+            Ok(format!(
+                r#"{{"commit":"{}","welcome":"{}","key_package_reused":{},"duplicate_identity":{},"commit_ref":"{}"}}"#,
+                prepared.commit,
+                prepared.welcome,
+                prepared.key_package_reused,
+                prepared.duplicate_identity,
+                prepared.commit_ref
+            ))
+        })
+    }
+
+    /// Like `add_member`, but first checks that the key package's
+    /// credential identity equals `expected_identity` (e.g. a username the
+    /// application verified), so the wrong person's key package cannot be
+    /// added by mistake.  On mismatch, fails with `IdentityMismatch` and
+    /// changes nothing.
+    pub fn add_member_if(
+        &self,
+        group_id: String,
+        key_package_hex: String,
+        expected_identity: Vec<u8>,
+    ) -> Result<AddMemberResult, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        state.add_member(&group_id, &key_package_hex, Some(&expected_identity), false)
+    }
+
+    /// Like `add_member`, but if the welcome is estimated to exceed
+    /// `max_welcome_size` bytes (see `estimate_welcome_size()`), e.g. a
+    /// transport's message size cap, leaves the ratchet tree out of it and
+    /// sets `ratchet_tree_omitted`.  Then publish `export_ratchet_tree()`
+    /// alongside the welcome; the new member joins with
+    /// `process_welcome_with_ratchet_tree()`.  Check the sizes reported in
+    /// the result before sending: the estimate is not exact.
+    pub fn add_member_with_welcome_limit(
+        &self,
+        group_id: String,
+        key_package_hex: String,
+        max_welcome_size: u64,
+    ) -> Result<AddMemberResult, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+
+        let signer = state.signer.as_ref().ok_or_else(MlsError::no_identity)?;
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+        let with_tree = state.group_settings(&group_id).use_ratchet_tree_extension;
+        let omit_ratchet_tree = welcome_size_estimate(group, &state.crypto, signer, with_tree, 1)? > max_welcome_size;
+
+        state.add_member(&group_id, &key_package_hex, None, omit_ratchet_tree)
+    }
+
+    /// Estimates the size in bytes of the welcome for adding
+    /// `pending_member_count` members to the group now, to check it against
+    /// a transport's message size cap before doing the work.  The estimate
+    /// leans high but is not a bound; `AddMemberResult` reports the actual
+    /// sizes.
+    pub fn estimate_welcome_size(&self, group_id: String, pending_member_count: u32) -> Result<u64, MlsError> {
+        let state = self.read_group(&group_id)?;
+        let signer = state.group_signer(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+        let with_tree = state.group_settings(&group_id).use_ratchet_tree_extension;
+        welcome_size_estimate(group, &state.crypto, signer, with_tree, pending_member_count)
+    }
+
+    /// Like `add_member`, but leaves the commit pending instead of merging
+    /// it, so the group stays at its current epoch until the delivery
+    /// service accepts the commit.
+    ///
+    /// * Once the commit is acknowledged, call `confirm_commit` and then
+    ///   send the welcome to the new member.
+    /// * If another member's commit won the epoch, call `abort_commit` and
+    ///   process theirs instead; the welcome must then be discarded.
+    ///
+    /// While the commit is pending, `process_commit` and `stage_commit`
+    /// fail with `PendingCommitConflict`.
+    pub fn add_member_prepare(
+        &self,
+        group_id: String,
+        new_member_key_package_hex: String,
+    ) -> Result<AddMemberResult, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        state.prepare_add_member(&group_id, &new_member_key_package_hex, None, false)
+    }
+
+    /// Like `add_member`, but does not merge the commit: the group stays at
+    /// its current epoch until `confirm_commit()` is called once the commit
+    /// has been distributed to the other members, or `abort_commit()` if
+    /// it could not be.  Only send the welcome after confirming.  Same as
+    /// `add_member_prepare()`.
+    pub fn add_member_deferred(
+        &self,
+        group_id: String,
+        key_package_hex: String,
+    ) -> Result<AddMemberResult, MlsError> {
+        self.add_member_prepare(group_id, key_package_hex)
+    }
+
+    /// Adds the members whose key packages are given and removes the
+    /// members at `remove_leaf_indices` in a single commit, so a swap
+    /// (e.g. a moderator replacing a device) takes one epoch instead of
+    /// two.  The key packages are checked as by `add_member()`; fails
+    /// with `UnknownMember` or `CannotRemoveSelf` as `remove_member()`
+    /// does, without committing anything.  At least one key package is
+    /// required; to only remove, use `remove_members()`.
+    ///
+    /// The commit is merged before this returns and journaled like an
+    /// add commit; `key_package_ref` lists the refs of all key packages,
+    /// comma-separated.  Broadcast the commit to the remaining members and
+    /// send the Welcome to the new ones.
+    pub fn update_membership(
+        &self,
+        group_id: String,
+        add_key_packages: Vec<String>,
+        remove_leaf_indices: Vec<u32>,
+    ) -> Result<AddMemberResult, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        state.update_membership(&group_id, &add_key_packages, &remove_leaf_indices)
+    }
+
+    /// Caps the group at `max_members` members: commits of ours that would
+    /// leave it with more, counting the add proposals queued in the group,
+    /// fail with `GroupFull` and change nothing.  This covers
+    /// `add_member()` and its variants, `update_membership()`,
+    /// `finish_commit()` and `commit_pending_proposals()`; commits of other
+    /// members are not checked.  0 removes the cap.  Saved with the group.
+    pub fn set_max_members(&self, group_id: String, max_members: u32) -> Result<(), MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        if !state.groups.contains_key(&group_id) {
+            return Err(MlsError::GroupNotFound { group_id });
+        }
+        if max_members == 0 {
+            state.max_members.remove(&group_id);
+        } else {
+            state.max_members.insert(group_id, max_members);
+        }
+        Ok(())
+    }
+
+    /// The cap set with `set_max_members()`, `None` if the group has none.
+    pub fn get_max_members(&self, group_id: String) -> Result<Option<u32>, MlsError> {
+        let state = self.read_group(&group_id)?;
+        if !state.groups.contains_key(&group_id) {
+            return Err(MlsError::GroupNotFound { group_id });
+        }
+        Ok(state.max_members.get(&group_id).copied())
+    }
+
+    /// Merges the commit left pending by `add_member_deferred` (or
+    /// `add_member_prepare`), advancing the group to the next epoch.  Call
+    /// once the delivery service has acknowledged the commit.
+    pub fn confirm_commit(&self, group_id: String) -> Result<(), MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            pending_events,
+            ..
+        } = &mut *state;
+
+        let group = groups
+            .get_mut(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        if group.pending_commit().is_none() {
+            return Err(MlsError::generic(format!(
+                "No pending commit for group {}",
+                group_id
+            )));
+        }
+
+        // Checked against the commit policy when it was prepared.
+        merge_own_commit(&group_id, group, crypto, None, pending_events)
+    }
+
+    /// Discards the commit left pending by `add_member_prepare`, e.g.
+    /// because the delivery service rejected it in favour of a competing
+    /// commit.  The group stays at its current epoch, ready to process
+    /// the winning commit.  No-op if there is no pending commit.
+    pub fn abort_commit(&self, group_id: String) -> Result<(), MlsError> {
+        self.clear_pending_commit(group_id)
+    }
+
+    /// The add commits of the group, with their welcomes, not yet
+    /// acknowledged with `ack_commit()`, oldest first.  The journal is
+    /// saved with the state, so after a crash or restart the application
+    /// can retry handing each pair to the delivery service.  Commits
+    /// dropped with `abort_commit()` are not listed.
+    pub fn get_unacked_commits(&self, group_id: String) -> Result<Vec<UnackedCommit>, MlsError> {
+        let state = self.read_group(&group_id)?;
+        if !state.groups.contains_key(&group_id) {
+            return Err(MlsError::GroupNotFound { group_id });
+        }
+        Ok(state.commit_journal.get(&group_id).cloned().unwrap_or_default())
+    }
+
+    /// Drops the add commit `commit_ref` (see `AddMemberResult`) from the
+    /// group's journal once the delivery service has taken both the commit
+    /// and the welcome.  No-op if it is not in the journal.
+    pub fn ack_commit(&self, group_id: String, commit_ref: String) -> Result<(), MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        let Some(journal) = state.commit_journal.get_mut(&group_id) else {
+            return Ok(());
+        };
+        journal.retain(|entry| entry.commit_ref != commit_ref);
+        if journal.is_empty() {
+            state.commit_journal.remove(&group_id);
+        }
+        Ok(())
+    }
+
+    /// Removes a member from the group by leaf index.
+    ///
+    /// Use `get_members()` to discover leaf indices.
+    /// Returns JSON: `{"commit":"<encoded>"}`.
+    /// Broadcast the commit to all remaining members.
+    pub fn remove_member(
+        &self,
+        group_id: String,
+        member_index: u32,
+    ) -> Result<String, MlsError> {
+        self.timed(Operation::RemoveMember, || {
+            let _flush = self.flush_on_return();
+            let mut state = self.write_group(&group_id)?;
+            state.ensure_can_commit(&group_id)?;
+
+            let MlsClientState {
+                groups,
+                crypto,
+                commit_policy,
+                signer,
+                pending_events,
+                encoding,
+                sent_messages,
+                message_cache_size,
+                ..
+            } = &mut *state;
+            let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+            let group = groups
+                .get_mut(&group_id)
+                .ok_or_else(|| MlsError::GroupNotFound {
+                    group_id: group_id.clone(),
+                })?;
+
+            let leaf = LeafNodeIndex::new(member_index);
+
+            let (commit, _welcome, _gi) = group
+                .remove_members(crypto, signer, &[leaf])
+                .map_err(|e| match e {
+                    RemoveMembersError::CreateCommitError(CreateCommitError::ProposalValidationError(
+                        ProposalValidationError::UnknownMemberRemoval,
+                    )) => MlsError::UnknownMember {
+                        detail: format!("No member at leaf {}", member_index),
+                    },
+                    e => MlsError::generic(format!("Failed to remove member: {:?}", e)),
+                })?;
+
+            merge_own_commit(&group_id, group, crypto, commit_policy.as_ref(), pending_events)?;
+
+            let commit_encoded = encode_sent(&commit, &group_id, crypto, *encoding, sent_messages, *message_cache_size)?;
+
+            Ok(format!(r#"{{"commit":"{}"}}"#, commit_encoded))
+        })
+    }
+
+    /// Removes every member whose credential identity is in `identities`,
+    /// in a single commit.  An identity held by several leaves (one per
+    /// device) removes all of them.
+    ///
+    /// Fails without committing anything if any identity matches no member
+    /// (`UnknownMembers`, listing all of them) or matches our own leaf
+    /// (`CannotRemoveSelf`).
+    /// Returns JSON: `{"commit":"<encoded>"}`.
+    /// Broadcast the commit to all remaining members.
+    pub fn remove_members(
+        &self,
+        group_id: String,
+        identities: Vec<String>,
+    ) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        state.ensure_can_commit(&group_id)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            commit_policy,
+            signer,
+            pending_events,
+            encoding,
+            sent_messages,
+            message_cache_size,
+            ..
+        } = &mut *state;
+        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        let group = groups
+            .get_mut(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let leaves = removal_leaves(&group_id, group, &identities)?;
+
+        let (commit, _welcome, _gi) = group
+            .remove_members(crypto, signer, &leaves)
+            .map_err(|e| MlsError::generic(format!("Failed to remove members: {:?}", e)))?;
+
+        merge_own_commit(&group_id, group, crypto, commit_policy.as_ref(), pending_events)?;
+
+        let commit_encoded = encode_sent(&commit, &group_id, crypto, *encoding, sent_messages, *message_cache_size)?;
+
+        Ok(format!(r#"{{"commit":"{}"}}"#, commit_encoded))
+    }
+
+    /// Performs a self-update, rotating this member's leaf key material.
+    ///
+    /// This is essential for **post-compromise security**: even if your
+    /// keys were leaked, future messages become secure after an update.
+    /// Returns JSON: `{"commit":"<encoded>"}`.
+    /// Broadcast the commit to all other members.
+    pub fn self_update(&self, group_id: String) -> Result<String, MlsError> {
+        self.timed(Operation::SelfUpdate, || {
+            let _flush = self.flush_on_return();
+            let mut state = self.write_group(&group_id)?;
+            state.ensure_can_commit(&group_id)?;
+            state.commit_self_update(&group_id, LeafNodeParameters::default())
+        })
+    }
+
+    /// Opens a commit transaction for the group, collecting membership
+    /// changes that `finish_commit()` then sends as a single commit: one
+    /// new epoch and one message to fan out instead of one per change.
+    /// Add changes with `txn_add()`, `txn_remove()` and
+    /// `txn_self_update()`, or drop them with `cancel_commit()`.
+    ///
+    /// One transaction can be open at a time.  While it is, other methods
+    /// creating commits or proposals in the group fail with
+    /// `CommitTransactionOpen`; sending and processing messages still work.
+    pub fn begin_commit(&self, group_id: String) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.ensure_resident(&group_id)?;
+        state.ensure_not_frozen(&group_id)?;
+        if let Some(txn) = &state.commit_txn {
+            return Err(MlsError::CommitTransactionOpen {
+                group_id: txn.group_id.clone(),
+            });
+        }
+
+        let group = state.groups.get(&group_id).ok_or_else(|| MlsError::GroupNotFound {
+            group_id: group_id.clone(),
+        })?;
+        if group.pending_commit().is_some() {
+            return Err(MlsError::PendingCommitConflict { group_id });
+        }
+
+        state.commit_txn = Some(CommitTxn {
+            group_id,
+            adds: Vec::new(),
+            removes: Vec::new(),
+            self_update: false,
+        });
+        Ok(())
+    }
+
+    /// Adds the member whose encoded key package is given in the open
+    /// commit transaction.  The key package is checked as by
+    /// `add_member()`, and fails with `DuplicateSignatureKey` if its
+    /// signature key is already added in the transaction.  Returns whether
+    /// the member's identity is already in the group (and not removed in
+    /// the transaction) or added in the transaction, as
+    /// `AddMemberResult.duplicate_identity`.
+    pub fn txn_add(&self, key_package_hex: String) -> Result<bool, MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        let group_id = state.commit_txn_group()?;
+        state.ensure_resident(&group_id)?;
+        let key_package = state.new_member_key_package(&group_id, &key_package_hex)?;
+
+        let txn = state.commit_txn.as_ref().ok_or_else(MlsError::no_commit_txn)?;
+        let signature_key = key_package.leaf_node().signature_key();
+        if txn.adds.iter().any(|kp| kp.leaf_node().signature_key() == signature_key) {
+            return Err(MlsError::DuplicateSignatureKey { group_id });
+        }
+        let duplicate_identity = state.check_duplicate_identity(&group_id, &key_package, &txn.adds, &txn.removes)?;
+
+        let txn = state.commit_txn.as_mut().ok_or_else(MlsError::no_commit_txn)?;
+        txn.adds.push(key_package);
+        Ok(duplicate_identity)
+    }
+
+    /// Removes the member with `identity` (every leaf holding it) in the
+    /// open commit transaction.  Fails with `UnknownMembers` or
+    /// `CannotRemoveSelf` as `remove_members()` does.
+    pub fn txn_remove(&self, identity: String) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        let group_id = state.commit_txn_group()?;
+        state.ensure_resident(&group_id)?;
+
+        let group = state.groups.get(&group_id).ok_or_else(|| MlsError::GroupNotFound {
+            group_id: group_id.clone(),
+        })?;
+        removal_leaves(&group_id, group, std::slice::from_ref(&identity))?;
+
+        let txn = state.commit_txn.as_mut().ok_or_else(MlsError::no_commit_txn)?;
+        if !txn.removes.contains(&identity) {
+            txn.removes.push(identity);
+        }
+        Ok(())
+    }
+
+    /// Includes a self-update (see `self_update()`) in the open commit
+    /// transaction.
+    pub fn txn_self_update(&self) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        let txn = state.commit_txn.as_mut().ok_or_else(MlsError::no_commit_txn)?;
+        txn.self_update = true;
+        Ok(())
+    }
+
+    /// Closes the group's commit transaction and creates and merges a
+    /// single commit with all its changes.  The transaction is closed even
+    /// if this fails; nothing is committed then.
+    ///
+    /// Broadcast the commit to the other members and send the Welcome, if
+    /// members were added, to the new ones.
+    pub fn finish_commit(&self, group_id: String) -> Result<CommitBundle, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        if state.commit_txn_group()? != group_id {
+            return Err(MlsError::no_commit_txn());
+        }
+        let txn = state.commit_txn.take().ok_or_else(MlsError::no_commit_txn)?;
+        state.ensure_not_frozen(&group_id)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            commit_policy,
+            signer,
+            pending_self_updates,
+            used_key_packages,
+            pending_events,
+            encoding,
+            sent_messages,
+            message_cache_size,
+            max_members,
+            ..
+        } = &mut *state;
+        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        let group = groups
+            .get_mut(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        // Members may have changed since `txn_remove()`.
+        let leaves = removal_leaves(&group_id, group, &txn.removes)?;
+        check_member_cap(
+            &group_id,
+            group,
+            max_members.get(&group_id).copied(),
+            txn.adds.len(),
+            leaves.len(),
+        )?;
+
+        let mut key_package_refs = Vec::new();
+        for key_package in txn.adds.iter().filter(|kp| !kp.last_resort()) {
+            let hash_ref = key_package
+                .hash_ref(crypto.crypto())
+                .map_err(|e| MlsError::crypto(format!("Failed to hash key package: {:?}", e)))?;
+            key_package_refs.push(hex::encode(hash_ref.as_slice()));
+        }
+
+        let bundle = group
+            .commit_builder()
+            .consume_proposal_store(false)
+            .propose_removals(leaves)
+            .propose_adds(txn.adds)
+            .force_self_update(txn.self_update)
+            .load_psks(crypto.storage())
+            .map_err(|e| MlsError::generic(format!("Failed to load PSKs: {:?}", e)))?
+            .build(crypto.rand(), crypto.crypto(), signer, |_| true)
+            .map_err(|e| MlsError::generic(format!("Failed to create commit: {:?}", e)))?
+            .stage_commit(crypto)
+            .map_err(|e| MlsError::generic(format!("Failed to stage commit: {:?}", e)))?;
+        let (commit, welcome, _gi) = bundle.into_messages();
+
+        merge_own_commit(&group_id, group, crypto, commit_policy.as_ref(), pending_events)?;
+
+        if txn.self_update {
+            pending_self_updates.remove(&group_id);
+        }
+        used_key_packages.extend(key_package_refs);
+
+        let welcome = welcome
+            .map(|welcome| {
+                welcome
+                    .tls_serialize_detached()
+                    .map(|bytes| encoding.encode(&bytes))
+                    .map_err(|e| MlsError::serialization(format!("{:?}", e)))
+            })
+            .transpose()?;
+
+        Ok(CommitBundle {
+            commit: encode_sent(&commit, &group_id, crypto, *encoding, sent_messages, *message_cache_size)?,
+            welcome,
+        })
+    }
+
+    /// Closes the group's commit transaction without committing anything.
+    /// Does nothing if no transaction is open for the group.
+    pub fn cancel_commit(&self, group_id: String) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        if state.commit_txn.as_ref().is_some_and(|txn| txn.group_id == group_id) {
+            state.commit_txn = None;
+        }
+        Ok(())
+    }
+
+    /// Reinitializes the group with `new_ciphersuite` (IANA codepoint),
+    /// e.g. to migrate a long-lived group to a stronger suite: commits an
+    /// announcement of a successor group with the new suite and the group's
+    /// current context extensions.  The group is then frozen; sending in
+    /// it fails with `GroupReinitialized`, naming the successor.
+    ///
+    /// This is an application-level migration, not an MLS ReInit: OpenMLS
+    /// can neither propose nor commit ReInit proposals, so the announcement
+    /// travels as a private group context extension with the same content
+    /// (see `MIGRATION_EXTENSION_TYPE`).  Every member's client must
+    /// advertise it, as all leaves created by this library do; other MLS
+    /// implementations see an ordinary context change.  Nor is the
+    /// successor tied to the old group by a resumption PSK: it is a new
+    /// group that members join from a Welcome.
+    ///
+    /// Returns JSON: `{"commit":"<encoded>","new_group_id":"<hex>"}`.
+    /// Broadcast the commit; after `process_commit()` every member sees the
+    /// successor in `get_reinit()` and publishes a key package for it with
+    /// `generate_key_package_for_ciphersuite()`.  Then call
+    /// `complete_reinit()` with those key packages.
+    pub fn propose_reinit(&self, group_id: String, new_ciphersuite: u16) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        state.ensure_can_commit(&group_id)?;
+
+        let suite = state.usable_ciphersuite(new_ciphersuite)?;
+        let (commit, new_group_id) = state.frozen_for(&group_id, "Reinitialization in progress", |state| {
+            state.commit_migration(&group_id, suite)
+        })?;
+
+        Ok(format!(
+            r#"{{"commit":"{}","new_group_id":"{}"}}"#,
+            commit, new_group_id
+        ))
+    }
+
+    /// Returns the group replacing `group_id` if a merged commit
+    /// reinitialized it (see `propose_reinit()`), `None` otherwise.
+    pub fn get_reinit(&self, group_id: String) -> Result<Option<ReInitInfo>, MlsError> {
+        let state = self.state.read().map_err(|_| MlsError::lock_poisoned())?;
+
+        Ok(state.reinits.get(&group_id).map(|target| ReInitInfo {
+            new_group_id: target.group_id.clone(),
+            ciphersuite: target.ciphersuite,
+        }))
+    }
+
+    /// Creates the successor of a group reinitialized by `propose_reinit()`
+    /// with its ID, ciphersuite, context extensions and configuration, and
+    /// adds the other members from `key_packages`: one per member, for the
+    /// new ciphersuite, with the same identities as the group's members.
+    ///
+    /// Returns JSON: `{"group_id":"<hex>","welcome":"<encoded>"}`.  Send
+    /// the Welcome to the members; they join with `process_welcome()`.
+    /// The new group starts at epoch 1, the commit that added everyone.
+    /// The old group stays frozen until discarded.
+    pub fn complete_reinit(&self, group_id: String, key_packages: Vec<String>) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+
+        let (new_group_id, welcome) = state.complete_reinit(&group_id, &key_packages)?;
+
+        Ok(format!(
+            r#"{{"group_id":"{}","welcome":"{}"}}"#,
+            new_group_id, welcome
+        ))
+    }
+
+    /// Rotates the credential identity in a group via a self-update commit
+    /// carrying a new leaf node.  The signature key is kept, so every other
+    /// group and pending message stays valid (see `rotate_signature_key()`
+    /// to replace the key instead).  Only this group takes the new
+    /// identity, kept for it across restarts and key rotations; our other
+    /// groups, key packages and new groups keep the identity's name.
+    ///
+    /// Returns JSON: `{"commit":"<encoded>"}`.
+    /// Broadcast the commit to all other members; after `process_commit()`
+    /// they will see the new identity in `get_members()`.
+    pub fn update_credential(
+        &self,
+        group_id: String,
+        new_identity: String,
+    ) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        state.ensure_can_commit(&group_id)?;
+
+        // `write_group()` has the group's key selected.
+        let signer = state.signer.as_ref().ok_or_else(MlsError::no_identity)?;
+        let credential = CredentialWithKey {
+            credential: Credential::new(CredentialType::Basic, new_identity.clone().into_bytes()),
+            signature_key: signer.to_public_vec().into(),
+        };
+
+        let params = LeafNodeParameters::builder()
+            .with_credential_with_key(credential)
+            .build();
+        let result = state.commit_self_update(&group_id, params)?;
+
+        if state.identity_name.as_ref() == Some(&new_identity) {
+            state.group_credentials.remove(&group_id);
+        } else {
+            state.group_credentials.insert(group_id, new_identity);
+        }
+
+        drop(state);
+        let _ = self.persist_state();
+
+        Ok(result)
+    }
+
+    /// Replaces this client's signature key pair, e.g. after a suspected
+    /// compromise.  Every group we are in gets a commit updating our leaf
+    /// to the new key; once the other members process it, anything signed
+    /// with the old key is rejected.  The old private key is deleted.
+    /// The credential keeps its identity bytes; only the key changes.  An
+    /// identity signs for all its groups with one key pair, so they all
+    /// rotate together rather than one at a time.  Only the default
+    /// identity's key is rotated; groups of identities added with
+    /// `add_identity()` are left alone.
+    ///
+    /// Fails without changing anything if any group is frozen
+    /// (`GroupFrozen`) or has a commit awaiting confirmation
+    /// (`PendingCommitConflict`).  Key packages generated before the
+    /// rotation carry the old key: replace any that were published.
+    ///
+    /// Returns one commit per group; broadcast each to its group.
+    pub fn rotate_signature_key(&self) -> Result<Vec<GroupCommit>, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        let commits = state.rotate_signature_key()?;
+
+        drop(state);
+        let _ = self.persist_state();
+
+        Ok(commits)
+    }
+
+    /// Like `rotate_signature_key()`, for one group only: commits a leaf
+    /// update moving our leaf in `group_id` to a new signature key pair,
+    /// e.g. for periodic rotation.  The credential keeps its identity
+    /// bytes.  Once the other members process the commit they accept only
+    /// the new key from us in the group.  Our other groups keep the key
+    /// they have; the new key is kept for this group alone, and a key an
+    /// earlier call gave it is deleted.
+    ///
+    /// Fails with `PendingCommitConflict` while a commit of ours awaits
+    /// confirmation, and with `GroupFrozen` in a frozen group.
+    ///
+    /// Returns JSON: `{"commit":"<encoded>"}`.
+    pub fn rotate_group_signature_key(&self, group_id: String) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.dirty_groups.insert(group_id.clone());
+        let commit = state.rotate_group_signature_key(&group_id)?;
+
+        drop(state);
+        let _ = self.persist_state();
+
+        Ok(format!(r#"{{"commit":"{}"}}"#, commit))
+    }
+
+    /// Emergency response to a compromised device: commits a self-update,
+    /// with fresh HPKE leaf keys, in every group we are in, and with
+    /// `rotate_signature_key` also moves the default identity's groups to
+    /// a new signature key pair as `rotate_signature_key()` does.  All
+    /// commits are merged locally and the state, including the new key,
+    /// is saved once at the end, so no saved state has groups on a key
+    /// the stored identity lacks.
+    ///
+    /// Unlike `rotate_signature_key()`, a group that cannot be updated
+    /// now (we were removed, a commit of ours awaits confirmation, or it is
+    /// frozen) does not stop the others: it is reported with
+    /// `skipped_reason` and no commit.  When the signature key is rotated,
+    /// such groups of the default identity keep the old key as a key of
+    /// their own, so they stay usable; call again once they are ready to
+    /// move them to the newest key.  A retired key is deleted once no
+    /// group is left on it.
+    ///
+    /// Returns one record per group, sorted by group ID.
+    pub fn rotate_all_groups(&self, rotate_signature_key: bool) -> Result<Vec<GroupRotation>, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        let rotations = state.rotate_all_groups(rotate_signature_key)?;
+
+        drop(state);
+        let _ = self.persist_state();
+
+        Ok(rotations)
+    }
+
+    /// Commits every proposal currently queued in the group (see
+    /// `get_pending_proposals()`) and merges the commit locally.
+    ///
+    /// Returns JSON: `{"commit":"<encoded>"}`, plus `"welcome":"<encoded>"` if the
+    /// queue contained add proposals.
+    pub fn commit_pending_proposals(&self, group_id: String) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        state.ensure_can_commit(&group_id)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            commit_policy,
+            signer,
+            external_psks,
+            pending_events,
+            encoding,
+            sent_messages,
+            message_cache_size,
+            max_members,
+            ..
+        } = &mut *state;
+        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        let group = groups
+            .get_mut(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+        let pending_removals = group
+            .pending_proposals()
+            .filter(|p| matches!(p.proposal(), Proposal::Remove(_)))
+            .count();
+        check_member_cap(&group_id, group, max_members.get(&group_id).copied(), 0, pending_removals)?;
+
+        let (commit, welcome, _gi) = group
+            .commit_to_pending_proposals(crypto, signer)
+            .map_err(|e| match e {
+                CommitToPendingProposalsError::CreateCommitError(
+                    CreateCommitError::PskError(PskError::KeyNotFound),
+                ) => missing_psk_error(group, external_psks),
+                e => MlsError::generic(format!("Failed to commit proposals: {:?}", e)),
+            })?;
+
+        merge_own_commit(&group_id, group, crypto, commit_policy.as_ref(), pending_events)?;
+
+        let commit_encoded = encode_sent(&commit, &group_id, crypto, *encoding, sent_messages, *message_cache_size)?;
+
+        match welcome {
+            Some(welcome) => {
+                let welcome_encoded = encoding.encode(
+                    &welcome
+                        .tls_serialize_detached()
+                        .map_err(|e| MlsError::serialization(format!("{:?}", e)))?,
+                );
+                Ok(format!(
+                    r#"{{"commit":"{}","welcome":"{}"}}"#,
+                    commit_encoded, welcome_encoded
+                ))
+            }
+            None => Ok(format!(r#"{{"commit":"{}"}}"#, commit_encoded)),
+        }
+    }
+
+    /// Processes a Welcome message to join a group.  Returns the group ID.
+    ///
+    /// Fails with `NoMatchingKeyPackage`, changing nothing, if the Welcome
+    /// is for a key package this client does not hold (never generated, or
+    /// already consumed); publish a fresh key package and ask to be re-added.
+    /// `process_welcome_full()` also reports which key package was consumed.
+    ///
+    /// A Welcome into a group we already hold, such as the same Welcome
+    /// processed twice, fails with `GroupAlreadyJoined` and leaves the
+    /// group as it is; mark the group out of sync with
+    /// `mark_group_desynced()` to replace it instead.
+    pub fn process_welcome(&self, welcome_hex: String) -> Result<String, MlsError> {
+        self.timed(Operation::ProcessWelcome, || {
+            let _flush = self.flush_on_return();
+            let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+
+            let (group_id, _, _) = state.join_group(&welcome_hex, None)?;
+            state.drain_pending(&group_id);
+            Ok(group_id)
+        })
+    }
+
+    /// Like `process_welcome()`, for groups created without the ratchet
+    /// tree extension: `ratchet_tree_hex` is the group's tree, exported by
+    /// the member who added us (`export_ratchet_tree()`) right after the
+    /// commit.
+    pub fn process_welcome_with_ratchet_tree(
+        &self,
+        welcome_hex: String,
+        ratchet_tree_hex: String,
+    ) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+
+        let (group_id, _, _) = state.join_group(&welcome_hex, Some(&ratchet_tree_hex))?;
+        state.drain_pending(&group_id);
+        Ok(group_id)
+    }
+
+    /// Like `process_welcome()`, but returns the joined group's ID, epoch,
+    /// members, metadata and our place in it in one call, e.g. to render
+    /// the conversation right away, along with the ref of the key package
+    /// the Welcome consumed and who invited us.
+    pub fn process_welcome_full(&self, welcome_hex: String) -> Result<JoinedGroup, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.join_group_full(&welcome_hex)
+    }
+
+    /// Reads a Welcome without joining, so the user can see who invited
+    /// them into which group and accept with `commit_welcome()` or decline
+    /// with `decline_welcome()`.  Nothing changes until then: the key
+    /// package the Welcome is for stays stored.
+    ///
+    /// Fails as `process_welcome()` would, e.g. with `NoMatchingKeyPackage`
+    /// or `GroupAlreadyJoined`.  Staging another Welcome for the same
+    /// group replaces the previous one.
+    pub fn stage_welcome(&self, welcome_hex: String) -> Result<WelcomePreview, MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        let preview = state.preview_welcome(&welcome_hex)?;
+        state.staged_welcomes.insert(preview.group_id.clone(), welcome_hex);
+        Ok(preview)
+    }
+
+    /// Joins the group of the Welcome staged by `stage_welcome()`, as
+    /// `process_welcome_full()` does.  If joining fails, the Welcome stays
+    /// staged.
+    pub fn commit_welcome(&self, group_id: String) -> Result<JoinedGroup, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+
+        let welcome = state
+            .staged_welcomes
+            .get(&group_id)
+            .cloned()
+            .ok_or_else(|| MlsError::generic("No staged Welcome for this group."))?;
+        let joined = state.join_group_full(&welcome)?;
+        state.staged_welcomes.remove(&group_id);
+        Ok(joined)
+    }
+
+    /// Forgets the Welcome staged by `stage_welcome()` without joining.
+    /// Tell the inviter, who should remove our leaf, or it stays in their
+    /// group unused.  No-op if nothing is staged.
+    pub fn decline_welcome(&self, group_id: String) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.staged_welcomes.remove(&group_id);
+        Ok(())
+    }
+
+    /// Returns whether the last Welcome this client joined with consumed a
+    /// last-resort key package (see `generate_last_resort_key_package()`).
+    /// Those stay stored after the join, so further Welcomes for the same
+    /// key package succeed; a regular key package is deleted and a second
+    /// Welcome for it fails with `NoMatchingKeyPackage`.  `false` until a
+    /// join succeeds in this session.
+    pub fn was_last_resort_used(&self) -> bool {
+        self.state
+            .read()
+            .map(|state| state.last_join_last_resort)
+            .unwrap_or(false)
+    }
+
+    /// Rejoins a group whose state we lost (or never had) by external
+    /// commit, using a GroupInfo and, unless it carries one, the ratchet
+    /// tree exported by a current member (`export_group_info()`,
+    /// `export_ratchet_tree()`).  Any local state still held for the group
+    /// is discarded first.
+    ///
+    /// If the group still holds a leaf with our signature key, the commit
+    /// removes it, so we take our old place.  A leaf from an earlier
+    /// identity (e.g. after `create_identity()` on a wiped device) stays
+    /// until a member removes it.
+    ///
+    /// Returns JSON: `{"group_id":"<hex>","commit":"<encoded>"}`.
+    /// Broadcast the commit to all members (via `process_commit`); if the
+    /// group moved on in the meantime, it fails for them with
+    /// `InviteBundleStale`, and we must rejoin from a fresh GroupInfo.
+    ///
+    /// We rejoin as `identity` (see `add_identity()`) if given, else as
+    /// the identity we were in the group as, else as the default one.
+    #[uniffi::method(default(identity = None))]
+    pub fn rejoin(
+        &self,
+        group_info_hex: String,
+        ratchet_tree_hex: Option<String>,
+        identity: Option<String>,
+    ) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_as(None)?;
+
+        let group_info = state
+            .encoding
+            .decode_limited(&group_info_hex, state.input_limits.welcome)?;
+        let ratchet_tree = ratchet_tree_hex
+            .map(|encoded| state.encoding.decode_limited(&encoded, state.input_limits.welcome))
+            .transpose()?;
+        let (group_id, commit) = state.rejoin_group(&group_info, ratchet_tree.as_deref(), identity, true)?;
+
+        Ok(format!(r#"{{"group_id":"{}","commit":"{}"}}"#, group_id, commit))
+    }
+
+    /// Packs what a non-member needs to join the group by external commit
+    /// into one blob for an invite link: the signed GroupInfo of the
+    /// current epoch (with the external public key) and the ratchet tree.
+    /// The invite is only good until the group's next commit; after that,
+    /// the members reject the join with `InviteBundleStale`.  See
+    /// `join_from_invite_bundle()`.
+    pub fn create_invite_bundle(&self, group_id: String) -> Result<Vec<u8>, MlsError> {
+        let state = self.read_group(&group_id)?;
+        let signer = state.group_signer(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let group_info = group
+            .export_group_info(state.crypto.crypto(), signer, false)
+            .map_err(|e| MlsError::generic(format!("Failed to export group info: {:?}", e)))?
+            .tls_serialize_detached()
+            .map_err(|e| MlsError::serialization(format!("Failed to serialize group info: {:?}", e)))?;
+        let ratchet_tree = group
+            .export_ratchet_tree()
+            .tls_serialize_detached()
+            .map_err(|e| MlsError::serialization(format!("Failed to serialize ratchet tree: {:?}", e)))?;
+
+        let mut bundle = INVITE_BUNDLE_MAGIC.to_vec();
+        bundle.push(INVITE_BUNDLE_VERSION);
+        for part in [&group_info, &ratchet_tree] {
+            bundle.extend_from_slice(&(part.len() as u32).to_be_bytes());
+            bundle.extend_from_slice(part);
+        }
+        Ok(bundle)
+    }
+
+    /// Joins a group by external commit from a `create_invite_bundle()`
+    /// blob, like `rejoin()`.  Broadcast the returned commit to the
+    /// members; if the group had a commit since the invite was created,
+    /// they reject it with `InviteBundleStale`, and we must join again
+    /// from a fresh invite.  Unlike `rejoin()`, it fails with
+    /// `GroupAlreadyJoined` for a group we hold, so `mark_group_desynced()`
+    /// the rejected join first.
+    ///
+    /// We join as `identity` (see `add_identity()`) if given, else as the
+    /// default one.  Fails with `SerializationError` for a blob that is not
+    /// a bundle or is from a newer release.
+    #[uniffi::method(default(identity = None))]
+    pub fn join_from_invite_bundle(
+        &self,
+        bundle: Vec<u8>,
+        identity: Option<String>,
+    ) -> Result<ExternalJoinResult, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_as(None)?;
+        check_input_size(bundle.len(), state.input_limits.welcome)?;
+
+        let (group_info, ratchet_tree) = unwrap_invite_bundle(&bundle)?;
+        let (group_id, commit) = state.rejoin_group(group_info, Some(ratchet_tree), identity, false)?;
+        let epoch = state
+            .groups
+            .get(&group_id)
+            .map_or(0, |group| group.epoch().as_u64());
+
+        Ok(ExternalJoinResult {
+            group_id,
+            commit,
+            epoch,
+        })
+    }
+
+    /// Processes a commit message from another member.
+    ///
+    /// If the commit depends on an external PSK that has not been registered,
+    /// fails with `UnknownPsk` *without* consuming the commit; register the
+    /// PSK with `store_external_psk()` and retry.
+    ///
+    /// Our own commits (already merged when created) and proposals echoed
+    /// back by the delivery service are ignored.
+    ///
+    /// If the commit references proposals we have not received yet, fails
+    /// with `MissingProposal` listing their references, again without
+    /// consuming the commit; process the proposals and retry.
+    ///
+    /// If the commit removes us, the group's state is deleted and every
+    /// later call on it fails with `NoLongerMember`.
+    pub fn process_commit(&self, group_id: String, commit_hex: String) -> Result<CommitOutcome, MlsError> {
+        self.timed(Operation::ProcessCommit, || {
+            let _flush = self.flush_on_return();
+            let mut state = self.write_group(&group_id)?;
+
+            let bytes = state.encoding.decode_limited(&commit_hex, state.input_limits.commit)?;
+            if state.queue_if_unknown(&group_id, &bytes) {
+                return Ok(CommitOutcome::QueuedForUnknownGroup);
+            }
+
+            let processed = match state.process_handshake(&group_id, &commit_hex) {
+                Ok(processed) => processed,
+                Err(MlsError::OwnMessageReflected { .. }) => {
+                    log::info!("Ignoring our own message echoed back in group {}", group_id);
+                    return Ok(CommitOutcome::OwnMessage);
+                }
+                Err(e) => return Err(e),
+            };
+
+            let sender = processed.credential().serialized_content().to_vec();
+            match processed.into_content() {
+                ProcessedMessageContent::StagedCommitMessage(staged) => {
+                    let epoch = staged.group_context().epoch().as_u64();
+                    let reinitialized = state.reinits.contains_key(&group_id);
+                    match state.merge_commit(&group_id, *staged, sender)? {
+                        Some(diff) => match state.reinits.get(&group_id) {
+                            Some(target) if !reinitialized => Ok(CommitOutcome::ReinitRequired {
+                                epoch,
+                                new_group_id: target.group_id.clone(),
+                                ciphersuite: target.ciphersuite,
+                            }),
+                            _ => Ok(CommitOutcome::Merged { epoch, diff }),
+                        },
+                        None => Ok(CommitOutcome::RemovedSelf),
+                    }
+                }
+                ProcessedMessageContent::ProposalMessage(proposal) => {
+                    // Proposals arriving standalone are stored for later commit.
+                    let proposal = state.store_proposal(&group_id, *proposal)?;
+                    Ok(CommitOutcome::ProposalStored { proposal })
+                }
+                _ => Err(MlsError::generic(
+                    "Expected a Commit message but received a different type.",
+                )),
+            }
+        })
+    }
+
+    /// Processes a commit from another member *without* applying it, and
+    /// describes what it would change.  Follow up with
+    /// `merge_staged_commit()` to apply it or `reject_staged_commit()` to
+    /// discard it.  Only one staged commit per group is held at a time.
+    pub fn stage_commit(
+        &self,
+        group_id: String,
+        commit_hex: String,
+    ) -> Result<StagedCommitDescription, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+
+        if state.staged_commits.contains_key(&group_id) {
+            return Err(MlsError::generic(
+                "A staged commit is already pending for this group. \
+                 Merge or reject it first.",
+            ));
+        }
+
+        let processed = state.process_handshake(&group_id, &commit_hex)?;
+        let sender = processed.credential().serialized_content().to_vec();
+        match processed.into_content() {
+            ProcessedMessageContent::StagedCommitMessage(staged) => {
+                let group = state
+                    .groups
+                    .get(&group_id)
+                    .ok_or_else(|| MlsError::GroupNotFound {
+                        group_id: group_id.clone(),
+                    })?;
+                let description = describe_staged_commit(group, &staged, sender.clone());
+                state.staged_commits.insert(group_id, (*staged, sender));
+                Ok(description)
+            }
+            ProcessedMessageContent::ProposalMessage(proposal) => {
+                state.store_proposal(&group_id, *proposal)?;
+                Err(MlsError::generic(
+                    "Received a Proposal, not a Commit. \
+                     The proposal has been stored.",
+                ))
+            }
+            _ => Err(MlsError::generic(
+                "Expected a Commit message but received a different type.",
+            )),
+        }
+    }
+
+    /// Applies the commit staged by `stage_commit()`.
+    ///
+    /// Returns `true` if the commit removed us from the group; the group is
+    /// then dropped from this client and the app should clean up its UI.
+    pub fn merge_staged_commit(&self, group_id: String) -> Result<bool, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+
+        let (staged, sender) = state
+            .staged_commits
+            .remove(&group_id)
+            .ok_or_else(|| MlsError::generic("No staged commit for this group."))?;
+
+        Ok(state.merge_commit(&group_id, staged, sender)?.is_none())
+    }
+
+    /// Discards the commit staged by `stage_commit()` without applying it.
+    /// No-op if nothing is staged.
+    pub fn reject_staged_commit(&self, group_id: String) -> Result<(), MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        state.staged_commits.remove(&group_id);
+        Ok(())
+    }
+
+    /// Sets application metadata (group name, avatar hash, ...) in the group
+    /// context, so that all members agree on it cryptographically.  Commits
+    /// a GroupContextExtensions proposal and merges it locally.
+    ///
+    /// Returns JSON: `{"commit":"<encoded>"}`.
+    /// Broadcast the commit to all other members.
+    pub fn set_group_metadata(&self, group_id: String, data: Vec<u8>) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        state.ensure_can_commit(&group_id)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            commit_policy,
+            signer,
+            pending_events,
+            encoding,
+            sent_messages,
+            message_cache_size,
+            ..
+        } = &mut *state;
+        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        let group = groups
+            .get_mut(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        // The proposal replaces the whole extension list, so start from the
+        // current one.
+        let mut extensions = group.extensions().clone();
+        extensions
+            .add_or_replace(Extension::Unknown(
+                GROUP_METADATA_EXTENSION_TYPE,
+                UnknownExtension(data),
+            ))
+            .map_err(|e| MlsError::generic(format!("Invalid metadata extension: {:?}", e)))?;
+        require_custom_extensions(&mut extensions)?;
+
+        let (commit, _welcome, _gi) = group
+            .update_group_context_extensions(crypto, extensions, signer)
+            .map_err(|e| MlsError::generic(format!("Failed to set group metadata: {:?}", e)))?;
+
+        merge_own_commit(&group_id, group, crypto, commit_policy.as_ref(), pending_events)?;
+
+        let commit_encoded = encode_sent(&commit, &group_id, crypto, *encoding, sent_messages, *message_cache_size)?;
+
+        Ok(format!(r#"{{"commit":"{}"}}"#, commit_encoded))
+    }
+
+    /// Chooses the wire format for this client's handshake messages
+    /// (commits and proposals) in the group.
+    ///
+    /// With `public_handshake = true`, handshakes are sent as `PublicMessage`
+    /// so that relay servers can read membership changes, and both formats
+    /// are accepted on input.  **Security tradeoff:** the server (and anyone
+    /// on the path) then sees who is added, removed or updated, though not
+    /// any group secrets.  Application messages are always `PrivateMessage`.
+    ///
+    /// With `false`, the default ciphertext-only policy is restored.  All
+    /// members should use the same setting, since ciphertext-only members
+    /// reject public handshakes.
+    pub fn set_wire_format_policy(&self, group_id: String, public_handshake: bool) -> Result<(), MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+
+        let settings = state.group_settings(&group_id);
+        let MlsClientState { groups, crypto, .. } = &mut *state;
+
+        let group = groups
+            .get_mut(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let config = join_config_builder(group.configuration(), settings)
+            .wire_format_policy(wire_format_policy(public_handshake))
+            .build();
+
+        group
+            .set_configuration(crypto.storage(), &config)
+            .map_err(|e| MlsError::io(format!("Failed to store group configuration: {:?}", e)))
+    }
+
+    /// Pads application messages to a multiple of `padding_size` bytes so
+    /// that ciphertext lengths do not reveal plaintext lengths.  Applies to
+    /// every current group and to groups created or joined later; 0
+    /// disables padding.  Padding is stripped on decryption, so members need
+    /// not agree on the setting.
+    pub fn set_padding_size(&self, padding_size: u32) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.padding_size = padding_size as usize;
+
+        let archived = state
+            .archived_groups
+            .iter()
+            .map(|id| Ok((state.load_group(id)?, state.group_settings(id))))
+            .collect::<Result<Vec<_>, MlsError>>()?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            group_settings,
+            ..
+        } = &mut *state;
+        for (mut group, settings) in archived {
+            set_group_padding(&mut group, crypto, settings, padding_size as usize)?;
+        }
+        for (id, group) in groups.iter_mut() {
+            let settings = group_settings.get(id).copied().unwrap_or_default();
+            set_group_padding(group, crypto, settings, padding_size as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Like `set_padding_size()`, but for one group only, e.g. one relayed
+    /// by a server that should not learn message lengths.  The setting is
+    /// kept with the group; a later `set_padding_size()` overrides it.
+    pub fn set_group_padding_size(&self, group_id: String, padding_size: u32) -> Result<(), MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+
+        let settings = state.group_settings(&group_id);
+        let MlsClientState { groups, crypto, .. } = &mut *state;
+        let group = groups
+            .get_mut(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+        set_group_padding(group, crypto, settings, padding_size as usize)
+    }
+
+    /// Sets how many past epochs' secrets groups keep, so that application
+    /// messages sent shortly before a commit can still be decrypted after
+    /// it.  0, the default, keeps the current epoch's only: each retained
+    /// epoch is one more epoch whose messages a compromise of this device
+    /// exposes.
+    ///
+    /// OpenMLS fixes how many epochs a group keeps when it is created or
+    /// joined, so for current groups the setting can only lower it: their
+    /// messages from epochs beyond it are refused at once, and the secrets
+    /// are overwritten by those of the next commits.  Groups created or
+    /// joined later keep exactly this many.
+    ///
+    /// Messages from epochs no longer retained fail with `EpochTooOld`.
+    pub fn set_max_past_epochs(&self, max_past_epochs: u32) -> Result<(), MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.max_past_epochs = max_past_epochs as usize;
+
+        let group_ids: Vec<String> = state
+            .groups
+            .keys()
+            .chain(state.archived_groups.iter())
+            .cloned()
+            .collect();
+        for group_id in group_ids {
+            state.set_group_max_past_epochs(&group_id, max_past_epochs as usize)?;
+            state.dirty_groups.insert(group_id);
+        }
+        Ok(())
+    }
+
+    /// Stops accepting messages of every past epoch the group retains (see
+    /// `set_max_past_epochs()`): late messages from before the last commit
+    /// then fail with `EpochTooOld`.  OpenMLS offers no way to drop the
+    /// secrets themselves, so they stay in the key store until the next
+    /// commits overwrite them; commit (e.g. `self_update()`) as many times
+    /// as epochs are retained to be rid of them, e.g. when a device may be
+    /// about to be seized.
+    pub fn purge_past_epochs(&self, group_id: String) -> Result<(), MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        let epoch = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?
+            .epoch()
+            .as_u64();
+        let mut settings = state.group_settings(&group_id);
+        settings.first_accepted_epoch = epoch;
+        state.group_settings.insert(group_id, settings);
+        Ok(())
+    }
+
+    /// Saves state and drops the group from memory to bound memory use
+    /// when in many groups.  It stays a member and is reloaded
+    /// transparently the next time any method is called with its ID.
+    /// No-op if the group is already archived.
+    pub fn archive_group(&self, group_id: String) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        if state.archived_groups.contains(&group_id) {
+            return Ok(());
+        }
+        if !state.groups.contains_key(&group_id) {
+            return Err(MlsError::GroupNotFound { group_id });
+        }
+        state.archive_group(&group_id);
+
+        drop(state);
+        self.persist_state().map(|_| ())
+    }
+
+    /// Deletes all local state of a group, e.g. for "delete conversation",
+    /// and scrubs it as far as we can: its key store entries are zeroed in
+    /// memory before being dropped, and the saved key store is rewritten
+    /// over its full previous length.  Copies the storage medium keeps on
+    /// its own (flash wear levelling, journals, backups) are out of reach.
+    ///
+    /// The other members are not told; remove ourselves from the group
+    /// first to leave it properly.
+    pub fn secure_wipe_group(&self, group_id: String) -> Result<(), MlsError> {
+        let old_store_len = self
+            .read_storage_file(KEY_STORE_KEY)?
+            .map_or(0, |store| store.len());
+        let group_key = group_store_key(&group_id);
+        let old_group_len = self.storage.get(group_key.clone())?.map_or(0, |store| store.len());
+
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        // Archived groups are loaded so that everything they own is found.
+        state.ensure_resident(&group_id)?;
+        if !state.groups.contains_key(&group_id) {
+            return Err(MlsError::GroupNotFound { group_id });
+        }
+        state.zeroize_group_values(&group_id)?;
+        state.discard_group(&group_id);
+        log::info!("Wiped group {}", group_id);
+
+        drop(state);
+        // Saving deletes the group's own entry; overwrite it first.
+        if old_group_len > 0 {
+            self.storage.put(group_key, vec![b' '; old_group_len])?;
+        }
+        self.persist_state_padded(old_store_len, SaveScope::Changed).map(|_| ())
+    }
+
+    /// Queues messages for groups we have not joined yet instead of failing
+    /// with `GroupNotFound`, e.g. when the delivery service fans out a
+    /// group's first messages before its Welcome.  They are reported as
+    /// `QueuedForUnknownGroup` (an error of that name from
+    /// `decrypt_message()`) and processed once `process_welcome()` joins
+    /// the group; `process_welcome_full()` returns their results.
+    ///
+    /// Each group keeps at most `max_messages` messages and, unless 0,
+    /// `max_bytes` bytes; beyond that the oldest are dropped and reported
+    /// to `MlsEventListener.on_pending_message_evicted()`.  A
+    /// `max_messages` of 0 (the default) disables queueing.  The queue is
+    /// not persisted.
+    pub fn set_pending_queue_limits(&self, max_messages: u32, max_bytes: u64) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.pending_max_messages = max_messages as usize;
+        state.pending_max_bytes = usize::try_from(max_bytes).unwrap_or(usize::MAX);
+        if max_messages == 0 {
+            state.pending_messages.clear();
+        }
+        Ok(())
+    }
+
+    /// Sets the largest key packages, commits, welcomes and application
+    /// messages accepted, in bytes once decoded.  Larger inputs fail with
+    /// `InputTooLarge` before they are decoded, so a hostile peer cannot
+    /// make us allocate and parse huge buffers.  The defaults are 1 MiB for
+    /// handshake messages and 10 MiB for application messages; raise the
+    /// latter to exchange large attachments in-band.
+    pub fn set_input_limits(&self, limits: InputLimits) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.input_limits = limits;
+        Ok(())
+    }
+
+    /// The limits set with `set_input_limits()`.
+    pub fn get_input_limits(&self) -> InputLimits {
+        self.state
+            .read()
+            .map(|s| s.input_limits)
+            .unwrap_or_default()
+    }
+
+    /// Sets whether adding a member whose credential identity is already
+    /// in the group, or added in the same commit, is flagged (the
+    /// default) or rejected with `DuplicateIdentity`.  Members with the
+    /// same identity are told apart by their signature keys only, so
+    /// `remove_members()` removes all of them.  A key package whose
+    /// signature key is already in the group is always rejected.
+    pub fn set_duplicate_identity_policy(&self, policy: DuplicateIdentityPolicy) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.duplicate_identity_policy = policy;
+        Ok(())
+    }
+
+    /// Sets whether bytes left over after an incoming key package
+    /// (`add_member()` and the other methods adding members), Welcome
+    /// (`process_welcome()`), commit or proposal (`process_commit()`) or
+    /// application message (`decrypt_message()`) is parsed are ignored
+    /// (the default) or rejected with `TrailingBytes`, which names the
+    /// structure and how many bytes were parsed and left over.
+    pub fn set_deserialization_mode(&self, mode: DeserializationMode) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.deserialization_mode = mode;
+        Ok(())
+    }
+
+    /// Reports how often the main operations were called, how long they
+    /// took and how they failed, along with the application traffic of
+    /// each group, since the client was created or `reset_metrics()` was
+    /// last called.  Counted operations: `create_group()` (and
+    /// `create_group_with_config()`), `generate_key_package()`,
+    /// `add_member()`, `remove_member()`, `self_update()`,
+    /// `process_welcome()`, `process_commit()`, `encrypt_message()` (and
+    /// `encrypt_message_bytes()`), and `decrypt_message()` (and
+    /// `decrypt_message_with_sender()`).  Application messages are counted
+    /// however they were sent or received.
+    ///
+    /// Counting needs the `metrics` cargo feature (on by default); without
+    /// it this returns an empty snapshot with `enabled` unset.
+    pub fn get_metrics(&self) -> MetricsSnapshot {
+        #[cfg(feature = "metrics")]
+        {
+            let (mut groups, trailing_bytes_ignored): (Vec<GroupTraffic>, _) = self
+                .state
+                .read()
+                .map(|s| (s.traffic.values().cloned().collect(), s.trailing_bytes_ignored.clone()))
+                .unwrap_or_default();
+            groups.sort_by(|a, b| a.group_id.cmp(&b.group_id));
+            MetricsSnapshot {
+                enabled: true,
+                operations: self.metrics.operations(),
+                failures: self.metrics.failures.lock().map(|f| f.clone()).unwrap_or_default(),
+                groups,
+                trailing_bytes_ignored,
+            }
+        }
+        #[cfg(not(feature = "metrics"))]
+        MetricsSnapshot::default()
+    }
+
+    /// Sets all counters reported by `get_metrics()` back to zero.
+    pub fn reset_metrics(&self) -> Result<(), MlsError> {
+        self.metrics.reset();
+        #[cfg(feature = "metrics")]
+        {
+            let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+            state.traffic.clear();
+            state.trailing_bytes_ignored.clear();
+        }
+        Ok(())
+    }
+
+    /// Keeps at most `max_groups` groups in memory, archiving the least
+    /// recently used ones beyond that (see `archive_group()`); 0 removes
+    /// the limit.  Also applies to groups restored by `load_state()`,
+    /// which stay archived past the limit until first used.
+    pub fn set_max_resident_groups(&self, max_groups: u32) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.max_resident_groups = max_groups as usize;
+        state.evict_excess_groups();
+        Ok(())
+    }
+
+    /// Discards a commit this client created but has not merged, leaving the
+    /// group's epoch and membership unchanged.  Use this when a commit
+    /// could not be delivered and should not take effect.  No-op if there is
+    /// no pending commit.
+    pub fn clear_pending_commit(&self, group_id: String) -> Result<(), MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            commit_journal,
+            ..
+        } = &mut *state;
+
+        let group = groups
+            .get_mut(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        // An add commit for the next epoch can no longer be delivered.
+        let next_epoch = group.epoch().as_u64() + 1;
+        if let Some(journal) = commit_journal.get_mut(&group_id) {
+            journal.retain(|entry| entry.epoch != next_epoch);
+        }
+        group
+            .clear_pending_commit(crypto.storage())
+            .map_err(|e| MlsError::io(format!("Failed to clear pending commit: {:?}", e)))
+    }
+
+    // ── Pre-shared keys ────────────────────────────────────────────────
+
+    /// Registers an external PSK (provisioned out-of-band) with the key
+    /// store so that commits referencing it can be created and processed.
+    pub fn store_external_psk(&self, psk_id_hex: String, psk_hex: String) -> Result<(), MlsError> {
+        let psk_id = Encoding::Hex.decode(&psk_id_hex)?;
+        let psk = Encoding::Hex.decode(&psk_hex)?;
+
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+
+        // The nonce is not part of the storage key, so any value will do here.
+        PreSharedKeyId::external(psk_id, Vec::new())
+            .store(&state.crypto, &psk)
+            .map_err(|e| MlsError::crypto(format!("Failed to store PSK: {:?}", e)))?;
+        state.external_psks.insert(psk_id_hex.to_lowercase());
+
+        drop(state);
+        self.persist_state().map(|_| ())
+    }
+
+    /// Proposes removing the member at leaf `member_index`, for whoever
+    /// commits next to include (see `commit_pending_proposals()`).
+    /// Useful for members that should not commit themselves.
+    ///
+    /// Returns JSON: `{"proposal":"<encoded>"}`.
+    /// Broadcast the proposal to all other members (via `process_commit`).
+    pub fn propose_remove_member(&self, group_id: String, member_index: u32) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        state.ensure_can_commit(&group_id)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            signer,
+            encoding,
+            sent_messages,
+            message_cache_size,
+            ..
+        } = &mut *state;
+        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        let group = groups
+            .get_mut(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let (proposal, _ref) = group
+            .propose_remove_member(crypto, signer, LeafNodeIndex::new(member_index))
+            .map_err(|e| match e {
+                ProposeRemoveMemberError::UnknownMember => MlsError::UnknownMember {
+                    detail: format!("No member at leaf {}", member_index),
+                },
+                e => MlsError::generic(format!("Failed to propose removal: {:?}", e)),
+            })?;
+
+        let proposal_encoded = encode_sent(&proposal, &group_id, crypto, *encoding, sent_messages, *message_cache_size)?;
+
+        Ok(format!(r#"{{"proposal":"{}"}}"#, proposal_encoded))
+    }
+
+    /// Proposes injecting a registered external PSK into the group's key
+    /// schedule.  The PSK takes effect once the proposal is committed with
+    /// `commit_pending_proposals()`; every member must hold the same PSK.
+    ///
+    /// Returns JSON: `{"proposal":"<encoded>"}`.
+    /// Broadcast the proposal to all other members (via `process_commit`).
+    pub fn propose_external_psk(&self, group_id: String, psk_id_hex: String) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let psk_id = Encoding::Hex.decode(&psk_id_hex)?;
+
+        let mut state = self.write_group(&group_id)?;
+        state.ensure_can_commit(&group_id)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            signer,
+            encoding,
+            sent_messages,
+            message_cache_size,
+            ..
+        } = &mut *state;
+        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        let group = groups
+            .get_mut(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let psk = PreSharedKeyId::new(
+            group.ciphersuite(),
+            crypto.rand(),
+            Psk::External(ExternalPsk::new(psk_id)),
+        )
+        .map_err(|e| MlsError::crypto(format!("Failed to create PSK id: {:?}", e)))?;
+
+        let (proposal, _ref) = group
+            .propose_external_psk(crypto, signer, psk)
+            .map_err(|e| MlsError::generic(format!("Failed to propose PSK: {:?}", e)))?;
+
+        let proposal_encoded = encode_sent(&proposal, &group_id, crypto, *encoding, sent_messages, *message_cache_size)?;
+
+        Ok(format!(r#"{{"proposal":"{}"}}"#, proposal_encoded))
+    }
+
+    /// Proposes replacing the group context extensions with `extensions`.
+    /// The list replaces the current one entirely, so start from
+    /// `get_group_context_extensions()` to change a single entry.  Custom
+    /// extension types are added to the required capabilities, so every
+    /// member must support them.  Takes effect
+    /// once committed with `commit_pending_proposals()`.
+    ///
+    /// Returns JSON: `{"proposal":"<encoded>"}`.
+    /// Broadcast the proposal to all other members (via `process_commit`).
+    pub fn propose_group_context_extensions(
+        &self,
+        group_id: String,
+        extensions: Vec<RawExtension>,
+    ) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        let extensions = extensions
+            .into_iter()
+            .map(|raw| raw.into_extension())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut extensions = Extensions::from_vec(extensions)
+            .map_err(|e| MlsError::generic(format!("Invalid extension list: {:?}", e)))?;
+        require_custom_extensions(&mut extensions)?;
+
+        let mut state = self.write_group(&group_id)?;
+        state.ensure_can_commit(&group_id)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            signer,
+            encoding,
+            sent_messages,
+            message_cache_size,
+            ..
+        } = &mut *state;
+        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        let group = groups
+            .get_mut(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let (proposal, _ref) = group
+            .propose_group_context_extensions(crypto, extensions, signer)
+            .map_err(|e| MlsError::generic(format!("Failed to propose extensions: {:?}", e)))?;
+
+        let proposal_encoded = encode_sent(&proposal, &group_id, crypto, *encoding, sent_messages, *message_cache_size)?;
+
+        Ok(format!(r#"{{"proposal":"{}"}}"#, proposal_encoded))
+    }
+
+    /// Proposes an application-defined operation (e.g. "promote to admin"),
+    /// ordered and authenticated with the group's other handshake messages.
+    /// `proposal_type` must be a non-default codepoint (use the private range
+    /// 0xF000-0xFFFF) that every member advertises in its capabilities
+    /// (see `create_identity_with_capabilities()`); otherwise this fails with
+    /// `CapabilitiesMismatch`, as committing it would.  Members receive it as
+    /// `IncomingMessage.CustomProposal`; it takes effect once committed with
+    /// `commit_pending_proposals()`.
+    ///
+    /// Returns JSON: `{"proposal":"<encoded>"}`.
+    /// Broadcast the proposal to all other members.
+    pub fn send_custom_proposal(
+        &self,
+        group_id: String,
+        proposal_type: u16,
+        payload: Vec<u8>,
+    ) -> Result<String, MlsError> {
+        let _flush = self.flush_on_return();
+        if is_default_proposal(ProposalType::from(proposal_type)) {
+            return Err(MlsError::generic(format!(
+                "Proposal type 0x{:04x} is not a custom proposal type",
+                proposal_type
+            )));
+        }
+
+        let mut state = self.write_group(&group_id)?;
+        state.ensure_can_commit(&group_id)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            signer,
+            encoding,
+            sent_messages,
+            message_cache_size,
+            ..
+        } = &mut *state;
+        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+
+        let group = groups
+            .get_mut(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let unsupported: Vec<String> = leaf_nodes(group)?
+            .values()
+            .filter(|leaf| !leaf.capabilities().proposals().contains(&ProposalType::from(proposal_type)))
+            .map(|leaf| String::from_utf8_lossy(leaf.credential().serialized_content()).into_owned())
+            .collect();
+        if !unsupported.is_empty() {
+            return Err(MlsError::CapabilitiesMismatch {
+                subject: format!("Members {}", unsupported.join(", ")),
+                missing_extensions: Vec::new(),
+                missing_proposals: vec![proposal_type],
+                missing_credentials: Vec::new(),
+            });
+        }
+
+        let (proposal, _ref) = group
+            .propose_custom_proposal_by_reference(crypto, signer, CustomProposal::new(proposal_type, payload))
+            .map_err(|e| MlsError::generic(format!("Failed to propose custom proposal: {:?}", e)))?;
+
+        let proposal_encoded = encode_sent(&proposal, &group_id, crypto, *encoding, sent_messages, *message_cache_size)?;
+
+        Ok(format!(r#"{{"proposal":"{}"}}"#, proposal_encoded))
+    }
+
+    /// Raw-bytes variant of `store_external_psk()`, for callers that hold
+    /// the PSK as a byte array (e.g. from a resumption or rejoin flow).
+    pub fn add_external_psk(&self, psk_id: Vec<u8>, psk_secret: Vec<u8>) -> Result<(), MlsError> {
+        self.store_external_psk(hex::encode(psk_id), hex::encode(psk_secret))
+    }
+
+    /// Raw-bytes variant of `propose_external_psk()`.
+    ///
+    /// Returns JSON: `{"proposal":"<encoded>"}`.
+    pub fn propose_psk(&self, group_id: String, psk_id: Vec<u8>) -> Result<String, MlsError> {
+        self.propose_external_psk(group_id, hex::encode(psk_id))
+    }
+
+    /// Returns the resumption PSK of the group's current epoch (RFC 9420,
+    /// section 8.6), which every member in that epoch derives alike.
+    /// Inject it into a successor or branch group so that only members of
+    /// this epoch can take part: each of them registers it with
+    /// `add_external_psk()` under an agreed ID (e.g. the old group ID and
+    /// epoch), and one proposes it with `propose_psk()` and commits.
+    ///
+    /// It is a secret of the group: never send it, even to members, and
+    /// wipe it once registered.
+    pub fn export_resumption_psk(&self, group_id: String) -> Result<Vec<u8>, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+        Ok(group.resumption_psk_secret().as_slice().to_vec())
+    }
+
+    /// Freezes a group for maintenance.  While frozen, every local commit
+    /// and send fails with `GroupFrozen { reason }`; incoming messages are
+    /// still processed so the group does not fall behind.
+    pub fn freeze_group(&self, group_id: String, reason: String) -> Result<(), MlsError> {
+        let mut state = self.write_group(&group_id)?;
+        if !state.groups.contains_key(&group_id) {
+            return Err(MlsError::GroupNotFound { group_id });
+        }
+        state.frozen_groups.insert(group_id, reason);
+        drop(state);
+        self.persist_state().map(|_| ())
+    }
+
+    /// Lifts a freeze set by `freeze_group()`.  No-op if not frozen.
+    pub fn unfreeze_group(&self, group_id: String) -> Result<(), MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        if state.frozen_groups.remove(&group_id).is_none() {
+            return Ok(());
+        }
+        drop(state);
+        self.persist_state().map(|_| ())
+    }
+
+    /// Compares our epoch of the group with `observed_epoch`, e.g. the
+    /// epoch the delivery service last saw a commit for, to notice missed
+    /// commits before messages start failing with `FutureEpoch`.
+    pub fn check_epoch(&self, group_id: String, observed_epoch: u64) -> Result<EpochStatus, MlsError> {
+        let epoch = self.get_epoch(group_id)?;
+        Ok(match epoch.cmp(&observed_epoch) {
+            std::cmp::Ordering::Equal => EpochStatus::InSync,
+            std::cmp::Ordering::Less => EpochStatus::Behind {
+                by: observed_epoch - epoch,
+            },
+            std::cmp::Ordering::Greater => EpochStatus::Ahead {
+                by: epoch - observed_epoch,
+            },
+        })
+    }
+
+    /// Marks a group we cannot catch up on as out of sync.  Every local
+    /// commit and send then fails with `GroupDesynced` until a Welcome
+    /// back into the group arrives: `process_welcome()` then discards the
+    /// old state and joins afresh under the same group ID, so messages the
+    /// app stored by group ID carry over.  See `request_rejoin_info()`.
+    pub fn mark_group_desynced(&self, group_id: String) -> Result<(), MlsError> {
+        let mut state = self.write_group(&group_id)?;
+        if !state.groups.contains_key(&group_id) {
+            return Err(MlsError::GroupNotFound { group_id });
+        }
+        state.desynced_groups.insert(group_id);
+        drop(state);
+        self.persist_state().map(|_| ())
+    }
+
+    /// What another member needs to re-add us to a group: a fresh key
+    /// package of the identity we are a member as, to remove our old leaf
+    /// and add in one commit.  Send the Welcome back to this client.
+    pub fn request_rejoin_info(&self, group_id: String) -> Result<RejoinInfo, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+        let (epoch, ciphersuite) = (group.epoch().as_u64(), group.ciphersuite());
+        state.key_packages_dirty = true;
+
+        let credential = state.credential.clone().ok_or_else(MlsError::no_identity)?;
+        let key_package = state
+            .encoded_key_package(ciphersuite, credential, state.leaf_lifetime(), false, false)?
+            .key_package;
+        Ok(RejoinInfo {
+            group_id,
+            epoch,
+            key_package,
+        })
+    }
+
+    // ── Messaging ──────────────────────────────────────────────────────
+
+    /// Encrypts a text message for the group: a thin wrapper around
+    /// `encrypt_message_bytes()` with the string's UTF-8 bytes.  Returns
+    /// the encoded ciphertext.
+    pub fn encrypt_message(
+        &self,
+        group_id: String,
+        plaintext: String,
+    ) -> Result<String, MlsError> {
+        self.encrypt_message_bytes(group_id, plaintext.into_bytes())
+    }
+
+    /// Encrypts an arbitrary binary payload for the group, e.g. protobuf or
+    /// CBOR; any bytes are carried unchanged, including none at all.
+    /// Returns the encoded ciphertext.
+    pub fn encrypt_message_bytes(
+        &self,
+        group_id: String,
+        plaintext: Vec<u8>,
+    ) -> Result<String, MlsError> {
+        self.timed(Operation::EncryptMessage, || {
+            let _flush = self.flush_on_return();
+            let plaintext = Zeroizing::new(plaintext);
+            let mut state = self.write_group(&group_id)?;
+            let (bytes, _) = state.encrypt(&group_id, PayloadKind::Chat, &plaintext, Vec::new())?;
+            Ok(state.encoding.encode(&bytes))
+        })
+    }
+
+    /// Like `encrypt_message()`, but also returns the epoch and generation
+    /// the message was sent at, e.g. for the transport to tag it so that
+    /// receivers can order it and route it to the right epoch.
+    pub fn encrypt_message_detailed(
+        &self,
+        group_id: String,
+        plaintext: String,
+    ) -> Result<EncryptedMessage, MlsError> {
+        self.timed(Operation::EncryptMessage, || {
+            let _flush = self.flush_on_return();
+            let plaintext = Zeroizing::new(plaintext.into_bytes());
+            let mut state = self.write_group(&group_id)?;
+            let (bytes, sent) = state.encrypt(&group_id, PayloadKind::Chat, &plaintext, Vec::new())?;
+            Ok(EncryptedMessage {
+                ciphertext: state.encoding.encode(&bytes),
+                epoch: sent.epoch,
+                generation: sent.generation,
+            })
+        })
+    }
+
+    /// Like `encrypt_message()`, but also signs the plaintext, the group ID
+    /// and the epoch with our signature key, detached from the message.
+    /// MLS only proves to the members of the epoch that a member sent a
+    /// message; the signature proves to anyone who trusts our signature
+    /// key that we wrote it, e.g. when a member forwards it or exports it
+    /// for an audit.  Check it with `verify_signed_message()`.
+    ///
+    /// Unlike the MLS ciphertext, the signature cannot be repudiated: do
+    /// not send it along with messages that should stay deniable.
+    pub fn encrypt_signed_message(&self, group_id: String, plaintext: String) -> Result<SignedMessage, MlsError> {
+        use openmls_traits::signatures::Signer;
+
+        self.timed(Operation::EncryptMessage, || {
+            let _flush = self.flush_on_return();
+            let plaintext = Zeroizing::new(plaintext.into_bytes());
+            let mut state = self.write_group(&group_id)?;
+            let (bytes, sent) = state.encrypt(&group_id, PayloadKind::Chat, &plaintext, Vec::new())?;
+
+            // `write_group()` has the group's identity selected.
+            let signer = state.signer.as_ref().ok_or_else(MlsError::no_identity)?;
+            let content = signed_message_content(&group_id, sent.epoch, &plaintext);
+            let signature = signer
+                .sign(&content)
+                .map_err(|e| MlsError::crypto(format!("Failed to sign message: {:?}", e)))?;
+            Ok(SignedMessage {
+                ciphertext: state.encoding.encode(&bytes),
+                group_id: group_id.clone(),
+                epoch: sent.epoch,
+                signature_scheme: signer.signature_scheme() as u16,
+                signature_key: signer.public().to_vec(),
+                signature,
+            })
+        })
+    }
+
+    /// Checks the signature of a message from `encrypt_signed_message()`
+    /// against `plaintext`, e.g. as decrypted from its ciphertext, without
+    /// needing the group.  Returns `false` if `plaintext`, the group ID,
+    /// the epoch or the signature was changed.
+    ///
+    /// This only shows that the holder of `message.signature_key` signed
+    /// it.  Compare that key with the sender's (see `get_members()`), or
+    /// with one otherwise known to belong to them.
+    pub fn verify_signed_message(&self, message: SignedMessage, plaintext: String) -> Result<bool, MlsError> {
+        let scheme = SignatureScheme::try_from(message.signature_scheme).map_err(|_| {
+            MlsError::crypto(format!("Unsupported signature scheme 0x{:04x}", message.signature_scheme))
+        })?;
+        let content = signed_message_content(&message.group_id, message.epoch, plaintext.as_bytes());
+        let state = self.state.read().map_err(|_| MlsError::lock_poisoned())?;
+        Ok(state
+            .crypto
+            .crypto()
+            .verify_signature(scheme, &content, &message.signature_key, &message.signature)
+            .is_ok())
+    }
+
+    /// Encrypts a control message for the group: a read receipt, typing
+    /// indicator or reaction that receivers keep apart from the chat, told
+    /// by `DecryptedMessage.payload_kind`.  It travels as an ordinary
+    /// application message, so only members can see what kind it is.
+    /// `Chat` sends `payload` as `encrypt_message_bytes()` does.  Returns
+    /// the encoded ciphertext.
+    ///
+    /// Only groups with payload envelopes (see
+    /// `GroupConfig::payload_envelope`) can carry control messages; in
+    /// others only `Chat` can be sent.  The envelope carries the kind in a
+    /// byte of its own, encrypted with the message, so a chat payload is
+    /// never taken for a control message whatever its first bytes are.
+    /// Received messages of a kind we do not know fail with
+    /// `SerializationError`.
+    pub fn send_control_message(
+        &self,
+        group_id: String,
+        kind: PayloadKind,
+        payload: Vec<u8>,
+    ) -> Result<String, MlsError> {
+        self.timed(Operation::EncryptMessage, || {
+            let _flush = self.flush_on_return();
+            let payload = Zeroizing::new(payload);
+            let mut state = self.write_group(&group_id)?;
+            let (bytes, _) = state.encrypt(&group_id, kind, &payload, Vec::new())?;
+            Ok(state.encoding.encode(&bytes))
+        })
+    }
+
+    /// Encrypts a batch of binary payloads for the group, as many
+    /// `encrypt_message_bytes()` calls would, but taking the client's lock
+    /// and saving once, e.g. for bursts of typing indicators or reactions.
+    /// Returns the encoded ciphertexts in order; send them in that order.
+    ///
+    /// Fails, returning nothing, if any payload fails; the ones before it
+    /// were encrypted all the same, leaving a gap in our generations.
+    pub fn encrypt_messages(&self, group_id: String, plaintexts: Vec<Vec<u8>>) -> Result<Vec<String>, MlsError> {
+        self.timed(Operation::EncryptMessage, || {
+            let _flush = self.flush_on_return();
+            let plaintexts: Vec<Zeroizing<Vec<u8>>> = plaintexts.into_iter().map(Zeroizing::new).collect();
+            let mut state = self.write_group(&group_id)?;
+            plaintexts
+                .iter()
+                .map(|plaintext| {
+                    let (bytes, _) = state.encrypt(&group_id, PayloadKind::Chat, plaintext, Vec::new())?;
+                    Ok(state.encoding.encode(&bytes))
+                })
+                .collect()
+        })
+    }
+
+    /// Encrypts a message for the group, binding `aad` to it as
+    /// authenticated additional data.  The AAD travels in the clear but
+    /// cannot be stripped or altered without decryption failing; receivers
+    /// get it back from `decrypt_message_with_aad()`.  Returns the
+    /// serialized ciphertext.  The AAD is sent exactly as given.
+    pub fn encrypt_message_with_aad(
+        &self,
+        group_id: String,
+        plaintext: Vec<u8>,
+        aad: Vec<u8>,
+    ) -> Result<Vec<u8>, MlsError> {
+        let _flush = self.flush_on_return();
+        let plaintext = Zeroizing::new(plaintext);
+        let mut state = self.write_group(&group_id)?;
+        state.encrypt(&group_id, PayloadKind::Chat, &plaintext, aad).map(|(bytes, _)| bytes)
+    }
+
+    /// Decrypts a text message from the group: a thin wrapper around
+    /// `decrypt_message_with_sender()` returning the plaintext as a string.
+    /// A payload that is not valid UTF-8 fails with `SerializationError`;
+    /// use `decrypt_message_with_sender()` for binary payloads.
+    ///
+    /// If the incoming bytes are actually a **Commit**, they are
+    /// automatically merged and an error is returned indicating so.
+    /// Use `process_commit()` if you want explicit commit handling.
+    ///
+    /// A ciphertext that was already processed fails with
+    /// `DuplicateMessage`, and one we produced ourselves fails with
+    /// `OwnMessageReflected`; both are safe to drop silently.
+    pub fn decrypt_message(
+        &self,
+        group_id: String,
+        ciphertext_hex: String,
+    ) -> Result<String, MlsError> {
+        let mut decrypted = self.decrypt_message_with_sender(group_id, ciphertext_hex)?;
+        if let Some(text) = decrypted.as_utf8.take() {
+            decrypted.plaintext.zeroize();
+            return Ok(text);
+        }
+        // The error would otherwise carry (and print) the plaintext.
+        String::from_utf8(decrypted.plaintext).map_err(|e| {
+            let error = e.utf8_error();
+            e.into_bytes().zeroize();
+            MlsError::serialization(format!("Invalid UTF-8: {}", error))
+        })
+    }
+
+    /// Like `decrypt_message()`, but takes the serialized ciphertext and
+    /// returns the plaintext together with the authenticated additional
+    /// data the sender bound to it (empty if none).  A ciphertext whose AAD
+    /// was tampered with fails with `CryptoError`.
+    pub fn decrypt_message_with_aad(
+        &self,
+        group_id: String,
+        ciphertext: Vec<u8>,
+    ) -> Result<DecryptedMessage, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+        check_input_size(ciphertext.len(), state.input_limits.application_message)?;
+
+        state
+            .process_incoming(&group_id, &ciphertext)?
+            .into_decrypted(group_id)
+    }
+
+    /// Like `decrypt_message()`, but returns the plaintext bytes along with
+    /// the sender and, for framed messages, their timestamp and sequence
+    /// number (see `new_with_message_framing()`).
+    pub fn decrypt_message_with_sender(
+        &self,
+        group_id: String,
+        ciphertext_hex: String,
+    ) -> Result<DecryptedMessage, MlsError> {
+        self.timed(Operation::DecryptMessage, || {
+            let _flush = self.flush_on_return();
+            let mut state = self.write_group(&group_id)?;
+
+            let bytes = state
+                .encoding
+                .decode_limited(&ciphertext_hex, state.input_limits.application_message)?;
+            state
+                .process_incoming(&group_id, &bytes)?
+                .into_decrypted(group_id)
+        })
+    }
+
+    /// Encrypts a payload that needs no forward secrecy, such as a typing
+    /// indicator or read receipt, for the members of the group's current
+    /// epoch.  Unlike `encrypt_message_bytes()` it changes no state: no
+    /// ratchet secret is used up and nothing needs saving, so it can be
+    /// sent at any rate.  The price is that a later compromise of the
+    /// epoch's secrets exposes every ephemeral message of the epoch, and
+    /// that a captured message can be replayed to the members until the
+    /// next commit.  Decrypt with `decrypt_ephemeral()`.
+    ///
+    /// The payload is encrypted with AES-256-GCM under a key exported from
+    /// the epoch, and the whole message is signed with our leaf's
+    /// signature key.  Integers are big-endian:
+    ///
+    /// ```text
+    /// offset  size  field
+    ///      0     4  magic, ASCII "MLSe"
+    ///      4     1  version, 1
+    ///      5     8  epoch
+    ///     13     4  sender leaf index
+    ///     17    12  nonce
+    ///     29     4  ciphertext length n; bytes 0..29 are its AAD
+    ///     33     n  ciphertext
+    ///   33+n     2  signature length m
+    ///   35+n     m  signature over bytes 0..33+n
+    /// ```
+    pub fn encrypt_ephemeral(&self, group_id: String, payload: Vec<u8>) -> Result<Vec<u8>, MlsError> {
+        let payload = Zeroizing::new(payload);
+        let state = self.read_group(&group_id)?;
+        check_input_size(payload.len(), state.input_limits.application_message)?;
+        state.ensure_not_frozen(&group_id)?;
+        let signer = state.group_signer(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+        if !group.is_active() {
+            return Err(MlsError::NoLongerMember { group_id });
+        }
+        seal_ephemeral(&state.crypto, group, signer, &payload)
+    }
+
+    /// Decrypts a message from `encrypt_ephemeral()`, changing no state.
+    /// Only messages of the group's current epoch decrypt; others fail
+    /// with `EpochMismatch` and should be dropped.  A message whose sender
+    /// signature does not verify fails with `InvalidSignature`, one that
+    /// was tampered with with `DecryptionFailed`.  Replays are not
+    /// detected.
+    pub fn decrypt_ephemeral(&self, group_id: String, blob: Vec<u8>) -> Result<EphemeralMessage, MlsError> {
+        let state = self.read_group(&group_id)?;
+        check_input_size(blob.len(), state.input_limits.application_message)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+        open_ephemeral(&state.crypto, &group_id, group, &blob)
+    }
+
+    /// Reads the cleartext header of a message without processing it or
+    /// touching any group state, e.g. to route it to the right group or to
+    /// drop messages for unknown groups early.  Nothing is authenticated:
+    /// the header may be forged, so only act on it once the message has
+    /// been processed.
+    pub fn inspect_message(&self, message_hex: String) -> Result<MessageMetadata, MlsError> {
+        let limit = self.get_input_limits().application_message;
+        let bytes = self.default_encoding().decode_limited(&message_hex, limit)?;
+        let message = MlsMessageIn::tls_deserialize_exact(bytes)
+            .map_err(|e| MlsError::serialization(format!("Invalid MLS message: {:?}", e)))?;
+
+        let encrypted = message.wire_format() == WireFormat::PrivateMessage;
+        let (kind, group_id, epoch) = match message.extract() {
+            MlsMessageBodyIn::PublicMessage(m) => {
+                let msg = ProtocolMessage::from(m);
+                (content_kind(msg.content_type()), Some(msg.group_id().clone()), Some(msg.epoch()))
+            }
+            MlsMessageBodyIn::PrivateMessage(m) => {
+                let msg = ProtocolMessage::from(m);
+                (content_kind(msg.content_type()), Some(msg.group_id().clone()), Some(msg.epoch()))
+            }
+            MlsMessageBodyIn::GroupInfo(info) => {
+                (MessageKind::GroupInfo, Some(info.group_id().clone()), Some(info.epoch()))
+            }
+            MlsMessageBodyIn::Welcome(_) => (MessageKind::Welcome, None, None),
+            MlsMessageBodyIn::KeyPackage(_) => (MessageKind::KeyPackage, None, None),
+        };
+
+        Ok(MessageMetadata {
+            kind,
+            group_id: group_id.map(|g| hex::encode(g.as_slice())),
+            epoch: epoch.map(|e| e.as_u64()),
+            encrypted,
+        })
+    }
+
+    /// Tells which kind of MLS message `blob_hex` is, to route a blob from
+    /// the transport to the right `process_*` method without trying each in
+    /// turn.  Only the outer framing is read; nothing is processed or
+    /// authenticated.  Any blob within the largest input limit is accepted.
+    /// Key packages classify when wrapped as MLS messages (as from
+    /// `generate_key_package_wrapped()`); bare ones fail with
+    /// `SerializationError`.
+    pub fn classify_message(&self, blob_hex: String) -> Result<MessageClass, MlsError> {
+        let limits = self.get_input_limits();
+        let limit = limits
+            .key_package
+            .max(limits.commit)
+            .max(limits.welcome)
+            .max(limits.application_message);
+        let bytes = self.default_encoding().decode_limited(&blob_hex, limit)?;
+        let message = MlsMessageIn::tls_deserialize_exact(bytes)
+            .map_err(|e| MlsError::serialization(format!("Invalid MLS message: {:?}", e)))?;
+
+        Ok(match message.wire_format() {
+            WireFormat::Welcome => MessageClass::Welcome,
+            WireFormat::KeyPackage => MessageClass::KeyPackage,
+            WireFormat::PublicMessage => MessageClass::PublicMessage,
+            WireFormat::PrivateMessage => MessageClass::PrivateMessage,
+            WireFormat::GroupInfo => MessageClass::GroupInfo,
+        })
+    }
+
+    /// Processes a batch of messages for one group in order, e.g. to catch
+    /// up after being offline: commits are merged, proposals stored and
+    /// application messages decrypted, as in `decrypt_message()`.
+    /// Duplicates and our own reflected messages are reported rather than
+    /// failing the batch.
+    ///
+    /// Returns one result per message.  If a commit removes us, processing
+    /// stops there and the rest of the batch is not returned.  If a message
+    /// fails, fails with `BatchFailed` carrying its index, the kind and
+    /// message of its error and the results of the messages before it,
+    /// which stay applied; resume from the failing index once the cause is
+    /// dealt with.  Commits and proposals are held to the `commit` input
+    /// limit, other messages to `application_message`.
+    pub fn process_messages(
+        &self,
+        group_id: String,
+        messages_hex: Vec<String>,
+    ) -> Result<Vec<IncomingMessage>, MlsError> {
+        let _flush = self.flush_on_return();
+        let mut state = self.write_group(&group_id)?;
+
+        let mut results = Vec::with_capacity(messages_hex.len());
+        for (index, message) in messages_hex.iter().enumerate() {
+            let processed = state
+                .decode_batch_message(message)
+                .and_then(|bytes| state.process_incoming(&group_id, &bytes));
+            match processed {
+                Ok(result) => {
+                    let removed = matches!(result, IncomingMessage::Removed { .. });
+                    results.push(result);
+                    if removed {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    return Err(MlsError::BatchFailed {
+                        index: index as u32,
+                        processed: results,
+                        error_kind: e.kind_name(),
+                        message: e.to_string(),
+                    })
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// Processes an envelope from the delivery service bundling a commit
+    /// with application messages sent in the epoch it starts.  The
+    /// messages are applied strictly in the order given, so the commit is
+    /// merged before the messages after it are decrypted under the new
+    /// epoch.  Results and failures are as for `process_messages()`.
+    pub fn process_envelope(
+        &self,
+        group_id: String,
+        messages_hex: Vec<String>,
+    ) -> Result<Vec<IncomingMessage>, MlsError> {
+        self.process_messages(group_id, messages_hex)
+    }
+
+    // ── Group queries ──────────────────────────────────────────────────
+
+    /// Lists hex-encoded IDs of all groups currently in memory.  Archived
+    /// groups are not included; see `list_all_groups()`.
+    ///
+    /// With `identity`, lists only the groups belonging to it (see
+    /// `add_identity()`); the default identity's name lists those of the
+    /// default identity.
+    #[uniffi::method(default(identity = None))]
+    pub fn list_active_groups(&self, identity: Option<String>) -> Vec<String> {
+        let Ok(state) = self.state.read() else {
+            return Vec::new();
+        };
+        state
+            .groups
+            .keys()
+            .filter(|id| match &identity {
+                Some(name) => match state.group_identities.get(*id) {
+                    Some(owner) => owner == name,
+                    None => state.identity_name.as_ref() == Some(name),
+                },
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Number of groups `list_active_groups()` would return.
+    pub fn count_active_groups(&self) -> u32 {
+        self.state.read().map(|s| s.groups.len() as u32).unwrap_or_default()
+    }
+
+    /// Returns up to `limit` IDs from `list_active_groups()`, sorted by
+    /// group ID and skipping the first `offset`, so pages fetched in turn
+    /// line up as long as no group is added or dropped in between.
+    pub fn list_active_groups_page(&self, offset: u32, limit: u32) -> Vec<String> {
+        let mut group_ids = self.list_active_groups(None);
+        group_ids.sort();
+        group_ids
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    /// Lists hex-encoded IDs of all groups, whether in memory or archived.
+    pub fn list_all_groups(&self) -> Vec<String> {
+        self.state
+            .read()
+            .map(|s| {
+                s.groups
+                    .keys()
+                    .chain(s.archived_groups.iter())
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Summarizes the groups `list_active_groups()` lists, plus those
+    /// another member removed us from, for a conversation list.  The
+    /// state is read once, so the summaries are consistent with each
+    /// other; filtering and sorting happen after the lock is released.
+    ///
+    /// Sorted by group ID unless `filter.newest_first` asks for the most
+    /// recently active groups first.
+    #[uniffi::method(default(filter = None))]
+    pub fn list_groups_detailed(&self, filter: Option<GroupFilter>) -> Vec<GroupSummary> {
+        let mut summaries: Vec<GroupSummary> = {
+            let Ok(state) = self.state.read() else {
+                return Vec::new();
+            };
+            let activity = |id: &str| {
+                state
+                    .group_activity
+                    .get(id)
+                    .copied()
+                    .or_else(|| state.group_times.get(id).map(|t| t.updated_at))
+            };
+            let resident = state.groups.iter().map(|(id, group)| GroupSummary {
+                group_id: id.clone(),
+                epoch: Some(group.epoch().as_u64()),
+                member_count: group.members().count() as u32,
+                status: if state.desynced_groups.contains(id) {
+                    MembershipStatus::Desynced
+                } else {
+                    MembershipStatus::Active
+                },
+                ciphersuite: Some(u16::from(group.ciphersuite())),
+                last_activity: activity(id),
+                frozen: state.frozen_groups.contains_key(id),
+            });
+            let removed = state.removed_groups.iter().map(|id| GroupSummary {
+                group_id: id.clone(),
+                epoch: None,
+                member_count: 0,
+                status: MembershipStatus::Removed,
+                ciphersuite: None,
+                last_activity: activity(id),
+                frozen: false,
+            });
+            resident.chain(removed).collect()
+        };
+
+        let filter = filter.unwrap_or(GroupFilter {
+            status: None,
+            min_members: None,
+            newest_first: false,
+        });
+        summaries.retain(|s| {
+            filter.status.is_none_or(|status| s.status == status)
+                && filter.min_members.is_none_or(|min| s.member_count >= min)
+        });
+        if filter.newest_first {
+            summaries.sort_by(|a, b| {
+                b.last_activity
+                    .cmp(&a.last_activity)
+                    .then_with(|| a.group_id.cmp(&b.group_id))
+            });
+        } else {
+            summaries.sort_by(|a, b| a.group_id.cmp(&b.group_id));
+        }
+        summaries
+    }
+
+    /// Returns JSON with group metadata: group_id, epoch, member_count,
+    /// frozen, ciphersuite, created_at and updated_at.
+    ///
+    /// `created_at` is when we created or joined the group and `updated_at`
+    /// when a commit last changed it (or `created_at`), in seconds since
+    /// the Unix epoch.  Both are local bookkeeping, not part of the MLS
+    /// group, and `null` for groups saved by releases that did not record
+    /// them.
+    pub fn get_group_info(&self, group_id: String) -> Result<String, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let times = state.group_times.get(&group_id);
+        let time = |t: Option<u64>| t.map_or("null".to_string(), |t| t.to_string());
+        Ok(format!(
+            r#"{{"group_id":"{}","epoch":{},"member_count":{},"frozen":{},"ciphersuite":{},"created_at":{},"updated_at":{}}}"#,
+            group_id,
+            group.epoch().as_u64(),
+            group.members().count(),
+            state.frozen_groups.contains_key(&group_id),
+            u16::from(group.ciphersuite()),
+            time(times.map(|t| t.created_at)),
+            time(times.map(|t| t.updated_at)),
+        ))
+    }
+
+    /// Returns the group's current epoch, e.g. to order or index stored
+    /// messages by epoch.
+    pub fn get_epoch(&self, group_id: String) -> Result<u64, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        Ok(group.epoch().as_u64())
+    }
+
+    /// Whether the group has a commit of ours awaiting `confirm_commit()`
+    /// or `abort_commit()` (see `add_member_prepare()`).  While it does,
+    /// processing other members' commits fails with
+    /// `PendingCommitConflict` and creating another commit fails too, so a
+    /// UI can disable the conflicting actions.
+    pub fn has_pending_commit(&self, group_id: String) -> Result<bool, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        Ok(group.pending_commit().is_some())
+    }
+
+    /// Returns the latest generation received from the member with
+    /// `sender_identity`, or `None` if no numbered message from them has
+    /// been processed, as in groups without payload envelopes.  Compare it with the generations of later messages
+    /// to spot lost ones and ask the delivery service to resend them.
+    pub fn get_last_seen_generation(
+        &self,
+        group_id: String,
+        sender_identity: Vec<u8>,
+    ) -> Result<Option<MessageGeneration>, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        Ok(state
+            .seen_generations
+            .get(&group_id)
+            .and_then(|seen| seen.get(&hex::encode(&sender_identity)))
+            .copied())
+    }
+
+    /// Returns the IANA codepoint of the ciphersuite the group uses.
+    pub fn group_ciphersuite(&self, group_id: String) -> Result<u16, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        Ok(u16::from(group.ciphersuite()))
+    }
+
+    /// Returns our own leaf in the group, for rendering "(you)" and for
+    /// suppressing self-targeted actions.
+    ///
+    /// Fails with `NoLongerMember` if we have been removed from the group.
+    pub fn get_own_member_info(&self, group_id: String) -> Result<MemberInfo, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let leaf = group
+            .own_leaf()
+            .filter(|_| group.is_active())
+            .ok_or(MlsError::NoLongerMember { group_id })?;
+
+        Ok(MemberInfo {
+            index: group.own_leaf_index().u32(),
+            identity: leaf.credential().serialized_content().to_vec(),
+            signature_key: leaf.signature_key().as_slice().to_vec(),
+            application_id: leaf_application_id(leaf),
+            unknown_extensions: unknown_extensions(leaf.extensions().iter())?,
+        })
+    }
+
+    /// Returns the credential identity we act as in the group, e.g. to
+    /// label "you" in the member list.  For groups of an identity added
+    /// with `add_identity()` it is that identity, not the default one.
+    pub fn get_own_identity(&self, group_id: String) -> Result<Vec<u8>, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let leaf = group
+            .own_leaf()
+            .ok_or_else(|| MlsError::generic("We have no leaf in this group."))?;
+        Ok(leaf.credential().serialized_content().to_vec())
+    }
+
+    /// Returns our own leaf index in the group, e.g. to tell which entry of
+    /// `get_members()` is us.
+    pub fn own_leaf_index(&self, group_id: String) -> Result<u32, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        Ok(group.own_leaf_index().u32())
+    }
+
+    /// Returns whether any leaf in the group has the given credential
+    /// identity (including our own).
+    pub fn is_member(&self, group_id: String, identity: String) -> Result<bool, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let found = group
+            .members()
+            .any(|m| m.credential.serialized_content() == identity.as_bytes());
+        Ok(found)
+    }
+
+    /// Returns the list of members (leaf index, credential identity bytes,
+    /// signature key and application id).
+    pub fn get_members(&self, group_id: String) -> Result<Vec<MemberInfo>, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        member_infos(group)
+    }
+
+    /// Returns the TLS-serialized leaf node at `leaf_index` (see
+    /// `get_members()`), signed by its owner and bound into the tree hash,
+    /// e.g. for a directory service to check that a member's claimed
+    /// signature key is really in the group's tree.
+    ///
+    /// Fails with `UnknownMember` if the leaf is blank or past the end of
+    /// the tree.
+    pub fn export_leaf_node(&self, group_id: String, leaf_index: u32) -> Result<Vec<u8>, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let mut leaves = leaf_nodes(group)?;
+        let leaf = leaves.remove(&leaf_index).ok_or_else(|| MlsError::UnknownMember {
+            detail: format!("No member at leaf {}", leaf_index),
+        })?;
+        leaf.tls_serialize_detached()
+            .map_err(|e| MlsError::serialization(format!("Failed to serialize leaf node: {:?}", e)))
+    }
+
+    /// Returns the first member whose credential identity is `identity`,
+    /// or `None` if there is no such member.
+    pub fn find_member(&self, group_id: String, identity: Vec<u8>) -> Result<Option<MemberInfo>, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let Some(member) = group
+            .members()
+            .find(|m| m.credential.serialized_content() == identity.as_slice())
+        else {
+            return Ok(None);
+        };
+        let leaves = leaf_nodes(group)?;
+
+        member_info(member, &leaves).map(Some)
+    }
+
+    /// Returns the application metadata set with `set_group_metadata()`, as
+    /// agreed in the current group context.  `None` if never set.
+    pub fn get_group_metadata(&self, group_id: String) -> Result<Option<Vec<u8>>, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        Ok(group
+            .extensions()
+            .unknown(GROUP_METADATA_EXTENSION_TYPE)
+            .map(|e| e.0.clone()))
+    }
+
+    /// Returns the extensions in the group's current context, as agreed by
+    /// all members, or an empty list if the group has none.  Application
+    /// data such as a group name belongs in `set_group_metadata()`.
+    pub fn get_group_context_extensions(&self, group_id: String) -> Result<Vec<RawExtension>, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        group.extensions().iter().map(RawExtension::from_extension).collect()
+    }
+
+    /// Returns the tree hash of the group's current ratchet tree.  Members
+    /// at the same epoch must agree on it, which makes it useful for
+    /// debugging state divergence.
+    pub fn tree_hash(&self, group_id: String) -> Result<Vec<u8>, MlsError> {
+        let state = self.read_group(&group_id)?;
+        let signer = state.group_signer(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        Ok(group_context(&state.crypto, signer, group)?.tree_hash().to_vec())
+    }
+
+    /// Reports the group's state for debugging interop failures, e.g.
+    /// whether two members disagree on the tree or the transcript.  See
+    /// `export_ratchet_tree_json()` for the tree itself.
+    pub fn get_group_diagnostics(&self, group_id: String) -> Result<GroupDiagnostics, MlsError> {
+        let state = self.read_group(&group_id)?;
+        let signer = state.group_signer(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let context = group_context(&state.crypto, signer, group)?;
+        let leaf_count = ratchet_tree_nodes(group)?.len().div_ceil(2) as u32;
+        Ok(GroupDiagnostics {
+            epoch: context.epoch().as_u64(),
+            tree_hash: hex::encode(context.tree_hash()),
+            confirmed_transcript_hash: hex::encode(context.confirmed_transcript_hash()),
+            leaf_count,
+            blank_leaf_count: leaf_count - group.members().count() as u32,
+            own_leaf_index: group.own_leaf_index().u32(),
+            ciphersuite: u16::from(context.ciphersuite()),
+            extension_types: context
+                .extensions()
+                .iter()
+                .map(|e| u16::from(e.extension_type()))
+                .collect(),
+            protocol_version: protocol_version_name(context.protocol_version()),
+            oldest_retained_epoch: state.group_settings(&group_id).oldest_retained_epoch(group),
+        })
+    }
+
+    /// Returns a JSON report of the group's state to paste into bug reports
+    /// about members falling out of sync:
+    ///
+    /// ```text
+    /// {"ciphersuite":1,"epoch":4,"epoch_authenticator_hash":"<hex>",
+    ///  "group_id":"<hex>","has_pending_commit":false,"member_count":3,
+    ///  "own_leaf_index":0,"pending_proposal_count":0,"tree_hash":"<hex>"}
+    /// ```
+    ///
+    /// Members in the same epoch and state report the same tree hash and
+    /// epoch authenticator hash.  The report holds no key material: the
+    /// epoch authenticator is a group secret, so only a SHA-256 digest of
+    /// it is included.
+    pub fn diagnostics(&self, group_id: String) -> Result<String, MlsError> {
+        let state = self.read_group(&group_id)?;
+        let signer = state.group_signer(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let context = group_context(&state.crypto, signer, group)?;
+        let authenticator_hash = state
+            .crypto
+            .crypto()
+            .hash(
+                HashType::Sha2_256,
+                &[DIAGNOSTICS_LABEL, group.epoch_authenticator().as_slice()].concat(),
+            )
+            .map_err(|e| MlsError::crypto(format!("Failed to hash epoch authenticator: {:?}", e)))?;
+        Ok(serde_json::json!({
+            "group_id": group_id,
+            "epoch": context.epoch().as_u64(),
+            "member_count": group.members().count(),
+            "own_leaf_index": group.own_leaf_index().u32(),
+            "ciphersuite": u16::from(context.ciphersuite()),
+            "tree_hash": hex::encode(context.tree_hash()),
+            "epoch_authenticator_hash": hex::encode(authenticator_hash),
+            "pending_proposal_count": group.pending_proposals().count(),
+            "has_pending_commit": group.pending_commit().is_some(),
+        })
+        .to_string())
+    }
+
+    /// Renders the public part of the group's ratchet tree as JSON for
+    /// support tooling:
+    /// `{"leaf_count":3,"leaves":[{"index":0,"identity":"<hex>","signature_key":"<hex>"}, ...]}`,
+    /// listing non-blank leaves only.
+    pub fn export_ratchet_tree_json(&self, group_id: String) -> Result<String, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let nodes = ratchet_tree_nodes(group)?;
+        let leaves: Vec<serde_json::Value> = group
+            .members()
+            .map(|m| {
+                serde_json::json!({
+                    "index": m.index.u32(),
+                    "identity": hex::encode(m.credential.serialized_content()),
+                    "signature_key": hex::encode(&m.signature_key),
+                })
+            })
+            .collect();
+        Ok(serde_json::json!({
+            "leaf_count": nodes.len().div_ceil(2),
+            "leaves": leaves,
+        })
+        .to_string())
+    }
+
+    /// Exports a signed GroupInfo for the group's current epoch, from
+    /// which a non-member (or a member that lost its state) can join by
+    /// external commit with `rejoin()`.  If `include_ratchet_tree` is false,
+    /// the joiner also needs `export_ratchet_tree()`.  Returns the encoded
+    /// GroupInfo message.
+    pub fn export_group_info(&self, group_id: String, include_ratchet_tree: bool) -> Result<String, MlsError> {
+        let state = self.read_group(&group_id)?;
+        let signer = state.group_signer(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let bytes = group
+            .export_group_info(state.crypto.crypto(), signer, include_ratchet_tree)
+            .map_err(|e| MlsError::generic(format!("Failed to export group info: {:?}", e)))?
+            .tls_serialize_detached()
+            .map_err(|e| MlsError::serialization(format!("Failed to serialize group info: {:?}", e)))?;
+
+        Ok(state.encoding.encode(&bytes))
+    }
+
+    /// Exports the group's current ratchet tree, for joiners given a
+    /// GroupInfo without one.  Returns the encoded tree.
+    pub fn export_ratchet_tree(&self, group_id: String) -> Result<String, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let bytes = group
+            .export_ratchet_tree()
+            .tls_serialize_detached()
+            .map_err(|e| MlsError::serialization(format!("Failed to serialize ratchet tree: {:?}", e)))?;
+
+        Ok(state.encoding.encode(&bytes))
+    }
+
+    /// Returns a code that every member computes identically in the same
+    /// epoch, derived from the epoch authenticator.  Matching codes show
+    /// that both sides share the same group state, and so that no one
+    /// else is silently in the group.
+    pub fn get_group_fingerprint(&self, group_id: String) -> Result<Fingerprint, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        fingerprint(
+            &state.crypto,
+            group,
+            &[FINGERPRINT_GROUP_LABEL, group.epoch_authenticator().as_slice()],
+        )
+    }
+
+    /// Returns the group's epoch authenticator (RFC 9420, section 8.7),
+    /// which every member in the same epoch shares.  Hand it to another
+    /// member to check with `is_member_in_sync()`; it is a secret of the
+    /// group, so only over a channel the group's members alone can read.
+    pub fn get_epoch_authenticator(&self, group_id: String) -> Result<Vec<u8>, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+        Ok(group.epoch_authenticator().as_slice().to_vec())
+    }
+
+    /// Whether our epoch authenticator equals `expected_epoch_authenticator`
+    /// from another member (see `get_epoch_authenticator()`), i.e. whether
+    /// we share their group state.  If not and they are ahead of us or on
+    /// another branch, rejoin with `rejoin()`.  Members in different epochs
+    /// never match, so compare at the same epoch.  The comparison takes
+    /// the same time wherever the values differ.
+    pub fn is_member_in_sync(&self, group_id: String, expected_epoch_authenticator: Vec<u8>) -> Result<bool, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+        Ok(constant_time_eq(
+            group.epoch_authenticator().as_slice(),
+            &expected_epoch_authenticator,
+        ))
+    }
+
+    /// Returns the key schedule outputs of the group's current epoch (see
+    /// `EpochDebugInfo`), to compare with another implementation's or the
+    /// RFC 9420 test vectors when messages fail to decrypt across stacks.
+    /// The joiner secret is not among them: OpenMLS does not keep it past
+    /// the commit or Welcome that created the epoch.
+    ///
+    /// Only with the `debug-secrets` feature; otherwise fails with
+    /// `Generic`, so the FFI surface is the same either way.
+    pub fn export_epoch_debug_info(&self, group_id: String) -> Result<EpochDebugInfo, MlsError> {
+        #[cfg(feature = "debug-secrets")]
+        {
+            self.read_group(&group_id)?.epoch_debug_info(&group_id)
+        }
+        #[cfg(not(feature = "debug-secrets"))]
+        {
+            let _ = group_id;
+            Err(MlsError::generic("debug feature disabled"))
+        }
+    }
+
+    /// Returns a code for one member's signature key in the current epoch,
+    /// bound to the group state through the epoch authenticator.  Compare
+    /// it with the code the member sees for themselves to verify their key.
+    ///
+    /// Fails with `UnknownMembers` if no member has the identity.
+    pub fn get_member_fingerprint(&self, group_id: String, identity: Vec<u8>) -> Result<Fingerprint, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let member = group
+            .members()
+            .find(|m| m.credential.serialized_content() == identity.as_slice())
+            .ok_or_else(|| MlsError::UnknownMembers {
+                identities: vec![String::from_utf8_lossy(&identity).into_owned()],
+            })?;
+
+        fingerprint(
+            &state.crypto,
+            group,
+            &[
+                FINGERPRINT_MEMBER_LABEL,
+                group.epoch_authenticator().as_slice(),
+                &(identity.len() as u32).to_be_bytes(),
+                &identity,
+                &member.signature_key,
+            ],
+        )
+    }
+
+    /// Returns the proposals queued in the group but not yet committed, so
+    /// they can be reviewed before committing.  Empty if nothing is pending.
+    pub fn get_pending_proposals(&self, group_id: String) -> Result<Vec<ProposalInfo>, MlsError> {
+        let state = self.read_group(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        Ok(group
+            .pending_proposals()
+            .map(|p| proposal_info(group, p))
+            .collect())
+    }
+
+    /// Checks the proposals queued in the group before committing them
+    /// with `commit_pending_proposals()`, so that an invalid set can be
+    /// explained rather than failing with an opaque error.
+    ///
+    /// A conflict does not always prevent committing: OpenMLS drops an
+    /// update of a member that is also removed, for instance.
+    /// `committable` says whether the commit would go through; it is
+    /// decided by building the commit on a scratch copy of the group,
+    /// which is then discarded.  Fails as `commit_pending_proposals()`
+    /// would if the group cannot commit at all, e.g. while frozen.
+    pub fn validate_pending_proposals(&self, group_id: String) -> Result<ProposalValidation, MlsError> {
+        let state = self.read_group(&group_id)?;
+        state.ensure_can_commit(&group_id)?;
+
+        let group = state
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+        let proposal_count = group.pending_proposals().count() as u32;
+
+        let mut conflicts = proposal_conflicts(group, &state.external_psks);
+        let pending_removals = group
+            .pending_proposals()
+            .filter(|p| matches!(p.proposal(), Proposal::Remove(_)))
+            .count();
+        if let Err(e) = check_member_cap(
+            &group_id,
+            group,
+            state.max_members.get(&group_id).copied(),
+            0,
+            pending_removals,
+        ) {
+            conflicts.push(ProposalConflict {
+                kind: ProposalConflictKind::GroupFull,
+                reason: e.to_string(),
+                proposal_refs: group
+                    .pending_proposals()
+                    .filter(|p| matches!(p.proposal(), Proposal::Add(_)))
+                    .map(|p| hex::encode(p.proposal_reference_ref().as_slice()))
+                    .collect(),
+            });
+        }
+
+        let trial = trial_commit(group, &state.crypto, state.group_signer(&group_id)?)?;
+        if let Err(reason) = &trial {
+            conflicts.push(ProposalConflict {
+                kind: ProposalConflictKind::Rejected,
+                reason: reason.clone(),
+                proposal_refs: Vec::new(),
+            });
+        }
+
+        Ok(ProposalValidation {
+            proposal_count,
+            committable: conflicts.iter().all(|c| c.kind != ProposalConflictKind::GroupFull) && trial.is_ok(),
+            conflicts,
+        })
+    }
+
+    /// Sanity-checks a group after a restore or a long processing run.
+    /// Returns `false` if the group is present but unusable, in which case
+    /// the client should rejoin:
+    /// - our own leaf is missing or signed with a key other than our identity;
+    /// - the member list is empty or does not include us;
+    /// - encrypting a throwaway message fails, or the result does not parse
+    ///   back as an application message for this group and epoch.
+    ///
+    /// MLS senders cannot decrypt their own messages, so the last check
+    /// stops at parsing.  It is skipped while a commit is pending or a
+    /// `self_update()` is required, as encrypting is not allowed then.
+    /// The throwaway message is never sent; receivers never see the gap it
+    /// leaves in our sending ratchet.
+    pub fn verify_group_integrity(&self, group_id: String) -> Result<bool, MlsError> {
+        let mut state = self.write_group(&group_id)?;
+
+        let MlsClientState {
+            groups,
+            crypto,
+            signer,
+            pending_self_updates,
+            ..
+        } = &mut *state;
         let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
 
-        let group = groups
-            .get_mut(&group_id)
-            .ok_or_else(|| MlsError::GroupNotFound {
-                group_id: group_id.clone(),
-            })?;
+        let group = groups
+            .get_mut(&group_id)
+            .ok_or_else(|| MlsError::GroupNotFound {
+                group_id: group_id.clone(),
+            })?;
+
+        let own_index = group.own_leaf_index();
+        let Some(own_leaf) = group.own_leaf() else {
+            return Ok(false);
+        };
+        if own_leaf.signature_key().as_slice() != signer.public() {
+            return Ok(false);
+        }
+        if !group
+            .members()
+            .any(|m| m.index == own_index && m.signature_key == signer.public())
+        {
+            return Ok(false);
+        }
+
+        if group.pending_commit().is_some() || pending_self_updates.contains(&group_id) {
+            return Ok(true);
+        }
+
+        let Ok(msg) = group.create_message(crypto, signer, b"") else {
+            return Ok(false);
+        };
+        let Ok(bytes) = msg.tls_serialize_detached() else {
+            return Ok(false);
+        };
+        let parsed = MlsMessageIn::tls_deserialize(&mut bytes.as_slice())
+            .ok()
+            .and_then(|m| m.try_into_protocol_message().ok());
+
+        Ok(parsed.is_some_and(|m| {
+            m.group_id() == group.group_id()
+                && m.epoch() == group.epoch()
+                && m.content_type() == ContentType::Application
+        }))
+    }
+
+    /// The membership audit log of a group: one entry per commit merged
+    /// since we joined, ours or another member's, oldest first.  With
+    /// `since_epoch` only the commits that started that epoch or a later
+    /// one.  The log is kept under the storage path, written as commits
+    /// are merged (even with autosave off) and kept after the group is
+    /// left or deleted; an unknown group has an empty log.  Ephemeral
+    /// clients keep none.
+    ///
+    /// A log edited beyond parsing fails with `SerializationError`; use
+    /// `verify_audit_log()` to detect subtler edits.
+    pub fn get_audit_log(&self, group_id: String, since_epoch: Option<u64>) -> Result<Vec<AuditEntry>, MlsError> {
+        let mut log = self.read_audit_log(&group_id)?;
+        log.retain(|entry| since_epoch.is_none_or(|epoch| entry.epoch >= epoch));
+        Ok(log)
+    }
+
+    /// Whether a group's audit log is intact: every entry's hash matches
+    /// its contents and the hash of the entry before it.  An edited,
+    /// inserted or reordered entry, or one removed from the middle, makes
+    /// it `false`; entries dropped from the end go unnoticed.
+    pub fn verify_audit_log(&self, group_id: String) -> Result<bool, MlsError> {
+        let log = match self.read_audit_log(&group_id) {
+            Ok(log) => log,
+            Err(MlsError::SerializationError { .. }) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let state = self.state.read().map_err(|_| MlsError::lock_poisoned())?;
+        let mut previous_hash = String::new();
+        for entry in &log {
+            if entry.group_id != group_id
+                || entry.previous_hash != previous_hash
+                || entry.entry_hash != audit_entry_hash(&state.crypto, entry)?
+            {
+                return Ok(false);
+            }
+            previous_hash = entry.entry_hash.clone();
+        }
+        Ok(true)
+    }
+
+    // ── Persistence ────────────────────────────────────────────────────
+
+    /// Persists ALL state to storage: identity keys, group secrets, ratchet
+    /// trees — everything OpenMLS needs to resume after a cold restart.
+    ///
+    /// Writes these entries to the storage backend (files by default):
+    /// - `state.json`: identity metadata
+    /// - `group_<group ID>.json`: the OpenMLS key store entries of each
+    ///   group (via MemoryStorage)
+    /// - `openmls_store.json`: the rest of the key store (key packages,
+    ///   signature keys, ...)
+    ///
+    /// Clients opened with `new_encrypted()` keep the whole key store in
+    /// `openmls_store.json`.
+    ///
+    /// Groups we have been removed from are skipped, and a group whose state
+    /// cannot be read back is left out rather than failing the whole save;
+    /// the returned report lists both.
+    ///
+    /// With autosave on (the default) calls that change a group or generate
+    /// key packages already save, rewriting only the groups that changed;
+    /// this rewrites every group.  Call it after batching with autosave
+    /// off, or to get the report.
+    pub fn save_state(&self) -> Result<SaveReport, MlsError> {
+        self.persist_state()
+    }
+
+    /// Saves one group, e.g. after a single operation with autosave off,
+    /// without rewriting the others (see `save_state()`).  Changes to other
+    /// groups stay unsaved, but key packages and client metadata are saved
+    /// too.  The report lists the group unless it failed or we were
+    /// removed from it.
+    pub fn save_group(&self, group_id: String) -> Result<SaveReport, MlsError> {
+        {
+            let state = self.state.read().map_err(|_| MlsError::lock_poisoned())?;
+            if !state.groups.contains_key(&group_id) && !state.archived_groups.contains(&group_id) {
+                return Err(MlsError::GroupNotFound { group_id });
+            }
+        }
+        self.persist_state_padded(0, SaveScope::Group(&group_id))
+    }
+
+    /// Turns autosave on or off.  When on (the default), every call that
+    /// advances a group's state (encrypting, decrypting, merging a commit,
+    /// joining, ...) or generates key packages saves the state before it
+    /// returns, so a crash cannot lose ratchet state or reuse consumed
+    /// keys.  Turn it off to batch many calls into one `save_state()`,
+    /// e.g. when catching up on a backlog of messages.
+    ///
+    /// Turning it on saves any changes made while it was off.
+    pub fn set_autosave(&self, enabled: bool) -> Result<(), MlsError> {
+        self.state.write().map_err(|_| MlsError::lock_poisoned())?.autosave = enabled;
+        self.autosave()
+    }
+
+    /// Loads previously-persisted state from disk, including full group
+    /// recovery.  Called automatically by the constructor.
+    ///
+    /// Fails with `StateRollbackDetected` if the stored state is older than
+    /// the last state this client wrote (e.g. an old backup was restored).
+    /// States saved by older versions of this library are upgraded; ones
+    /// saved by a newer version fail with `UnsupportedStateVersion`, and
+    /// saving is then refused so they are not overwritten.
+    pub fn load_state(&self) -> Result<(), MlsError> {
+        self.restore_state(false)
+    }
+
+    /// Loads the on-disk state even though it is older than the high-water
+    /// mark.  Every restored group must then `self_update()` before it can
+    /// send again, so that stale ratchet state is never reused.
+    pub fn force_accept_rollback(&self) -> Result<(), MlsError> {
+        self.restore_state(true)
+    }
+
+    /// Serializes the entire client state (identity, signer, every group and
+    /// its secrets) into a single blob encrypted under `passphrase`, for
+    /// backup or device migration.  Restore it with `import_state()`.
+    pub fn export_state(&self, passphrase: String) -> Result<Vec<u8>, MlsError> {
+        let state = self.state.read().map_err(|_| MlsError::lock_poisoned())?;
+
+        let backup = StateBackup {
+            state: state.persisted_state(state.generation, &|_| true)?.0,
+            store: state.store_values()?,
+        };
+        let json = serde_json::to_vec(&backup)
+            .map(Zeroizing::new)
+            .map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+
+        seal_with_passphrase(&state.crypto, &passphrase, &json)
+    }
+
+    /// Replaces this client's entire state with a blob from
+    /// `export_state()` and saves it to disk.
+    ///
+    /// A wrong passphrase fails with `CryptoError` and leaves the current
+    /// state untouched.  If the blob is older than state this client has
+    /// already saved, the restored groups must `self_update()` before
+    /// sending again (see `force_accept_rollback()`).
+    pub fn import_state(&self, blob: Vec<u8>, passphrase: String) -> Result<(), MlsError> {
+        let json = open_with_passphrase(&passphrase, &blob)?;
+        let mut backup: StateBackup = serde_json::from_slice(&json)
+            .map_err(|e| MlsError::serialization(format!("Corrupt state blob: {}", json_error(&e))))?;
+        backup.state = backup.state.migrate()?;
+
+        self.restore_backup(backup)
+    }
+
+    /// Serializes one group's complete state, secrets included, for
+    /// selective backup.  Restore it with `import_group()` on a client with
+    /// the same identity.  The blob is not encrypted: protect it like
+    /// `export_state()` output.  Stop using the group here once exported
+    /// elsewhere, or the two copies will reuse each other's keys.
+    pub fn export_group(&self, group_id: String) -> Result<Vec<u8>, MlsError> {
+        let state = self.read_group(&group_id)?;
+        if !state.groups.contains_key(&group_id) {
+            return Err(MlsError::GroupNotFound { group_id });
+        }
+
+        let backup = GroupBackup {
+            store: state.group_store_values(&group_id)?,
+            send_generation: state.send_generations.get(&group_id).copied(),
+            send_sequence: state.send_sequences.get(&group_id).copied(),
+            self_update_required: state.pending_self_updates.contains(&group_id),
+            times: state.group_times.get(&group_id).copied(),
+            settings: state.group_settings.get(&group_id).copied(),
+            group_id,
+        };
+        serde_json::to_vec(&backup).map_err(|e| MlsError::serialization(format!("{:?}", e)))
+    }
+
+    /// Restores a group from `export_group()` and returns its ID.  Fails if
+    /// this client already has the group, or if the group's leaf for us is
+    /// not signed with this client's identity.
+    pub fn import_group(&self, blob: Vec<u8>) -> Result<String, MlsError> {
+        use base64::Engine;
+        let _flush = self.flush_on_return();
+        let backup: GroupBackup = serde_json::from_slice(&blob)
+            .map_err(|e| MlsError::serialization(format!("Corrupt group blob: {}", json_error(&e))))?;
+        let group_id = backup.group_id;
+        let gid = GroupId::from_slice(&Encoding::Hex.decode(&group_id)?);
+
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        if state.groups.contains_key(&group_id) || state.archived_groups.contains(&group_id) {
+            return Err(MlsError::generic(format!("Group {} already exists", group_id)));
+        }
+
+        // Only the group's own entries: a blob must not overwrite our keys.
+        let is_group_key = group_key_matcher(&gid)?;
+        let mut entries = Vec::with_capacity(backup.store.len());
+        for (key_b64, val_b64) in &backup.store {
+            let decode = |s: &str| {
+                base64::prelude::BASE64_STANDARD
+                    .decode(s)
+                    .map_err(|e| MlsError::serialization(format!("Corrupt group blob: {:?}", e)))
+            };
+            let key = decode(key_b64)?;
+            if !is_group_key(&key) {
+                return Err(MlsError::serialization("Group blob holds entries of another group"));
+            }
+            entries.push((key, decode(val_b64)?));
+        }
+        let keys: Vec<Vec<u8>> = entries.iter().map(|(k, _)| k.clone()).collect();
+        state
+            .crypto
+            .storage()
+            .values
+            .write()
+            .map_err(|_| MlsError::lock_poisoned())?
+            .extend(entries);
+
+        let own_key = state.signer.as_ref().map(|s| s.public().to_vec());
+        let mut identity = None;
+        let group = state.load_group(&group_id).and_then(|group| {
+            let leaf_key = group.own_leaf_node().map(|leaf| leaf.signature_key().as_slice().to_vec());
+            identity = leaf_key.as_ref().and_then(|key| state.extra_identity_with_key(key));
+            if leaf_key.is_some() && (leaf_key == own_key || identity.is_some()) {
+                Ok(group)
+            } else {
+                Err(MlsError::generic("Group was exported by a different identity"))
+            }
+        });
+        let group = match group {
+            Ok(group) => group,
+            Err(e) => {
+                if let Ok(mut values) = state.crypto.storage().values.write() {
+                    for key in &keys {
+                        values.remove(key);
+                    }
+                }
+                return Err(e);
+            }
+        };
+
+        state.insert_group(group_id.clone(), group);
+        if let Some(name) = identity {
+            state.group_identities.insert(group_id.clone(), name);
+        }
+        if let Some(generation) = backup.send_generation {
+            state.send_generations.insert(group_id.clone(), generation);
+        }
+        if let Some(sequence) = backup.send_sequence {
+            state.send_sequences.insert(group_id.clone(), sequence);
+        }
+        if backup.self_update_required {
+            state.pending_self_updates.insert(group_id.clone());
+        }
+        state
+            .group_times
+            .insert(group_id.clone(), backup.times.unwrap_or_else(GroupTimes::now));
+        if let Some(settings) = backup.settings {
+            state.group_settings.insert(group_id.clone(), settings);
+        }
+        Ok(group_id)
+    }
+
+    /// Exports everything needed to move this client to another device
+    /// (identity key pair and credential, unused key packages, every group
+    /// and its secrets) as a versioned blob encrypted under `passphrase`.
+    /// Restore it with `MlsClient::import_backup()`.
+    ///
+    /// The old device must stop using its groups once the backup is taken.
+    pub fn export_backup(&self, passphrase: String) -> Result<Vec<u8>, MlsError> {
+        let sealed = self.export_state(passphrase)?;
+
+        let mut blob = BACKUP_MAGIC.to_vec();
+        blob.push(BACKUP_VERSION);
+        blob.extend_from_slice(&(sealed.len() as u64).to_be_bytes());
+        blob.extend_from_slice(&sealed);
+        Ok(blob)
+    }
+
+    /// Moves stored files that would make loading fail or that belong to
+    /// no group, e.g. after an interrupted save or a group deleted behind
+    /// the client's back, into a `.corrupt` subfolder of the storage
+    /// directory (keys prefixed with `.corrupt/` for other backends).
+    /// Returns the names of the files moved.
+    ///
+    /// Covers the per-group key store files that cannot be read or parsed
+    /// or whose group is neither in memory nor archived, and audit logs
+    /// that cannot be parsed.  The state file and the shared key store are
+    /// never moved: without them nothing can be restored.  `load_state()`
+    /// does this on its own after skipping the files it could not read.
+    pub fn purge_orphaned_state(&self) -> Result<Vec<String>, MlsError> {
+        let state = self.state.read().map_err(|_| MlsError::lock_poisoned())?;
+        if let Some(version) = state.newer_state_version {
+            return Err(MlsError::UnsupportedStateVersion { version });
+        }
+        if state.storage_locked {
+            return Err(MlsError::WrongPassphrase {
+                msg: "Stored state could not be decrypted; cannot tell corrupt files apart".into(),
+            });
+        }
+        let groups: HashSet<&str> = state
+            .groups
+            .keys()
+            .chain(&state.archived_groups)
+            .map(String::as_str)
+            .collect();
+        // Holding the lock keeps saves from writing files meanwhile.
+        self.quarantine_files(&groups)
+    }
+
+    /// Lists the IDs of the groups in the saved state.  See
+    /// `list_restorable_groups()` for which of them can be restored.
+    pub fn list_saved_groups(&self) -> Result<Vec<String>, MlsError> {
+        let Some(json) = self.read_storage_file(STATE_KEY)? else {
+            return Ok(Vec::new());
+        };
+
+        let persisted: PersistedState = serde_json::from_slice(&json)
+            .map_err(|e| MlsError::serialization(format!("Corrupt state file: {}", json_error(&e))))?;
+
+        Ok(persisted.groups.into_iter().map(|g| g.group_id).collect())
+    }
+
+    /// Lists the groups in the saved state and whether each can be
+    /// restored from it: its private state must be saved and load, with
+    /// our leaf in it and the signature key of that leaf among our
+    /// identities.  The others are stubs, e.g. left by a save that only
+    /// kept group metadata or whose key store entries were lost, and must
+    /// be rejoined.  Reads the saved state without loading it.
+    pub fn list_restorable_groups(&self) -> Result<Vec<SavedGroup>, MlsError> {
+        use base64::Engine;
+        let Some(backup) = self.read_persisted_state()? else {
+            return Ok(Vec::new());
+        };
+
+        let storage = openmls_memory_storage::MemoryStorage::default();
+        {
+            let mut values = storage.values.write().map_err(|_| MlsError::lock_poisoned())?;
+            for (key_b64, val_b64) in &backup.store {
+                if let (Ok(key), Ok(val)) = (
+                    base64::prelude::BASE64_STANDARD.decode(key_b64),
+                    base64::prelude::BASE64_STANDARD.decode(val_b64),
+                ) {
+                    values.insert(key, val);
+                }
+            }
+        }
+        let signature_keys: HashSet<Vec<u8>> = backup
+            .state
+            .identity
+            .iter()
+            .chain(backup.state.identities.values())
+            .filter_map(|id| serde_json::from_str::<SigningKeyPair>(&id.signer_json).ok())
+            .map(|signer| signer.to_public_vec())
+            .collect();
+
+        let groups = backup
+            .state
+            .groups
+            .iter()
+            .map(|meta| {
+                let loaded = Encoding::Hex
+                    .decode(&meta.group_id)
+                    .and_then(|gid| {
+                        MlsGroup::load(&storage, &GroupId::from_slice(&gid))
+                            .map_err(|e| MlsError::io(format!("Failed to load group: {:?}", e)))
+                    });
+                let reason = match loaded {
+                    Err(e) => Some(e.to_string()),
+                    Ok(None) => Some("Group state is missing from the key store".to_string()),
+                    Ok(Some(group)) => match group.own_leaf() {
+                        None => Some("Our leaf is missing from the group".to_string()),
+                        Some(leaf) if !signature_keys.contains(leaf.signature_key().as_slice()) => {
+                            Some("The signature key of our leaf is missing".to_string())
+                        }
+                        Some(_) => None,
+                    },
+                };
+                SavedGroup {
+                    group_id: meta.group_id.clone(),
+                    epoch: meta.epoch,
+                    restorable: reason.is_none(),
+                    reason,
+                }
+            })
+            .collect();
+
+        if let Ok(mut values) = storage.values.write() {
+            values.values_mut().for_each(|value| value.zeroize());
+        }
+        Ok(groups)
+    }
+}
+
+// ── Private helpers (not exported via UniFFI) ──────────────────────────────
+
+/// Reads the successor group's ID, ciphersuite and extensions from a
+/// migration announcement (see `MIGRATION_EXTENSION_TYPE`).
+fn migration_target(announcement: &[u8]) -> Result<MigrationTarget, MlsError> {
+    let mut rest = announcement;
+    let invalid = |e: tls_codec::Error| MlsError::serialization(format!("Invalid migration announcement: {:?}", e));
+    let group_id = GroupId::tls_deserialize(&mut rest).map_err(invalid)?;
+    ProtocolVersion::tls_deserialize(&mut rest).map_err(invalid)?;
+    let ciphersuite = Ciphersuite::tls_deserialize(&mut rest).map_err(invalid)?;
+    Ok(MigrationTarget {
+        group_id: hex::encode(group_id.as_slice()),
+        ciphersuite: u16::from(ciphersuite),
+        extensions: rest.to_vec(),
+    })
+}
+
+/// The name of an MLS protocol version as used in RFC 9420, e.g.
+/// `"mls10"`, or its hex codepoint if OpenMLS does not know it.
+fn protocol_version_name(version: ProtocolVersion) -> String {
+    match version {
+        ProtocolVersion::Mls10 => "mls10".to_string(),
+        ProtocolVersion::Other(v) => format!("0x{:04x}", v),
+    }
+}
+
+/// Fails with `ProtocolVersionMismatch` if an encoded MLSMessage or bare
+/// KeyPackage, both of which lead with the protocol version, is for a
+/// version other than ours.  OpenMLS would only report a decoding or
+/// validation error.
+fn check_protocol_version(bytes: &[u8]) -> Result<(), MlsError> {
+    let Some(&[hi, lo]) = bytes.get(..2) else {
+        return Ok(());
+    };
+    let theirs = ProtocolVersion::from(u16::from_be_bytes([hi, lo]));
+    if theirs == ProtocolVersion::default() {
+        return Ok(());
+    }
+    Err(MlsError::ProtocolVersionMismatch {
+        ours: protocol_version_name(ProtocolVersion::default()),
+        theirs: protocol_version_name(theirs),
+    })
+}
+
+/// Decodes a key package sent either as an MLSMessage (as RFC 9420 peers
+/// publish them) or as a bare TLS-encoded KeyPackage (as this library has
+/// always emitted them).  Trailing bytes are ignored.
+fn parse_key_package(bytes: &[u8]) -> Result<KeyPackageIn, MlsError> {
+    parse_key_package_prefix(bytes).map(|(key_package, _)| key_package)
+}
+
+/// Like `parse_key_package()`, but also returns the number of bytes left
+/// over after the key package.
+fn parse_key_package_prefix(bytes: &[u8]) -> Result<(KeyPackageIn, usize), MlsError> {
+    check_protocol_version(bytes)?;
+    // Both start with the protocol version; an MLSMessage follows it with
+    // the wire format, a KeyPackage with the ciphersuite.  Only try the
+    // MLSMessage form if the wire format fits: decoding a bare key package
+    // as one can trip debug assertions in tls_codec.
+    let wrapped_err = if bytes.get(2..4) == Some(&(WireFormat::KeyPackage as u16).to_be_bytes()[..]) {
+        let mut rest = bytes;
+        match MlsMessageIn::tls_deserialize(&mut rest) {
+            Ok(msg) => match msg.extract() {
+                MlsMessageBodyIn::KeyPackage(kp) => return Ok((kp, rest.len())),
+                _ => "MLSMessage does not carry a key package".to_string(),
+            },
+            Err(e) => format!("{:?}", e),
+        }
+    } else {
+        "not an mls_key_package wire format".to_string()
+    };
+    let mut rest = bytes;
+    let key_package = KeyPackageIn::tls_deserialize(&mut rest).map_err(|e| {
+        MlsError::serialization(format!(
+            "Invalid key package: not an MLSMessage key package ({}) nor a bare KeyPackage ({:?})",
+            wrapped_err, e
+        ))
+    })?;
+    Ok((key_package, rest.len()))
+}
+
+/// The ciphersuite codepoint of an encoded key package, bare or wrapped in
+/// an MLSMessage (told apart as in `parse_key_package()`).
+fn key_package_ciphersuite(bytes: &[u8]) -> Option<u16> {
+    let offset = if bytes.get(2..4) == Some(&(WireFormat::KeyPackage as u16).to_be_bytes()[..]) {
+        4
+    } else {
+        2
+    };
+    bytes
+        .get(offset..offset + 2)
+        .map(|cs| u16::from_be_bytes([cs[0], cs[1]]))
+}
+
+/// Fails with `KeyPackageExpired` or `KeyPackageNotYetValid` if the (not
+/// yet validated) key package is outside its lifetime, which OpenMLS would
+/// only report as an opaque validation error.
+fn check_key_package_lifetime(kp_in: &KeyPackageIn) -> Result<(), MlsError> {
+    // The leaf's lifetime is not exposed before validation; read it from
+    // the serde form instead.
+    let json = serde_json::to_value(kp_in)
+        .map_err(|e| MlsError::serialization(format!("Failed to inspect key package: {:?}", e)))?;
+    let lifetime: Lifetime = json
+        .pointer("/payload/leaf_node/payload/leaf_node_source/KeyPackage")
+        .cloned()
+        .and_then(|v| serde_json::from_value(v).ok())
+        .ok_or_else(|| MlsError::serialization("Key package has no lifetime"))?;
+
+    let now = unix_time();
+    if now >= lifetime.not_after() {
+        return Err(MlsError::KeyPackageExpired {
+            not_after: lifetime.not_after(),
+        });
+    }
+    if now <= lifetime.not_before() {
+        return Err(MlsError::KeyPackageNotYetValid {
+            not_before: lifetime.not_before(),
+        });
+    }
+    Ok(())
+}
+
+/// Seconds since the Unix epoch.
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Describes a JSON error by its category and position only: serde's own
+/// messages may quote the offending input, which can be key material.
+fn json_error(e: &serde_json::Error) -> String {
+    format!("{:?} error at line {} column {}", e.classify(), e.line(), e.column())
+}
+
+/// Rebuilds an identity saved in `state.json`.
+fn restored_identity(crypto: &MlsProvider, id: &PersistedIdentity) -> Result<Identity, MlsError> {
+    let signer: SigningKeyPair = serde_json::from_str(&id.signer_json)
+        .map_err(|e| MlsError::serialization(format!("Failed to deserialize signer: {}", json_error(&e))))?;
+
+    // The key store normally holds it already, but re-register just in
+    // case it was created fresh (e.g. store file was deleted).
+    let _ = signer.store(crypto.storage());
+
+    let credential = CredentialWithKey {
+        credential: Credential::new(CredentialType::Basic, id.name.clone().into_bytes()),
+        signature_key: signer.to_public_vec().into(),
+    };
+    Ok(Identity {
+        signer: Some(signer),
+        credential: Some(credential),
+        identity_name: Some(id.name.clone()),
+    })
+}
+
+/// Estimates the size in bytes of a Welcome adding `count` members to the
+/// group in its current state: its GroupInfo (with the ratchet tree if the
+/// group embeds it) grown by their leaves and our updated path, plus the
+/// secrets encrypted to each of them.
+fn welcome_size_estimate(
+    group: &MlsGroup,
+    crypto: &MlsProvider,
+    signer: &SigningKeyPair,
+    with_tree: bool,
+    count: u32,
+) -> Result<u64, MlsError> {
+    let group_info = group
+        .export_group_info(crypto.crypto(), signer, with_tree)
+        .map_err(|e| MlsError::generic(format!("Failed to export group info: {:?}", e)))?
+        .tls_serialized_len() as u64;
+    let count = u64::from(count);
+    let mut size = group_info + count * WELCOME_SECRETS_SIZE;
+    if with_tree {
+        let leaf = group.own_leaf_node().map_or(0, |leaf| leaf.tls_serialized_len() as u64);
+        // Each joiner's leaf plus a parent node, blank or not; the commit
+        // also fills the parent nodes on our direct path.
+        let leaves = group.members().count() as u64 + count;
+        let depth = u64::from(leaves.next_power_of_two().trailing_zeros());
+        size += count * (leaf + PARENT_NODE_SIZE) + depth * PARENT_NODE_SIZE;
+    }
+    // Welcome framing and the AEAD tag on the GroupInfo.
+    Ok(size + 32)
+}
+
+/// Compares two secrets without an early exit, so the time taken does not
+/// reveal how long a prefix matched.  Only the lengths may leak.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    std::hint::black_box(diff) == 0
+}
+
+/// Fails with `InputTooLarge` if an input of `len` bytes exceeds `limit`.
+fn check_input_size(len: usize, limit: u64) -> Result<(), MlsError> {
+    let len = len as u64;
+    if len > limit {
+        log::warn!("Refusing input of {} bytes (limit {})", len, limit);
+        return Err(MlsError::InputTooLarge { limit, actual: len });
+    }
+    Ok(())
+}
+
+fn unix_time_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// The group's ratchet tree in array representation, without trailing
+/// blank nodes.
+fn ratchet_tree_nodes(group: &MlsGroup) -> Result<Vec<Option<Node>>, MlsError> {
+    let tree = serde_json::to_value(group.export_ratchet_tree())
+        .map_err(|e| MlsError::serialization(format!("Failed to export ratchet tree: {:?}", e)))?;
+    serde_json::from_value(tree)
+        .map_err(|e| MlsError::serialization(format!("Failed to read ratchet tree: {:?}", e)))
+}
+
+/// The group's leaf nodes by leaf index.  OpenMLS only exposes members'
+/// credentials and keys, so the leaves are read from the exported
+/// ratchet tree (node `2i` is leaf `i`).
+fn leaf_nodes(group: &MlsGroup) -> Result<HashMap<u32, LeafNode>, MlsError> {
+    Ok(ratchet_tree_nodes(group)?
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, node)| match node {
+            Some(Node::LeafNode(leaf)) => Some(((i / 2) as u32, *leaf)),
+            _ => None,
+        })
+        .collect())
+}
+
+/// The group's current context.  OpenMLS only exposes it through a
+/// GroupInfo outside of its test-utils feature.
+fn group_context(
+    crypto: &MlsProvider,
+    signer: &SigningKeyPair,
+    group: &MlsGroup,
+) -> Result<GroupContext, MlsError> {
+    let group_info = group
+        .export_group_info(crypto.crypto(), signer, false)
+        .map_err(|e| MlsError::generic(format!("Failed to export group info: {:?}", e)))?;
+
+    match group_info.body() {
+        MlsMessageBodyOut::GroupInfo(info) => Ok(info.group_context().clone()),
+        _ => Err(MlsError::generic("Unexpected group info message")),
+    }
+}
+
+/// Every member of the group, as returned by `get_members()`.
+fn member_infos(group: &MlsGroup) -> Result<Vec<MemberInfo>, MlsError> {
+    let leaves = leaf_nodes(group)?;
+    group.members().map(|m| member_info(m, &leaves)).collect()
+}
+
+/// Describes a member, given the group's leaf nodes.
+fn member_info(member: Member, leaves: &HashMap<u32, LeafNode>) -> Result<MemberInfo, MlsError> {
+    let leaf = leaves.get(&member.index.u32());
+    Ok(MemberInfo {
+        index: member.index.u32(),
+        identity: member.credential.serialized_content().to_vec(),
+        signature_key: member.signature_key,
+        application_id: leaf.and_then(leaf_application_id),
+        unknown_extensions: match leaf {
+            Some(leaf) => unknown_extensions(leaf.extensions().iter())?,
+            None => Vec::new(),
+        },
+    })
+}
+
+/// The extensions of types OpenMLS does not know (including GREASE), which
+/// it passes through as opaque bytes.
+/// The key package bundles in the key store.
+fn stored_key_packages(crypto: &MlsProvider) -> Result<Vec<KeyPackageBundle>, MlsError> {
+    let values = crypto.storage().values.read().map_err(|_| MlsError::lock_poisoned())?;
+    Ok(values
+        .iter()
+        .filter(|(key, _)| key.starts_with(b"KeyPackage"))
+        .filter_map(|(_, value)| serde_json::from_slice::<KeyPackageBundle>(value).ok())
+        .collect())
+}
+
+fn unknown_extensions<'a>(extensions: impl Iterator<Item = &'a Extension>) -> Result<Vec<RawExtension>, MlsError> {
+    extensions
+        .filter(|e| matches!(e, Extension::Unknown(..)))
+        .map(RawExtension::from_extension)
+        .collect()
+}
+
+fn leaf_application_id(leaf: &LeafNode) -> Option<Vec<u8>> {
+    leaf.extensions()
+        .application_id()
+        .map(|ext| ext.as_slice().to_vec())
+}
+
+/// The leaves of the members with `identities`, sorted, for a commit
+/// removing them.  Fails with `UnknownMembers` listing every identity that
+/// matches no member, or `CannotRemoveSelf` if one matches our own leaf.
+fn removal_leaves(group_id: &str, group: &MlsGroup, identities: &[String]) -> Result<Vec<LeafNodeIndex>, MlsError> {
+    let mut leaves = Vec::new();
+    let mut unknown = Vec::new();
+    for identity in identities {
+        let matching: Vec<LeafNodeIndex> = group
+            .members()
+            .filter(|m| m.credential.serialized_content() == identity.as_bytes())
+            .map(|m| m.index)
+            .collect();
+        if matching.is_empty() {
+            unknown.push(identity.clone());
+        }
+        leaves.extend(matching);
+    }
+    if !unknown.is_empty() {
+        return Err(MlsError::UnknownMembers { identities: unknown });
+    }
+    if leaves.contains(&group.own_leaf_index()) {
+        return Err(MlsError::CannotRemoveSelf {
+            group_id: group_id.to_string(),
+        });
+    }
+    leaves.sort();
+    leaves.dedup();
+    Ok(leaves)
+}
+
+/// Fails with `GroupFull` if a commit adding `adding` members and removing
+/// `removing` would leave the group with more than `max_members`.  Add
+/// proposals queued in the group count as well, since a commit may take
+/// them along.
+fn check_member_cap(
+    group_id: &str,
+    group: &MlsGroup,
+    max_members: Option<u32>,
+    adding: usize,
+    removing: usize,
+) -> Result<(), MlsError> {
+    let Some(max_members) = max_members else {
+        return Ok(());
+    };
+    let pending_adds = group
+        .pending_proposals()
+        .filter(|p| matches!(p.proposal(), Proposal::Add(_)))
+        .count();
+    let size = (group.members().count() + pending_adds + adding).saturating_sub(removing);
+    if size > max_members as usize {
+        return Err(MlsError::GroupFull {
+            group_id: group_id.to_string(),
+            max_members,
+        });
+    }
+    Ok(())
+}
+
+/// Verifies that a joiner's leaf node supports everything the group's
+/// `RequiredCapabilities` extension demands.
+fn check_required_capabilities(group: &MlsGroup, key_package: &KeyPackage) -> Result<(), MlsError> {
+    let Some(required) = group.extensions().required_capabilities() else {
+        return Ok(());
+    };
+    check_capabilities(required, key_package.leaf_node().capabilities(), "Key package")
+}
+
+/// Verifies that `offered` covers every non-default type in `required`.
+/// `subject` names the leaf in the error message.
+fn check_capabilities(
+    required: &RequiredCapabilitiesExtension,
+    offered: &Capabilities,
+    subject: &str,
+) -> Result<(), MlsError> {
+
+    let missing_extensions: Vec<u16> = required
+        .extension_types()
+        .iter()
+        .filter(|e| !is_default_extension(**e) && !offered.extensions().contains(e))
+        .map(|&e| u16::from(e))
+        .collect();
+    let missing_proposals: Vec<u16> = required
+        .proposal_types()
+        .iter()
+        .filter(|p| !is_default_proposal(**p) && !offered.proposals().contains(p))
+        .map(|&p| u16::from(p))
+        .collect();
+    let missing_credentials: Vec<u16> = required
+        .credential_types()
+        .iter()
+        .filter(|c| !offered.credentials().contains(c))
+        .map(|&c| u16::from(c))
+        .collect();
+
+    if missing_extensions.is_empty() && missing_proposals.is_empty() && missing_credentials.is_empty() {
+        return Ok(());
+    }
+    Err(MlsError::CapabilitiesMismatch {
+        subject: subject.to_string(),
+        missing_extensions,
+        missing_proposals,
+        missing_credentials,
+    })
+}
+
+/// Extension types every MLS client supports implicitly (RFC 9420 §7.2).
+fn is_default_extension(extension: ExtensionType) -> bool {
+    matches!(
+        extension,
+        ExtensionType::ApplicationId
+            | ExtensionType::RatchetTree
+            | ExtensionType::RequiredCapabilities
+            | ExtensionType::ExternalPub
+            | ExtensionType::ExternalSenders
+    )
+}
+
+/// Proposal types every MLS client supports implicitly (RFC 9420 §7.2).
+fn is_default_proposal(proposal: ProposalType) -> bool {
+    matches!(
+        proposal,
+        ProposalType::Add
+            | ProposalType::Update
+            | ProposalType::Remove
+            | ProposalType::PreSharedKey
+            | ProposalType::Reinit
+            | ProposalType::ExternalInit
+            | ProposalType::GroupContextExtensions
+    )
+}
+
+// Application message payloads in groups with the payload envelope
+// extension (see `PAYLOAD_ENVELOPE_EXTENSION_TYPE`), inside the MLS
+// encryption so that the delivery service learns neither a sender's
+// message count nor what kind of message it is.  All integers are
+// big-endian:
+//
+//   offset  size  field
+//        0     1  envelope version
+//        1     1  payload kind: 0 chat, 1 read receipt, 2 typing, 3 reaction
+//        2     1  flags: bit 0 set if the message header follows
+//        3     4  sender generation
+//        7     8  header: sender timestamp, milliseconds since the Unix epoch
+//       15     8  header: sequence number, from 0 per sender and group
+//    7, 23     -  application payload
+//
+// Every application message sent in such a group has it, so no payload is
+// ever mistaken for a header or a control message, whatever its first
+// bytes are.  In other groups payloads are sent as they are.
+const PAYLOAD_ENVELOPE_VERSION: u8 = 1;
+const PAYLOAD_FLAG_HEADER: u8 = 0x01;
+
+/// An application message payload with what travels alongside it.
+struct PayloadEnvelope<'a> {
+    generation: u32,
+    kind: PayloadKind,
+    header: Option<MessageHeader>,
+    payload: &'a [u8],
+}
+
+impl PayloadEnvelope<'_> {
+    /// Encodes the envelope for `create_message()`.
+    fn seal(&self) -> Zeroizing<Vec<u8>> {
+        let mut envelope = Zeroizing::new(Vec::with_capacity(7 + 16 + self.payload.len()));
+        envelope.push(PAYLOAD_ENVELOPE_VERSION);
+        envelope.push(self.kind.tag());
+        envelope.push(if self.header.is_some() { PAYLOAD_FLAG_HEADER } else { 0 });
+        envelope.extend_from_slice(&self.generation.to_be_bytes());
+        if let Some(header) = self.header {
+            envelope.extend_from_slice(&header.timestamp_ms.to_be_bytes());
+            envelope.extend_from_slice(&header.sequence.to_be_bytes());
+        }
+        envelope.extend_from_slice(self.payload);
+        envelope
+    }
+
+    /// Splits a decrypted application message into its parts.  Fails on
+    /// kinds we do not know, rather than show them as chat.
+    fn open(envelope: &[u8]) -> Result<PayloadEnvelope<'_>, MlsError> {
+        let truncated = || MlsError::serialization("Truncated application message");
+        let ([version, kind, flags], rest) = envelope.split_first_chunk::<3>().ok_or_else(truncated)?;
+        if *version != PAYLOAD_ENVELOPE_VERSION {
+            return Err(MlsError::serialization(format!(
+                "Unsupported application message version {}",
+                version
+            )));
+        }
+        let (generation, mut payload) = rest.split_first_chunk::<4>().ok_or_else(truncated)?;
+        let header = if flags & PAYLOAD_FLAG_HEADER != 0 {
+            let (timestamp_ms, rest) = payload.split_first_chunk::<8>().ok_or_else(truncated)?;
+            let (sequence, rest) = rest.split_first_chunk::<8>().ok_or_else(truncated)?;
+            payload = rest;
+            Some(MessageHeader {
+                timestamp_ms: u64::from_be_bytes(*timestamp_ms),
+                sequence: u64::from_be_bytes(*sequence),
+            })
+        } else {
+            None
+        };
+        let kind = PayloadKind::from_tag(*kind)
+            .ok_or_else(|| MlsError::serialization(format!("Unknown application message kind {}", kind)))?;
+        Ok(PayloadEnvelope {
+            generation: u32::from_be_bytes(*generation),
+            kind,
+            header,
+            payload,
+        })
+    }
+}
+
+/// Whether the group's application messages carry a payload envelope.
+fn uses_payload_envelope(group: &MlsGroup) -> bool {
+    group
+        .extensions()
+        .unknown(PAYLOAD_ENVELOPE_EXTENSION_TYPE)
+        .is_some()
+}
+
+/// What `encrypt_signed_message()` signs: `SIGNED_MESSAGE_LABEL`, the
+/// group ID and epoch, and the plaintext, each length-prefixed.
+fn signed_message_content(group_id: &str, epoch: u64, plaintext: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mut content = Zeroizing::new(Vec::new());
+    for field in [SIGNED_MESSAGE_LABEL, group_id.as_bytes(), &epoch.to_be_bytes(), plaintext] {
+        content.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        content.extend_from_slice(field);
+    }
+    content
+}
+
+/// Prefix of `encrypt_ephemeral()` messages, and their format version.
+const EPHEMERAL_MAGIC: &[u8; 4] = b"MLSe";
+const EPHEMERAL_VERSION: u8 = 1;
+/// Magic, version, epoch, sender leaf index and AES-GCM nonce.
+const EPHEMERAL_HEADER_LEN: usize = 4 + 1 + 8 + 4 + 12;
+
+/// The key of the group's current epoch for ephemeral messages.
+fn ephemeral_key(crypto: &MlsProvider, group: &MlsGroup) -> Result<Zeroizing<Vec<u8>>, MlsError> {
+    group
+        .export_secret(crypto.crypto(), EPHEMERAL_LABEL, &[], 32)
+        .map(Zeroizing::new)
+        .map_err(|e| MlsError::crypto(format!("Failed to derive ephemeral key: {:?}", e)))
+}
+
+/// Encrypts `payload` as an ephemeral message of our leaf in the group's
+/// current epoch, in the layout documented on `encrypt_ephemeral()`.
+fn seal_ephemeral(
+    crypto: &MlsProvider,
+    group: &MlsGroup,
+    signer: &SigningKeyPair,
+    payload: &[u8],
+) -> Result<Vec<u8>, MlsError> {
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
+    use openmls_traits::signatures::Signer;
+
+    let nonce: [u8; 12] = crypto
+        .rand()
+        .random_array()
+        .map_err(|e| MlsError::crypto(format!("Failed to generate nonce: {:?}", e)))?;
+    let mut blob = EPHEMERAL_MAGIC.to_vec();
+    blob.push(EPHEMERAL_VERSION);
+    blob.extend_from_slice(&group.epoch().as_u64().to_be_bytes());
+    blob.extend_from_slice(&group.own_leaf_index().u32().to_be_bytes());
+    blob.extend_from_slice(&nonce);
+
+    let key = ephemeral_key(crypto, group)?;
+    let cipher = aes_gcm::Aes256Gcm::new_from_slice(&key)
+        .map_err(|e| MlsError::crypto(format!("Invalid key: {:?}", e)))?;
+    let ciphertext = cipher
+        .encrypt(
+            aes_gcm::Nonce::from_slice(&nonce),
+            Payload {
+                msg: payload,
+                aad: &blob,
+            },
+        )
+        .map_err(|e| MlsError::crypto(format!("Encryption failed: {:?}", e)))?;
+    blob.extend_from_slice(&(ciphertext.len() as u32).to_be_bytes());
+    blob.extend_from_slice(&ciphertext);
+
+    let signature = signer
+        .sign(&blob)
+        .map_err(|e| MlsError::crypto(format!("Failed to sign ephemeral message: {:?}", e)))?;
+    blob.extend_from_slice(&(signature.len() as u16).to_be_bytes());
+    blob.extend_from_slice(&signature);
+    Ok(blob)
+}
+
+/// Checks and decrypts a blob from `seal_ephemeral()` against the group's
+/// current epoch.
+fn open_ephemeral(
+    crypto: &MlsProvider,
+    group_id: &str,
+    group: &MlsGroup,
+    blob: &[u8],
+) -> Result<EphemeralMessage, MlsError> {
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
+
+    let malformed = || MlsError::serialization("Not an ephemeral message");
+    let rest = blob.strip_prefix(EPHEMERAL_MAGIC).ok_or_else(malformed)?;
+    let (&version, rest) = rest.split_first().ok_or_else(malformed)?;
+    if version != EPHEMERAL_VERSION {
+        return Err(MlsError::serialization(format!(
+            "Unsupported ephemeral message version {}",
+            version
+        )));
+    }
+    let (epoch, rest) = rest.split_first_chunk::<8>().ok_or_else(malformed)?;
+    let (sender, rest) = rest.split_first_chunk::<4>().ok_or_else(malformed)?;
+    let (nonce, rest) = rest.split_first_chunk::<12>().ok_or_else(malformed)?;
+    let (ciphertext_len, rest) = rest.split_first_chunk::<4>().ok_or_else(malformed)?;
+    let ciphertext_len = u32::from_be_bytes(*ciphertext_len) as usize;
+    if rest.len() < ciphertext_len {
+        return Err(malformed());
+    }
+    let (ciphertext, rest) = rest.split_at(ciphertext_len);
+    let (signature_len, signature) = rest.split_first_chunk::<2>().ok_or_else(malformed)?;
+    if signature.len() != u16::from_be_bytes(*signature_len) as usize {
+        return Err(malformed());
+    }
+
+    let message_epoch = u64::from_be_bytes(*epoch);
+    let current_epoch = group.epoch().as_u64();
+    if message_epoch != current_epoch {
+        return Err(MlsError::EpochMismatch {
+            group_id: group_id.to_string(),
+            message_epoch,
+            current_epoch,
+        });
+    }
+
+    let sender_leaf_index = u32::from_be_bytes(*sender);
+    let member = group
+        .members()
+        .find(|m| m.index.u32() == sender_leaf_index)
+        .ok_or_else(|| MlsError::UnknownMember {
+            detail: format!("No member at leaf {}", sender_leaf_index),
+        })?;
+    let signed = &blob[..blob.len() - signature.len() - 2];
+    crypto
+        .crypto()
+        .verify_signature(
+            group.ciphersuite().signature_algorithm(),
+            signed,
+            &member.signature_key,
+            signature,
+        )
+        .map_err(|e| MlsError::InvalidSignature {
+            detail: format!("Ephemeral message from leaf {}: {:?}", sender_leaf_index, e),
+        })?;
+
+    let key = ephemeral_key(crypto, group)?;
+    let cipher = aes_gcm::Aes256Gcm::new_from_slice(&key)
+        .map_err(|e| MlsError::crypto(format!("Invalid key: {:?}", e)))?;
+    let payload = cipher
+        .decrypt(
+            aes_gcm::Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &blob[..EPHEMERAL_HEADER_LEN],
+            },
+        )
+        .map_err(|_| MlsError::DecryptionFailed {
+            detail: "Ephemeral message does not decrypt under this epoch's key".into(),
+        })?;
+
+    Ok(EphemeralMessage {
+        sender_leaf_index,
+        sender_identity: member.credential.serialized_content().to_vec(),
+        payload,
+    })
+}
+
+fn content_kind(content_type: ContentType) -> MessageKind {
+    match content_type {
+        ContentType::Application => MessageKind::Application,
+        ContentType::Proposal => MessageKind::Proposal,
+        ContentType::Commit => MessageKind::Commit,
+    }
+}
+
+/// Credential identity of a message's sender, or empty if it is not a
+/// member (an external sender or new member).
+fn sender_identity(group: &MlsGroup, sender: &Sender) -> Vec<u8> {
+    match sender {
+        Sender::Member(leaf) => group
+            .member(*leaf)
+            .map(|c| c.serialized_content().to_vec())
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Describes a queued proposal for display to the caller.
+fn proposal_info(group: &MlsGroup, queued: &QueuedProposal) -> ProposalInfo {
+    let sender_index = match queued.sender() {
+        Sender::Member(leaf) => Some(leaf.u32()),
+        _ => None,
+    };
+
+    let (leaf_index, identity) = match queued.proposal() {
+        Proposal::Add(add) => (
+            None,
+            Some(
+                add.key_package()
+                    .leaf_node()
+                    .credential()
+                    .serialized_content()
+                    .to_vec(),
+            ),
+        ),
+        Proposal::Remove(remove) => (
+            Some(remove.removed().u32()),
+            group
+                .member(remove.removed())
+                .map(|c| c.serialized_content().to_vec()),
+        ),
+        Proposal::Update(update) => (
+            sender_index,
+            Some(update.leaf_node().credential().serialized_content().to_vec()),
+        ),
+        _ => (None, None),
+    };
+
+    // OpenMLS keeps the index itself private; it is a u32 on the wire.
+    let external_sender_index = match queued.sender() {
+        Sender::External(index) => index
+            .tls_serialize_detached()
+            .ok()
+            .and_then(|bytes| Some(u32::from_be_bytes(bytes.try_into().ok()?))),
+        _ => None,
+    };
+
+    ProposalInfo {
+        proposal_type: format!("{:?}", queued.proposal().proposal_type()),
+        leaf_index,
+        identity,
+        sender_index,
+        external_sender_index,
+        proposal_ref: hex::encode(queued.proposal_reference_ref().as_slice()),
+    }
+}
+
+/// Finds the conflicts among a group's queued proposals that can be told
+/// from the proposals themselves (see `ProposalConflictKind`).
+fn proposal_conflicts(group: &MlsGroup, known_psks: &HashSet<String>) -> Vec<ProposalConflict> {
+    let own_index = group.own_leaf_index().u32();
+    let mut removes: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    let mut updates: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    let mut adds: BTreeMap<Vec<u8>, Vec<String>> = BTreeMap::new();
+    let mut extensions = Vec::new();
+    let mut reinits = Vec::new();
+    let mut unknown_psks = Vec::new();
+    let mut count = 0;
+
+    for queued in group.pending_proposals() {
+        count += 1;
+        let proposal_ref = hex::encode(queued.proposal_reference_ref().as_slice());
+        match queued.proposal() {
+            Proposal::Remove(remove) => removes.entry(remove.removed().u32()).or_default().push(proposal_ref),
+            Proposal::Update(_) => {
+                if let Sender::Member(leaf) = queued.sender() {
+                    updates.entry(leaf.u32()).or_default().push(proposal_ref);
+                }
+            }
+            Proposal::Add(add) => adds
+                .entry(add.key_package().leaf_node().signature_key().as_slice().to_vec())
+                .or_default()
+                .push(proposal_ref),
+            Proposal::GroupContextExtensions(_) => extensions.push(proposal_ref),
+            Proposal::ReInit(_) => reinits.push(proposal_ref),
+            Proposal::PreSharedKey(psk) => {
+                if let Some(id) = external_psk_id(psk).filter(|id| !known_psks.contains(id)) {
+                    unknown_psks.push((id, proposal_ref));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let conflict = |kind, reason: String, proposal_refs: Vec<String>| ProposalConflict {
+        kind,
+        reason,
+        proposal_refs,
+    };
+    let mut conflicts = Vec::new();
+    for (leaf, refs) in &removes {
+        if *leaf == own_index {
+            conflicts.push(conflict(
+                ProposalConflictKind::RemovesCommitter,
+                format!("Leaf {} is ours; only another member can commit our removal", leaf),
+                refs.clone(),
+            ));
+        }
+        if refs.len() > 1 {
+            conflicts.push(conflict(
+                ProposalConflictKind::DuplicateRemove,
+                format!("Leaf {} is removed {} times", leaf, refs.len()),
+                refs.clone(),
+            ));
+        }
+        if let Some(updated) = updates.get(leaf) {
+            conflicts.push(conflict(
+                ProposalConflictKind::UpdateOfRemovedMember,
+                format!("Leaf {} is both updated and removed", leaf),
+                updated.iter().chain(refs).cloned().collect(),
+            ));
+        }
+    }
+    for (signature_key, refs) in &adds {
+        if refs.len() > 1 {
+            conflicts.push(conflict(
+                ProposalConflictKind::DuplicateAdd,
+                format!("The same key package is added {} times", refs.len()),
+                refs.clone(),
+            ));
+        }
+        let member = group
+            .members()
+            .find(|m| m.signature_key == *signature_key && !removes.contains_key(&m.index.u32()));
+        if let Some(member) = member {
+            conflicts.push(conflict(
+                ProposalConflictKind::AlreadyMember,
+                format!("The joiner's signature key is already used by leaf {}", member.index.u32()),
+                refs.clone(),
+            ));
+        }
+    }
+    if extensions.len() > 1 {
+        conflicts.push(conflict(
+            ProposalConflictKind::MultipleGroupContextExtensions,
+            format!("{} GroupContextExtensions proposals are queued", extensions.len()),
+            extensions,
+        ));
+    }
+    if !reinits.is_empty() && count > 1 {
+        conflicts.push(conflict(
+            ProposalConflictKind::ReInitNotAlone,
+            format!("A ReInit proposal is queued with {} other proposals", count - 1),
+            reinits,
+        ));
+    }
+    for (psk_id, proposal_ref) in unknown_psks {
+        conflicts.push(conflict(
+            ProposalConflictKind::UnknownPsk,
+            format!("PSK {} is not registered", psk_id),
+            vec![proposal_ref],
+        ));
+    }
+    conflicts
+}
+
+/// Builds a commit of the group's queued proposals on a scratch copy of
+/// the key store, leaving the group itself untouched.  Returns OpenMLS's
+/// reason if the commit cannot be built.  The whole store is copied, as
+/// the commit also reads the external PSKs.
+fn trial_commit(
+    group: &MlsGroup,
+    crypto: &MlsProvider,
+    signer: &SigningKeyPair,
+) -> Result<Result<(), String>, MlsError> {
+    let scratch = MlsProvider::default();
+    {
+        let values = crypto.storage().values.read().map_err(|_| MlsError::lock_poisoned())?;
+        let mut copy = scratch.storage().values.write().map_err(|_| MlsError::lock_poisoned())?;
+        copy.extend(values.iter().map(|(key, value)| (key.clone(), value.clone())));
+    }
+    let mut probe = MlsGroup::load(scratch.storage(), group.group_id())
+        .map_err(|e| MlsError::io(format!("Failed to load group: {:?}", e)))?
+        .ok_or_else(|| MlsError::generic("Group missing from the key store"))?;
+    Ok(probe
+        .commit_to_pending_proposals(&scratch, signer)
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e)))
+}
 
-        let leaf = LeafNodeIndex::new(member_index);
+/// Describes a staged commit for review before merging.
+fn describe_staged_commit(group: &MlsGroup, staged: &StagedCommit, sender_identity: Vec<u8>) -> StagedCommitDescription {
+    let extensions = staged.group_context().extensions();
+    StagedCommitDescription {
+        sender_identity,
+        added_identities: staged
+            .add_proposals()
+            .map(|p| {
+                p.add_proposal()
+                    .key_package()
+                    .leaf_node()
+                    .credential()
+                    .serialized_content()
+                    .to_vec()
+            })
+            .collect(),
+        removed_identities: staged
+            .remove_proposals()
+            .filter_map(|p| {
+                group
+                    .member(p.remove_proposal().removed())
+                    .map(|c| c.serialized_content().to_vec())
+            })
+            .collect(),
+        self_removed: staged.self_removed(),
+        update_count: staged.update_proposals().count() as u32,
+        psk_ids: staged
+            .psk_proposals()
+            .filter_map(|p| external_psk_id(p.psk_proposal()))
+            .collect(),
+        new_extensions: (extensions != group.extensions())
+            .then(|| extensions.iter().filter_map(|e| RawExtension::from_extension(e).ok()).collect()),
+    }
+}
 
-        let (commit, _welcome, _gi) = group
-            .remove_members(crypto, signer, &[leaf])
-            .map_err(|e| MlsError::generic(format!("Failed to remove member: {:?}", e)))?;
+/// Membership events for a commit, computed before it is merged (removed
+/// members are no longer in the tree afterwards).
+fn membership_events(group_id: &str, group: &MlsGroup, staged: &StagedCommit) -> Vec<MlsEvent> {
+    let added = staged.add_proposals().map(|p| MlsEvent::MemberAdded {
+        group_id: group_id.to_string(),
+        identity: p
+            .add_proposal()
+            .key_package()
+            .leaf_node()
+            .credential()
+            .serialized_content()
+            .to_vec(),
+    });
+    let removed = staged.remove_proposals().filter_map(|p| {
+        let leaf = p.remove_proposal().removed();
+        group.member(leaf).map(|c| MlsEvent::MemberRemoved {
+            group_id: group_id.to_string(),
+            identity: c.serialized_content().to_vec(),
+            was_self: leaf == group.own_leaf_index(),
+        })
+    });
+    added.chain(removed).collect()
+}
 
-        group
-            .merge_pending_commit(crypto)
-            .map_err(|e| MlsError::generic(format!("Failed to merge commit: {:?}", e)))?;
+/// The audit log entry for merging `staged`, whose `membership_events()`
+/// are `membership`.  Its hashes are filled in once it is appended to the
+/// log (see `append_audit_entries()`).
+fn commit_merged_event(group_id: &str, staged: &StagedCommit, committer: Vec<u8>, membership: &[MlsEvent]) -> MlsEvent {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    for event in membership {
+        match event {
+            MlsEvent::MemberAdded { identity, .. } => added.push(identity.clone()),
+            MlsEvent::MemberRemoved { identity, .. } => removed.push(identity.clone()),
+            _ => {}
+        }
+    }
+    let context = staged.group_context();
+    MlsEvent::CommitMerged {
+        entry: AuditEntry {
+            group_id: group_id.to_string(),
+            epoch: context.epoch().as_u64(),
+            committer,
+            added,
+            removed,
+            commit_hash: hex::encode(context.confirmed_transcript_hash()),
+            timestamp: unix_time(),
+            previous_hash: String::new(),
+            entry_hash: String::new(),
+        },
+    }
+}
 
-        let commit_hex = hex::encode(
-            commit
-                .tls_serialize_detached()
-                .map_err(|e| MlsError::serialization(format!("{:?}", e)))?,
-        );
+/// The `entry_hash` of `entry`: SHA-256 over its other fields, starting
+/// with `previous_hash` so that each entry vouches for the ones before.
+fn audit_entry_hash(crypto: &MlsProvider, entry: &AuditEntry) -> Result<String, MlsError> {
+    let fields = serde_json::to_vec(&(
+        &entry.previous_hash,
+        &entry.group_id,
+        entry.epoch,
+        &entry.committer,
+        &entry.added,
+        &entry.removed,
+        &entry.commit_hash,
+        entry.timestamp,
+    ))
+    .map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+    let digest = crypto
+        .crypto()
+        .hash(HashType::Sha2_256, &fields)
+        .map_err(|e| MlsError::crypto(format!("Failed to hash audit entry: {:?}", e)))?;
+    Ok(hex::encode(digest))
+}
 
-        Ok(format!(r#"{{"commit":"{}"}}"#, commit_hex))
+/// Handshakes in the clear (readable by the delivery service) or
+/// encrypted only.
+fn wire_format_policy(public_handshake: bool) -> WireFormatPolicy {
+    if public_handshake {
+        MIXED_PLAINTEXT_WIRE_FORMAT_POLICY
+    } else {
+        PURE_CIPHERTEXT_WIRE_FORMAT_POLICY
     }
+}
 
-    /// Performs a self-update, rotating this member's leaf key material.
-    ///
-    /// This is essential for **post-compromise security**: even if your
-    /// keys were leaked, future messages become secure after an update.
-    /// Returns JSON: `{"commit":"<hex>"}`.
-    /// Broadcast the commit to all other members.
-    pub fn self_update(&self, group_id: String) -> Result<String, MlsError> {
-        let mut state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
+/// A builder for a group's join configuration as it is, to change part of
+/// it.  `settings` supplies what OpenMLS has no getter for.
+fn join_config_builder(current: &MlsGroupJoinConfig, settings: GroupSettings) -> MlsGroupJoinConfigBuilder {
+    MlsGroupJoinConfig::builder()
+        .wire_format_policy(current.wire_format_policy())
+        .padding_size(current.padding_size())
+        .max_past_epochs(settings.max_past_epochs)
+        .sender_ratchet_configuration(*current.sender_ratchet_configuration())
+        .use_ratchet_tree_extension(settings.use_ratchet_tree_extension)
+}
 
-        let MlsClientState {
-            groups,
-            crypto,
-            signer,
-            ..
-        } = &mut *state;
-        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+/// Updates the padding size in a group's join configuration, keeping the
+/// rest of it.
+fn set_group_padding(
+    group: &mut MlsGroup,
+    crypto: &MlsProvider,
+    settings: GroupSettings,
+    padding_size: usize,
+) -> Result<(), MlsError> {
+    let config = join_config_builder(group.configuration(), settings)
+        .padding_size(padding_size)
+        .build();
+
+    group
+        .set_configuration(crypto.storage(), &config)
+        .map_err(|e| MlsError::io(format!("Failed to store group configuration: {:?}", e)))
+}
 
-        let group = groups
-            .get_mut(&group_id)
-            .ok_or_else(|| MlsError::GroupNotFound {
-                group_id: group_id.clone(),
-            })?;
+/// Stores a group's join configuration as it is with `settings`.
+fn set_group_config(group: &mut MlsGroup, crypto: &MlsProvider, settings: GroupSettings) -> Result<(), MlsError> {
+    let config = join_config_builder(group.configuration(), settings).build();
 
-        let bundle = group
-            .self_update(crypto, signer, LeafNodeParameters::default())
-            .map_err(|e| MlsError::generic(format!("Failed to self-update: {:?}", e)))?;
+    group
+        .set_configuration(crypto.storage(), &config)
+        .map_err(|e| MlsError::io(format!("Failed to store group configuration: {:?}", e)))
+}
 
-        let commit = bundle.into_commit();
+/// Serializes and encodes a handshake message we created, remembering its
+/// digest so that the delivery service echoing it back to us is recognised.
+fn encode_sent(
+    message: &MlsMessageOut,
+    group_id: &str,
+    crypto: &MlsProvider,
+    encoding: Encoding,
+    sent_messages: &mut HashMap<String, MessageCache>,
+    cache_size: usize,
+) -> Result<String, MlsError> {
+    let bytes = message
+        .tls_serialize_detached()
+        .map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+    let digest = message_digest(crypto, &bytes)?;
+    sent_messages.entry(group_id.to_string()).or_default().insert(digest, cache_size);
+    Ok(encoding.encode(&bytes))
+}
 
+/// Runs the commit we just created past the commit policy, if it checks
+/// our own commits.  A rejected commit is discarded so it is never sent.
+fn check_own_commit(
+    group_id: &str,
+    group: &mut MlsGroup,
+    crypto: &MlsProvider,
+    commit_policy: Option<&CommitPolicyHook>,
+) -> Result<(), MlsError> {
+    let (Some(hook), Some(staged)) = (commit_policy.filter(|h| h.check_own_commits), group.pending_commit()) else {
+        return Ok(());
+    };
+    let own_identity = group
+        .own_leaf_node()
+        .map(|leaf| leaf.credential().serialized_content().to_vec())
+        .unwrap_or_default();
+    let decision = hook.check(group_id, describe_staged_commit(group, staged, own_identity));
+    if decision.is_err() {
         group
-            .merge_pending_commit(crypto)
-            .map_err(|e| MlsError::generic(format!("Failed to merge commit: {:?}", e)))?;
+            .clear_pending_commit(crypto.storage())
+            .map_err(|e| MlsError::io(format!("Failed to discard commit: {:?}", e)))?;
+    }
+    decision
+}
 
-        let commit_hex = hex::encode(
-            commit
-                .tls_serialize_detached()
-                .map_err(|e| MlsError::serialization(format!("{:?}", e)))?,
+/// Merges the commit we just created, recording the resulting events.
+/// Fails with `CommitRejectedByPolicy` if the commit policy rejects it.
+///
+/// If merging fails, the group is rolled back to the epoch before the
+/// commit and the commit is discarded, so the group stays usable and the
+/// commit must not be sent.
+fn merge_own_commit(
+    group_id: &str,
+    group: &mut MlsGroup,
+    crypto: &MlsProvider,
+    commit_policy: Option<&CommitPolicyHook>,
+    events: &mut Vec<MlsEvent>,
+) -> Result<(), MlsError> {
+    check_own_commit(group_id, group, crypto, commit_policy)?;
+
+    let (membership, audit) = match group.pending_commit() {
+        Some(staged) => {
+            let membership = membership_events(group_id, group, staged);
+            let committer = group
+                .own_leaf_node()
+                .map(|leaf| leaf.credential().serialized_content().to_vec())
+                .unwrap_or_default();
+            let audit = commit_merged_event(group_id, staged, committer, &membership);
+            (membership, Some(audit))
+        }
+        None => (Vec::new(), None),
+    };
+
+    merge_or_roll_back(group_id, group, crypto, |group| {
+        group.merge_pending_commit(crypto).map_err(|e| format!("{:?}", e))
+    })?;
+
+    for event in membership.into_iter().chain(audit) {
+        record_event(events, event);
+    }
+    record_event(
+        events,
+        MlsEvent::EpochChanged {
+            group_id: group_id.to_string(),
+            epoch: group.epoch().as_u64(),
+        },
+    );
+    Ok(())
+}
+
+/// Merges the group's pending commit with `merge`.  OpenMLS may fail with
+/// the merge half written, both in memory and in the key store, so the
+/// store is rolled back and the group reloaded from it, without the
+/// pending commit, if it does.
+fn merge_or_roll_back(
+    group_id: &str,
+    group: &mut MlsGroup,
+    crypto: &MlsProvider,
+    merge: impl FnOnce(&mut MlsGroup) -> Result<(), String>,
+) -> Result<(), MlsError> {
+    let snapshot = store_snapshot(crypto)?;
+    if let Err(e) = merge(group) {
+        log::warn!("Failed to merge commit in group {}: {}", group_id, e);
+        if let Err(restore) = restore_store(crypto, group, snapshot) {
+            log::error!("Failed to roll back group {}: {}", group_id, restore);
+        }
+        return Err(MlsError::generic(format!("Failed to merge commit: {}", e)));
+    }
+    Ok(())
+}
+
+/// Raw key store entries, as taken by `store_snapshot()`.  The values
+/// hold epoch secrets, so they are wiped once dropped.
+type StoreEntries = Vec<(Vec<u8>, Zeroizing<Vec<u8>>)>;
+
+/// Every key store entry, for `restore_store()`.  The whole store, as
+/// which entries a merge writes (leaf and epoch encryption keys, signature
+/// keys) cannot all be told apart by group.
+fn store_snapshot(crypto: &MlsProvider) -> Result<StoreEntries, MlsError> {
+    let values = crypto.storage().values.read().map_err(|_| MlsError::lock_poisoned())?;
+    Ok(values
+        .iter()
+        .map(|(k, v)| (k.clone(), Zeroizing::new(v.clone())))
+        .collect())
+}
+
+/// Replaces the key store's entries with `snapshot`, wiping the ones
+/// written since, and reloads `group` from them without its pending
+/// commit.
+fn restore_store(crypto: &MlsProvider, group: &mut MlsGroup, snapshot: StoreEntries) -> Result<(), MlsError> {
+    {
+        let mut values = crypto.storage().values.write().map_err(|_| MlsError::lock_poisoned())?;
+        for value in values.values_mut() {
+            value.zeroize();
+        }
+        *values = snapshot
+            .into_iter()
+            .map(|(k, mut v)| (k, std::mem::take(&mut *v)))
+            .collect();
+    }
+    let mut restored = MlsGroup::load(crypto.storage(), group.group_id())
+        .map_err(|e| MlsError::io(format!("Failed to reload group: {:?}", e)))?
+        .ok_or_else(|| MlsError::io("Group vanished from the key store"))?;
+    restored
+        .clear_pending_commit(crypto.storage())
+        .map_err(|e| MlsError::io(format!("Failed to clear pending commit: {:?}", e)))?;
+    *group = restored;
+    Ok(())
+}
+
+/// Drops the add commit `commit_ref` from the group's journal after
+/// merging it failed: it must never be delivered.
+fn forget_unmerged_commit(journal: &mut HashMap<String, Vec<UnackedCommit>>, group_id: &str, commit_ref: &str) {
+    if let Some(entries) = journal.get_mut(group_id) {
+        entries.retain(|entry| entry.commit_ref != commit_ref);
+    }
+}
+
+/// Lists every custom extension in `extensions` as a required capability,
+/// which OpenMLS demands before committing them to the group context.
+fn require_custom_extensions(extensions: &mut Extensions<GroupContext>) -> Result<(), MlsError> {
+    let (mut extension_types, proposal_types, credential_types) = match extensions.required_capabilities() {
+        Some(rc) => (
+            rc.extension_types().to_vec(),
+            rc.proposal_types().to_vec(),
+            rc.credential_types().to_vec(),
+        ),
+        None => (Vec::new(), Vec::new(), Vec::new()),
+    };
+    let missing: Vec<ExtensionType> = extensions
+        .iter()
+        .map(|e| e.extension_type())
+        .filter(|t| matches!(t, ExtensionType::Unknown(_)) && !extension_types.contains(t))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    extension_types.extend(missing);
+    extensions
+        .add_or_replace(Extension::RequiredCapabilities(RequiredCapabilitiesExtension::new(
+            &extension_types,
+            &proposal_types,
+            &credential_types,
+        )))
+        .map_err(|e| MlsError::generic(format!("Invalid required capabilities: {:?}", e)))?;
+    Ok(())
+}
+
+/// Hex ID of the external PSK in a PSK proposal (`None` for resumption PSKs).
+fn external_psk_id(proposal: &PreSharedKeyProposal) -> Option<String> {
+    // `PreSharedKeyProposal` has no public accessor, but it is a plain
+    // wrapper around the `PreSharedKeyId` on the wire.
+    let bytes = proposal.tls_serialize_detached().ok()?;
+    let id = PreSharedKeyId::tls_deserialize_exact(bytes).ok()?;
+    match id.psk() {
+        Psk::External(external) => Some(hex::encode(external.psk_id())),
+        _ => None,
+    }
+}
+
+/// Hex IDs of the external PSKs referenced by the group's queued proposals.
+fn pending_external_psk_ids(group: &MlsGroup) -> Vec<String> {
+    group
+        .pending_proposals()
+        .filter_map(|p| match p.proposal() {
+            Proposal::PreSharedKey(psk) => external_psk_id(psk),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Builds an `UnknownPsk` error naming the first queued PSK we don't hold.
+fn missing_psk_error(group: &MlsGroup, known: &HashSet<String>) -> MlsError {
+    let pending = pending_external_psk_ids(group);
+    let psk_id = pending
+        .iter()
+        .find(|id| !known.contains(*id))
+        .or(pending.first())
+        .cloned()
+        .unwrap_or_default();
+    MlsError::UnknownPsk { psk_id }
+}
+
+/// Fails with `UnknownPsk` if a queued PSK proposal references a PSK that
+/// has not been registered with `store_external_psk()`.
+fn check_pending_psks(group: &MlsGroup, known: &HashSet<String>) -> Result<(), MlsError> {
+    match pending_external_psk_ids(group)
+        .into_iter()
+        .find(|id| !known.contains(id))
+    {
+        Some(psk_id) => Err(MlsError::UnknownPsk { psk_id }),
+        None => Ok(()),
+    }
+}
+
+/// Matches the key store keys holding a group's state: OpenMLS includes
+/// the serialized group ID in each of them.
+fn group_key_matcher(group_id: &GroupId) -> Result<impl Fn(&[u8]) -> bool, MlsError> {
+    let needle = serde_json::to_vec(group_id).map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+    Ok(move |key: &[u8]| key.windows(needle.len()).any(|w| w == needle.as_slice()))
+}
+
+/// The group whose serialized ID (as matched by `group_key_matcher()`) a
+/// key store key contains, among `groups` (serialized ID → hex ID).  Finds
+/// the ID without trying each group in turn.
+fn key_group<'a>(key: &[u8], groups: &'a HashMap<Vec<u8>, String>) -> Option<&'a String> {
+    const START: &[u8] = br#"{"value":{"vec":["#;
+    const END: &[u8] = b"]}}";
+    let mut rest = key;
+    while let Some(start) = rest.windows(START.len()).position(|w| w == START) {
+        rest = &rest[start..];
+        if let Some(end) = rest.windows(END.len()).position(|w| w == END) {
+            if let Some(id) = groups.get(&rest[..end + END.len()]) {
+                return Some(id);
+            }
+        }
+        rest = &rest[START.len()..];
+    }
+    None
+}
+
+/// The storage key of a group's key store entries (see
+/// `GROUP_STORE_PREFIX`).
+fn group_store_key(group_id: &str) -> String {
+    format!("{}{}.json", GROUP_STORE_PREFIX, group_id)
+}
+
+/// The storage key of a group's audit log (see `AUDIT_LOG_PREFIX`).
+fn audit_log_key(group_id: &str) -> String {
+    format!("{}{}.json", AUDIT_LOG_PREFIX, group_id)
+}
+
+/// The group ID of a key returned by `group_store_key()`.
+fn group_store_id(key: &str) -> Option<&str> {
+    key.strip_prefix(GROUP_STORE_PREFIX)?.strip_suffix(".json")
+}
+
+/// Fails with `MissingProposal` if the commit `message` references
+/// proposals that are not in the group's queue, e.g. because the delivery
+/// service reordered them.  Decrypting the commit consumes its ratchet
+/// secret, so it is opened on a scratch copy of the group: the real one is
+/// left untouched and the commit can be retried once the proposals arrive.
+fn check_committed_proposals(
+    group_id: &str,
+    group: &MlsGroup,
+    crypto: &MlsProvider,
+    message: &ProtocolMessage,
+) -> Result<(), MlsError> {
+    let is_group_key = group_key_matcher(group.group_id())?;
+    let scratch = MlsProvider::default();
+    {
+        let values = crypto.storage().values.read().map_err(|_| MlsError::lock_poisoned())?;
+        let mut copy = scratch.storage().values.write().map_err(|_| MlsError::lock_poisoned())?;
+        copy.extend(
+            values
+                .iter()
+                .filter(|(key, _)| is_group_key(key))
+                .map(|(key, value)| (key.clone(), value.clone())),
         );
+    }
 
-        Ok(format!(r#"{{"commit":"{}"}}"#, commit_hex))
+    // Anything wrong with the commit itself is reported by the real run.
+    let Ok(Some(mut probe)) = MlsGroup::load(scratch.storage(), group.group_id()) else {
+        return Ok(());
+    };
+    let Ok(unverified) = probe.unprotect_message(&scratch, message.clone()) else {
+        return Ok(());
+    };
+
+    let queued: HashSet<&[u8]> = group
+        .pending_proposals()
+        .map(|p| p.proposal_reference_ref().as_slice())
+        .collect();
+    let proposal_refs: Vec<String> = unverified
+        .committed_proposals()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|p| match p {
+            ProposalOrRefIn::Reference(r) if !queued.contains(r.as_slice()) => Some(hex::encode(r.as_slice())),
+            _ => None,
+        })
+        .collect();
+    if proposal_refs.is_empty() {
+        Ok(())
+    } else {
+        Err(MlsError::MissingProposal {
+            group_id: group_id.to_string(),
+            proposal_refs,
+        })
     }
+}
 
-    /// Processes a Welcome message to join a group.  Returns the group ID.
-    pub fn process_welcome(&self, welcome_hex: String) -> Result<String, MlsError> {
-        let mut state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
+/// Parses `openmls_store.json` as a base64 key → base64 value map.
+fn parse_store(json: &[u8]) -> Result<HashMap<String, String>, MlsError> {
+    serde_json::from_slice(json)
+        .map(|wrapper: serde_json::Value| {
+            // The format is {"values":{"base64key":"base64val",...}}
+            wrapper
+                .get("values")
+                .and_then(|v| v.as_object())
+                .map(|obj| {
+                    obj.iter()
+                        .map(|(k, v)| (k.clone(), v.as_str().unwrap_or("").to_string()))
+                        .collect()
+                })
+                .unwrap_or_default()
+        })
+        .map_err(|e| MlsError::io(format!("Failed to parse store: {}", json_error(&e))))
+}
 
-        let bytes = hex::decode(&welcome_hex)
-            .map_err(|e| MlsError::serialization(format!("Invalid hex: {:?}", e)))?;
+// Passphrase-encrypted blobs:
+//   magic (4) | version (1) | Argon2id salt (16) | AES-256-GCM nonce (12) | ciphertext
+// The header up to the nonce is authenticated as associated data.
+const PASSPHRASE_BLOB_MAGIC: &[u8; 4] = b"MLSB";
+const PASSPHRASE_BLOB_VERSION: u8 = 1;
+const PASSPHRASE_SALT_LEN: usize = 16;
+const PASSPHRASE_NONCE_LEN: usize = 12;
+
+// Client backups (`export_backup()`):
+//   magic (4) | format version (1) | sealed length (8, big-endian) | passphrase-encrypted blob
+// The length lets a truncated backup be told apart from a wrong passphrase.
+const BACKUP_MAGIC: &[u8; 4] = b"MLSK";
+const BACKUP_VERSION: u8 = 1;
+
+// Invite bundles (`create_invite_bundle()`):
+//   magic (4) | format version (1) | GroupInfo length (4, big-endian) | GroupInfo message
+//   | ratchet tree length (4, big-endian) | ratchet tree
+const INVITE_BUNDLE_MAGIC: &[u8; 4] = b"MLSI";
+const INVITE_BUNDLE_VERSION: u8 = 1;
+
+/// Splits a `create_invite_bundle()` blob into the serialized GroupInfo
+/// message and ratchet tree.
+fn unwrap_invite_bundle(bundle: &[u8]) -> Result<(&[u8], &[u8]), MlsError> {
+    if bundle.len() < INVITE_BUNDLE_MAGIC.len() + 1 || !bundle.starts_with(INVITE_BUNDLE_MAGIC) {
+        return Err(MlsError::serialization("Not an invite bundle"));
+    }
+    let version = bundle[INVITE_BUNDLE_MAGIC.len()];
+    if version != INVITE_BUNDLE_VERSION {
+        return Err(MlsError::serialization(format!("Unsupported invite bundle version {}", version)));
+    }
 
-        let mls_msg = MlsMessageIn::tls_deserialize(&mut bytes.as_slice())
-            .map_err(|e| MlsError::serialization(format!("Invalid MLS message: {:?}", e)))?;
+    let mut rest = &bundle[INVITE_BUNDLE_MAGIC.len() + 1..];
+    let mut part = || -> Result<&[u8], MlsError> {
+        if rest.len() < 4 {
+            return Err(MlsError::serialization("Truncated invite bundle"));
+        }
+        let (length, tail) = rest.split_at(4);
+        let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+        if tail.len() < length {
+            return Err(MlsError::serialization("Truncated invite bundle"));
+        }
+        let (part, tail) = tail.split_at(length);
+        rest = tail;
+        Ok(part)
+    };
+    let group_info = part()?;
+    let ratchet_tree = part()?;
+    if !rest.is_empty() {
+        return Err(MlsError::serialization("Trailing bytes after invite bundle"));
+    }
+    Ok((group_info, ratchet_tree))
+}
+
+/// Checks the framing of an `export_backup()` blob and returns the
+/// passphrase-encrypted part.
+fn unwrap_backup(blob: &[u8]) -> Result<&[u8], MlsError> {
+    let header_len = BACKUP_MAGIC.len() + 1 + 8;
+    if blob.len() < BACKUP_MAGIC.len() + 1 || !blob.starts_with(BACKUP_MAGIC) {
+        return Err(MlsError::serialization("Not a client backup"));
+    }
+    let version = blob[BACKUP_MAGIC.len()];
+    if version != BACKUP_VERSION {
+        return Err(MlsError::UnsupportedBackupVersion {
+            version: version.into(),
+        });
+    }
+    if blob.len() < header_len {
+        return Err(MlsError::serialization("Truncated backup header"));
+    }
+
+    let (header, sealed) = blob.split_at(header_len);
+    let mut length = [0u8; 8];
+    length.copy_from_slice(&header[BACKUP_MAGIC.len() + 1..]);
+    let expected = u64::from_be_bytes(length);
+    if sealed.len() as u64 != expected {
+        return Err(MlsError::serialization(format!(
+            "Backup is {} bytes long, expected {}",
+            sealed.len(),
+            expected
+        )));
+    }
+    Ok(sealed)
+}
+
+fn passphrase_key(passphrase: &str, salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, MlsError> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| MlsError::crypto(format!("Key derivation failed: {:?}", e)))?;
+    Ok(key)
+}
+
+/// Encrypts `plaintext` under a key derived from `passphrase`.
+fn seal_with_passphrase(
+    crypto: &MlsProvider,
+    passphrase: &str,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, MlsError> {
+    let salt = passphrase_salt(crypto)?;
+    let key = passphrase_key(passphrase, &salt)?;
+    seal_with_key(crypto, &salt, &key, plaintext)
+}
+
+/// Decrypts a blob produced by `seal_with_passphrase()`.  A wrong passphrase
+/// and a tampered blob are indistinguishable; both fail authentication.
+/// The plaintext is wiped when dropped.
+fn open_with_passphrase(passphrase: &str, blob: &[u8]) -> Result<Zeroizing<Vec<u8>>, MlsError> {
+    let key = passphrase_key(passphrase, passphrase_blob_salt(blob)?)?;
+    open_with_key(&key, blob)
+}
+
+fn passphrase_salt(crypto: &MlsProvider) -> Result<[u8; PASSPHRASE_SALT_LEN], MlsError> {
+    crypto
+        .rand()
+        .random_array()
+        .map_err(|e| MlsError::crypto(format!("Failed to generate salt: {:?}", e)))
+}
+
+/// The salt a passphrase-encrypted blob's key was derived with.
+fn passphrase_blob_salt(blob: &[u8]) -> Result<&[u8], MlsError> {
+    let header_len = PASSPHRASE_BLOB_MAGIC.len() + 1 + PASSPHRASE_SALT_LEN;
+    if blob.len() < header_len + PASSPHRASE_NONCE_LEN || !blob.starts_with(PASSPHRASE_BLOB_MAGIC) {
+        return Err(MlsError::serialization("Not an encrypted state blob"));
+    }
+    if blob[PASSPHRASE_BLOB_MAGIC.len()] != PASSPHRASE_BLOB_VERSION {
+        return Err(MlsError::serialization(format!(
+            "Unsupported blob version {}",
+            blob[PASSPHRASE_BLOB_MAGIC.len()]
+        )));
+    }
+    Ok(&blob[PASSPHRASE_BLOB_MAGIC.len() + 1..header_len])
+}
+
+/// Encrypts `plaintext` under `key`, derived with `salt`, and a fresh
+/// nonce.
+fn seal_with_key(
+    crypto: &MlsProvider,
+    salt: &[u8; PASSPHRASE_SALT_LEN],
+    key: &[u8; 32],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, MlsError> {
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
+
+    let nonce: [u8; PASSPHRASE_NONCE_LEN] = crypto
+        .rand()
+        .random_array()
+        .map_err(|e| MlsError::crypto(format!("Failed to generate nonce: {:?}", e)))?;
+
+    let mut blob = PASSPHRASE_BLOB_MAGIC.to_vec();
+    blob.push(PASSPHRASE_BLOB_VERSION);
+    blob.extend_from_slice(salt);
+
+    let cipher = aes_gcm::Aes256Gcm::new_from_slice(key)
+        .map_err(|e| MlsError::crypto(format!("Invalid key: {:?}", e)))?;
+    let ciphertext = cipher
+        .encrypt(
+            aes_gcm::Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &blob,
+            },
+        )
+        .map_err(|e| MlsError::crypto(format!("Encryption failed: {:?}", e)))?;
+
+    blob.extend_from_slice(&nonce);
+    blob.extend_from_slice(&ciphertext);
+    Ok(blob)
+}
+
+/// Decrypts a passphrase-encrypted blob with `key`, already derived with
+/// the blob's salt.
+fn open_with_key(key: &[u8; 32], blob: &[u8]) -> Result<Zeroizing<Vec<u8>>, MlsError> {
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
+
+    passphrase_blob_salt(blob)?;
+    let header_len = PASSPHRASE_BLOB_MAGIC.len() + 1 + PASSPHRASE_SALT_LEN;
+    let (header, rest) = blob.split_at(header_len);
+    let (nonce, ciphertext) = rest.split_at(PASSPHRASE_NONCE_LEN);
+
+    let cipher = aes_gcm::Aes256Gcm::new_from_slice(key)
+        .map_err(|e| MlsError::crypto(format!("Invalid key: {:?}", e)))?;
+    cipher
+        .decrypt(
+            aes_gcm::Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| MlsError::crypto("Wrong passphrase or corrupted data"))
+}
+
+/// The key encrypting an encrypted client's stored values (see
+/// `new_encrypted()`).  Deriving it is deliberately slow, so it is derived
+/// once and reused for every value written, each under a fresh nonce.
+struct StorageKey {
+    passphrase: Zeroizing<String>,
+    /// The salt and key of the last value read or written.
+    derived: Mutex<Option<DerivedKey>>,
+}
+
+struct DerivedKey {
+    salt: [u8; PASSPHRASE_SALT_LEN],
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl StorageKey {
+    fn new(passphrase: String) -> Self {
+        Self {
+            passphrase: Zeroizing::new(passphrase),
+            derived: Mutex::new(None),
+        }
+    }
 
-        let welcome = match mls_msg.extract() {
-            MlsMessageBodyIn::Welcome(w) => w,
-            _ => return Err(MlsError::generic("Expected a Welcome message")),
+    /// Encrypts `plaintext`, deriving the key with a fresh salt if none has
+    /// been derived yet.
+    fn seal(&self, crypto: &MlsProvider, plaintext: &[u8]) -> Result<Vec<u8>, MlsError> {
+        let mut derived = self.derived.lock().map_err(|_| MlsError::lock_poisoned())?;
+        let derived = match &mut *derived {
+            Some(derived) => derived,
+            empty => {
+                let salt = passphrase_salt(crypto)?;
+                let key = passphrase_key(&self.passphrase, &salt)?;
+                empty.insert(DerivedKey { salt, key })
+            }
         };
+        seal_with_key(crypto, &derived.salt, &derived.key, plaintext)
+    }
 
-        let join_config = MlsGroupJoinConfig::builder()
-            .use_ratchet_tree_extension(true)
-            .build();
+    /// Decrypts `blob`, deriving the key again only if it was sealed with
+    /// another salt.  The new key is kept once it has opened the blob.
+    fn open(&self, blob: &[u8]) -> Result<Zeroizing<Vec<u8>>, MlsError> {
+        let salt = passphrase_blob_salt(blob)?;
+        let mut derived = self.derived.lock().map_err(|_| MlsError::lock_poisoned())?;
+        if let Some(derived) = derived.as_ref().filter(|d| d.salt == salt) {
+            return open_with_key(&derived.key, blob);
+        }
 
-        let staged = StagedWelcome::new_from_welcome(&state.crypto, &join_config, welcome, None)
-            .map_err(|e| MlsError::generic(format!("Failed to stage welcome: {:?}", e)))?;
+        let key = passphrase_key(&self.passphrase, salt)?;
+        let plaintext = open_with_key(&key, blob)?;
+        let salt = salt
+            .try_into()
+            .map_err(|_| MlsError::serialization("Invalid salt"))?;
+        *derived = Some(DerivedKey { salt, key });
+        Ok(plaintext)
+    }
+}
 
-        let group = staged
-            .into_group(&state.crypto)
-            .map_err(|e| MlsError::generic(format!("Failed to join group: {:?}", e)))?;
+/// Maps OpenMLS's epoch-related processing failures to `StaleMessage` or
+/// `FutureEpoch`.  Handshakes must match the current epoch exactly;
+/// application messages may be older, but only while the old epoch's secrets
+/// are still retained: pass the oldest retained epoch for those to report
+/// `EpochTooOld` instead of `StaleMessage`.
+fn epoch_mismatch_error<E>(
+    group: &MlsGroup,
+    message_epoch: GroupEpoch,
+    oldest_retained: Option<u64>,
+    error: &ProcessMessageError<E>,
+) -> Option<MlsError> {
+    match error {
+        ProcessMessageError::ValidationError(
+            ValidationError::WrongEpoch
+            | ValidationError::NoPastEpochData
+            | ValidationError::UnableToDecrypt(MessageDecryptionError::SecretTreeError(
+                SecretTreeError::TooDistantInThePast,
+            )),
+        ) => {
+            let expected = group.epoch().as_u64();
+            let got = message_epoch.as_u64();
+            if let Some(oldest_retained) = oldest_retained.filter(|_| got < expected) {
+                Some(MlsError::EpochTooOld {
+                    message_epoch: got,
+                    oldest_retained,
+                })
+            } else if got < expected {
+                Some(MlsError::StaleMessage { expected, got })
+            } else if got > expected {
+                Some(MlsError::FutureEpoch { expected, got })
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
 
-        let gid = hex::encode(group.group_id().as_slice());
-        state.groups.insert(gid.clone(), group);
-        Ok(gid)
+/// Whether a message claims to come from one of the group's external
+/// senders (as opposed to a member or a joiner).
+fn from_external_sender(message: &ProtocolMessage) -> bool {
+    match message {
+        ProtocolMessage::PublicMessage(m) => matches!(m.sender(), Sender::External(_)),
+        ProtocolMessage::PrivateMessage(_) => false,
     }
+}
 
-    /// Processes a commit message from another member.
-    pub fn process_commit(&self, group_id: String, commit_hex: String) -> Result<(), MlsError> {
-        let mut state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
+/// Whether a message is an external commit, i.e. someone joining from a
+/// GroupInfo (see `join_from_invite_bundle()` and `rejoin()`).
+fn is_external_commit(message: &ProtocolMessage) -> bool {
+    match message {
+        ProtocolMessage::PublicMessage(m) => matches!(m.sender(), Sender::NewMemberCommit),
+        ProtocolMessage::PrivateMessage(_) => false,
+    }
+}
+
+/// Reports an external commit made from a GroupInfo of a past epoch as
+/// `InviteBundleStale` rather than `StaleMessage`, so that the joiner can
+/// be told to fetch a fresh invite.
+fn stale_join_error(external_commit: bool, error: MlsError) -> MlsError {
+    match error {
+        MlsError::StaleMessage { expected, got } if external_commit => MlsError::InviteBundleStale {
+            bundle_epoch: got,
+            current_epoch: expected,
+        },
+        e => e,
+    }
+}
+
+/// Maps the rejection of an external sender's message to
+/// `UnauthorizedExternalSender`: the group lists no such sender, or the
+/// signature is not from the listed key.
+fn external_sender_error<E>(
+    group_id: &str,
+    external: bool,
+    error: &ProcessMessageError<E>,
+) -> Option<MlsError> {
+    match error {
+        ProcessMessageError::ValidationError(
+            ValidationError::UnauthorizedExternalSender
+            | ValidationError::NoExternalSendersExtension
+            | ValidationError::InvalidSignature,
+        ) if external => Some(MlsError::UnauthorizedExternalSender {
+            group_id: group_id.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+/// Maps signature, sender and decryption failures to `InvalidSignature`,
+/// `UnknownMember` and `DecryptionFailed`, with OpenMLS's error as detail.
+fn validation_error<E: std::fmt::Debug>(error: &ProcessMessageError<E>) -> Option<MlsError> {
+    let detail = format!("{:?}", error);
+    match error {
+        ProcessMessageError::ValidationError(
+            ValidationError::InvalidSignature | ValidationError::InvalidMembershipTag,
+        ) => Some(MlsError::InvalidSignature { detail }),
+        ProcessMessageError::ValidationError(ValidationError::UnknownMember) => {
+            Some(MlsError::UnknownMember { detail })
+        }
+        ProcessMessageError::ValidationError(ValidationError::UnableToDecrypt(_)) => {
+            Some(MlsError::DecryptionFailed { detail })
+        }
+        _ => None,
+    }
+}
+
+/// SHA-256 digest of a serialized MLS message, used as a cache key.
+fn message_digest(crypto: &MlsProvider, bytes: &[u8]) -> Result<Vec<u8>, MlsError> {
+    crypto
+        .crypto()
+        .hash(HashType::Sha2_256, bytes)
+        .map_err(|e| MlsError::crypto(format!("Failed to hash message: {:?}", e)))
+}
+
+/// Hashes `parts` into a `Fingerprint` for the group's current epoch.
+fn fingerprint(crypto: &MlsProvider, group: &MlsGroup, parts: &[&[u8]]) -> Result<Fingerprint, MlsError> {
+    let digest = crypto
+        .crypto()
+        .hash(HashType::Sha2_256, &parts.concat())
+        .map_err(|e| MlsError::crypto(format!("Failed to hash fingerprint: {:?}", e)))?;
+
+    // Six groups of five digits, each from five bytes of the digest.
+    let code = digest
+        .chunks_exact(5)
+        .take(6)
+        .map(|chunk| {
+            let n = chunk.iter().fold(0u64, |n, &b| (n << 8) | u64::from(b));
+            format!("{:05}", n % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    Ok(Fingerprint {
+        code,
+        epoch: group.epoch().as_u64(),
+    })
+}
+
+/// Reproducible clients for cross-implementation interop tests.
+#[cfg(feature = "deterministic-tests")]
+#[uniffi::export]
+impl MlsClient {
+    /// **INSECURE — test builds only.**  Creates a client whose randomness
+    /// is derived entirely from `seed`: the same seed and the same sequence
+    /// of calls yield byte-identical group IDs, key packages, commits,
+    /// welcomes and ciphertexts.  Anyone who knows the seed can recover
+    /// every key, so this only exists with the `deterministic-tests`
+    /// feature.
+    #[uniffi::constructor]
+    pub fn new_for_testing(storage_path: String, seed: Vec<u8>) -> Result<Self, MlsError> {
+        let crypto = MlsProvider::seeded(&seed)
+            .map_err(|e| MlsError::crypto(format!("Failed to seed provider: {:?}", e)))?;
+        Ok(Self::with_provider(Self::file_storage(storage_path), crypto, None))
+    }
+
+    /// **INSECURE — test builds only.**  Same as `new_for_testing()`: the
+    /// crypto provider's RNG is seeded from `seed`, so golden test vectors
+    /// can be generated and bugs reproduced byte for byte.
+    #[uniffi::constructor]
+    pub fn new_with_seed(storage_path: String, seed: Vec<u8>) -> Result<Self, MlsError> {
+        Self::new_for_testing(storage_path, seed)
+    }
+}
+
+/// The public operations counted by `get_metrics()`.
+#[derive(Clone, Copy)]
+enum Operation {
+    CreateGroup,
+    GenerateKeyPackage,
+    AddMember,
+    RemoveMember,
+    SelfUpdate,
+    ProcessWelcome,
+    ProcessCommit,
+    EncryptMessage,
+    DecryptMessage,
+}
+
+#[cfg(feature = "metrics")]
+impl Operation {
+    const ALL: [Operation; 9] = [
+        Operation::CreateGroup,
+        Operation::GenerateKeyPackage,
+        Operation::AddMember,
+        Operation::RemoveMember,
+        Operation::SelfUpdate,
+        Operation::ProcessWelcome,
+        Operation::ProcessCommit,
+        Operation::EncryptMessage,
+        Operation::DecryptMessage,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Operation::CreateGroup => "create_group",
+            Operation::GenerateKeyPackage => "generate_key_package",
+            Operation::AddMember => "add_member",
+            Operation::RemoveMember => "remove_member",
+            Operation::SelfUpdate => "self_update",
+            Operation::ProcessWelcome => "process_welcome",
+            Operation::ProcessCommit => "process_commit",
+            Operation::EncryptMessage => "encrypt_message",
+            Operation::DecryptMessage => "decrypt_message",
+        }
+    }
+}
+
+/// Latency histogram buckets: under 100 µs, 1 ms, 10 ms, 100 ms, 1 s, and
+/// slower.
+#[cfg(feature = "metrics")]
+const LATENCY_BUCKETS: usize = 6;
+
+#[cfg(feature = "metrics")]
+#[derive(Default)]
+struct OperationCounters {
+    calls: std::sync::atomic::AtomicU64,
+    failures: std::sync::atomic::AtomicU64,
+    total_micros: std::sync::atomic::AtomicU64,
+    max_micros: std::sync::atomic::AtomicU64,
+    latency: [std::sync::atomic::AtomicU64; LATENCY_BUCKETS],
+}
+
+/// Call counters of the operations in `Operation`.  Successful calls only
+/// touch atomics; the failure map is locked when a call fails.  Counters
+/// are updated independently, so a snapshot taken during a call may count
+/// the call but not yet its duration.
+#[derive(Default)]
+struct Metrics {
+    #[cfg(feature = "metrics")]
+    operations: [OperationCounters; Operation::ALL.len()],
+    #[cfg(feature = "metrics")]
+    failures: Mutex<HashMap<String, u64>>,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    fn record(&self, operation: Operation, elapsed: std::time::Duration, error: Option<&MlsError>) {
+        use std::sync::atomic::Ordering::Relaxed;
+
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let counters = &self.operations[operation as usize];
+        counters.calls.fetch_add(1, Relaxed);
+        counters.total_micros.fetch_add(micros, Relaxed);
+        counters.max_micros.fetch_max(micros, Relaxed);
+        let bucket = (1..LATENCY_BUCKETS)
+            .find(|&i| micros < 10u64.pow(i as u32 + 1))
+            .map_or(LATENCY_BUCKETS - 1, |i| i - 1);
+        counters.latency[bucket].fetch_add(1, Relaxed);
+
+        if let Some(error) = error {
+            counters.failures.fetch_add(1, Relaxed);
+            if let Ok(mut failures) = self.failures.lock() {
+                *failures.entry(error_variant(error)).or_default() += 1;
+            }
+        }
+    }
+
+    fn operations(&self) -> Vec<OperationMetrics> {
+        use std::sync::atomic::Ordering::Relaxed;
+
+        Operation::ALL
+            .iter()
+            .map(|&operation| {
+                let counters = &self.operations[operation as usize];
+                OperationMetrics {
+                    operation: operation.name().to_string(),
+                    calls: counters.calls.load(Relaxed),
+                    failures: counters.failures.load(Relaxed),
+                    total_micros: counters.total_micros.load(Relaxed),
+                    max_micros: counters.max_micros.load(Relaxed),
+                    latency_histogram: counters.latency.iter().map(|c| c.load(Relaxed)).collect(),
+                }
+            })
+            .collect()
+    }
+
+    fn reset(&self) {
+        use std::sync::atomic::Ordering::Relaxed;
+
+        for counters in &self.operations {
+            counters.calls.store(0, Relaxed);
+            counters.failures.store(0, Relaxed);
+            counters.total_micros.store(0, Relaxed);
+            counters.max_micros.store(0, Relaxed);
+            counters.latency.iter().for_each(|c| c.store(0, Relaxed));
+        }
+        if let Ok(mut failures) = self.failures.lock() {
+            failures.clear();
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+impl Metrics {
+    fn reset(&self) {}
+}
+
+/// The name of `error`'s variant, e.g. `"GroupNotFound"`.
+#[cfg(feature = "metrics")]
+fn error_variant(error: &MlsError) -> String {
+    let debug = format!("{:?}", error);
+    debug
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Which groups a save writes to storage.
+#[derive(Clone, Copy)]
+enum SaveScope<'a> {
+    /// Every group, each checked to load back (`save_state()`).
+    All,
+    /// The groups changed since the last save (autosave).
+    Changed,
+    /// One group (`save_group()`).
+    Group(&'a str),
+}
+
+/// Once a call returns, saves the state if the call left it dirty and
+/// autosave is on, then delivers the events recorded during the call.
+/// Groups whose epoch changed get their `updated_at` time set first, and
+/// merged commits are appended to the audit log whether or not autosave
+/// is on.  Declare it *before* locking the state: locals drop in reverse order, so
+/// the lock is released by the time it runs.
+struct CallFlush<'a>(&'a MlsClient);
+
+impl Drop for CallFlush<'_> {
+    fn drop(&mut self) {
+        let events = match self.0.state.write() {
+            Ok(mut state) => {
+                let mut events = std::mem::take(&mut state.pending_events);
+                state.note_epoch_changes(&events);
+                self.0.append_audit_entries(&state, &mut events);
+                events
+            }
+            Err(_) => return,
+        };
+        let _ = self.0.autosave();
+        let listener = match self.0.listener.lock() {
+            Ok(listener) => listener.clone(),
+            Err(_) => return,
+        };
+        if let Some(listener) = listener {
+            for event in events {
+                event.dispatch(listener.as_ref());
+            }
+        }
+    }
+}
+
+/// A write lock on the state with the identity of the group being worked
+/// on (or the one a new group is created for) selected, with the group's
+/// own signature key if it has one.  The default identity is swapped back
+/// in when the lock is released.
+struct IdentityGuard<'a>(RwLockWriteGuard<'a, MlsClientState>);
+
+impl std::ops::Deref for IdentityGuard<'_> {
+    type Target = MlsClientState;
+
+    fn deref(&self) -> &MlsClientState {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for IdentityGuard<'_> {
+    fn deref_mut(&mut self) -> &mut MlsClientState {
+        &mut self.0
+    }
+}
+
+impl Drop for IdentityGuard<'_> {
+    fn drop(&mut self) {
+        self.0.restore_identity();
+    }
+}
 
-        let bytes = hex::decode(&commit_hex)
-            .map_err(|e| MlsError::serialization(format!("Invalid hex: {:?}", e)))?;
+// Calls from several threads at once rely on this (see `MlsClient`).
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<MlsClient>();
+};
 
-        let mls_msg = MlsMessageIn::tls_deserialize(&mut bytes.as_slice())
-            .map_err(|e| MlsError::serialization(format!("Invalid MLS message: {:?}", e)))?;
+impl MlsClient {
+    fn flush_on_return(&self) -> CallFlush<'_> {
+        CallFlush(self)
+    }
 
-        let protocol_msg = mls_msg
-            .try_into_protocol_message()
-            .map_err(|e| MlsError::serialization(format!("Not a protocol message: {:?}", e)))?;
+    /// Runs `call`, counting it as a call of `operation` (see
+    /// `get_metrics()`).
+    fn timed<T>(&self, operation: Operation, call: impl FnOnce() -> Result<T, MlsError>) -> Result<T, MlsError> {
+        #[cfg(feature = "metrics")]
+        {
+            let started = std::time::Instant::now();
+            let result = call();
+            self.metrics.record(operation, started.elapsed(), result.as_ref().err());
+            result
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = operation;
+            call()
+        }
+    }
 
-        let MlsClientState { groups, crypto, .. } = &mut *state;
+    /// Saves the state if autosave is on and anything changed since the
+    /// last save, writing only the groups that changed.
+    fn autosave(&self) -> Result<(), MlsError> {
+        let dirty = {
+            let state = self.state.read().map_err(|_| MlsError::lock_poisoned())?;
+            state.autosave && (!state.dirty_groups.is_empty() || state.key_packages_dirty)
+        };
+        if dirty {
+            self.persist_state_padded(0, SaveScope::Changed)?;
+        }
+        Ok(())
+    }
 
-        let group = groups
-            .get_mut(&group_id)
-            .ok_or_else(|| MlsError::GroupNotFound {
-                group_id: group_id.clone(),
-            })?;
+    /// Write-locks the state with `group_id` resident, reloading it from
+    /// the key store if it was archived, and marks the group as changed.
+    /// The identity the group belongs to is selected (see
+    /// `select_group()`).
+    fn write_group(&self, group_id: &str) -> Result<IdentityGuard<'_>, MlsError> {
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        state.ensure_resident(group_id)?;
+        state.dirty_groups.insert(group_id.to_string());
+        let mut state = IdentityGuard(state);
+        state.select_group(group_id)?;
+        Ok(state)
+    }
 
-        let processed = group
-            .process_message(crypto, protocol_msg)
-            .map_err(|e| MlsError::generic(format!("Failed to process commit: {:?}", e)))?;
+    /// Write-locks the state with `identity` (the default one if `None`)
+    /// selected, for creating groups and key packages.
+    fn write_as(&self, identity: Option<&str>) -> Result<IdentityGuard<'_>, MlsError> {
+        let state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+        let mut state = IdentityGuard(state);
+        if let Some(name) = identity.filter(|name| state.identity_name.as_deref() != Some(*name)) {
+            state.select_identity(name)?;
+        }
+        Ok(state)
+    }
 
-        match processed.into_content() {
-            ProcessedMessageContent::StagedCommitMessage(staged) => {
-                group
-                    .merge_staged_commit(crypto, *staged)
-                    .map_err(|e| MlsError::generic(format!("Failed to merge commit: {:?}", e)))?;
-            }
-            ProcessedMessageContent::ProposalMessage(proposal) => {
-                // Proposals arriving standalone are stored for later commit.
-                let _ = group.store_pending_proposal(crypto.storage(), *proposal);
+    /// Read-locks the state with `group_id` resident.  Reloading an
+    /// archived group needs the write lock, so take that first and retry
+    /// in case another thread archives it again in between.
+    fn read_group(&self, group_id: &str) -> Result<RwLockReadGuard<'_, MlsClientState>, MlsError> {
+        loop {
+            let state = self.state.read().map_err(|_| MlsError::lock_poisoned())?;
+            if state.removed_groups.contains(group_id) {
+                return Err(MlsError::NoLongerMember {
+                    group_id: group_id.to_string(),
+                });
             }
-            _ => {
-                return Err(MlsError::generic(
-                    "Expected a Commit message but received a different type.",
-                ));
+            if !state.archived_groups.contains(group_id) {
+                return Ok(state);
             }
+            drop(state);
+            self.state
+                .write()
+                .map_err(|_| MlsError::lock_poisoned())?
+                .ensure_resident(group_id)?;
         }
-
-        Ok(())
     }
 
-    // ── Messaging ──────────────────────────────────────────────────────
-
-    /// Encrypts a plaintext message for the group.  Returns hex ciphertext.
-    pub fn encrypt_message(
+    /// Creates the signature key pair and credential behind `create_identity()`
+    /// and its variants.
+    fn init_identity(
         &self,
-        group_id: String,
-        plaintext: String,
+        name: String,
+        capabilities: ClientCapabilities,
+        application_id: Option<Vec<u8>>,
     ) -> Result<String, MlsError> {
-        let mut state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
+        // Validate up front so a bad codepoint doesn't leave us half-initialised.
+        capabilities.to_leaf_capabilities()?;
 
-        let MlsClientState {
-            groups,
-            crypto,
-            signer,
-            ..
-        } = &mut *state;
-        let signer = signer.as_ref().ok_or_else(MlsError::no_identity)?;
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
 
-        let group = groups
-            .get_mut(&group_id)
-            .ok_or_else(|| MlsError::GroupNotFound {
-                group_id: group_id.clone(),
-            })?;
+        // Generated through the provider so seeded test clients get
+        // reproducible keys.
+        let scheme = CIPHERSUITE.signature_algorithm();
+        let (private, public) = state
+            .crypto
+            .crypto()
+            .signature_key_gen(scheme)
+            .map_err(|e| MlsError::crypto(format!("Failed to create signature keys: {:?}", e)))?;
+        let signer = SigningKeyPair::from_raw(scheme, private, public);
 
-        let msg = group
-            .create_message(crypto, signer, plaintext.as_bytes())
-            .map_err(|e| MlsError::generic(format!("Encryption failed: {:?}", e)))?;
+        let credential = CredentialWithKey {
+            credential: Credential::new(CredentialType::Basic, name.clone().into_bytes()),
+            signature_key: signer.to_public_vec().into(),
+        };
 
-        let bytes = msg
-            .tls_serialize_detached()
-            .map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+        // Store the key pair in the crypto provider so that OpenMLS can find
+        // the private key when processing Welcome messages later.
+        signer
+            .store(state.crypto.storage())
+            .map_err(|e| MlsError::crypto(format!("Failed to store signer: {:?}", e)))?;
 
-        Ok(hex::encode(bytes))
-    }
+        state.signer = Some(signer);
+        state.credential = Some(credential);
+        state.identity_name = Some(name.clone());
+        state.capabilities = capabilities;
+        state.application_id = application_id;
 
-    /// Decrypts an application message from the group.  Returns plaintext.
-    ///
-    /// If the incoming bytes are actually a **Commit**, they are
-    /// automatically merged and an error is returned indicating so.
-    /// Use `process_commit()` if you want explicit commit handling.
-    pub fn decrypt_message(
-        &self,
-        group_id: String,
-        ciphertext_hex: String,
-    ) -> Result<String, MlsError> {
-        let mut state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
+        // Persist identity to disk so it survives restarts.
+        drop(state);
+        let _ = self.persist_state();
 
-        let bytes = hex::decode(&ciphertext_hex)
-            .map_err(|e| MlsError::serialization(format!("Invalid hex: {:?}", e)))?;
+        Ok(name)
+    }
 
-        let mls_msg = MlsMessageIn::tls_deserialize(&mut bytes.as_slice())
-            .map_err(|e| MlsError::serialization(format!("Invalid MLS message: {:?}", e)))?;
+    fn file_storage(storage_path: String) -> Box<dyn StorageBackend> {
+        Box::new(FileStorageBackend {
+            dir: PathBuf::from(storage_path),
+        })
+    }
 
-        let protocol_msg = mls_msg
-            .try_into_protocol_message()
-            .map_err(|e| MlsError::serialization(format!("Not a protocol message: {:?}", e)))?;
+    fn with_provider(
+        storage: Box<dyn StorageBackend>,
+        crypto: MlsProvider,
+        passphrase: Option<String>,
+    ) -> Self {
+        let client = Self {
+            state: Arc::new(RwLock::new(MlsClientState::new(crypto))),
+            storage,
+            storage_key: passphrase.map(StorageKey::new),
+            ephemeral: false,
+            listener: Mutex::new(None),
+            metrics: Metrics::default(),
+        };
 
-        let MlsClientState { groups, crypto, .. } = &mut *state;
+        // Attempt to restore a previously-persisted identity.
+        let _ = client.load_state();
 
-        let group = groups
-            .get_mut(&group_id)
-            .ok_or_else(|| MlsError::GroupNotFound {
-                group_id: group_id.clone(),
-            })?;
+        client
+    }
 
-        let processed = group
-            .process_message(crypto, protocol_msg)
-            .map_err(|e| MlsError::generic(format!("Decryption failed: {:?}", e)))?;
+    fn persist_state(&self) -> Result<SaveReport, MlsError> {
+        self.persist_state_padded(0, SaveScope::All)
+    }
 
-        match processed.into_content() {
-            ProcessedMessageContent::ApplicationMessage(app) => {
-                String::from_utf8(app.into_bytes())
-                    .map_err(|e| MlsError::serialization(format!("Invalid UTF-8: {:?}", e)))
-            }
-            ProcessedMessageContent::StagedCommitMessage(staged) => {
-                // Auto-merge so the group doesn't get stuck.
-                group
-                    .merge_staged_commit(crypto, *staged)
-                    .map_err(|e| MlsError::generic(format!("Failed to merge commit: {:?}", e)))?;
-                Err(MlsError::generic(
-                    "Received a Commit, not an application message. \
-                     The commit has been merged. Group epoch advanced.",
-                ))
-            }
-            ProcessedMessageContent::ProposalMessage(proposal) => {
-                let _ = group.store_pending_proposal(crypto.storage(), *proposal);
-                Err(MlsError::generic(
-                    "Received a Proposal, not an application message. \
-                     The proposal has been stored.",
-                ))
+    /// Like `persist_state()`, but writes only the groups `scope` selects,
+    /// and pads the saved key store to at least `min_store_len` bytes so
+    /// that it overwrites a longer previous one in full (see
+    /// `secure_wipe_group()`).
+    fn persist_state_padded(&self, min_store_len: usize, scope: SaveScope) -> Result<SaveReport, MlsError> {
+        let result = self.write_state(min_store_len, scope);
+        match &result {
+            Ok(report) => {
+                log::info!("Saved state with {} groups", report.saved_groups.len());
+                for failure in &report.failed_groups {
+                    log::warn!("Failed to save group {}: {}", failure.group_id, failure.reason);
+                }
             }
-            _ => Err(MlsError::generic("Unknown MLS message type received.")),
+            Err(e) => log::warn!("Failed to save state: {}", e),
         }
+        result
     }
 
-    // ── Group queries ──────────────────────────────────────────────────
+    fn write_state(&self, min_store_len: usize, scope: SaveScope) -> Result<SaveReport, MlsError> {
+        if self.ephemeral {
+            return Ok(SaveReport {
+                saved_groups: Vec::new(),
+                removed_groups: Vec::new(),
+                failed_groups: Vec::new(),
+            });
+        }
 
-    /// Lists hex-encoded IDs of all groups currently in memory.
-    pub fn list_active_groups(&self) -> Vec<String> {
-        self.state
-            .lock()
-            .map(|s| s.groups.keys().cloned().collect())
-            .unwrap_or_default()
-    }
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
 
-    /// Returns JSON with group metadata: group_id, epoch, member_count.
-    pub fn get_group_info(&self, group_id: String) -> Result<String, MlsError> {
-        let state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
+        // Never overwrite newer on-disk state with a client that refused to
+        // load it.
+        if let Some((found, expected_at_least)) = state.rollback {
+            return Err(MlsError::StateRollbackDetected {
+                found,
+                expected_at_least,
+            });
+        }
+        if let Some(version) = state.newer_state_version {
+            return Err(MlsError::UnsupportedStateVersion { version });
+        }
+        if state.storage_locked {
+            return Err(MlsError::WrongPassphrase {
+                msg: "Refusing to overwrite stored state that could not be decrypted".into(),
+            });
+        }
 
-        let group = state
-            .groups
-            .get(&group_id)
-            .ok_or_else(|| MlsError::GroupNotFound {
-                group_id: group_id.clone(),
-            })?;
+        // Groups without their own key store entry yet must be written
+        // whatever the scope: the shared entry no longer holds them.
+        let known: HashSet<String> = state.groups.keys().chain(&state.archived_groups).cloned().collect();
+        let write: HashSet<String> = known
+            .iter()
+            .filter(|id| {
+                !state.saved_group_files.contains(*id)
+                    || match scope {
+                        SaveScope::All => true,
+                        SaveScope::Changed => state.dirty_groups.contains(*id),
+                        SaveScope::Group(group_id) => group_id == id.as_str(),
+                    }
+            })
+            .cloned()
+            .collect();
 
-        Ok(format!(
-            r#"{{"group_id":"{}","epoch":{},"member_count":{}}}"#,
-            group_id,
-            group.epoch().as_u64(),
-            group.members().count()
-        ))
-    }
+        // ── 1. Save identity metadata ──────────────────────────────────
+        let generation = state.generation + 1;
+        let (persisted, report) = state.persisted_state(generation, &|id| write.contains(id))?;
 
-    /// Returns the list of members (leaf index + credential identity bytes).
-    pub fn get_members(&self, group_id: String) -> Result<Vec<MemberInfo>, MlsError> {
-        let state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
+        let json = serde_json::to_vec_pretty(&persisted)
+            .map(Zeroizing::new)
+            .map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
 
-        let group = state
-            .groups
-            .get(&group_id)
-            .ok_or_else(|| MlsError::GroupNotFound {
-                group_id: group_id.clone(),
-            })?;
+        self.write_storage_file(&state, STATE_KEY, &json)
+            .map_err(|e| MlsError::io(format!("Failed to write state: {:?}", e)))?;
 
-        let members: Vec<MemberInfo> = group
-            .members()
-            .map(|m| MemberInfo {
-                index: m.index.u32(),
-                identity: m.credential.serialized_content().to_vec(),
-            })
+        // ── 2. Save the OpenMLS key store (groups, secrets, etc.) ──────
+        // Same format as `MemoryStorage::save_to_file()`.
+        let (shared, by_group) = state.group_split_store_values(&write)?;
+        for (group_id, values) in &by_group {
+            let json = serde_json::to_vec_pretty(&serde_json::json!({ "values": values }))
+                .map(Zeroizing::new)
+                .map_err(|e| MlsError::serialization(format!("Failed to serialize key store: {:?}", e)))?;
+            self.write_storage_file(&state, &group_store_key(group_id), &json)
+                .map_err(|e| MlsError::io(format!("Failed to save group {}: {:?}", group_id, e)))?;
+        }
+        // Entries of groups that are gone, or any not written above once
+        // the shared entry holds them again.
+        let mut stale: Vec<String> = state
+            .saved_group_files
+            .iter()
+            .filter(|id| !by_group.contains_key(*id) && !known.contains(*id))
+            .cloned()
             .collect();
+        if matches!(scope, SaveScope::All) {
+            for key in self.storage.list(GROUP_STORE_PREFIX.into())? {
+                let Some(id) = group_store_id(&key) else { continue };
+                if !by_group.contains_key(id) && !stale.iter().any(|s| s == id) {
+                    stale.push(id.to_string());
+                }
+            }
+        }
 
-        Ok(members)
-    }
+        let mut store = serde_json::to_vec_pretty(&serde_json::json!({ "values": shared }))
+            .map(Zeroizing::new)
+            .map_err(|e| MlsError::serialization(format!("Failed to serialize key store: {:?}", e)))?;
+        // Trailing whitespace is still valid JSON.
+        if store.len() < min_store_len {
+            store.resize(min_store_len, b' ');
+        }
 
-    // ── Persistence ────────────────────────────────────────────────────
+        self.write_storage_file(&state, KEY_STORE_KEY, &store)
+            .map_err(|e| MlsError::io(format!("Failed to save key store: {:?}", e)))?;
+        for group_id in &stale {
+            self.storage.delete(group_store_key(group_id))?;
+            state.saved_group_files.remove(group_id);
+        }
+        state.saved_group_files.extend(by_group.into_keys());
 
-    /// Persists ALL state to disk: identity keys, group secrets, ratchet
-    /// trees — everything OpenMLS needs to resume after a cold restart.
-    ///
-    /// Writes two files:
-    /// - `state.json`: identity metadata
-    /// - `openmls_store.json`: full OpenMLS key store (via MemoryStorage)
-    pub fn save_state(&self) -> Result<(), MlsError> {
-        self.persist_state()
-    }
+        // ── 3. Advance the high-water mark ─────────────────────────────
+        self.storage
+            .put(HIGH_WATER_MARK_KEY.into(), generation.to_string().into_bytes())
+            .map_err(|e| MlsError::io(format!("Failed to write high-water mark: {:?}", e)))?;
+        state.generation = generation;
+        state.dirty_groups.retain(|id| known.contains(id) && !write.contains(id));
+        state.key_packages_dirty = false;
 
-    /// Loads previously-persisted state from disk, including full group
-    /// recovery.  Called automatically by the constructor.
-    pub fn load_state(&self) -> Result<(), MlsError> {
-        self.restore_state()
+        Ok(report)
     }
 
-    /// Lists group IDs that are currently active (in memory).
-    /// After `load_state()`, this includes groups restored from disk.
-    pub fn list_saved_groups(&self) -> Result<Vec<String>, MlsError> {
-        let path = self.storage_path.join("state.json");
-        if !path.exists() {
-            return Ok(Vec::new());
-        }
+    /// Stores a value, encrypting it if this client has a passphrase.
+    fn write_storage_file(
+        &self,
+        state: &MlsClientState,
+        name: &str,
+        contents: &[u8],
+    ) -> Result<(), MlsError> {
+        let contents = match &self.storage_key {
+            Some(key) => key.seal(&state.crypto, contents)?,
+            None => contents.to_vec(),
+        };
+        self.storage.put(name.into(), contents)
+    }
 
-        let json = fs::read_to_string(&path)
-            .map_err(|e| MlsError::io(format!("Failed to read state file: {:?}", e)))?;
+    /// Reads a stored value, decrypting it if this client has a passphrase.
+    /// `None` if nothing is stored under `name`.  The contents are wiped
+    /// when dropped.
+    fn read_storage_file(&self, name: &str) -> Result<Option<Zeroizing<Vec<u8>>>, MlsError> {
+        let Some(contents) = self.storage.get(name.into())? else {
+            return Ok(None);
+        };
 
-        let persisted: PersistedState = serde_json::from_str(&json)
-            .map_err(|e| MlsError::serialization(format!("Corrupt state file: {:?}", e)))?;
+        match &self.storage_key {
+            Some(key) => key.open(&contents)
+                .map(Some)
+                .map_err(|e| match e {
+                    MlsError::CryptoError { msg } => MlsError::WrongPassphrase { msg },
+                    e => e,
+                }),
+            None if contents.starts_with(PASSPHRASE_BLOB_MAGIC) => Err(MlsError::WrongPassphrase {
+                msg: format!("{} is encrypted; open it with new_encrypted()", name),
+            }),
+            None => Ok(Some(Zeroizing::new(contents))),
+        }
+    }
 
-        Ok(persisted.groups.into_iter().map(|g| g.group_id).collect())
+    /// The audit log of `group_id`, oldest entry first; empty if none was
+    /// written.  `SerializationError` if it was tampered with beyond
+    /// parsing.
+    fn read_audit_log(&self, group_id: &str) -> Result<Vec<AuditEntry>, MlsError> {
+        match self.read_storage_file(&audit_log_key(group_id))? {
+            Some(json) => serde_json::from_slice(&json)
+                .map_err(|e| MlsError::serialization(format!("Corrupt audit log: {}", json_error(&e)))),
+            None => Ok(Vec::new()),
+        }
     }
-}
 
-// ── Private helpers (not exported via UniFFI) ──────────────────────────────
+    /// Moves the `CommitMerged` events out of `events` and appends their
+    /// entries, chained, to the audit logs of their groups.  Failures are
+    /// logged: the commits are merged already.
+    fn append_audit_entries(&self, state: &MlsClientState, events: &mut Vec<MlsEvent>) {
+        let (merged, rest): (Vec<_>, Vec<_>) = std::mem::take(events)
+            .into_iter()
+            .partition(|event| matches!(event, MlsEvent::CommitMerged { .. }));
+        *events = rest;
+
+        let mut by_group: Vec<(String, Vec<AuditEntry>)> = Vec::new();
+        for event in merged {
+            let MlsEvent::CommitMerged { entry } = event else { continue };
+            match by_group.iter_mut().find(|(group_id, _)| *group_id == entry.group_id) {
+                Some((_, entries)) => entries.push(entry),
+                None => by_group.push((entry.group_id.clone(), vec![entry])),
+            }
+        }
+        if self.ephemeral || state.storage_locked || state.newer_state_version.is_some() {
+            return;
+        }
 
-impl MlsClient {
-    fn persist_state(&self) -> Result<(), MlsError> {
-        let state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
+        for (group_id, entries) in by_group {
+            let result = self.read_audit_log(&group_id).and_then(|mut log| {
+                for mut entry in entries {
+                    entry.previous_hash = log.last().map(|e| e.entry_hash.clone()).unwrap_or_default();
+                    entry.entry_hash = audit_entry_hash(&state.crypto, &entry)?;
+                    log.push(entry);
+                }
+                let json = serde_json::to_vec_pretty(&log).map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+                self.write_storage_file(state, &audit_log_key(&group_id), &json)
+            });
+            if let Err(e) = result {
+                log::warn!("Failed to append to the audit log of group {}: {}", group_id, e);
+            }
+        }
+    }
 
-        fs::create_dir_all(&self.storage_path)
-            .map_err(|e| MlsError::io(format!("Failed to create directory: {:?}", e)))?;
+    fn read_high_water_mark(&self) -> Result<u64, MlsError> {
+        let Some(value) = self.storage.get(HIGH_WATER_MARK_KEY.into())? else {
+            return Ok(0);
+        };
+        String::from_utf8_lossy(&value)
+            .trim()
+            .parse()
+            .map_err(|e| MlsError::serialization(format!("Corrupt high-water mark: {:?}", e)))
+    }
 
-        // ── 1. Save identity metadata ──────────────────────────────────
-        let identity = match (&state.signer, &state.identity_name) {
-            (Some(signer), Some(name)) => {
-                let signer_json = serde_json::to_string(signer)
-                    .map_err(|e| MlsError::serialization(format!("Failed to serialize signer: {:?}", e)))?;
-                Some(PersistedIdentity {
-                    name: name.clone(),
-                    signer_json,
-                })
+    fn restore_state(&self, accept_rollback: bool) -> Result<(), MlsError> {
+        // Read and decrypt everything before touching in-memory state, so a
+        // wrong passphrase leaves the client as it was.
+        let StateBackup { state: persisted, store } = match self.read_persisted_state() {
+            Ok(Some(loaded)) => loaded,
+            Ok(None) => {
+                // Fresh storage: keep counting from the high-water mark so
+                // the next save is not mistaken for a rollback.
+                let high_water_mark = self.read_high_water_mark()?;
+                let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+                state.generation = state.generation.max(high_water_mark);
+                log::info!("No stored state found");
+                return Ok(());
+            }
+            Err(e @ MlsError::WrongPassphrase { .. }) => {
+                let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+                state.storage_locked = true;
+                log::warn!("Stored state could not be decrypted; saving is disabled");
+                return Err(e);
+            }
+            Err(MlsError::UnsupportedStateVersion { version }) => {
+                let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+                state.newer_state_version = Some(version);
+                log::warn!("Stored state has newer format version {}; saving is disabled", version);
+                return Err(MlsError::UnsupportedStateVersion { version });
+            }
+            Err(e) => {
+                log::warn!("Failed to read stored state: {}", e);
+                return Err(e);
             }
-            _ => None,
         };
 
-        let groups: Vec<PersistedGroupMeta> = state
-            .groups
-            .iter()
-            .map(|(id, g)| PersistedGroupMeta {
-                group_id: id.clone(),
-                epoch: g.epoch().as_u64(),
-            })
-            .collect();
-
-        let persisted = PersistedState { identity, groups };
+        let generation = persisted.generation;
+        let group_count = persisted.groups.len();
+        let saved: Vec<String> = persisted.groups.iter().map(|g| g.group_id.clone()).collect();
+        let result = self.apply_persisted_state(persisted, store, accept_rollback);
+        if result.is_ok() {
+            let groups: HashSet<&str> = saved.iter().map(String::as_str).collect();
+            if let Err(e) = self.quarantine_files(&groups) {
+                log::warn!("Failed to move aside unusable stored files: {}", e);
+            }
+            let group_files = self.storage.list(GROUP_STORE_PREFIX.into())?;
+            let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+            state.saved_group_files = group_files
+                .iter()
+                .filter_map(|key| group_store_id(key))
+                .map(str::to_string)
+                .collect();
+        }
+        match &result {
+            Ok(()) => log::info!("Loaded state generation {} with {} groups", generation, group_count),
+            Err(e) => log::warn!("Failed to load state: {}", e),
+        }
+        result
+    }
 
-        let json = serde_json::to_string_pretty(&persisted)
-            .map_err(|e| MlsError::serialization(format!("{:?}", e)))?;
+    /// The stored state and key store, or `None` if nothing was saved yet.
+    fn read_persisted_state(&self) -> Result<Option<StateBackup>, MlsError> {
+        let Some(json) = self.read_storage_file(STATE_KEY)? else {
+            return Ok(None);
+        };
 
-        fs::write(self.storage_path.join("state.json"), json)
-            .map_err(|e| MlsError::io(format!("Failed to write state: {:?}", e)))?;
+        let persisted = serde_json::from_slice::<PersistedState>(&json)
+            .map_err(|e| MlsError::serialization(format!("Corrupt state: {}", json_error(&e))))?
+            .migrate()?;
 
-        // ── 2. Save full OpenMLS key store (groups, secrets, etc.) ─────
-        let store_path = self.storage_path.join("openmls_store.json");
-        let store_file = File::create(&store_path)
-            .map_err(|e| MlsError::io(format!("Failed to create store file: {:?}", e)))?;
-        state
-            .crypto
-            .storage()
-            .save_to_file(&store_file)
-            .map_err(|e| MlsError::io(format!("Failed to save key store: {:?}", e)))?;
+        let mut store = match self.read_storage_file(KEY_STORE_KEY)? {
+            Some(store) => parse_store(&store)?,
+            None => HashMap::new(),
+        };
+        // One unreadable group must not keep the others from loading; its
+        // file is moved aside once the rest is loaded.
+        for key in self.storage.list(GROUP_STORE_PREFIX.into())? {
+            if group_store_id(&key).is_none() {
+                continue;
+            }
+            match self.read_storage_file(&key).and_then(|s| s.map(|s| parse_store(&s)).transpose()) {
+                Ok(Some(group_store)) => store.extend(group_store),
+                Ok(None) => {}
+                Err(e) => log::warn!("Skipping unreadable {}: {}", key, e),
+            }
+        }
 
-        Ok(())
+        Ok(Some(StateBackup {
+            state: persisted,
+            store,
+        }))
     }
 
-    fn restore_state(&self) -> Result<(), MlsError> {
-        let state_path = self.storage_path.join("state.json");
-        if !state_path.exists() {
-            return Ok(());
+    /// Moves the group key store files that cannot be read or whose group
+    /// is not in `groups`, and the audit logs that cannot be read, under
+    /// `CORRUPT_PREFIX`, as they were stored.  Returns their keys.
+    fn quarantine_files(&self, groups: &HashSet<&str>) -> Result<Vec<String>, MlsError> {
+        let mut unusable = Vec::new();
+        for key in self.storage.list(GROUP_STORE_PREFIX.into())? {
+            let Some(group_id) = group_store_id(&key) else { continue };
+            let readable = self
+                .read_storage_file(&key)
+                .and_then(|s| s.map(|s| parse_store(&s)).transpose())
+                .is_ok();
+            if !readable || !groups.contains(group_id) {
+                unusable.push(key);
+            }
+        }
+        for key in self.storage.list(AUDIT_LOG_PREFIX.into())? {
+            let readable = self
+                .read_storage_file(&key)
+                .and_then(|s| {
+                    s.map(|s| serde_json::from_slice::<Vec<AuditEntry>>(&s))
+                        .transpose()
+                        .map_err(|e| MlsError::serialization(json_error(&e)))
+                })
+                .is_ok();
+            if !readable {
+                unusable.push(key);
+            }
         }
 
-        let json = fs::read_to_string(&state_path)
-            .map_err(|e| MlsError::io(format!("Failed to read state: {:?}", e)))?;
-
-        let persisted: PersistedState = serde_json::from_str(&json)
-            .map_err(|e| MlsError::serialization(format!("Corrupt state: {:?}", e)))?;
+        for key in &unusable {
+            if let Some(contents) = self.storage.get(key.clone())? {
+                self.storage.put(format!("{}{}", CORRUPT_PREFIX, key), contents)?;
+            }
+            self.storage.delete(key.clone())?;
+            log::warn!("Moved unusable stored file {} to {}", key, CORRUPT_PREFIX);
+        }
+        Ok(unusable)
+    }
 
-        let mut state = self.state.lock().map_err(|_| MlsError::lock_poisoned())?;
+    /// Replaces the whole client state with `backup` and saves it.
+    fn restore_backup(&self, backup: StateBackup) -> Result<(), MlsError> {
+        {
+            let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+            let crypto = std::mem::take(&mut state.crypto);
+            crypto
+                .storage()
+                .values
+                .write()
+                .map_err(|_| MlsError::lock_poisoned())?
+                .clear();
+            let encoding = state.encoding;
+            let max_resident_groups = state.max_resident_groups;
+            let message_framing = state.message_framing;
+            let message_cache_size = state.message_cache_size;
+            let autosave = state.autosave;
+            let commit_policy = state.commit_policy.take();
+            *state = MlsClientState::new(crypto);
+            state.encoding = encoding;
+            state.max_resident_groups = max_resident_groups;
+            state.message_framing = message_framing;
+            state.message_cache_size = message_cache_size;
+            state.autosave = autosave;
+            state.commit_policy = commit_policy;
+        }
 
-        // ── 1. Restore the OpenMLS key store from disk ────────────────
-        let store_path = self.storage_path.join("openmls_store.json");
-        if store_path.exists() {
-            let store_file = File::open(&store_path)
-                .map_err(|e| MlsError::io(format!("Failed to open store file: {:?}", e)))?;
+        self.apply_persisted_state(backup.state, backup.store, true)?;
+        self.persist_state().map(|_| ())
+    }
 
+    /// Loads a persisted state and key store into memory, subject to the
+    /// rollback check against the high-water mark.
+    fn apply_persisted_state(
+        &self,
+        persisted: PersistedState,
+        store: HashMap<String, String>,
+        accept_rollback: bool,
+    ) -> Result<(), MlsError> {
+        let high_water_mark = self.read_high_water_mark()?;
+
+        let mut state = self.state.write().map_err(|_| MlsError::lock_poisoned())?;
+
+        let rolled_back = persisted.generation < high_water_mark;
+        if rolled_back && !accept_rollback {
+            state.rollback = Some((persisted.generation, high_water_mark));
+            return Err(MlsError::StateRollbackDetected {
+                found: persisted.generation,
+                expected_at_least: high_water_mark,
+            });
+        }
+        state.rollback = None;
+        state.storage_locked = false;
+        state.newer_state_version = None;
+        state.generation = persisted.generation.max(high_water_mark);
+        state
+            .pending_self_updates
+            .extend(persisted.pending_self_updates.iter().cloned());
+        state.frozen_groups.extend(persisted.frozen_groups.clone());
+        state
+            .external_psks
+            .extend(persisted.external_psks.iter().cloned());
+        state
+            .used_key_packages
+            .extend(persisted.used_key_packages.iter().cloned());
+        state
+            .removed_groups
+            .extend(persisted.removed_groups.iter().cloned());
+        state.send_generations.extend(persisted.send_generations.clone());
+        state.seen_generations.extend(persisted.seen_generations.clone());
+        state.send_sequences.extend(persisted.send_sequences.clone());
+        state.reinits.extend(persisted.reinits.clone());
+        state
+            .desynced_groups
+            .extend(persisted.desynced_groups.iter().cloned());
+        state.max_members.extend(persisted.max_members.clone());
+        state.joined_welcomes.extend(persisted.joined_welcomes.clone());
+        state.commit_journal.extend(persisted.commit_journal.clone());
+        state.commit_sequence = persisted.commit_sequence;
+        state.group_activity.extend(persisted.group_activity.clone());
+
+        // ── 1. Restore the OpenMLS key store ──────────────────────────
+        {
             // MemoryStorage::load_from_file requires &mut self, but we only
             // have &MemoryStorage via the provider. Since `values` is pub and
             // behind RwLock, we can load manually:
-            let reader = std::io::BufReader::new(store_file);
-            let ser_store: std::collections::HashMap<String, String> =
-                serde_json::from_reader(reader)
-                    .map(|wrapper: serde_json::Value| {
-                        // The format is {"values":{"base64key":"base64val",...}}
-                        wrapper
-                            .get("values")
-                            .and_then(|v| v.as_object())
-                            .map(|obj| {
-                                obj.iter()
-                                    .map(|(k, v)| {
-                                        (k.clone(), v.as_str().unwrap_or("").to_string())
-                                    })
-                                    .collect()
-                            })
-                            .unwrap_or_default()
-                    })
-                    .map_err(|e| MlsError::io(format!("Failed to parse store: {:?}", e)))?;
-
-            // Decode base64 and insert into the storage's RwLock
             use base64::Engine;
             let storage = state.crypto.storage();
             let mut values = storage.values.write().map_err(|_| MlsError::lock_poisoned())?;
-            for (key_b64, val_b64) in &ser_store {
+            for (key_b64, val_b64) in &store {
                 if let (Ok(key), Ok(val)) = (
                     base64::prelude::BASE64_STANDARD.decode(key_b64),
                     base64::prelude::BASE64_STANDARD.decode(val_b64),
@@ -727,27 +12142,29 @@ impl MlsClient {
                     values.insert(key, val);
                 }
             }
-            drop(values);
         }
 
         // ── 2. Restore identity ───────────────────────────────────────
         if let Some(id) = persisted.identity {
-            let signer: SignatureKeyPair = serde_json::from_str(&id.signer_json)
-                .map_err(|e| MlsError::serialization(format!("Failed to deserialize signer: {:?}", e)))?;
-
-            // The key store was already loaded above, but re-register just
-            // in case it was created fresh (e.g. store file was deleted).
+            let identity = restored_identity(&state.crypto, &id)?;
+            state.signer = identity.signer;
+            state.credential = identity.credential;
+            state.identity_name = identity.identity_name;
+            state.capabilities = id.capabilities;
+            state.application_id = id.application_id;
+        }
+        for (name, id) in &persisted.identities {
+            let identity = restored_identity(&state.crypto, id)?;
+            state.identities.insert(name.clone(), identity);
+        }
+        state.group_identities.extend(persisted.group_identities.clone());
+        for (group_id, signer_json) in &persisted.group_signers {
+            let signer: SigningKeyPair = serde_json::from_str(signer_json)
+                .map_err(|e| MlsError::serialization(format!("Failed to deserialize signer: {}", json_error(&e))))?;
             let _ = signer.store(state.crypto.storage());
-
-            let credential = CredentialWithKey {
-                credential: Credential::new(CredentialType::Basic, id.name.clone().into_bytes()),
-                signature_key: signer.to_public_vec().into(),
-            };
-
-            state.signer = Some(signer);
-            state.credential = Some(credential);
-            state.identity_name = Some(id.name);
+            state.group_signers.insert(group_id.clone(), signer);
         }
+        state.group_credentials.extend(persisted.group_credentials.clone());
 
         // ── 3. Restore groups from the loaded key store ───────────────
         for group_meta in &persisted.groups {
@@ -755,21 +12172,39 @@ impl MlsClient {
                 .map_err(|e| MlsError::serialization(format!("Invalid group ID hex: {:?}", e)))?;
 
             let group_id = GroupId::from_slice(&gid_bytes);
+            if let Some(times) = group_meta.times {
+                state.group_times.insert(group_meta.group_id.clone(), times);
+            }
+            if let Some(settings) = group_meta.settings {
+                state.group_settings.insert(group_meta.group_id.clone(), settings);
+            }
+
+            // Past the resident limit, leave groups in the key store until
+            // they are first used.
+            if state.max_resident_groups != 0 && state.groups.len() >= state.max_resident_groups {
+                if rolled_back {
+                    state.pending_self_updates.insert(group_meta.group_id.clone());
+                }
+                state.archived_groups.insert(group_meta.group_id.clone());
+                state.archived_epochs.insert(group_meta.group_id.clone(), group_meta.epoch);
+                continue;
+            }
 
             match MlsGroup::load(state.crypto.storage(), &group_id) {
                 Ok(Some(group)) => {
-                    state.groups.insert(group_meta.group_id.clone(), group);
+                    if rolled_back {
+                        state.pending_self_updates.insert(group_meta.group_id.clone());
+                    }
+                    state.insert_group(group_meta.group_id.clone(), group);
                 }
                 Ok(None) => {
-                    // Group data was not found in the store — skip silently.
+                    // Group data was not found in the store — skip it.
                     // This can happen if the store was corrupted or truncated.
+                    log::warn!("Group {} is missing from the key store", group_meta.group_id);
                 }
                 Err(e) => {
                     // Log but don't fail — partial restore is better than none.
-                    eprintln!(
-                        "Warning: Failed to restore group {}: {:?}",
-                        group_meta.group_id, e
-                    );
+                    log::warn!("Failed to restore group {}: {:?}", group_meta.group_id, e);
                 }
             }
         }
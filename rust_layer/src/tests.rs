@@ -0,0 +1,6208 @@
+use super::*;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A fresh directory for a test client.  Tests run in parallel, so every
+/// test passes names of its own.
+fn tmp(name: &str) -> String {
+    let p = tmp_keep(name);
+    let _ = std::fs::remove_dir_all(&p);
+    let _ = std::fs::remove_file(format!("{}.hwm", p));
+    p
+}
+
+/// The directory `tmp(name)` returns, left as it is, to reopen a client.
+fn tmp_keep(name: &str) -> String {
+    let p = std::env::temp_dir().join(format!("kotlin_mls_test_{}_{}", name, std::process::id()));
+    p.to_string_lossy().into_owned()
+}
+
+/// Alice's group `"g"` with Bob added, each client in a directory of its
+/// own.
+fn pair() -> (MlsClient, MlsClient, String) {
+    static PAIRS: AtomicUsize = AtomicUsize::new(0);
+    let n = PAIRS.fetch_add(1, Ordering::Relaxed);
+    let a = MlsClient::new(tmp(&format!("pair{}_a", n)));
+    let b = MlsClient::new(tmp(&format!("pair{}_b", n)));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    let out = a.add_member(gid.clone(), kp).unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    (a, b, gid)
+}
+
+/// Like `pair()`, but the group has payload envelopes.
+fn enveloped_pair() -> (MlsClient, MlsClient, String) {
+    static PAIRS: AtomicUsize = AtomicUsize::new(0);
+    let n = PAIRS.fetch_add(1, Ordering::Relaxed);
+    let a = MlsClient::new(tmp(&format!("epair{}_a", n)));
+    let b = MlsClient::new(tmp(&format!("epair{}_b", n)));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a
+        .create_group_with_config("g".into(), envelope_config(), None)
+        .unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    let out = a.add_member(gid.clone(), kp).unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    (a, b, gid)
+}
+
+/// `create_group_with_config()` settings for a group with payload
+/// envelopes, otherwise the defaults.
+fn envelope_config() -> GroupConfig {
+    GroupConfig {
+        use_ratchet_tree_extension: true,
+        wire_format_public: false,
+        padding_size: 0,
+        external_senders: vec![],
+        max_past_epochs: 0,
+        payload_envelope: true,
+    }
+}
+
+/// The string `key` of a JSON object.
+fn field(json: &str, key: &str) -> String {
+    let v: serde_json::Value = serde_json::from_str(json).unwrap();
+    v[key].as_str().unwrap().to_string()
+}
+
+#[test]
+fn dup() {
+    let (a, b, gid) = pair();
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct.clone()).unwrap(), "hi");
+    assert!(matches!(
+        b.decrypt_message(gid.clone(), ct.clone()),
+        Err(MlsError::DuplicateMessage { .. })
+    ));
+    assert!(matches!(
+        a.decrypt_message(gid.clone(), ct.clone()),
+        Err(MlsError::OwnMessageReflected { .. })
+    ));
+}
+
+#[test]
+fn rollback() {
+    let dir = tmp("rb");
+    let bak = tmp("rbbak");
+    let a = MlsClient::new(dir.clone());
+    a.create_identity("alice".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    a.save_state().unwrap();
+    std::fs::create_dir_all(&bak).unwrap();
+    for f in ["state.json", "openmls_store.json"] {
+        std::fs::copy(format!("{dir}/{f}"), format!("{bak}/{f}")).unwrap();
+    }
+    a.self_update(gid.clone()).unwrap();
+    a.save_state().unwrap();
+    drop(a);
+    for f in ["state.json", "openmls_store.json"] {
+        std::fs::copy(format!("{bak}/{f}"), format!("{dir}/{f}")).unwrap();
+    }
+    let a = MlsClient::new(dir.clone());
+    assert!(
+        matches!(a.load_state(), Err(MlsError::StateRollbackDetected { found, expected_at_least }) if found < expected_at_least)
+    );
+    assert!(a.save_state().is_err());
+    a.force_accept_rollback().unwrap();
+    assert!(matches!(
+        a.encrypt_message(gid.clone(), "x".into()),
+        Err(MlsError::SelfUpdateRequired { .. })
+    ));
+    a.self_update(gid.clone()).unwrap();
+    a.encrypt_message(gid.clone(), "x".into()).unwrap();
+    a.save_state().unwrap();
+    let a2 = MlsClient::new(dir.clone());
+    a2.load_state().unwrap();
+}
+
+#[test]
+fn caps() {
+    let a = MlsClient::new(tmp("ca"));
+    let b = MlsClient::new(tmp("cb"));
+    a.create_identity_with_capabilities(
+        "alice".into(),
+        ClientCapabilities {
+            ciphersuites: vec![1, 3],
+            extensions: vec![0xff00],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    b.create_identity_with_capabilities(
+        "bob".into(),
+        ClientCapabilities {
+            ciphersuites: vec![],
+            extensions: vec![0xff00],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    a.add_member(gid.clone(), kp).unwrap();
+    assert!(a
+        .create_identity_with_capabilities(
+            "x".into(),
+            ClientCapabilities {
+                ciphersuites: vec![0x7777],
+                extensions: vec![],
+                ..Default::default()
+            }
+        )
+        .is_err());
+}
+
+#[test]
+fn freeze() {
+    let (a, b, gid) = pair();
+    a.freeze_group(gid.clone(), "migrating".into()).unwrap();
+    assert!(matches!(
+        a.encrypt_message(gid.clone(), "x".into()),
+        Err(MlsError::GroupFrozen { .. })
+    ));
+    let ct = b.encrypt_message(gid.clone(), "hey".into()).unwrap();
+    assert_eq!(a.decrypt_message(gid.clone(), ct).unwrap(), "hey");
+    assert!(a
+        .get_group_info(gid.clone())
+        .unwrap()
+        .contains("\"frozen\":true"));
+    a.unfreeze_group(gid.clone()).unwrap();
+    a.encrypt_message(gid.clone(), "x".into()).unwrap();
+}
+
+struct RejectAll;
+
+impl CommitPolicy for RejectAll {
+    fn evaluate(&self, _: StagedCommitDescription) -> PolicyDecision {
+        PolicyDecision::Reject {
+            reason: "maintenance window".into(),
+        }
+    }
+}
+
+#[test]
+fn maintenance_freeze() {
+    let (a, b, gid) = pair();
+    let frozen = |c: &MlsClient| {
+        c.get_group_info(gid.clone())
+            .unwrap()
+            .contains("\"frozen\":true")
+    };
+    // A failed reinit lifts its freeze again.
+    a.set_commit_policy(Box::new(RejectAll), true);
+    assert!(matches!(
+        a.propose_reinit(gid.clone(), 3),
+        Err(MlsError::CommitRejectedByPolicy { .. })
+    ));
+    assert!(!frozen(&a));
+    let ct = a.encrypt_message(gid.clone(), "still open".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "still open");
+    // A rotation lifts it once done.
+    let rotations = a.rotate_all_groups(true).unwrap();
+    assert!(rotations[0].commit.is_some());
+    assert!(!frozen(&a));
+    // A manual freeze is kept.
+    a.freeze_group(gid.clone(), "migrating".into()).unwrap();
+    assert!(matches!(
+        a.propose_reinit(gid.clone(), 3),
+        Err(MlsError::GroupFrozen { reason }) if reason == "migrating"
+    ));
+    assert!(frozen(&a));
+    // A successful reinit leaves the group frozen for good.
+    a.unfreeze_group(gid.clone()).unwrap();
+    a.clear_commit_policy();
+    a.propose_reinit(gid.clone(), 3).unwrap();
+    assert!(frozen(&a));
+}
+
+fn commit_of(json: &str) -> String {
+    let v: serde_json::Value = serde_json::from_str(json).unwrap();
+    v["commit"].as_str().unwrap().to_string()
+}
+
+#[test]
+fn cred() {
+    let a = MlsClient::new(tmp("cred_a"));
+    let b = MlsClient::new(tmp("cred_b"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    let other = a.create_group("other".into(), None).unwrap();
+    let key = a.get_own_member_info(gid.clone()).unwrap().signature_key;
+
+    let c = a.update_credential(gid.clone(), "alice2".into()).unwrap();
+    b.process_commit(gid.clone(), commit_of(&c)).unwrap();
+    let names = |cl: &MlsClient| -> Vec<String> {
+        cl.get_members(gid.clone())
+            .unwrap()
+            .into_iter()
+            .map(|m| String::from_utf8(m.identity).unwrap())
+            .collect()
+    };
+    for cl in [&a, &b] {
+        assert_eq!(names(cl), vec!["alice2".to_string(), "bob".to_string()]);
+    }
+    let own = a.get_own_member_info(gid.clone()).unwrap();
+    assert_eq!(own.signature_key, key);
+    let ct = a.encrypt_message(gid.clone(), "x".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "x");
+
+    // Only this group is renamed.
+    assert_eq!(a.list_identities(), vec!["alice".to_string()]);
+    assert_eq!(
+        a.get_own_member_info(other.clone()).unwrap().identity,
+        b"alice".to_vec()
+    );
+    let g3 = a.create_group("later".into(), None).unwrap();
+    assert_eq!(
+        a.get_own_member_info(g3).unwrap().identity,
+        b"alice".to_vec()
+    );
+
+    // The name sticks across a restart and a key rotation.
+    drop(a);
+    let a = MlsClient::new(tmp_keep("cred_a"));
+    let c = a.rotate_group_signature_key(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), commit_of(&c)).unwrap();
+    assert_eq!(names(&b), vec!["alice2".to_string(), "bob".to_string()]);
+    for commit in a.rotate_signature_key().unwrap() {
+        if commit.group_id == gid {
+            b.process_commit(gid.clone(), commit.commit).unwrap();
+        }
+    }
+    assert_eq!(names(&a), vec!["alice2".to_string(), "bob".to_string()]);
+    assert_eq!(names(&b), vec!["alice2".to_string(), "bob".to_string()]);
+    assert_eq!(
+        a.get_own_member_info(other).unwrap().identity,
+        b"alice".to_vec()
+    );
+
+    // Back to the identity's name.
+    let c = a.update_credential(gid.clone(), "alice".into()).unwrap();
+    b.process_commit(gid.clone(), commit_of(&c)).unwrap();
+    assert_eq!(names(&b), vec!["alice".to_string(), "bob".to_string()]);
+    assert!(a.state.read().unwrap().group_credentials.is_empty());
+}
+
+#[test]
+fn psk() {
+    let (a, b, gid) = pair();
+    a.store_external_psk("0a0b".into(), "11223344".into())
+        .unwrap();
+    let p = a.propose_external_psk(gid.clone(), "0a0b".into()).unwrap();
+    b.process_commit(gid.clone(), field(&p, "proposal"))
+        .unwrap();
+    let c = a.commit_pending_proposals(gid.clone()).unwrap();
+    let err = b
+        .process_commit(gid.clone(), field(&c, "commit"))
+        .unwrap_err();
+    assert!(
+        matches!(&err, MlsError::UnknownPsk { psk_id } if psk_id == "0a0b"),
+        "{err:?}"
+    );
+    b.store_external_psk("0a0b".into(), "11223344".into())
+        .unwrap();
+    b.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+    let ct = a.encrypt_message(gid.clone(), "x".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "x");
+}
+
+#[test]
+fn staged() {
+    let (a, b, gid) = pair();
+    let c = MlsClient::new(tmp("sc"));
+    c.create_identity("carol".into()).unwrap();
+    let out = a
+        .add_member(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    let d = b.stage_commit(gid.clone(), field(&out, "commit")).unwrap();
+    assert_eq!(d.added_identities, vec![b"carol".to_vec()]);
+    assert!(!b.merge_staged_commit(gid.clone()).unwrap());
+    c.process_welcome(field(&out, "welcome")).unwrap();
+    let members = a.get_members(gid.clone()).unwrap();
+    let bi = members.iter().find(|m| m.identity == b"bob").unwrap().index;
+    let r = a.remove_member(gid.clone(), bi).unwrap();
+    let d = b.stage_commit(gid.clone(), field(&r, "commit")).unwrap();
+    assert!(d.self_removed);
+    assert_eq!(d.removed_identities, vec![b"bob".to_vec()]);
+    assert!(b.merge_staged_commit(gid.clone()).unwrap());
+    assert!(b.list_active_groups(None).is_empty());
+    let d = c.stage_commit(gid.clone(), field(&r, "commit")).unwrap();
+    assert!(!d.self_removed);
+    c.reject_staged_commit(gid.clone()).unwrap();
+    c.save_state().unwrap();
+}
+
+#[test]
+fn metadata() {
+    let a = MlsClient::new(tmp("ma"));
+    let b = MlsClient::new(tmp("mb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    a.set_group_metadata(gid.clone(), b"name1".to_vec())
+        .unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    assert_eq!(
+        b.get_group_metadata(gid.clone()).unwrap(),
+        Some(b"name1".to_vec())
+    );
+    let c = a
+        .set_group_metadata(gid.clone(), b"name2".to_vec())
+        .unwrap();
+    b.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+    assert_eq!(
+        b.get_group_metadata(gid.clone()).unwrap(),
+        Some(b"name2".to_vec())
+    );
+}
+
+#[test]
+fn wire() {
+    let (a, b, gid) = pair();
+    a.set_wire_format_policy(gid.clone(), true).unwrap();
+    b.set_wire_format_policy(gid.clone(), true).unwrap();
+    let c = a.self_update(gid.clone()).unwrap();
+    let bytes = hex::decode(field(&c, "commit")).unwrap();
+    let m = MlsMessageIn::tls_deserialize_exact(bytes).unwrap();
+    assert_eq!(m.wire_format(), WireFormat::PublicMessage);
+    b.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+    let ct = a.encrypt_message(gid.clone(), "x".into()).unwrap();
+    let m = MlsMessageIn::tls_deserialize_exact(hex::decode(&ct).unwrap()).unwrap();
+    assert_eq!(m.wire_format(), WireFormat::PrivateMessage);
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "x");
+}
+
+#[cfg(feature = "deterministic-tests")]
+fn seeded_run() -> Vec<String> {
+    let a = MlsClient::new_for_testing(tmp("da"), b"alice-seed".to_vec()).unwrap();
+    let b = MlsClient::new_for_testing(tmp("db"), b"bob-seed".to_vec()).unwrap();
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    let out = a.add_member(gid.clone(), kp.clone()).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct.clone()).unwrap(), "hi");
+    let up = a.self_update(gid.clone()).unwrap();
+    vec![gid, kp, out, ct, up]
+}
+
+#[cfg(feature = "deterministic-tests")]
+#[test]
+fn with_seed() {
+    let kp = |name: &str, seed: &[u8]| {
+        let c = MlsClient::new_with_seed(tmp(name), seed.to_vec()).unwrap();
+        c.create_identity("alice".into()).unwrap();
+        c.generate_key_package(None).unwrap()
+    };
+    assert_eq!(kp("ws1", b"seed"), kp("ws2", b"seed"));
+    assert_ne!(kp("ws3", b"seed"), kp("ws4", b"other"));
+}
+
+#[cfg(feature = "deterministic-tests")]
+#[test]
+fn deterministic() {
+    let r1 = seeded_run();
+    let r2 = seeded_run();
+    assert_eq!(r1, r2);
+    let (a, _b, gid) = pair();
+    assert_ne!(
+        a.encrypt_message(gid.clone(), "x".into()).unwrap(),
+        a.encrypt_message(gid, "x".into()).unwrap()
+    );
+}
+
+#[test]
+fn psk_bytes() {
+    let (a, b, gid) = pair();
+    a.add_external_psk(b"rejoin".to_vec(), vec![7; 32]).unwrap();
+    let p = a.propose_psk(gid.clone(), b"rejoin".to_vec()).unwrap();
+    b.process_commit(gid.clone(), field(&p, "proposal"))
+        .unwrap();
+    let c = a.commit_pending_proposals(gid.clone()).unwrap();
+    assert!(b.process_commit(gid.clone(), field(&c, "commit")).is_err());
+    let ct = a.encrypt_message(gid.clone(), "x".into()).unwrap();
+    assert!(b.decrypt_message(gid.clone(), ct).is_err());
+}
+
+#[derive(Default, Clone)]
+struct Cap(std::sync::Arc<Mutex<Vec<String>>>);
+
+impl MlsEventListener for Cap {
+    fn on_member_added(&self, g: String, id: Vec<u8>) {
+        self.0.lock().unwrap().push(format!(
+            "add {} {}",
+            &g[..4],
+            String::from_utf8(id).unwrap()
+        ));
+    }
+    fn on_member_removed(&self, g: String, id: Vec<u8>, s: bool) {
+        self.0.lock().unwrap().push(format!(
+            "rm {} {} {}",
+            &g[..4],
+            String::from_utf8(id).unwrap(),
+            s
+        ));
+    }
+    fn on_epoch_changed(&self, g: String, e: u64) {
+        self.0
+            .lock()
+            .unwrap()
+            .push(format!("epoch {} {}", &g[..4], e));
+    }
+    fn on_group_joined(&self, g: String) {
+        self.0.lock().unwrap().push(format!("join {}", &g[..4]));
+    }
+    fn on_pending_message_evicted(&self, g: String) {
+        self.0.lock().unwrap().push(format!("evict {}", &g[..4]));
+    }
+}
+
+struct Reenter(std::sync::Arc<MlsClient>, Cap);
+
+impl MlsEventListener for Reenter {
+    fn on_member_added(&self, g: String, id: Vec<u8>) {
+        self.0.get_members(g.clone()).unwrap();
+        self.1.on_member_added(g, id)
+    }
+    fn on_member_removed(&self, g: String, id: Vec<u8>, s: bool) {
+        self.1.on_member_removed(g, id, s)
+    }
+    fn on_epoch_changed(&self, g: String, e: u64) {
+        self.0.get_group_info(g.clone()).unwrap();
+        self.1.on_epoch_changed(g, e)
+    }
+    fn on_group_joined(&self, g: String) {
+        self.1.on_group_joined(g)
+    }
+    fn on_pending_message_evicted(&self, g: String) {
+        self.1.on_pending_message_evicted(g)
+    }
+}
+
+#[test]
+fn events() {
+    let a = std::sync::Arc::new(MlsClient::new(tmp("ea")));
+    let b = MlsClient::new(tmp("eb"));
+    let c = MlsClient::new(tmp("ec"));
+    let ca = Cap::default();
+    let cb = Cap::default();
+    a.set_event_listener(Box::new(Reenter(a.clone(), ca.clone())));
+    b.set_event_listener(Box::new(cb.clone()));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    c.create_identity("carol".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    let out = a
+        .add_member(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_commit(gid.clone(), field(&out, "commit"))
+        .unwrap();
+    let bi = a
+        .get_members(gid.clone())
+        .unwrap()
+        .iter()
+        .find(|m| m.identity == b"bob")
+        .unwrap()
+        .index;
+    let r = a.remove_member(gid.clone(), bi).unwrap();
+    b.process_commit(gid.clone(), field(&r, "commit")).unwrap();
+    let g = &gid[..4];
+    let ea = ca.0.lock().unwrap();
+    assert!(ea.contains(&format!("add {} bob", g)));
+    assert!(ea.contains(&format!("add {} carol", g)));
+    assert!(ea.contains(&format!("rm {} bob false", g)));
+    let eb = cb.0.lock().unwrap();
+    assert!(eb.contains(&format!("join {}", g)));
+    assert!(eb.contains(&format!("add {} carol", g)));
+    assert!(eb.contains(&format!("rm {} bob true", g)));
+}
+
+#[test]
+fn backup() {
+    let (a, b, gid) = pair();
+    let blob = a.export_state("pw".into()).unwrap();
+    let dir = tmp("bk");
+    let d = MlsClient::new(dir.clone());
+    d.create_identity("dave".into()).unwrap();
+    assert!(matches!(
+        d.import_state(blob.clone(), "nope".into()),
+        Err(MlsError::CryptoError { .. })
+    ));
+    assert!(d.list_active_groups(None).is_empty());
+    assert!(d.has_identity());
+    d.import_state(blob, "pw".into()).unwrap();
+    assert_eq!(d.list_active_groups(None), vec![gid.clone()]);
+    let ct = b.encrypt_message(gid.clone(), "hey".into()).unwrap();
+    assert_eq!(d.decrypt_message(gid.clone(), ct).unwrap(), "hey");
+    let d2 = MlsClient::new(dir);
+    assert_eq!(d2.list_active_groups(None), vec![gid.clone()]);
+}
+
+#[test]
+fn at_rest() {
+    let dir = tmp("enc");
+    let a = MlsClient::new_encrypted(dir.clone(), "pw".into());
+    a.create_identity("alice".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    a.save_state().unwrap();
+    let raw = std::fs::read(format!("{dir}/openmls_store.json")).unwrap();
+    assert!(raw.starts_with(b"MLSB"));
+    // One key for every file, each sealed under its own nonce.
+    a.save_state().unwrap();
+    let again = std::fs::read(format!("{dir}/openmls_store.json")).unwrap();
+    let state = std::fs::read(format!("{dir}/{STATE_KEY}")).unwrap();
+    assert_eq!(raw[5..21], again[5..21]);
+    assert_eq!(raw[5..21], state[5..21]);
+    assert_ne!(raw[21..33], again[21..33]);
+    let a2 = MlsClient::new_encrypted(dir.clone(), "pw".into());
+    assert_eq!(a2.list_active_groups(None), vec![gid.clone()]);
+    assert_eq!(a2.list_saved_groups().unwrap(), vec![gid.clone()]);
+    let bad = MlsClient::new_encrypted(dir.clone(), "wrong".into());
+    assert!(!bad.has_identity());
+    assert!(matches!(
+        bad.load_state(),
+        Err(MlsError::WrongPassphrase { .. })
+    ));
+    bad.create_identity("mallory".into()).unwrap();
+    assert!(matches!(
+        bad.save_state(),
+        Err(MlsError::WrongPassphrase { .. })
+    ));
+    assert_eq!(
+        MlsClient::new_encrypted(dir.clone(), "pw".into()).list_active_groups(None),
+        vec![gid.clone()]
+    );
+    let plain = MlsClient::new(dir.clone());
+    assert!(matches!(
+        plain.load_state(),
+        Err(MlsError::WrongPassphrase { .. })
+    ));
+}
+
+#[test]
+fn remove_many() {
+    let names = ["alice", "bob", "carol", "dave", "erin"];
+    let cs: Vec<MlsClient> = names
+        .iter()
+        .map(|n| {
+            let c = MlsClient::new(tmp(&format!("rm{n}")));
+            c.create_identity(n.to_string()).unwrap();
+            c
+        })
+        .collect();
+    let gid = cs[0].create_group("g".into(), None).unwrap();
+    for i in 1..5 {
+        let out = cs[0]
+            .add_member(gid.clone(), cs[i].generate_key_package(None).unwrap())
+            .unwrap();
+        for member in &cs[1..i] {
+            member
+                .process_commit(gid.clone(), field(&out, "commit"))
+                .unwrap();
+        }
+        cs[i].process_welcome(field(&out, "welcome")).unwrap();
+    }
+    let e = cs[0]
+        .remove_members(gid.clone(), vec!["bob".into(), "zed".into(), "yan".into()])
+        .unwrap_err();
+    assert!(
+        matches!(&e, MlsError::UnknownMembers { identities } if identities == &vec!["zed".to_string(), "yan".to_string()])
+    );
+    assert!(matches!(
+        cs[0].remove_members(gid.clone(), vec!["bob".into(), "alice".into()]),
+        Err(MlsError::CannotRemoveSelf { .. })
+    ));
+    let c = cs[0]
+        .remove_members(
+            gid.clone(),
+            vec!["bob".into(), "carol".into(), "erin".into()],
+        )
+        .unwrap();
+    for member in &cs[1..] {
+        member
+            .process_commit(gid.clone(), field(&c, "commit"))
+            .unwrap();
+    }
+    assert_eq!(cs[0].get_members(gid.clone()).unwrap().len(), 2);
+    assert!(cs[1].list_active_groups(None).is_empty());
+    let ct = cs[0].encrypt_message(gid.clone(), "x".into()).unwrap();
+    assert_eq!(cs[3].decrypt_message(gid.clone(), ct).unwrap(), "x");
+}
+
+#[test]
+fn epochs() {
+    let (a, b, gid) = pair();
+    let c1 = a.self_update(gid.clone()).unwrap();
+    let c2 = a.self_update(gid.clone()).unwrap();
+    let e = b
+        .process_commit(gid.clone(), field(&c2, "commit"))
+        .unwrap_err();
+    assert!(
+        matches!(
+            e,
+            MlsError::FutureEpoch {
+                expected: 1,
+                got: 2
+            }
+        ),
+        "{e:?}"
+    );
+    b.process_commit(gid.clone(), field(&c1, "commit")).unwrap();
+    let e = b
+        .process_commit(gid.clone(), field(&c1, "commit"))
+        .unwrap_err();
+    assert!(
+        matches!(
+            e,
+            MlsError::StaleMessage {
+                expected: 2,
+                got: 1
+            }
+        ),
+        "{e:?}"
+    );
+    let ct = a.encrypt_message(gid.clone(), "x".into()).unwrap();
+    let e = b.decrypt_message(gid.clone(), ct).unwrap_err();
+    assert!(
+        matches!(
+            e,
+            MlsError::FutureEpoch {
+                expected: 2,
+                got: 3
+            }
+        ),
+        "{e:?}"
+    );
+    b.process_commit(gid.clone(), field(&c2, "commit")).unwrap();
+    let old = b.encrypt_message(gid.clone(), "y".into()).unwrap();
+    let c3 = b.self_update(gid.clone()).unwrap();
+    a.process_commit(gid.clone(), field(&c3, "commit")).unwrap();
+    let e = a.decrypt_message(gid.clone(), old).unwrap_err();
+    assert!(
+        matches!(
+            e,
+            MlsError::EpochTooOld {
+                message_epoch: 3,
+                oldest_retained: 4
+            }
+        ),
+        "{e:?}"
+    );
+}
+
+#[test]
+fn own_info() {
+    let (a, b, gid) = pair();
+    let me = b.get_own_member_info(gid.clone()).unwrap();
+    assert_eq!(me.identity, b"bob");
+    assert_eq!(me.index, 1);
+    assert!(a.is_member(gid.clone(), "bob".into()).unwrap());
+    assert!(!a.is_member(gid.clone(), "zed".into()).unwrap());
+    let r = a.remove_member(gid.clone(), 1).unwrap();
+    b.process_commit(gid.clone(), field(&r, "commit")).unwrap();
+    assert!(b.get_own_member_info(gid.clone()).is_err());
+    let r = b.save_state().unwrap();
+    assert!(r.saved_groups.is_empty() && r.failed_groups.is_empty());
+    assert_eq!(a.save_state().unwrap().saved_groups, vec![gid.clone()]);
+}
+
+#[test]
+fn save_after_removal() {
+    let (a, b, gid) = pair();
+    let other = a.create_group("h".into(), None).unwrap();
+    let out = a
+        .add_member(other.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    let r = a.remove_member(gid.clone(), 1).unwrap();
+    b.process_commit(gid.clone(), field(&r, "commit")).unwrap();
+    let rep = b.save_state().unwrap();
+    assert_eq!(rep.saved_groups, vec![other.clone()]);
+    assert!(rep.failed_groups.is_empty());
+    let ct = a
+        .encrypt_message(other.clone(), "still here".into())
+        .unwrap();
+    assert_eq!(b.decrypt_message(other, ct).unwrap(), "still here");
+}
+
+#[test]
+fn already_member() {
+    let a = MlsClient::new(tmp("am_a"));
+    let b = MlsClient::new(tmp("am_b"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    a.add_member(gid.clone(), kp.clone()).unwrap();
+    assert!(matches!(
+        a.add_member(gid.clone(), kp),
+        Err(MlsError::AlreadyMember { leaf_index: 1, .. })
+    ));
+    assert!(matches!(
+        a.add_member(gid.clone(), "zz".into()),
+        Err(MlsError::SerializationError { .. })
+    ));
+    assert!(matches!(
+        a.add_member(gid.clone(), "00".into()),
+        Err(MlsError::SerializationError { .. })
+    ));
+    assert!(matches!(
+        a.add_member(gid.clone(), b.generate_key_package(None).unwrap()),
+        Err(MlsError::AlreadyMember { .. })
+    ));
+}
+
+#[test]
+fn tree_hash() {
+    let (a, b, gid) = pair();
+    let h1 = a.tree_hash(gid.clone()).unwrap();
+    assert_eq!(h1, b.tree_hash(gid.clone()).unwrap());
+    let c = a.self_update(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+    let h2 = a.tree_hash(gid.clone()).unwrap();
+    assert_ne!(h1, h2);
+    assert_eq!(h2, b.tree_hash(gid.clone()).unwrap());
+}
+
+#[test]
+fn encodings() {
+    for enc in [Encoding::Hex, Encoding::Base64, Encoding::Base64Url] {
+        let a = MlsClient::new(tmp("enc_a"));
+        let b = MlsClient::new(tmp("enc_b"));
+        a.set_default_encoding(enc);
+        b.set_default_encoding(enc);
+        a.create_identity("alice".into()).unwrap();
+        b.create_identity("bob".into()).unwrap();
+        let gid = a.create_group("g".into(), None).unwrap();
+        let kp = b.generate_key_package(None).unwrap();
+        let out = a.add_member(gid.clone(), kp).unwrap();
+        let w = field(&out, "welcome");
+        if enc == Encoding::Base64Url {
+            assert!(!w.contains('=') && !w.contains('+') && !w.contains('/'));
+        }
+        b.process_welcome(w).unwrap();
+        let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+        assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+        let c = b.self_update(gid.clone()).unwrap();
+        a.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+        let ct = b.encrypt_message(gid.clone(), "yo".into()).unwrap();
+        assert_eq!(a.decrypt_message(gid.clone(), ct).unwrap(), "yo");
+    }
+    // padding tolerated
+    let a = MlsClient::new(tmp("ep"));
+    a.set_default_encoding(Encoding::Base64);
+    a.create_identity("alice".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let b = MlsClient::new(tmp("eq"));
+    b.create_identity("bob".into()).unwrap();
+    a.set_default_encoding(Encoding::Base64);
+    let kp = {
+        b.set_default_encoding(Encoding::Base64);
+        b.generate_key_package(None).unwrap()
+    };
+    let stripped = kp.trim_end_matches('=').to_string();
+    a.add_member(gid.clone(), stripped).unwrap();
+    let err = a
+        .decrypt_message(gid.clone(), "AAAA*AAA".into())
+        .unwrap_err();
+    assert!(matches!(&err, MlsError::SerializationError { msg } if msg.contains("position 4")));
+    a.set_default_encoding(Encoding::Hex);
+    let err = a.decrypt_message(gid.clone(), "00zz".into()).unwrap_err();
+    assert!(matches!(&err, MlsError::SerializationError { msg } if msg.contains("position 2")));
+}
+
+#[test]
+fn enc_padding() {
+    assert_eq!(Encoding::Base64.decode("AAE").unwrap(), vec![0, 1]);
+    assert_eq!(Encoding::Base64.decode("AAE=").unwrap(), vec![0, 1]);
+    assert_eq!(
+        Encoding::Base64Url.decode("_-E=").unwrap(),
+        Encoding::Base64Url.decode("_-E").unwrap()
+    );
+    assert!(Encoding::Base64.decode("AAE=A").is_err());
+    assert!(Encoding::Hex.decode("abc").is_err());
+}
+
+#[test]
+fn last_resort() {
+    let a = MlsClient::new(tmp("lra"));
+    let c = MlsClient::new(tmp("lrc"));
+    let b = MlsClient::new(tmp("lrb"));
+    a.create_identity("alice".into()).unwrap();
+    c.create_identity("carol".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let kp = b.generate_last_resort_key_package("bob".into()).unwrap();
+    let g1 = a.create_group("g".into(), None).unwrap();
+    let g2 = c.create_group("g".into(), None).unwrap();
+    let w1 = field(&a.add_member(g1.clone(), kp.clone()).unwrap(), "welcome");
+    let w2 = field(&c.add_member(g2.clone(), kp.clone()).unwrap(), "welcome");
+    assert_eq!(b.process_welcome(w1).unwrap(), g1);
+    assert_eq!(b.process_welcome(w2).unwrap(), g2);
+    let ct = a.encrypt_message(g1.clone(), "x".into()).unwrap();
+    assert_eq!(b.decrypt_message(g1.clone(), ct).unwrap(), "x");
+
+    let kp = b.generate_key_package(None).unwrap();
+    let g3 = a.create_group("g".into(), None).unwrap();
+    let g4 = c.create_group("g".into(), None).unwrap();
+    let w3 = field(&a.add_member(g3.clone(), kp.clone()).unwrap(), "welcome");
+    let w4 = field(&c.add_member(g4.clone(), kp.clone()).unwrap(), "welcome");
+    b.process_welcome(w3).unwrap();
+    assert!(b.process_welcome(w4).is_err());
+}
+
+#[test]
+fn gce() {
+    let (a, b, gid) = pair();
+    let mut exts = a.get_group_context_extensions(gid.clone()).unwrap();
+    exts.push(RawExtension {
+        extension_type: 0xff01,
+        data: b"team".to_vec(),
+    });
+    let p = a
+        .propose_group_context_extensions(gid.clone(), exts)
+        .unwrap();
+    b.process_commit(gid.clone(), field(&p, "proposal"))
+        .unwrap();
+    let c = a.commit_pending_proposals(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+    for cl in [&a, &b] {
+        let e = cl.get_group_context_extensions(gid.clone()).unwrap();
+        assert!(e
+            .iter()
+            .any(|e| e.extension_type == 0xff01 && e.data == b"team"));
+    }
+    assert_eq!(b.get_group_metadata(gid.clone()).unwrap().unwrap(), b"team");
+    // unsupported custom type is rejected
+    let p = a.propose_group_context_extensions(
+        gid.clone(),
+        vec![RawExtension {
+            extension_type: 0xff77,
+            data: vec![1],
+        }],
+    );
+    assert!(p.is_err());
+    let bad = a.propose_group_context_extensions(
+        gid.clone(),
+        vec![RawExtension {
+            extension_type: 3,
+            data: vec![9, 9],
+        }],
+    );
+    assert!(bad.is_err());
+}
+
+#[test]
+fn migrate() {
+    let (a, b, g1) = pair();
+    let c = MlsClient::new(tmp("mc"));
+    c.create_identity("carol".into()).unwrap();
+    let g2 = c.create_group("g".into(), None).unwrap();
+    let w = field(
+        &c.add_member(g2.clone(), b.generate_key_package(None).unwrap())
+            .unwrap(),
+        "welcome",
+    );
+    b.process_welcome(w).unwrap();
+    let spare = b.generate_key_package(None).unwrap();
+
+    let blob = b.export_backup("pw".into()).unwrap();
+    assert!(matches!(
+        MlsClient::import_backup(tmp("mb_bad"), blob.clone(), "nope".into()),
+        Err(MlsError::CryptoError { .. })
+    ));
+    assert!(matches!(
+        MlsClient::import_backup(tmp("mb_bad"), blob[..blob.len() - 5].to_vec(), "pw".into()),
+        Err(MlsError::SerializationError { .. })
+    ));
+    let mut v = blob.clone();
+    v[4] = 9;
+    assert!(matches!(
+        MlsClient::import_backup(tmp("mb_bad"), v, "pw".into()),
+        Err(MlsError::UnsupportedBackupVersion { version: 9 })
+    ));
+    let path = tmp("mb2");
+    let b2 = MlsClient::import_backup(path.clone(), blob, "pw".into()).unwrap();
+    drop(b);
+    let ct = a.encrypt_message(g1.clone(), "one".into()).unwrap();
+    assert_eq!(b2.decrypt_message(g1.clone(), ct).unwrap(), "one");
+    let ct = c.encrypt_message(g2.clone(), "two".into()).unwrap();
+    assert_eq!(b2.decrypt_message(g2.clone(), ct).unwrap(), "two");
+    // key package bundle came along
+    let g3 = a.create_group("g".into(), None).unwrap();
+    let w = field(&a.add_member(g3.clone(), spare).unwrap(), "welcome");
+    b2.process_welcome(w).unwrap();
+    // persisted on new path
+    let b3 = MlsClient::new(path);
+    assert_eq!(b3.list_active_groups(None).len(), 3);
+}
+
+#[test]
+fn required_caps() {
+    let a = MlsClient::new(tmp("rca"));
+    a.create_identity_with_capabilities(
+        "alice".into(),
+        ClientCapabilities {
+            ciphersuites: vec![],
+            extensions: vec![0xff05],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let gid = a
+        .create_group_with_required_capabilities("g".into(), vec![0xff05], vec![], vec![1], None)
+        .unwrap();
+    let b = MlsClient::new(tmp("rcb"));
+    b.create_identity_with_capabilities(
+        "bob".into(),
+        ClientCapabilities {
+            ciphersuites: vec![],
+            extensions: vec![0xff05],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let c = MlsClient::new(tmp("rcc"));
+    c.create_identity("carol".into()).unwrap();
+    let err = a
+        .add_member(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap_err();
+    assert!(matches!(err, MlsError::CapabilitiesMismatch { .. }));
+    let w = field(
+        &a.add_member(gid.clone(), b.generate_key_package(None).unwrap())
+            .unwrap(),
+        "welcome",
+    );
+    b.process_welcome(w).unwrap();
+    let e = b.get_group_context_extensions(gid.clone()).unwrap();
+    assert!(e.iter().any(|e| e.extension_type == 3));
+    // creator lacking capability
+    let err =
+        c.create_group_with_required_capabilities("g".into(), vec![0xff06], vec![], vec![], None);
+    assert!(err.is_err());
+}
+
+#[derive(Clone, Default)]
+struct MemBackend(std::sync::Arc<Mutex<HashMap<String, Vec<u8>>>>);
+
+impl StorageBackend for MemBackend {
+    fn get(&self, key: String) -> Result<Option<Vec<u8>>, MlsError> {
+        Ok(self.0.lock().unwrap().get(&key).cloned())
+    }
+    fn put(&self, key: String, value: Vec<u8>) -> Result<(), MlsError> {
+        self.0.lock().unwrap().insert(key, value);
+        Ok(())
+    }
+    fn delete(&self, key: String) -> Result<(), MlsError> {
+        self.0.lock().unwrap().remove(&key);
+        Ok(())
+    }
+    fn list(&self, prefix: String) -> Result<Vec<String>, MlsError> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|k| k.starts_with(&prefix))
+            .cloned()
+            .collect())
+    }
+}
+
+#[test]
+fn backend() {
+    let mem = MemBackend::default();
+    let (a, _b, g) = pair();
+    let b = MlsClient::new_with_backend(Box::new(mem.clone()));
+    b.create_identity("bob".into()).unwrap();
+    let w = field(
+        &a.add_member(g.clone(), b.generate_key_package(None).unwrap())
+            .unwrap(),
+        "welcome",
+    );
+    b.process_welcome(w).unwrap();
+    let rep = b.save_state().unwrap();
+    assert_eq!(rep.saved_groups, vec![g.clone()]);
+    drop(b);
+    let b = MlsClient::new_with_backend(Box::new(mem.clone()));
+    assert!(b.has_identity());
+    assert_eq!(b.list_saved_groups().unwrap(), vec![g.clone()]);
+    let ct = a.encrypt_message(g.clone(), "m".into()).unwrap();
+    assert_eq!(b.decrypt_message(g.clone(), ct).unwrap(), "m");
+    // file backend list
+    let f = FileStorageBackend {
+        dir: std::path::PathBuf::from(tmp("fb")),
+    };
+    f.put("state.json".into(), vec![1]).unwrap();
+    f.put(HIGH_WATER_MARK_KEY.into(), b"3".to_vec()).unwrap();
+    f.delete("state.json".into()).unwrap();
+    f.delete("state.json".into()).unwrap();
+    assert_eq!(f.get("state.json".into()).unwrap(), None);
+}
+
+#[test]
+fn own_index() {
+    let (a, b, g) = pair();
+    assert_eq!(a.own_leaf_index(g.clone()).unwrap(), 0);
+    assert_eq!(b.own_leaf_index(g.clone()).unwrap(), 1);
+    assert!(matches!(
+        a.own_leaf_index("nope".into()),
+        Err(MlsError::GroupNotFound { .. })
+    ));
+}
+
+#[test]
+fn find() {
+    let (a, _b, g) = pair();
+    let m = a.find_member(g.clone(), b"bob".to_vec()).unwrap().unwrap();
+    assert_eq!(m.index, 1);
+    assert!(a.find_member(g.clone(), b"zed".to_vec()).unwrap().is_none());
+}
+
+#[test]
+fn padding() {
+    let (a, b, g) = pair();
+    let short = a.encrypt_message(g.clone(), "k".into()).unwrap().len();
+    let long = a.encrypt_message(g.clone(), "x".repeat(100)).unwrap().len();
+    assert!(long > short);
+    a.set_padding_size(256).unwrap();
+    let c1 = a.encrypt_message(g.clone(), "k".into()).unwrap();
+    let c2 = a.encrypt_message(g.clone(), "x".repeat(100)).unwrap();
+    assert_eq!(c1.len(), c2.len());
+    b.decrypt_message(
+        g.clone(),
+        a.encrypt_message(g.clone(), "warmup".into()).unwrap(),
+    )
+    .ok();
+    let _ = b;
+    let (a2, b2, g2) = pair();
+    a2.set_padding_size(256).unwrap();
+    let c1 = a2.encrypt_message(g2.clone(), "k".into()).unwrap();
+    let c2 = a2.encrypt_message(g2.clone(), "x".repeat(100)).unwrap();
+    assert_eq!(b2.decrypt_message(g2.clone(), c1).unwrap(), "k");
+    assert_eq!(b2.decrypt_message(g2.clone(), c2).unwrap(), "x".repeat(100));
+    // new groups inherit
+    let g3 = a2.create_group("g".into(), None).unwrap();
+    let w = field(
+        &a2.add_member(g3.clone(), b2.generate_key_package(None).unwrap())
+            .unwrap(),
+        "welcome",
+    );
+    b2.set_padding_size(256).unwrap();
+    b2.process_welcome(w).unwrap();
+    let c1 = b2.encrypt_message(g3.clone(), "k".into()).unwrap();
+    let c2 = b2.encrypt_message(g3.clone(), "x".repeat(100)).unwrap();
+    assert_eq!(c1.len(), c2.len());
+    assert_eq!(a2.decrypt_message(g3.clone(), c2).unwrap(), "x".repeat(100));
+}
+
+#[test]
+fn two_phase_fork() {
+    let (a, b, gid) = pair();
+    let c = MlsClient::new(tmp("c2p"));
+    let d = MlsClient::new(tmp("d2p"));
+    c.create_identity("carol".into()).unwrap();
+    d.create_identity("dave".into()).unwrap();
+    let ra = a
+        .add_member_prepare(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    let rb = b
+        .add_member_prepare(gid.clone(), d.generate_key_package(None).unwrap())
+        .unwrap();
+    // server picks b
+    assert!(matches!(
+        a.process_commit(gid.clone(), rb.commit.clone()),
+        Err(MlsError::PendingCommitConflict { .. })
+    ));
+    a.abort_commit(gid.clone()).unwrap();
+    a.process_commit(gid.clone(), rb.commit.clone()).unwrap();
+    b.confirm_commit(gid.clone()).unwrap();
+    d.process_welcome(rb.welcome).unwrap();
+    assert!(matches!(
+        b.confirm_commit(gid.clone()),
+        Err(MlsError::Generic { .. })
+    ));
+    let _ = ra;
+    let ct = b.encrypt_message(gid.clone(), "x".into()).unwrap();
+    assert_eq!(a.decrypt_message(gid.clone(), ct.clone()).unwrap(), "x");
+    assert_eq!(d.decrypt_message(gid.clone(), ct).unwrap(), "x");
+    assert_eq!(a.get_members(gid.clone()).unwrap().len(), 3);
+}
+
+#[test]
+fn deferred_add() {
+    let (a, b, gid) = pair();
+    let c = MlsClient::new(tmp("cdef"));
+    c.create_identity("carol".into()).unwrap();
+    let epoch = a.get_epoch(gid.clone()).unwrap();
+    let r = a
+        .add_member_deferred(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    // Nothing merged yet: same epoch and roster.
+    assert_eq!(a.get_epoch(gid.clone()).unwrap(), epoch);
+    assert_eq!(a.get_members(gid.clone()).unwrap().len(), 2);
+    assert_eq!(r.epoch, epoch + 1);
+    // Delivery failed: abort and the group is usable at the old epoch.
+    a.abort_commit(gid.clone()).unwrap();
+    let ct = a.encrypt_message(gid.clone(), "still".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "still");
+    // Delivered: confirm, then the welcome works.
+    let r = a
+        .add_member_deferred(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_commit(gid.clone(), r.commit).unwrap();
+    a.confirm_commit(gid.clone()).unwrap();
+    assert_eq!(a.get_epoch(gid.clone()).unwrap(), epoch + 1);
+    c.process_welcome(r.welcome).unwrap();
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct.clone()).unwrap(), "hi");
+    assert_eq!(c.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+    // Nothing left to confirm.
+    assert!(a.confirm_commit(gid).is_err());
+}
+
+#[test]
+fn integrity() {
+    let (a, b, gid) = pair();
+    assert!(a.verify_group_integrity(gid.clone()).unwrap());
+    assert!(b.verify_group_integrity(gid.clone()).unwrap());
+    assert!(matches!(
+        a.verify_group_integrity("00".into()),
+        Err(MlsError::GroupNotFound { .. })
+    ));
+    let ct = a.encrypt_message(gid.clone(), "x".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "x");
+}
+
+#[test]
+fn caps_mismatch() {
+    let a = MlsClient::new(tmp("cm_a"));
+    a.create_identity_with_capabilities(
+        "alice".into(),
+        ClientCapabilities {
+            extensions: vec![0xff07],
+            proposals: vec![0xff08],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let gid = a
+        .create_group_with_required_capabilities(
+            "g".into(),
+            vec![0xff07],
+            vec![0xff08],
+            vec![],
+            None,
+        )
+        .unwrap();
+    let b = MlsClient::new(tmp("cm_b"));
+    b.create_identity("bob".into()).unwrap();
+    let err = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap_err();
+    match err {
+        MlsError::CapabilitiesMismatch {
+            missing_extensions,
+            missing_proposals,
+            missing_credentials,
+            ..
+        } => {
+            assert_eq!(missing_extensions, vec![0xff07]);
+            assert_eq!(missing_proposals, vec![0xff08]);
+            assert!(missing_credentials.is_empty());
+        }
+        e => panic!("{e:?}"),
+    }
+    b.set_capabilities(ClientCapabilities {
+        extensions: vec![0xff07],
+        proposals: vec![0xff08],
+        ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(b.get_capabilities().unwrap().proposals, vec![0xff08]);
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+}
+
+#[test]
+fn batch() {
+    let (a, b, gid) = pair();
+    let c = MlsClient::new(tmp("batc"));
+    c.create_identity("carol".into()).unwrap();
+    let m1 = a.encrypt_message(gid.clone(), "one".into()).unwrap();
+    let add = a
+        .add_member(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    let m2 = a.encrypt_message(gid.clone(), "two".into()).unwrap();
+    let own = b.encrypt_message(gid.clone(), "mine".into()).unwrap();
+    let res = b.process_messages(
+        gid.clone(),
+        vec![
+            m1.clone(),
+            m1.clone(),
+            own,
+            commit_of(&add),
+            m2.clone(),
+            "zz".into(),
+            m2.clone(),
+        ],
+    );
+    match res {
+        Err(MlsError::BatchFailed {
+            index,
+            processed,
+            error_kind,
+            ..
+        }) => {
+            assert_eq!(index, 5);
+            assert_eq!(error_kind, "SerializationError");
+            assert!(
+                matches!(&processed[0], IncomingMessage::Application { plaintext, .. } if plaintext == b"one")
+            );
+            assert!(matches!(processed[1], IncomingMessage::Duplicate));
+            assert!(matches!(processed[2], IncomingMessage::OwnMessage));
+            assert!(matches!(processed[3], IncomingMessage::Commit { epoch: 2 }));
+            assert!(matches!(processed[4], IncomingMessage::Application { .. }));
+        }
+        r => panic!("{r:?}"),
+    }
+    let r = b.process_messages(gid.clone(), vec![m2]).unwrap();
+    assert!(matches!(r[0], IncomingMessage::Duplicate));
+
+    // Handshakes are held to the commit limit, not the application one.
+    let up = field(&a.self_update(gid.clone()).unwrap(), "commit");
+    let m3 = a.encrypt_message(gid.clone(), "three".into()).unwrap();
+    let commit_len = hex::decode(&up).unwrap().len() as u64;
+    let message_len = hex::decode(&m3).unwrap().len() as u64;
+    b.set_input_limits(InputLimits {
+        commit: commit_len - 1,
+        application_message: commit_len + 1,
+        ..Default::default()
+    })
+    .unwrap();
+    match b.process_messages(gid.clone(), vec![up.clone()]) {
+        Err(MlsError::BatchFailed {
+            index: 0,
+            error_kind,
+            message,
+            ..
+        }) => {
+            assert_eq!(error_kind, "InputTooLarge");
+            assert!(message.contains(&commit_len.to_string()));
+        }
+        r => panic!("{r:?}"),
+    }
+    b.set_input_limits(InputLimits {
+        commit: commit_len,
+        application_message: message_len,
+        ..Default::default()
+    })
+    .unwrap();
+    let r = b.process_messages(gid.clone(), vec![up, m3]).unwrap();
+    assert!(
+        matches!(&r[1], IncomingMessage::Application { plaintext, .. } if plaintext == b"three")
+    );
+}
+
+#[test]
+fn aad() {
+    let (a, b, gid) = pair();
+    let ct = a
+        .encrypt_message_with_aad(gid.clone(), b"body".to_vec(), b"msgid=42".to_vec())
+        .unwrap();
+    let mut bad = ct.clone();
+    let pos = bad.windows(8).position(|w| w == b"msgid=42").unwrap();
+    bad[pos + 6] = b'9';
+    assert!(matches!(
+        b.decrypt_message_with_aad(gid.clone(), bad),
+        Err(MlsError::DecryptionFailed { .. })
+    ));
+    let ct2 = a
+        .encrypt_message_with_aad(gid.clone(), b"body2".to_vec(), b"msgid=43".to_vec())
+        .unwrap();
+    let d = b.decrypt_message_with_aad(gid.clone(), ct2).unwrap();
+    assert_eq!(
+        (d.plaintext, d.aad),
+        (b"body2".to_vec(), b"msgid=43".to_vec())
+    );
+    // aad reset after use
+    let ct3 = a.encrypt_message(gid.clone(), "plain".into()).unwrap();
+    let d = b
+        .decrypt_message_with_aad(gid.clone(), hex::decode(ct3).unwrap())
+        .unwrap();
+    assert!(d.aad.is_empty());
+    assert!(b.decrypt_message_with_aad(gid.clone(), ct).is_err());
+}
+
+#[test]
+fn suite() {
+    let (a, _b, gid) = pair();
+    assert_eq!(a.group_ciphersuite(gid.clone()).unwrap(), 1);
+    assert!(a
+        .get_group_info(gid.clone())
+        .unwrap()
+        .contains(r#""ciphersuite":1,"#));
+    assert!(matches!(
+        a.group_ciphersuite("00".into()),
+        Err(MlsError::GroupNotFound { .. })
+    ));
+}
+
+#[test]
+fn archive() {
+    let (a, b, gid) = pair();
+    let gid2 = a.create_group("h".into(), None).unwrap();
+    for i in 0..6 {
+        a.archive_group(gid.clone()).unwrap();
+        b.archive_group(gid.clone()).unwrap();
+        assert!(!a.list_active_groups(None).contains(&gid));
+        assert!(a.list_all_groups().contains(&gid));
+        let ct = a.encrypt_message(gid.clone(), format!("m{i}")).unwrap();
+        b.archive_group(gid.clone()).unwrap();
+        assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), format!("m{i}"));
+        a.archive_group(gid.clone()).unwrap();
+        let ct = b.encrypt_message(gid.clone(), format!("r{i}")).unwrap();
+        assert_eq!(a.decrypt_message(gid.clone(), ct).unwrap(), format!("r{i}"));
+        if i % 2 == 0 {
+            let c = a.self_update(gid.clone()).unwrap();
+            b.archive_group(gid.clone()).unwrap();
+            b.process_commit(gid.clone(), commit_of(&c)).unwrap();
+        }
+        a.archive_group(gid.clone()).unwrap();
+        assert_eq!(a.get_members(gid.clone()).unwrap().len(), 2);
+    }
+    // LRU
+    a.set_max_resident_groups(1).unwrap();
+    assert_eq!(a.list_active_groups(None).len(), 1);
+    a.encrypt_message(gid.clone(), "x".into()).unwrap();
+    assert_eq!(a.list_active_groups(None), vec![gid.clone()]);
+    a.get_members(gid2.clone()).unwrap();
+    assert_eq!(a.list_active_groups(None), vec![gid2.clone()]);
+    let rep = a.save_state().unwrap();
+    assert_eq!(rep.saved_groups.len(), 2);
+    // reload lazily
+    let dir_groups = a.list_saved_groups().unwrap();
+    assert_eq!(dir_groups.len(), 2);
+    a.set_padding_size(64).unwrap();
+    let ct = a.encrypt_message(gid.clone(), "y".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "y");
+    assert!(matches!(
+        a.archive_group("00".into()),
+        Err(MlsError::GroupNotFound { .. })
+    ));
+}
+
+fn foreign_kp(cs: Ciphersuite, name: &str) -> String {
+    let provider = MlsProvider::default();
+    let signer = SignatureKeyPair::new(cs.signature_algorithm()).unwrap();
+    let cred = CredentialWithKey {
+        credential: Credential::new(CredentialType::Basic, name.as_bytes().to_vec()),
+        signature_key: signer.public().into(),
+    };
+    let kpb = KeyPackage::builder()
+        .build(cs, &provider, &signer, cred)
+        .unwrap();
+    hex::encode(kpb.key_package().tls_serialize_detached().unwrap())
+}
+
+#[test]
+fn suites() {
+    let a = MlsClient::new(tmp("su_a"));
+    a.create_identity("alice".into()).unwrap();
+    let p256 = foreign_kp(Ciphersuite::MLS_128_DHKEMP256_AES128GCM_SHA256_P256, "pat");
+    let x = foreign_kp(
+        Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+        "xav",
+    );
+    let i1 = a.get_key_package_info(p256.clone()).unwrap();
+    let i2 = a.get_key_package_info(x.clone()).unwrap();
+    assert_eq!((i1.ciphersuite, i2.ciphersuite), (2, 3));
+    assert_eq!(i1.identity, b"pat");
+    assert!(i1.not_after > i1.not_before);
+    let gid = a.create_group("g".into(), None).unwrap();
+    assert!(matches!(
+        a.add_member(gid.clone(), p256.clone()),
+        Err(MlsError::CiphersuiteMismatch {
+            group: 1,
+            key_package: 2
+        })
+    ));
+    assert!(matches!(
+        a.create_group_with_ciphersuite("g".into(), 2, None),
+        Err(MlsError::UnsupportedCapabilities { .. })
+    ));
+    let g3 = a
+        .create_group_with_ciphersuite("g".into(), i2.ciphersuite, None)
+        .unwrap();
+    assert_eq!(a.group_ciphersuite(g3.clone()).unwrap(), 3);
+    a.add_member(g3.clone(), x).unwrap();
+    // real client in suite 3 joining
+    let b = MlsClient::new(tmp("su_b"));
+    b.create_identity("bob".into()).unwrap();
+    assert!(matches!(
+        a.add_member(g3.clone(), b.generate_key_package(None).unwrap()),
+        Err(MlsError::CiphersuiteMismatch {
+            group: 3,
+            key_package: 1
+        })
+    ));
+}
+
+#[test]
+fn kp_reuse() {
+    let a = MlsClient::new(tmp("kr_a"));
+    let b = MlsClient::new(tmp("kr_b"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    let lr = b.generate_last_resort_key_package("bob".into()).unwrap();
+    let g1 = a.create_group("g".into(), None).unwrap();
+    let g2 = a.create_group("g".into(), None).unwrap();
+    let out = a.add_member(g1.clone(), kp.clone()).unwrap();
+    assert!(out.contains(r#""key_package_reused":false,"#));
+    assert!(
+        a.add_member_prepare(g2.clone(), kp.clone())
+            .unwrap()
+            .key_package_reused
+    );
+    a.abort_commit(g2.clone()).unwrap();
+    let g3 = a.create_group("g".into(), None).unwrap();
+    assert!(
+        !a.add_member_prepare(g2.clone(), lr.clone())
+            .unwrap()
+            .key_package_reused
+    );
+    assert!(
+        !a.add_member_prepare(g3.clone(), lr.clone())
+            .unwrap()
+            .key_package_reused
+    );
+    a.save_state().unwrap();
+    let a2 = MlsClient::new(tmp_keep("kr_a"));
+    let g4 = a2.create_group("g".into(), None).unwrap();
+    assert!(a2.add_member_prepare(g4, kp).unwrap().key_package_reused);
+}
+
+#[test]
+fn app_id() {
+    let a = MlsClient::new(tmp("appa"));
+    let b = MlsClient::new(tmp("appb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity_with_application_id("bob".into(), b"uuid-bob".to_vec())
+        .unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let m = a.get_members(gid.clone()).unwrap();
+    assert_eq!(m[0].application_id, None);
+    assert_eq!(m[1].application_id.as_deref(), Some(&b"uuid-bob"[..]));
+    let c = b.self_update(gid.clone()).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&c).unwrap();
+    a.process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+        .unwrap();
+    let f = a
+        .find_member(gid.clone(), b"bob".to_vec())
+        .unwrap()
+        .unwrap();
+    assert_eq!(f.application_id.as_deref(), Some(&b"uuid-bob"[..]));
+    let c = b.update_credential(gid.clone(), "robert".into()).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&c).unwrap();
+    a.process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+        .unwrap();
+    assert_eq!(
+        a.find_member(gid.clone(), b"robert".to_vec())
+            .unwrap()
+            .unwrap()
+            .application_id
+            .as_deref(),
+        Some(&b"uuid-bob"[..])
+    );
+    assert_eq!(
+        b.get_own_member_info(gid.clone())
+            .unwrap()
+            .application_id
+            .as_deref(),
+        Some(&b"uuid-bob"[..])
+    );
+    // group creator with app id, and persistence
+    let c2 = MlsClient::new(tmp("appc"));
+    c2.create_identity_with_application_id("carol".into(), vec![7])
+        .unwrap();
+    let g2 = c2.create_group("h".into(), None).unwrap();
+    assert_eq!(c2.get_members(g2).unwrap()[0].application_id, Some(vec![7]));
+    drop(c2);
+    let c3 = MlsClient::new(tmp_keep("appc"));
+    assert_eq!(c3.get_application_id().unwrap(), Some(vec![7]));
+}
+
+fn store_len(c: &MlsClient) -> usize {
+    c.state
+        .read()
+        .unwrap()
+        .crypto
+        .storage()
+        .values
+        .read()
+        .unwrap()
+        .len()
+}
+
+#[test]
+fn kp_prune() {
+    let a = MlsClient::new(tmp("kpa"));
+    let b = MlsClient::new(tmp("kpb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let n0 = store_len(&b);
+    let kp = b.generate_key_package(None).unwrap();
+    let spare = b.generate_key_package(None).unwrap();
+    let lr = b.generate_last_resort_key_package("bob".into()).unwrap();
+    assert_eq!(store_len(&b), n0 + 3);
+    b.delete_key_package(spare.clone()).unwrap();
+    b.delete_key_package(spare).unwrap();
+    b.delete_key_package(lr).unwrap();
+    assert_eq!(store_len(&b), n0 + 1);
+    let out = a.add_member(gid.clone(), kp.clone()).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let st = b.state.read().unwrap();
+    let bytes = hex::decode(&kp).unwrap();
+    let k = KeyPackageIn::tls_deserialize(&mut bytes.as_slice())
+        .unwrap()
+        .validate(st.crypto.crypto(), ProtocolVersion::default())
+        .unwrap();
+    let r = k.hash_ref(st.crypto.crypto()).unwrap();
+    let got: Option<KeyPackageBundle> = st.crypto.storage().key_package(&r).unwrap();
+    assert!(got.is_none());
+}
+
+struct Capture(Arc<Mutex<Vec<(LogLevel, String)>>>);
+
+impl MlsLogger for Capture {
+    fn log(&self, level: LogLevel, _target: String, message: String) {
+        self.0.lock().unwrap().push((level, message));
+    }
+}
+
+#[test]
+fn logging() {
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    let a = MlsClient::new(tmp("loga"));
+    a.set_logger(Box::new(Capture(logs.clone())));
+    let b = MlsClient::new(tmp("logb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let ct = a
+        .encrypt_message(gid.clone(), "secret-plaintext".into())
+        .unwrap();
+    b.decrypt_message(gid.clone(), ct).unwrap();
+    assert!(b.decrypt_message(gid.clone(), "zz".into()).is_err());
+    let logs = logs.lock().unwrap();
+    let has = |lvl: LogLevel, s: &str| logs.iter().any(|(l, m)| *l == lvl && m.contains(s));
+    assert!(has(LogLevel::Info, &format!("Created group {}", gid)));
+    assert!(has(
+        LogLevel::Info,
+        &format!("Member added to group {}", gid)
+    ));
+    assert!(has(
+        LogLevel::Info,
+        &format!("Commit merged in group {}, now at epoch 1", gid)
+    ));
+    assert!(has(LogLevel::Info, &format!("Joined group {}", gid)));
+    assert!(has(LogLevel::Info, "Saved state"));
+    assert!(has(LogLevel::Warn, "Failed to decode"));
+    assert!(!logs.iter().any(|(_, m)| m.contains("secret-plaintext")));
+}
+
+#[test]
+fn welcome_full() {
+    let a = MlsClient::new(tmp("wfa"));
+    let b = MlsClient::new(tmp("wfb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    let j = b
+        .process_welcome_full(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    assert_eq!(j.group_id, gid);
+    assert_eq!(j.epoch, 1);
+    assert_eq!(
+        j.members
+            .iter()
+            .map(|m| m.identity.clone())
+            .collect::<Vec<_>>(),
+        vec![b"alice".to_vec(), b"bob".to_vec()]
+    );
+}
+
+#[test]
+fn own_commit_echo() {
+    let a = MlsClient::new(tmp("eca"));
+    let b = MlsClient::new(tmp("ecb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    let commit = v["commit"].as_str().unwrap().to_string();
+    a.process_commit(gid.clone(), commit.clone()).unwrap();
+    let r = a
+        .process_messages(gid.clone(), vec![commit.clone()])
+        .unwrap();
+    assert!(matches!(r[0], IncomingMessage::OwnMessage));
+    assert!(matches!(
+        a.stage_commit(gid.clone(), commit),
+        Err(MlsError::OwnMessageReflected { .. })
+    ));
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let su: serde_json::Value = serde_json::from_str(&a.self_update(gid.clone()).unwrap()).unwrap();
+    let c = su["commit"].as_str().unwrap().to_string();
+    a.process_commit(gid.clone(), c.clone()).unwrap();
+    b.process_commit(gid.clone(), c).unwrap();
+    let ct = a.encrypt_message(gid.clone(), "x".into()).unwrap();
+    assert!(matches!(
+        a.decrypt_message(gid.clone(), ct.clone()),
+        Err(MlsError::OwnMessageReflected { .. })
+    ));
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "x");
+    let info: serde_json::Value =
+        serde_json::from_str(&a.get_group_info(gid.clone()).unwrap()).unwrap();
+    assert_eq!(info["epoch"], 2);
+}
+
+#[test]
+fn fingerprints() {
+    let (a, b, gid) = pair();
+    let fa = a.get_group_fingerprint(gid.clone()).unwrap();
+    let fb = b.get_group_fingerprint(gid.clone()).unwrap();
+    assert_eq!(fa.code, fb.code);
+    assert_eq!(fa.epoch, 1);
+    assert_eq!(fa.code.len(), 35);
+    let ma = a
+        .get_member_fingerprint(gid.clone(), b"bob".to_vec())
+        .unwrap();
+    let mb = b
+        .get_member_fingerprint(gid.clone(), b"bob".to_vec())
+        .unwrap();
+    assert_eq!(ma.code, mb.code);
+    assert_ne!(
+        ma.code,
+        a.get_member_fingerprint(gid.clone(), b"alice".to_vec())
+            .unwrap()
+            .code
+    );
+    assert_ne!(ma.code, fa.code);
+    assert!(matches!(
+        a.get_member_fingerprint(gid.clone(), b"eve".to_vec()),
+        Err(MlsError::UnknownMembers { .. })
+    ));
+    let su: serde_json::Value = serde_json::from_str(&a.self_update(gid.clone()).unwrap()).unwrap();
+    b.process_commit(gid.clone(), su["commit"].as_str().unwrap().to_string())
+        .unwrap();
+    let fa2 = a.get_group_fingerprint(gid.clone()).unwrap();
+    assert_eq!(fa2.epoch, 2);
+    assert_ne!(fa2.code, fa.code);
+    assert_eq!(fa2.code, b.get_group_fingerprint(gid.clone()).unwrap().code);
+    assert_ne!(
+        a.get_member_fingerprint(gid.clone(), b"bob".to_vec())
+            .unwrap()
+            .code,
+        ma.code
+    );
+}
+
+#[test]
+fn rotate_key() {
+    let (a, b, gid) = pair();
+    let g2 = a.create_group("solo".into(), None).unwrap();
+    let old = a.get_own_member_info(gid.clone()).unwrap().signature_key;
+    a.freeze_group(g2.clone(), "x".into()).unwrap();
+    assert!(matches!(
+        a.rotate_signature_key(),
+        Err(MlsError::GroupFrozen { .. })
+    ));
+    assert_eq!(
+        a.get_own_member_info(gid.clone()).unwrap().signature_key,
+        old
+    );
+    a.unfreeze_group(g2.clone()).unwrap();
+    let commits = a.rotate_signature_key().unwrap();
+    assert_eq!(commits.len(), 2);
+    let c = commits.iter().find(|c| c.group_id == gid).unwrap();
+    b.process_commit(gid.clone(), c.commit.clone()).unwrap();
+    let new = a.get_own_member_info(gid.clone()).unwrap().signature_key;
+    assert_ne!(new, old);
+    assert_eq!(
+        b.find_member(gid.clone(), b"alice".to_vec())
+            .unwrap()
+            .unwrap()
+            .signature_key,
+        new
+    );
+    assert_eq!(
+        a.get_own_member_info(gid.clone()).unwrap().identity,
+        b"alice".to_vec()
+    );
+    assert_eq!(
+        a.get_own_member_info(g2.clone()).unwrap().signature_key,
+        new
+    );
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+    {
+        let st = a.state.read().unwrap();
+        assert!(
+            SignatureKeyPair::read(st.crypto.storage(), &old, SignatureScheme::ED25519).is_none()
+        );
+    }
+    let c2 = MlsClient::new(tmp("rkc"));
+    c2.create_identity("carol".into()).unwrap();
+    let out = a
+        .add_member(gid.clone(), c2.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    c2.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    b.process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+        .unwrap();
+    let ct = a.encrypt_message(gid.clone(), "yo".into()).unwrap();
+    assert_eq!(c2.decrypt_message(gid.clone(), ct.clone()).unwrap(), "yo");
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "yo");
+}
+
+#[test]
+fn rotate_group_key() {
+    let a = MlsClient::new(tmp("rgk_a"));
+    let b = MlsClient::new(tmp("rgk_b"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    let g2 = a.create_group("solo".into(), None).unwrap();
+    let old = a.get_own_member_info(gid.clone()).unwrap().signature_key;
+    let mut keys = vec![old.clone()];
+    for _ in 0..2 {
+        let c = a.rotate_group_signature_key(gid.clone()).unwrap();
+        b.process_commit(gid.clone(), commit_of(&c)).unwrap();
+        let new = a.get_own_member_info(gid.clone()).unwrap().signature_key;
+        assert!(!keys.contains(&new));
+        assert_eq!(
+            b.find_member(gid.clone(), b"alice".to_vec())
+                .unwrap()
+                .unwrap()
+                .signature_key,
+            new
+        );
+        keys.push(new);
+    }
+    assert_eq!(
+        a.get_own_member_info(gid.clone()).unwrap().identity,
+        b"alice".to_vec()
+    );
+    assert_eq!(
+        a.get_own_member_info(g2.clone()).unwrap().signature_key,
+        old
+    );
+    // The key is kept for the group, not registered as an identity.
+    assert_eq!(a.list_identities(), vec!["alice".to_string()]);
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+    {
+        let st = a.state.read().unwrap();
+        let scheme = SignatureScheme::ED25519;
+        assert!(SignatureKeyPair::read(st.crypto.storage(), &old, scheme).is_some());
+        assert!(SignatureKeyPair::read(st.crypto.storage(), &keys[1], scheme).is_none());
+        assert!(SignatureKeyPair::read(st.crypto.storage(), &keys[2], scheme).is_some());
+    }
+    let g3 = a.create_group("later".into(), None).unwrap();
+    assert_eq!(a.get_own_member_info(g3).unwrap().signature_key, old);
+
+    // The group's key survives a restart.
+    drop(a);
+    let a = MlsClient::new(tmp_keep("rgk_a"));
+    let ct = a.encrypt_message(gid.clone(), "again".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "again");
+    let c = a.rotate_group_signature_key(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), commit_of(&c)).unwrap();
+
+    // Rotating the identity's key moves the group back onto it.
+    let commits = a.rotate_signature_key().unwrap();
+    let own = a.get_own_member_info(g2.clone()).unwrap().signature_key;
+    assert_eq!(
+        a.get_own_member_info(gid.clone()).unwrap().signature_key,
+        own
+    );
+    for commit in commits.into_iter().filter(|c| c.group_id == gid) {
+        b.process_commit(gid.clone(), commit.commit).unwrap();
+    }
+    let ct = a.encrypt_message(gid.clone(), "back".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "back");
+    a.freeze_group(g2.clone(), "x".into()).unwrap();
+    assert!(matches!(
+        a.rotate_group_signature_key(g2.clone()),
+        Err(MlsError::GroupFrozen { .. })
+    ));
+
+    // Removed from a group with a key of its own: the key goes, the
+    // identity's stays in use.
+    let c = a.rotate_group_signature_key(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), commit_of(&c)).unwrap();
+    let group_key = a.get_own_member_info(gid.clone()).unwrap().signature_key;
+    let removal = b.remove_member(gid.clone(), 0).unwrap();
+    assert!(matches!(
+        a.process_messages(gid.clone(), vec![commit_of(&removal)])
+            .unwrap()
+            .as_slice(),
+        [IncomingMessage::Removed { .. }]
+    ));
+    {
+        let st = a.state.read().unwrap();
+        let scheme = SignatureScheme::ED25519;
+        assert!(st.group_signers.is_empty());
+        assert_eq!(st.signer.as_ref().unwrap().public(), own.as_slice());
+        assert!(SignatureKeyPair::read(st.crypto.storage(), &group_key, scheme).is_none());
+        assert!(SignatureKeyPair::read(st.crypto.storage(), &own, scheme).is_some());
+    }
+    let g4 = a.create_group("after".into(), None).unwrap();
+    assert_eq!(a.get_own_member_info(g4).unwrap().signature_key, own);
+}
+
+#[test]
+fn welcome_wrong_kp() {
+    let a = MlsClient::new(tmp("wka"));
+    let b = MlsClient::new(tmp("wkb"));
+    let c = MlsClient::new(tmp("wkc"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    c.create_identity("carol".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    let w = v["welcome"].as_str().unwrap().to_string();
+    let before = store_len(&c);
+    match c.process_welcome(w.clone()) {
+        Err(MlsError::NoMatchingKeyPackage { key_package_refs }) => {
+            assert_eq!(key_package_refs.len(), 1)
+        }
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+    assert_eq!(store_len(&c), before);
+    assert!(c.list_all_groups().is_empty());
+    b.process_welcome(w.clone()).unwrap();
+    let b2 = MlsClient::new(tmp("wkb2"));
+    b2.create_identity("bob".into()).unwrap();
+    assert!(matches!(
+        b.process_welcome(w),
+        Err(MlsError::GroupAlreadyJoined { .. })
+    ));
+}
+
+type Removals = Arc<Mutex<Vec<(Vec<u8>, bool)>>>;
+
+struct Removed(Removals);
+
+impl MlsEventListener for Removed {
+    fn on_member_added(&self, _: String, _: Vec<u8>) {}
+    fn on_member_removed(&self, _: String, identity: Vec<u8>, was_self: bool) {
+        self.0.lock().unwrap().push((identity, was_self));
+    }
+    fn on_epoch_changed(&self, _: String, _: u64) {}
+    fn on_group_joined(&self, _: String) {}
+    fn on_pending_message_evicted(&self, _: String) {}
+}
+
+#[test]
+fn removed_self() {
+    let dir = tmp("rsb");
+    let a = MlsClient::new(tmp("rsa"));
+    let b = MlsClient::new(dir.clone());
+    let c = MlsClient::new(tmp("rsc"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    c.create_identity("carol".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let out = a
+        .add_member(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    c.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    assert!(matches!(
+        b.process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+            .unwrap(),
+        CommitOutcome::Merged { epoch: 2, .. }
+    ));
+    let ev = Arc::new(Mutex::new(Vec::new()));
+    b.set_event_listener(Box::new(Removed(ev.clone())));
+    let r: serde_json::Value =
+        serde_json::from_str(&a.remove_member(gid.clone(), 1).unwrap()).unwrap();
+    let commit = r["commit"].as_str().unwrap().to_string();
+    assert!(matches!(
+        c.process_commit(gid.clone(), commit.clone()).unwrap(),
+        CommitOutcome::Merged { epoch: 3, .. }
+    ));
+    assert!(matches!(
+        b.process_commit(gid.clone(), commit).unwrap(),
+        CommitOutcome::RemovedSelf
+    ));
+    assert_eq!(*ev.lock().unwrap(), vec![(b"bob".to_vec(), true)]);
+    assert!(matches!(
+        b.encrypt_message(gid.clone(), "x".into()),
+        Err(MlsError::NoLongerMember { .. })
+    ));
+    assert!(matches!(
+        b.get_members(gid.clone()),
+        Err(MlsError::NoLongerMember { .. })
+    ));
+    assert!(b.list_active_groups(None).is_empty());
+    b.save_state().unwrap();
+    drop(b);
+    let b = MlsClient::new(tmp_keep("rsb"));
+    assert!(b.list_all_groups().is_empty());
+    assert!(matches!(
+        b.encrypt_message(gid.clone(), "x".into()),
+        Err(MlsError::NoLongerMember { .. })
+    ));
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(c.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+    // re-added
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    b.encrypt_message(gid.clone(), "back".into()).unwrap();
+}
+
+#[test]
+fn epoch_accessor() {
+    let (a, b, gid) = pair();
+    assert_eq!(a.get_epoch(gid.clone()).unwrap(), 1);
+    assert_eq!(b.get_epoch(gid.clone()).unwrap(), 1);
+    assert!(matches!(
+        a.get_epoch("00".into()),
+        Err(MlsError::GroupNotFound { .. })
+    ));
+}
+
+#[test]
+fn wrapped_kp() {
+    let a = MlsClient::new(tmp("wpa"));
+    let b = MlsClient::new(tmp("wpb"));
+    let c = MlsClient::new(tmp("wpc"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    c.create_identity("carol".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let wkp = b.generate_key_package_wrapped().unwrap();
+    let raw = c.generate_key_package(None).unwrap();
+    assert!(wkp.len() > raw.len());
+    assert_eq!(
+        a.get_key_package_info(wkp.clone()).unwrap().identity,
+        b"bob"
+    );
+    let out = a.add_member(gid.clone(), wkp).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let out = a.add_member(gid.clone(), raw).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    c.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    assert!(matches!(
+        a.add_member(gid.clone(), "deadbeef".into()),
+        Err(MlsError::ProtocolVersionMismatch { .. })
+    ));
+    match a.add_member(gid, "0001beef".into()) {
+        Err(MlsError::SerializationError { msg }) => {
+            assert!(
+                msg.contains("MLSMessage") && msg.contains("bare KeyPackage"),
+                "{}",
+                msg
+            )
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn commit_diff() {
+    let a = MlsClient::new(tmp("cda"));
+    let b = MlsClient::new(tmp("cdb"));
+    let c = MlsClient::new(tmp("cdc"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    c.create_identity("carol".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let out = a
+        .add_member(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    match b
+        .process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+        .unwrap()
+    {
+        CommitOutcome::Merged { diff, .. } => {
+            assert_eq!(diff.added.len(), 1);
+            assert_eq!(diff.added[0].identity, b"carol");
+            assert!(diff.removed.is_empty());
+        }
+        o => panic!("{:?}", o),
+    }
+    let upd = a.self_update(gid.clone()).unwrap();
+    let upd = serde_json::from_str::<serde_json::Value>(&upd)
+        .map(|v| v["commit"].as_str().unwrap().to_string())
+        .unwrap_or(upd);
+    match b.process_commit(gid.clone(), upd).unwrap() {
+        CommitOutcome::Merged { diff, .. } => {
+            assert!(diff.added.is_empty() && diff.removed.is_empty())
+        }
+        o => panic!("{:?}", o),
+    }
+    let r: serde_json::Value =
+        serde_json::from_str(&a.remove_member(gid.clone(), 2).unwrap()).unwrap();
+    match b
+        .process_commit(gid, r["commit"].as_str().unwrap().to_string())
+        .unwrap()
+    {
+        CommitOutcome::Merged { diff, .. } => {
+            assert!(diff.added.is_empty());
+            assert_eq!(diff.removed[0].identity, b"carol");
+        }
+        o => panic!("{:?}", o),
+    }
+}
+
+#[test]
+fn kp_lifetimes() {
+    let a = MlsClient::new(tmp("kla"));
+    let b = MlsClient::new(tmp("klb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let now = unix_time();
+    assert!(b.generate_key_packages(1, now, now, None).is_err());
+    let expired = b
+        .generate_key_packages(2, now - 7200, now - 3600, None)
+        .unwrap();
+    let future = b
+        .generate_key_packages(1, now + 3600, now + 7200, None)
+        .unwrap();
+    let ok = b
+        .generate_key_packages(1, now - 60, now + 3600, None)
+        .unwrap();
+    assert_eq!(expired.len(), 2);
+    match a.add_member(gid.clone(), expired[0].key_package.clone()) {
+        Err(MlsError::KeyPackageExpired { not_after }) => assert_eq!(not_after, now - 3600),
+        other => panic!("{:?}", other),
+    }
+    match a.get_key_package_info(future[0].key_package.clone()) {
+        Err(MlsError::KeyPackageNotYetValid { not_before }) => assert_eq!(not_before, now + 3600),
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+    assert!(matches!(
+        a.add_member(gid.clone(), future[0].key_package.clone()),
+        Err(MlsError::KeyPackageNotYetValid { .. })
+    ));
+    let info = a.get_key_package_info(ok[0].key_package.clone()).unwrap();
+    assert_eq!((info.not_before, info.not_after), (now - 60, now + 3600));
+    let before = store_len(&b);
+    assert_eq!(b.prune_expired_key_packages().unwrap(), 2);
+    assert!(store_len(&b) < before);
+    assert_eq!(b.prune_expired_key_packages().unwrap(), 0);
+    let out = a.add_member(gid, ok[0].key_package.clone()).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+}
+
+#[test]
+fn custom_proposal() {
+    let caps = ClientCapabilities {
+        proposals: vec![0xF0F0],
+        ..Default::default()
+    };
+    let a = MlsClient::new(tmp("cpa"));
+    let b = MlsClient::new(tmp("cpb"));
+    let c = MlsClient::new(tmp("cpc"));
+    let d = MlsClient::new(tmp("cpd"));
+    a.create_identity_with_capabilities("alice".into(), caps.clone())
+        .unwrap();
+    b.create_identity_with_capabilities("bob".into(), caps.clone())
+        .unwrap();
+    c.create_identity_with_capabilities("carol".into(), caps)
+        .unwrap();
+    d.create_identity("dave".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    for m in [&b, &c] {
+        let out = a
+            .add_member(gid.clone(), m.generate_key_package(None).unwrap())
+            .unwrap();
+        let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+        m.process_welcome(v["welcome"].as_str().unwrap().to_string())
+            .unwrap();
+        if std::ptr::eq(m, &c) {
+            b.process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+                .unwrap();
+        }
+    }
+    assert!(a.send_custom_proposal(gid.clone(), 0x0001, vec![]).is_err());
+    let p: serde_json::Value = serde_json::from_str(
+        &a.send_custom_proposal(gid.clone(), 0xF0F0, b"admin:carol".to_vec())
+            .unwrap(),
+    )
+    .unwrap();
+    let p = p["proposal"].as_str().unwrap().to_string();
+    for m in [&b, &c] {
+        match m
+            .process_messages(gid.clone(), vec![p.clone()])
+            .unwrap()
+            .remove(0)
+        {
+            IncomingMessage::CustomProposal {
+                proposal_type,
+                payload,
+                sender_identity,
+            } => {
+                assert_eq!(
+                    (proposal_type, payload, sender_identity),
+                    (0xF0F0, b"admin:carol".to_vec(), b"alice".to_vec())
+                );
+            }
+            o => panic!("{:?}", o),
+        }
+    }
+    let commit: serde_json::Value =
+        serde_json::from_str(&b.commit_pending_proposals(gid.clone()).unwrap()).unwrap();
+    let commit = commit["commit"].as_str().unwrap().to_string();
+    assert!(matches!(
+        a.process_commit(gid.clone(), commit.clone()).unwrap(),
+        CommitOutcome::Merged { .. }
+    ));
+    assert!(matches!(
+        c.process_commit(gid.clone(), commit).unwrap(),
+        CommitOutcome::Merged { .. }
+    ));
+    assert_eq!(
+        a.get_epoch(gid.clone()).unwrap(),
+        c.get_epoch(gid.clone()).unwrap()
+    );
+
+    let out = a
+        .add_member(gid.clone(), d.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    d.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    match a.send_custom_proposal(gid, 0xF0F0, vec![]) {
+        Err(MlsError::CapabilitiesMismatch {
+            subject,
+            missing_proposals,
+            ..
+        }) => {
+            assert!(subject.contains("dave"));
+            assert_eq!(missing_proposals, vec![0xF0F0]);
+        }
+        o => panic!("{:?}", o),
+    }
+}
+
+#[test]
+fn inspect() {
+    let (a, b, gid) = pair();
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    let m = b.inspect_message(ct.clone()).unwrap();
+    assert_eq!(
+        (m.kind, m.group_id.as_deref(), m.epoch, m.encrypted),
+        (MessageKind::Application, Some(gid.as_str()), Some(1), true)
+    );
+    let before = b.get_epoch(gid.clone()).unwrap();
+    let up: serde_json::Value = serde_json::from_str(&a.self_update(gid.clone()).unwrap()).unwrap();
+    let commit = up["commit"].as_str().unwrap().to_string();
+    let m = b.inspect_message(commit.clone()).unwrap();
+    assert_eq!((m.kind, m.epoch), (MessageKind::Commit, Some(1)));
+    assert_eq!(b.get_epoch(gid.clone()).unwrap(), before);
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+    b.process_commit(gid, commit).unwrap();
+    let c = MlsClient::new(tmp("insc"));
+    c.create_identity("c".into()).unwrap();
+    let kp = c.generate_key_package_wrapped().unwrap();
+    let m = b.inspect_message(kp).unwrap();
+    assert_eq!(
+        (m.kind, m.group_id, m.encrypted),
+        (MessageKind::KeyPackage, None, false)
+    );
+    assert!(matches!(
+        b.inspect_message("00".into()),
+        Err(MlsError::SerializationError { .. })
+    ));
+}
+
+#[test]
+fn classify() {
+    let (a, b, gid) = pair();
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(
+        b.classify_message(ct).unwrap(),
+        MessageClass::PrivateMessage
+    );
+    let info = a.export_group_info(gid.clone(), true).unwrap();
+    assert_eq!(b.classify_message(info).unwrap(), MessageClass::GroupInfo);
+    let c = MlsClient::new(tmp("clc"));
+    c.create_identity("carol".into()).unwrap();
+    let wrapped = c.generate_key_package_wrapped().unwrap();
+    assert_eq!(
+        a.classify_message(wrapped).unwrap(),
+        MessageClass::KeyPackage
+    );
+    let kp = c.generate_key_package(None).unwrap();
+    let out = a.add_member(gid.clone(), kp).unwrap();
+    assert_eq!(
+        c.classify_message(field(&out, "welcome")).unwrap(),
+        MessageClass::Welcome
+    );
+    b.process_commit(gid.clone(), commit_of(&out)).unwrap();
+    a.set_wire_format_policy(gid.clone(), true).unwrap();
+    let up = a.self_update(gid.clone()).unwrap();
+    assert_eq!(
+        b.classify_message(commit_of(&up)).unwrap(),
+        MessageClass::PublicMessage
+    );
+    assert!(matches!(
+        b.classify_message("00".into()),
+        Err(MlsError::SerializationError { .. })
+    ));
+}
+
+#[test]
+fn rejoin_after_loss() {
+    let a = MlsClient::new(tmp("rja"));
+    let b = MlsClient::new(tmp("rjb"));
+    let c = MlsClient::new(tmp("rjc"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    c.create_identity("carol".into()).unwrap();
+    let backup = b.export_backup("pw".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    for m in [&b, &c] {
+        let out = a
+            .add_member(gid.clone(), m.generate_key_package(None).unwrap())
+            .unwrap();
+        let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+        m.process_welcome(v["welcome"].as_str().unwrap().to_string())
+            .unwrap();
+        if std::ptr::eq(m, &c) {
+            b.process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+                .unwrap();
+        }
+    }
+    // Bob loses the device and restores the pre-group backup.
+    let b2 = MlsClient::import_backup(tmp("rjb2"), backup, "pw".into()).unwrap();
+    assert!(b2.list_all_groups().is_empty());
+
+    let info = a.export_group_info(gid.clone(), false).unwrap();
+    let tree = a.export_ratchet_tree(gid.clone()).unwrap();
+    assert!(b2.rejoin(info.clone(), None, None).is_err());
+    let r: serde_json::Value =
+        serde_json::from_str(&b2.rejoin(info, Some(tree), None).unwrap()).unwrap();
+    assert_eq!(r["group_id"], gid.as_str());
+    let commit = r["commit"].as_str().unwrap().to_string();
+    for m in [&a, &c] {
+        m.process_commit(gid.clone(), commit.clone()).unwrap();
+        assert_eq!(m.get_members(gid.clone()).unwrap().len(), 3);
+    }
+    assert_eq!(
+        b2.get_epoch(gid.clone()).unwrap(),
+        a.get_epoch(gid.clone()).unwrap()
+    );
+    let ct = b2.encrypt_message(gid.clone(), "back".into()).unwrap();
+    assert_eq!(a.decrypt_message(gid.clone(), ct).unwrap(), "back");
+    let ct = c.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(b2.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+
+    // Rejoining again over live state replaces it.
+    let info = c.export_group_info(gid.clone(), true).unwrap();
+    let r: serde_json::Value = serde_json::from_str(&b2.rejoin(info, None, None).unwrap()).unwrap();
+    a.process_commit(gid.clone(), r["commit"].as_str().unwrap().to_string())
+        .unwrap();
+    assert_eq!(a.get_members(gid.clone()).unwrap().len(), 3);
+    let ct = a.encrypt_message(gid.clone(), "again".into()).unwrap();
+    assert_eq!(b2.decrypt_message(gid, ct).unwrap(), "again");
+}
+
+#[test]
+fn generation_gaps() {
+    let a = MlsClient::new(tmp("gga"));
+    let b = MlsClient::new(tmp("ggb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a
+        .create_group_with_config("g".into(), envelope_config(), None)
+        .unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    assert!(b
+        .get_last_seen_generation(gid.clone(), b"alice".to_vec())
+        .unwrap()
+        .is_none());
+    let cts: Vec<Vec<u8>> = (0..5)
+        .map(|i| {
+            a.encrypt_message_with_aad(gid.clone(), vec![i], b"x".to_vec())
+                .unwrap()
+        })
+        .collect();
+    let mut got = Vec::new();
+    for i in [0, 1, 3, 4] {
+        let m = b
+            .decrypt_message_with_aad(gid.clone(), cts[i].clone())
+            .unwrap();
+        assert_eq!(
+            (m.plaintext, m.aad, m.sender_index),
+            (vec![i as u8], b"x".to_vec(), 0)
+        );
+        got.push(m.generation.unwrap());
+    }
+    let gens: Vec<u32> = got.iter().map(|g| g.generation).collect();
+    assert_eq!(gens, vec![0, 1, 3, 4]);
+    // The generation is not in the clear, and the AAD is sent as given
+    // even when it looks like a framed one.
+    let aad = [b"MLSg".as_slice(), &[0, 0, 0, 9], b"app"].concat();
+    let ct = a
+        .encrypt_message_with_aad(gid.clone(), b"z".to_vec(), aad.clone())
+        .unwrap();
+    let wire = MlsMessageIn::tls_deserialize(&mut ct.as_slice()).unwrap();
+    match wire.extract() {
+        MlsMessageBodyIn::PrivateMessage(m) => assert_eq!(m.aad(), aad.as_slice()),
+        _ => panic!("not a private message"),
+    }
+    let m = b.decrypt_message_with_aad(gid.clone(), ct).unwrap();
+    assert_eq!((m.aad, m.generation.unwrap().generation), (aad, 5));
+    let missing: Vec<u32> = gens.windows(2).flat_map(|w| w[0] + 1..w[1]).collect();
+    assert_eq!(missing, vec![2]);
+    assert_eq!(
+        b.get_last_seen_generation(gid.clone(), b"alice".to_vec())
+            .unwrap(),
+        Some(MessageGeneration {
+            epoch: 1,
+            generation: 5
+        })
+    );
+    // The late message does not move the high-water mark back.
+    assert_eq!(
+        b.decrypt_message_with_aad(gid.clone(), cts[2].clone())
+            .unwrap()
+            .generation
+            .unwrap()
+            .generation,
+        2
+    );
+    assert_eq!(
+        b.get_last_seen_generation(gid.clone(), b"alice".to_vec())
+            .unwrap()
+            .unwrap()
+            .generation,
+        5
+    );
+    // New epoch, counter restarts.
+    let up: serde_json::Value = serde_json::from_str(&a.self_update(gid.clone()).unwrap()).unwrap();
+    b.process_commit(gid.clone(), up["commit"].as_str().unwrap().to_string())
+        .unwrap();
+    let ct = a.encrypt_message(gid.clone(), "y".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "y");
+    assert_eq!(
+        b.get_last_seen_generation(gid.clone(), b"alice".to_vec())
+            .unwrap(),
+        Some(MessageGeneration {
+            epoch: 2,
+            generation: 0
+        })
+    );
+    // Survives a save and reload.
+    b.save_state().unwrap();
+    let b2 = MlsClient::new(tmp_keep("ggb"));
+    assert_eq!(
+        b2.get_last_seen_generation(gid, b"alice".to_vec()).unwrap(),
+        Some(MessageGeneration {
+            epoch: 2,
+            generation: 0
+        })
+    );
+}
+
+#[test]
+fn pending_queue() {
+    let a = MlsClient::new(tmp("pqa"));
+    let b = MlsClient::new(tmp("pqb"));
+    let c = MlsClient::new(tmp("pqc"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    c.create_identity("carol".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let welcome_b = serde_json::from_str::<serde_json::Value>(&out).unwrap()["welcome"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let m1 = a.encrypt_message(gid.clone(), "one".into()).unwrap();
+    let out = a
+        .add_member(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    let add_c = serde_json::from_str::<serde_json::Value>(&out).unwrap()["commit"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let m2 = a.encrypt_message(gid.clone(), "two".into()).unwrap();
+
+    // Disabled by default.
+    assert!(matches!(
+        b.decrypt_message(gid.clone(), m1.clone()),
+        Err(MlsError::GroupNotFound { .. })
+    ));
+    b.set_pending_queue_limits(8, 0).unwrap();
+    let cap = Cap::default();
+    b.set_event_listener(Box::new(cap.clone()));
+    assert!(matches!(
+        b.decrypt_message(gid.clone(), m1.clone()),
+        Err(MlsError::QueuedForUnknownGroup { .. })
+    ));
+    assert!(matches!(
+        b.process_commit(gid.clone(), add_c).unwrap(),
+        CommitOutcome::QueuedForUnknownGroup
+    ));
+    let r = b.process_messages(gid.clone(), vec![m2]).unwrap();
+    assert!(matches!(r[0], IncomingMessage::QueuedForUnknownGroup));
+    assert!(matches!(
+        b.decrypt_message(gid.clone(), "00".into()),
+        Err(MlsError::GroupNotFound { .. }) | Err(MlsError::SerializationError { .. })
+    ));
+
+    let joined = b.process_welcome_full(welcome_b).unwrap();
+    assert_eq!(joined.epoch, 1);
+    assert_eq!(joined.queued.len(), 3);
+    assert!(
+        matches!(&joined.queued[0], IncomingMessage::Application { plaintext, .. } if plaintext == b"one")
+    );
+    assert!(matches!(
+        joined.queued[1],
+        IncomingMessage::Commit { epoch: 2 }
+    ));
+    assert!(
+        matches!(&joined.queued[2], IncomingMessage::Application { plaintext, .. } if plaintext == b"two")
+    );
+    assert_eq!(b.get_members(gid.clone()).unwrap().len(), 3);
+
+    // Overflow evicts the oldest.
+    b.set_pending_queue_limits(2, 0).unwrap();
+    let other = a.create_group("h".into(), None).unwrap();
+    let out = a
+        .add_member(other.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let welcome = serde_json::from_str::<serde_json::Value>(&out).unwrap()["welcome"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    for text in ["x", "y", "z"] {
+        let ct = a.encrypt_message(other.clone(), text.into()).unwrap();
+        assert!(b.decrypt_message(other.clone(), ct).is_err());
+    }
+    assert_eq!(
+        cap.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| e.starts_with("evict"))
+            .count(),
+        1
+    );
+    let joined = b.process_welcome_full(welcome).unwrap();
+    let texts: Vec<Vec<u8>> = joined
+        .queued
+        .into_iter()
+        .map(|m| match m {
+            IncomingMessage::Application { plaintext, .. } => plaintext,
+            o => panic!("{:?}", o),
+        })
+        .collect();
+    assert_eq!(texts, vec![b"y".to_vec(), b"z".to_vec()]);
+}
+
+#[test]
+fn raw_payloads() {
+    let a = MlsClient::new_with_message_framing(tmp("rpl_a"), true);
+    let b = MlsClient::new(tmp("rpl_b"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    b.process_welcome(field(&a.add_member(gid.clone(), kp).unwrap(), "welcome"))
+        .unwrap();
+    // Plain OpenMLS application messages, as any MLS client sends them.
+    let openmls_message = |client: &MlsClient, gid: &str, payload: &[u8]| {
+        let mut state = client.state.write().unwrap();
+        let st = &mut *state;
+        let group = st.groups.get_mut(gid).unwrap();
+        let msg = group
+            .create_message(&st.crypto, st.signer.as_ref().unwrap(), payload)
+            .unwrap();
+        st.encoding.encode(&msg.tls_serialize_detached().unwrap())
+    };
+    for payload in [&b"plain"[..], b"", b"\x01\x00\x00\0\0\0\0x"] {
+        let d = b
+            .decrypt_message_with_sender(gid.clone(), openmls_message(&a, &gid, payload))
+            .unwrap();
+        assert_eq!(d.plaintext, payload);
+        assert_eq!(
+            (d.payload_kind, d.generation, d.header),
+            (PayloadKind::Chat, None, None)
+        );
+    }
+    // And ours are sent as they are, with no header despite the framing.
+    let ct = a
+        .encrypt_message_bytes(gid.clone(), b"ours".to_vec())
+        .unwrap();
+    let bytes = a.state.read().unwrap().encoding.decode(&ct).unwrap();
+    let wire = MlsMessageIn::tls_deserialize(&mut bytes.as_slice()).unwrap();
+    let mut state = b.state.write().unwrap();
+    let st = &mut *state;
+    let processed = st
+        .groups
+        .get_mut(&gid)
+        .unwrap()
+        .process_message(&st.crypto, wire.try_into_protocol_message().unwrap())
+        .unwrap();
+    match processed.into_content() {
+        ProcessedMessageContent::ApplicationMessage(app) => assert_eq!(app.into_bytes(), b"ours"),
+        _ => panic!("not an application message"),
+    }
+    drop(state);
+    assert!(b
+        .get_last_seen_generation(gid.clone(), b"alice".to_vec())
+        .unwrap()
+        .is_none());
+    assert!(matches!(
+        a.send_control_message(gid.clone(), PayloadKind::Reaction, b"+1".to_vec()),
+        Err(MlsError::Generic { .. })
+    ));
+    a.send_control_message(gid.clone(), PayloadKind::Chat, b"chat".to_vec())
+        .unwrap();
+
+    // With envelopes, payloads that are not one, or of an unknown kind,
+    // are refused rather than shown as chat.
+    let (a, b, gid) = enveloped_pair();
+    for payload in [&b"plain"[..], b"\x01\x09\x00\0\0\0\0x"] {
+        let Err(err) =
+            b.decrypt_message_with_sender(gid.clone(), openmls_message(&a, &gid, payload))
+        else {
+            panic!("accepted {payload:?}");
+        };
+        assert!(
+            matches!(err, MlsError::SerializationError { .. }),
+            "{err:?}"
+        );
+    }
+    let ct = a
+        .send_control_message(gid.clone(), PayloadKind::Reaction, b"+1".to_vec())
+        .unwrap();
+    let d = b.decrypt_message_with_sender(gid.clone(), ct).unwrap();
+    assert_eq!(
+        (d.payload_kind, d.plaintext.as_slice()),
+        (PayloadKind::Reaction, &b"+1"[..])
+    );
+}
+
+#[test]
+fn framed_messages() {
+    let a = MlsClient::new_with_message_framing(tmp("fma"), true);
+    let b = MlsClient::new_with_message_framing(tmp("fmb"), true);
+    let c = MlsClient::new(tmp("fmc"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    c.create_identity("carol".into()).unwrap();
+    let gid = a
+        .create_group_with_config("g".into(), envelope_config(), None)
+        .unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let out = a
+        .add_member(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+        .unwrap();
+    c.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let before = unix_time_ms();
+    for i in 0..3u64 {
+        let ct = a.encrypt_message(gid.clone(), format!("m{i}")).unwrap();
+        let m = b
+            .decrypt_message_with_sender(gid.clone(), ct.clone())
+            .unwrap();
+        assert_eq!(m.plaintext, format!("m{i}").into_bytes());
+        assert_eq!(m.sender_identity, b"alice".to_vec());
+        let h = m.header.unwrap();
+        assert_eq!(h.sequence, i);
+        assert!(h.timestamp_ms >= before && h.timestamp_ms <= unix_time_ms());
+        // A raw client gets the header too.
+        let raw = c.decrypt_message_with_sender(gid.clone(), ct).unwrap();
+        assert_eq!(raw.header, Some(h));
+        assert_eq!(raw.plaintext, format!("m{i}").into_bytes());
+    }
+    // Raw sender: passed through.
+    let ct = c.encrypt_message(gid.clone(), "raw".into()).unwrap();
+    let m = b.decrypt_message_with_sender(gid.clone(), ct).unwrap();
+    assert!(m.header.is_none());
+    assert_eq!(m.plaintext, b"raw".to_vec());
+    // Sequence continues across epochs and restarts.
+    let up: serde_json::Value = serde_json::from_str(&a.self_update(gid.clone()).unwrap()).unwrap();
+    b.process_commit(gid.clone(), up["commit"].as_str().unwrap().to_string())
+        .unwrap();
+    a.save_state().unwrap();
+    let a2 = MlsClient::new_with_message_framing(tmp_keep("fma"), true);
+    let ct = a2.encrypt_message(gid.clone(), "later".into()).unwrap();
+    let m = b
+        .decrypt_message_with_sender(gid.clone(), ct.clone())
+        .unwrap();
+    assert_eq!(m.header.unwrap().sequence, 3);
+    assert_eq!(m.generation.unwrap().generation, 0);
+}
+
+#[test]
+fn wipe_and_error_hygiene() {
+    let a = MlsClient::new(tmp("wipe_a"));
+    let b = MlsClient::new(tmp("wipe_b"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    // Invalid UTF-8 plaintext: the error must not echo it.
+    let mut secret = b"TOPSECRET".to_vec();
+    secret.push(0xff);
+    let ct = a
+        .encrypt_message_with_aad(gid.clone(), secret.clone(), vec![])
+        .unwrap();
+    let err = b
+        .decrypt_message(gid.clone(), hex::encode(ct))
+        .unwrap_err()
+        .to_string();
+    assert!(
+        !err.contains("TOPSECRET") && !err.contains("84, 79, 80"),
+        "{err}"
+    );
+    // Corrupt state: no quoting of content.
+    let dir = tmp("wipe_c");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        format!("{dir}/state.json"),
+        br#"{"identity": "SECRETKEYBYTES"}"#,
+    )
+    .unwrap();
+    let c = MlsClient::new(dir.clone());
+    let err = c.load_state().unwrap_err().to_string();
+    assert!(!err.contains("SECRETKEYBYTES"), "{err}");
+    // Wipe.
+    let g2 = a.create_group("g2".into(), None).unwrap();
+    a.save_state().unwrap();
+    let store = format!("{}/openmls_store.json", tmp_keep("wipe_a"));
+    let before = std::fs::read(&store).unwrap().len();
+    a.secure_wipe_group(gid.clone()).unwrap();
+    let after = std::fs::read(&store).unwrap();
+    assert_eq!(after.len(), before);
+    assert!(matches!(
+        a.encrypt_message(gid.clone(), "x".into()),
+        Err(MlsError::GroupNotFound { .. })
+    ));
+    assert!(matches!(
+        a.secure_wipe_group(gid.clone()),
+        Err(MlsError::GroupNotFound { .. })
+    ));
+    let a2 = MlsClient::new(tmp_keep("wipe_a"));
+    assert!(a2.encrypt_message(gid.clone(), "x".into()).is_err());
+    a2.encrypt_message(g2.clone(), "y".into()).unwrap();
+    // Archived groups too.
+    a2.archive_group(g2.clone()).unwrap();
+    a2.secure_wipe_group(g2.clone()).unwrap();
+    assert!(a2.encrypt_message(g2, "y".into()).is_err());
+}
+
+#[test]
+fn group_config() {
+    let a = MlsClient::new(tmp("gca"));
+    let b = MlsClient::new(tmp("gcb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a
+        .create_group_with_config(
+            "g".into(),
+            GroupConfig {
+                use_ratchet_tree_extension: false,
+                wire_format_public: true,
+                padding_size: 256,
+                external_senders: vec![],
+                max_past_epochs: 0,
+                payload_envelope: false,
+            },
+            None,
+        )
+        .unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    let commit = v["commit"].as_str().unwrap();
+    assert!(!a.inspect_message(commit.to_string()).unwrap().encrypted);
+    let welcome = v["welcome"].as_str().unwrap().to_string();
+    let tree = a.export_ratchet_tree(gid.clone()).unwrap();
+    assert_eq!(
+        b.process_welcome_with_ratchet_tree(welcome, tree).unwrap(),
+        gid
+    );
+    let short = a.encrypt_message(gid.clone(), "x".into()).unwrap();
+    let long = a.encrypt_message(gid.clone(), "y".repeat(100)).unwrap();
+    assert_eq!(short.len(), long.len());
+    assert_eq!(
+        b.decrypt_message(gid.clone(), long).unwrap(),
+        "y".repeat(100)
+    );
+    // Policy changes keep the tree setting.
+    a.set_wire_format_policy(gid.clone(), false).unwrap();
+    let c = MlsClient::new(tmp("gcc"));
+    c.create_identity("carol".into()).unwrap();
+    let out = a
+        .add_member(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    assert!(
+        a.inspect_message(v["commit"].as_str().unwrap().to_string())
+            .unwrap()
+            .encrypted
+    );
+    assert!(c
+        .process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .is_err());
+    // So do padding changes, archiving and a restart.
+    drop(a);
+    let a = MlsClient::new(tmp_keep("gca"));
+    a.set_padding_size(0).unwrap();
+    a.archive_group(gid.clone()).unwrap();
+    let e = MlsClient::new(tmp("gce"));
+    e.create_identity("erin".into()).unwrap();
+    let out = a
+        .add_member(gid.clone(), e.generate_key_package(None).unwrap())
+        .unwrap();
+    let welcome = field(&out, "welcome");
+    let err = e.process_welcome(welcome).unwrap_err();
+    assert!(err.to_string().contains("MissingRatchetTree"), "{err:?}");
+    // Defaults unchanged.
+    let g2 = a.create_group("g2".into(), None).unwrap();
+    let d = MlsClient::new(tmp("gcd"));
+    d.create_identity("dave".into()).unwrap();
+    let out = a
+        .add_member(g2, d.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    d.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+}
+
+#[test]
+fn diagnostics_match() {
+    let a = MlsClient::new(tmp("dga"));
+    let b = MlsClient::new(tmp("dgb"));
+    let c = MlsClient::new(tmp("dgc"));
+    for (cl, n) in [(&a, "alice"), (&b, "bob"), (&c, "carol")] {
+        cl.create_identity(n.into()).unwrap();
+    }
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let out = a
+        .add_member(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+        .unwrap();
+    c.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let (da, db) = (
+        a.get_group_diagnostics(gid.clone()).unwrap(),
+        b.get_group_diagnostics(gid.clone()).unwrap(),
+    );
+    assert_eq!(
+        (
+            da.epoch,
+            &da.tree_hash,
+            &da.confirmed_transcript_hash,
+            da.leaf_count
+        ),
+        (
+            db.epoch,
+            &db.tree_hash,
+            &db.confirmed_transcript_hash,
+            db.leaf_count
+        )
+    );
+    assert_eq!((da.own_leaf_index, db.own_leaf_index), (0, 1));
+    let out = a.remove_member(gid.clone(), 1).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    c.process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+        .unwrap();
+    let (da, dc) = (
+        a.get_group_diagnostics(gid.clone()).unwrap(),
+        c.get_group_diagnostics(gid.clone()).unwrap(),
+    );
+    assert_eq!((da.leaf_count, da.blank_leaf_count, da.epoch), (3, 1, 3));
+    assert_eq!(da.tree_hash, hex::encode(a.tree_hash(gid.clone()).unwrap()));
+    assert_eq!(
+        (
+            &da.tree_hash,
+            &da.confirmed_transcript_hash,
+            da.blank_leaf_count,
+            &da.extension_types
+        ),
+        (
+            &dc.tree_hash,
+            &dc.confirmed_transcript_hash,
+            dc.blank_leaf_count,
+            &dc.extension_types
+        )
+    );
+    assert_eq!(
+        a.export_ratchet_tree_json(gid.clone()).unwrap(),
+        c.export_ratchet_tree_json(gid.clone()).unwrap()
+    );
+    let ja: serde_json::Value =
+        serde_json::from_str(&a.export_ratchet_tree_json(gid.clone()).unwrap()).unwrap();
+    assert_eq!(ja["leaf_count"], 3);
+    assert_eq!(ja["leaves"].as_array().unwrap().len(), 2);
+    assert_eq!(ja["leaves"][1]["index"], 2);
+    assert_eq!(ja["leaves"][1]["identity"], hex::encode("carol"));
+}
+
+fn grease_key_package(
+    name: &str,
+    caps: &[u16],
+    kp_ext: Option<u16>,
+    leaf_ext: Option<u16>,
+) -> String {
+    let provider = MlsProvider::default();
+    let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).unwrap();
+    signer.store(provider.storage()).unwrap();
+    let credential = CredentialWithKey {
+        credential: BasicCredential::new(name.as_bytes().to_vec()).into(),
+        signature_key: signer.public().into(),
+    };
+    let caps: Vec<ExtensionType> = caps.iter().map(|c| ExtensionType::from(*c)).collect();
+    let caps = Capabilities::new(None, None, Some(&caps), None, None);
+    let mut b = KeyPackage::builder().leaf_node_capabilities(caps);
+    if let Some(t) = kp_ext {
+        b = b.key_package_extensions(
+            Extensions::single(Extension::Unknown(t, UnknownExtension(vec![1, 2, 3]))).unwrap(),
+        );
+    }
+    if let Some(t) = leaf_ext {
+        b = b.leaf_node_extensions(
+            Extensions::single(Extension::Unknown(t, UnknownExtension(vec![9]))).unwrap(),
+        );
+    }
+    let kp = b
+        .build(CIPHERSUITE, &provider, &signer, credential)
+        .unwrap();
+    hex::encode(kp.key_package().tls_serialize_detached().unwrap())
+}
+
+#[test]
+fn grease_extensions() {
+    let a = MlsClient::new(tmp("gra"));
+    let b = MlsClient::new(tmp("grb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    // GREASE in capabilities only.
+    let out = a
+        .add_member(
+            gid.clone(),
+            grease_key_package("caps", &[0x0a0a, 0x2a2a], None, None),
+        )
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+        .unwrap();
+    let kp = grease_key_package("future", &[0xff00, 0xff01], Some(0xff00), Some(0xff01));
+    let info = a.get_key_package_info(kp.clone()).unwrap();
+    assert_eq!(
+        info.unknown_extensions
+            .iter()
+            .map(|e| (e.extension_type, e.data.clone()))
+            .collect::<Vec<_>>(),
+        vec![(0xff00, vec![1, 2, 3])]
+    );
+    assert_eq!(
+        info.unknown_leaf_extensions
+            .iter()
+            .map(|e| (e.extension_type, e.data.clone()))
+            .collect::<Vec<_>>(),
+        vec![(0xff01, vec![9])]
+    );
+    let out = a.add_member(gid.clone(), kp).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+        .unwrap();
+    for c in [&a, &b] {
+        let m = c
+            .find_member(gid.clone(), b"future".to_vec())
+            .unwrap()
+            .unwrap();
+        assert_eq!(m.unknown_extensions.len(), 1);
+        assert_eq!(m.unknown_extensions[0].extension_type, 0xff01);
+        let all = c.get_members(gid.clone()).unwrap();
+        assert!(all
+            .iter()
+            .filter(|m| m.identity != b"future")
+            .all(|m| m.unknown_extensions.is_empty()));
+    }
+    // Not advertised: rejected.
+    assert!(a
+        .add_member(
+            gid.clone(),
+            grease_key_package("bad", &[], Some(0xff00), None)
+        )
+        .is_err());
+}
+
+#[test]
+fn external_remove() {
+    let server = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).unwrap();
+    let rogue = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).unwrap();
+    let a = MlsClient::new(tmp("exa"));
+    let b = MlsClient::new(tmp("exb"));
+    let c = MlsClient::new(tmp("exc"));
+    for (cl, n) in [(&a, "alice"), (&b, "bob"), (&c, "carol")] {
+        cl.create_identity(n.into()).unwrap();
+    }
+    let config = GroupConfig {
+        use_ratchet_tree_extension: true,
+        wire_format_public: false,
+        padding_size: 0,
+        external_senders: vec![ExternalSenderKey {
+            identity: b"ds".to_vec(),
+            signature_key: server.public().to_vec(),
+        }],
+        max_past_epochs: 0,
+        payload_envelope: false,
+    };
+    let gid = a
+        .create_group_with_config("g".into(), config, None)
+        .unwrap();
+    for cl in [&b, &c] {
+        let out = a
+            .add_member(gid.clone(), cl.generate_key_package(None).unwrap())
+            .unwrap();
+        let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+        if std::ptr::eq(cl, &c) {
+            b.process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+                .unwrap();
+        }
+        cl.process_welcome(v["welcome"].as_str().unwrap().to_string())
+            .unwrap();
+    }
+    assert!(b
+        .get_group_context_extensions(gid.clone())
+        .unwrap()
+        .iter()
+        .any(|e| e.extension_type == 5));
+    let group_id = GroupId::from_slice(&hex::decode(&gid).unwrap());
+    let epoch = GroupEpoch::from(a.get_epoch(gid.clone()).unwrap());
+    let propose = |signer: &SignatureKeyPair, index: u32| {
+        let m = ExternalProposal::new_remove::<MlsProvider>(
+            LeafNodeIndex::new(2),
+            group_id.clone(),
+            epoch,
+            signer,
+            SenderExtensionIndex::new(index),
+        )
+        .unwrap();
+        hex::encode(m.tls_serialize_detached().unwrap())
+    };
+    // Unknown index and wrong key are both rejected.
+    for (signer, index) in [(&server, 1), (&rogue, 0)] {
+        let err = b
+            .decrypt_message(gid.clone(), propose(signer, index))
+            .unwrap_err();
+        assert!(
+            matches!(err, MlsError::UnauthorizedExternalSender { .. }),
+            "{err:?}"
+        );
+    }
+    // Valid one: stored by members, committed by bob.
+    let p = propose(&server, 0);
+    for cl in [&a, &b] {
+        assert!(matches!(
+            cl.process_messages(gid.clone(), vec![p.clone()]).unwrap()[..],
+            [IncomingMessage::Proposal { .. }]
+        ));
+    }
+    let pending = b.get_pending_proposals(gid.clone()).unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(
+        (
+            pending[0].external_sender_index,
+            pending[0].sender_index,
+            pending[0].leaf_index
+        ),
+        (Some(0), None, Some(2))
+    );
+    assert_eq!(pending[0].identity.as_deref(), Some(&b"carol"[..]));
+    let out: serde_json::Value =
+        serde_json::from_str(&b.commit_pending_proposals(gid.clone()).unwrap()).unwrap();
+    a.process_commit(gid.clone(), out["commit"].as_str().unwrap().to_string())
+        .unwrap();
+    for cl in [&a, &b] {
+        assert!(cl
+            .find_member(gid.clone(), b"carol".to_vec())
+            .unwrap()
+            .is_none());
+        assert_eq!(cl.get_members(gid.clone()).unwrap().len(), 2);
+    }
+}
+
+#[test]
+fn ephemeral_client() {
+    let a = MlsClient::new_ephemeral();
+    let b = MlsClient::new_ephemeral();
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    let out = a.add_member(gid.clone(), kp).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+    let report = a.save_state().unwrap();
+    assert!(report.saved_groups.is_empty());
+    a.load_state().unwrap();
+    let ct = b.encrypt_message(gid.clone(), "back".into()).unwrap();
+    assert_eq!(a.decrypt_message(gid.clone(), ct).unwrap(), "back");
+    let blob = a.export_state("pw".into()).unwrap();
+    let c = MlsClient::new_ephemeral();
+    c.import_state(blob, "pw".into()).unwrap();
+}
+
+#[test]
+fn ctx_ext_default() {
+    let a = MlsClient::new_ephemeral();
+    a.create_identity("alice".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let e = a.get_group_context_extensions(gid).unwrap();
+    assert!(e.is_empty());
+}
+
+#[test]
+fn kp_refs() {
+    let a = MlsClient::new_ephemeral();
+    let b = MlsClient::new_ephemeral();
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let kps = b
+        .generate_key_packages(3, now - 60, now + 86400, None)
+        .unwrap();
+    assert_eq!(b.list_stored_key_packages().unwrap().len(), 3);
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), kps[1].key_package.clone())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    let joined = b
+        .process_welcome_full(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    assert_eq!(joined.key_package_ref, kps[1].key_package_ref);
+    let left = b.list_stored_key_packages().unwrap();
+    assert_eq!(left.len(), 2);
+    assert!(left
+        .iter()
+        .all(|k| k.key_package_ref != kps[1].key_package_ref
+            && !k.last_resort
+            && k.identity == b"bob"));
+    b.delete_key_package_by_ref(kps[0].key_package_ref.clone())
+        .unwrap();
+    let left = b.list_stored_key_packages().unwrap();
+    assert_eq!(left.len(), 1);
+    assert_eq!(left[0].key_package_ref, kps[2].key_package_ref);
+    assert!(b.delete_key_package_by_ref("zz".into()).is_err());
+    // Welcome for a deleted package
+    let gid2 = a.create_group("h".into(), None).unwrap();
+    let out = a.add_member(gid2, kps[0].key_package.clone()).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    assert!(matches!(
+        b.process_welcome(v["welcome"].as_str().unwrap().to_string()),
+        Err(MlsError::NoMatchingKeyPackage { .. })
+    ));
+}
+
+fn tmp_copy(dir: &str) -> String {
+    let dst = format!(
+        "{}_copy{}",
+        dir,
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    );
+    std::fs::create_dir_all(&dst).unwrap();
+    for e in std::fs::read_dir(dir).unwrap() {
+        let e = e.unwrap();
+        std::fs::copy(e.path(), std::path::Path::new(&dst).join(e.file_name())).unwrap();
+    }
+    dst
+}
+
+#[test]
+fn autosave_crash() {
+    let da = tmp("asa");
+    let db = tmp("asb");
+    let a = MlsClient::new(da.clone());
+    let b = MlsClient::new(db.clone());
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    drop(b);
+    let b = MlsClient::new(db.clone());
+    let out = a.add_member(gid.clone(), kp).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let m1 = a.encrypt_message(gid.clone(), "one".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), m1.clone()).unwrap(), "one");
+    drop(b);
+    let b = MlsClient::new(db.clone());
+    assert!(b.decrypt_message(gid.clone(), m1).is_err());
+    let m2 = a.encrypt_message(gid.clone(), "two".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), m2).unwrap(), "two");
+    // sender side survives too
+    drop(a);
+    let a = MlsClient::new(da.clone());
+    let m3 = a.encrypt_message(gid.clone(), "three".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), m3).unwrap(), "three");
+    // autosave off: nothing written until save_state
+    b.set_autosave(false).unwrap();
+    let m4 = a.encrypt_message(gid.clone(), "four".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), m4.clone()).unwrap(), "four");
+    let b2 = MlsClient::new(tmp_copy(&db));
+    assert_eq!(b2.decrypt_message(gid.clone(), m4.clone()).unwrap(), "four");
+    b.set_autosave(true).unwrap();
+    let b3 = MlsClient::new(tmp_copy(&db));
+    assert!(b3.decrypt_message(gid.clone(), m4).is_err());
+    // Values are written aside and renamed into place; a write cut short
+    // leaves only a temporary file, which is ignored.
+    let files = |dir: &str| -> Vec<String> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect()
+    };
+    assert!(files(&db).iter().all(|f| !f.ends_with(".tmp")));
+    std::fs::write(format!("{db}/state.json.tmp"), b"{\"torn").unwrap();
+    let backend = FileStorageBackend {
+        dir: std::path::PathBuf::from(&db),
+    };
+    assert!(!backend
+        .list("state".into())
+        .unwrap()
+        .iter()
+        .any(|k| k.ends_with(".tmp")));
+    drop(b);
+    let b = MlsClient::new(db.clone());
+    let m5 = a.encrypt_message(gid.clone(), "five".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), m5).unwrap(), "five");
+}
+
+#[test]
+fn reinit() {
+    let a = MlsClient::new_ephemeral();
+    let b = MlsClient::new_ephemeral();
+    let c = MlsClient::new_ephemeral();
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    c.create_identity("carol".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    for x in [&b, &c] {
+        let out = a
+            .add_member(gid.clone(), x.generate_key_package(None).unwrap())
+            .unwrap();
+        let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+        if std::ptr::eq(x, &c) {
+            b.process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+                .unwrap();
+        }
+        x.process_welcome(v["welcome"].as_str().unwrap().to_string())
+            .unwrap();
+    }
+    let ct = b.encrypt_message(gid.clone(), "before".into()).unwrap();
+    assert_eq!(
+        c.decrypt_message(gid.clone(), ct.clone()).unwrap(),
+        "before"
+    );
+    assert_eq!(a.decrypt_message(gid.clone(), ct).unwrap(), "before");
+    let out = a.propose_reinit(gid.clone(), 3).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    let new_id = v["new_group_id"].as_str().unwrap().to_string();
+    assert!(a.encrypt_message(gid.clone(), "x".into()).is_err());
+    for x in [&b, &c] {
+        let outcome = x
+            .process_commit(gid.clone(), v["commit"].as_str().unwrap().to_string())
+            .unwrap();
+        assert!(
+            matches!(outcome, CommitOutcome::ReinitRequired { ref new_group_id, ciphersuite: 3, .. } if *new_group_id == new_id)
+        );
+        let info = x.get_reinit(gid.clone()).unwrap().unwrap();
+        assert_eq!(
+            (info.new_group_id.as_str(), info.ciphersuite),
+            (new_id.as_str(), 3)
+        );
+        assert!(matches!(
+            x.encrypt_message(gid.clone(), "x".into()),
+            Err(MlsError::GroupReinitialized { ref new_group_id, .. }) if *new_group_id == new_id
+        ));
+    }
+    let kb = b.generate_key_package_for_ciphersuite(3).unwrap();
+    let kc = c.generate_key_package_for_ciphersuite(3).unwrap();
+    assert!(a.complete_reinit(gid.clone(), vec![kb.clone()]).is_err());
+    assert!(matches!(
+        a.complete_reinit(
+            gid.clone(),
+            vec![kb.clone(), c.generate_key_package(None).unwrap()]
+        ),
+        Err(MlsError::CiphersuiteMismatch { .. })
+    ));
+    let out = a.complete_reinit(gid.clone(), vec![kb, kc]).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(v["group_id"].as_str().unwrap(), new_id);
+    for x in [&b, &c] {
+        let j = x
+            .process_welcome_full(v["welcome"].as_str().unwrap().to_string())
+            .unwrap();
+        assert_eq!(j.group_id, new_id);
+        assert_eq!(j.members.len(), 3);
+    }
+    let d = b.get_group_diagnostics(new_id.clone()).unwrap();
+    assert_eq!(d.ciphersuite, 3);
+    let ct = a.encrypt_message(new_id.clone(), "hi".into()).unwrap();
+    assert_eq!(c.decrypt_message(new_id.clone(), ct).unwrap(), "hi");
+    let ct = c.encrypt_message(new_id.clone(), "after".into()).unwrap();
+    assert_eq!(b.decrypt_message(new_id.clone(), ct).unwrap(), "after");
+}
+
+#[test]
+fn group_padding() {
+    let a = MlsClient::new_ephemeral();
+    let b = MlsClient::new_ephemeral();
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let other = a.create_group("h".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    let short = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    let long = a.encrypt_message(gid.clone(), "x".repeat(40)).unwrap();
+    assert_ne!(short.len(), long.len());
+    b.decrypt_message(gid.clone(), short).unwrap();
+    b.decrypt_message(gid.clone(), long).unwrap();
+    a.set_group_padding_size(gid.clone(), 64).unwrap();
+    let short = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    let long = a.encrypt_message(gid.clone(), "x".repeat(30)).unwrap();
+    assert_eq!(short.len(), long.len());
+    assert_eq!(
+        b.decrypt_message(gid.clone(), long).unwrap(),
+        "x".repeat(30)
+    );
+    assert_ne!(
+        a.encrypt_message(other.clone(), "hi".into()).unwrap().len(),
+        a.encrypt_message(other, "x".repeat(40)).unwrap().len()
+    );
+    a.set_group_padding_size(gid.clone(), 0).unwrap();
+    assert_ne!(
+        a.encrypt_message(gid.clone(), "hi".into()).unwrap().len(),
+        a.encrypt_message(gid.clone(), "x".repeat(40))
+            .unwrap()
+            .len()
+    );
+}
+
+#[test]
+fn peer_proposals() {
+    let clients: Vec<MlsClient> = ["a", "b", "c", "d"]
+        .iter()
+        .map(|_| MlsClient::new_ephemeral())
+        .collect();
+    for (c, n) in clients.iter().zip(["alice", "bob", "carol", "dave"]) {
+        c.create_identity(n.into()).unwrap();
+    }
+    let (a, b, c, d) = (&clients[0], &clients[1], &clients[2], &clients[3]);
+    let gid = a.create_group("g".into(), None).unwrap();
+    for (i, joiner) in [b, c, d].into_iter().enumerate() {
+        let kp = joiner.generate_key_package(None).unwrap();
+        let out = a.add_member(gid.clone(), kp).unwrap();
+        for earlier in &clients[1..=i] {
+            earlier
+                .process_commit(gid.clone(), field(&out, "commit"))
+                .unwrap();
+        }
+        joiner.process_welcome(field(&out, "welcome")).unwrap();
+    }
+    let d_index = b
+        .get_members(gid.clone())
+        .unwrap()
+        .iter()
+        .find(|m| m.identity == b"dave")
+        .unwrap()
+        .index;
+    let proposal = field(
+        &b.propose_remove_member(gid.clone(), d_index).unwrap(),
+        "proposal",
+    );
+
+    let stored = match a.process_commit(gid.clone(), proposal.clone()).unwrap() {
+        CommitOutcome::ProposalStored { proposal } => proposal,
+        o => panic!("{:?}", o),
+    };
+    assert_eq!(stored.proposal_type, "Remove");
+    assert_eq!(stored.leaf_index, Some(d_index));
+    assert_eq!(stored.identity.as_deref(), Some(&b"dave"[..]));
+    let commit = field(&a.commit_pending_proposals(gid.clone()).unwrap(), "commit");
+
+    // Commit before proposal: nothing consumed, retry works.
+    match c.process_commit(gid.clone(), commit.clone()) {
+        Err(MlsError::MissingProposal { proposal_refs, .. }) => {
+            assert_eq!(proposal_refs, vec![stored.proposal_ref.clone()])
+        }
+        o => panic!("{:?}", o),
+    }
+    match &c
+        .process_messages(gid.clone(), vec![proposal.clone()])
+        .unwrap()[..]
+    {
+        [IncomingMessage::Proposal { proposal }] => {
+            assert_eq!(proposal.proposal_ref, stored.proposal_ref)
+        }
+        o => panic!("{:?}", o),
+    }
+    assert!(matches!(
+        c.process_commit(gid.clone(), commit.clone()).unwrap(),
+        CommitOutcome::Merged { .. }
+    ));
+    assert!(matches!(
+        b.process_commit(gid.clone(), commit.clone()).unwrap(),
+        CommitOutcome::Merged { .. }
+    ));
+    d.process_commit(gid.clone(), proposal.clone()).unwrap();
+    assert!(matches!(
+        d.process_commit(gid.clone(), commit.clone()).unwrap(),
+        CommitOutcome::RemovedSelf
+    ));
+    for m in [a, b, c] {
+        assert_eq!(m.get_members(gid.clone()).unwrap().len(), 3);
+    }
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(c.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+}
+
+struct AdminOnlyRemovals;
+
+impl CommitPolicy for AdminOnlyRemovals {
+    fn evaluate(&self, d: StagedCommitDescription) -> PolicyDecision {
+        if !d.removed_identities.is_empty() && d.sender_identity != b"alice" {
+            PolicyDecision::Reject {
+                reason: "only admins may remove".into(),
+            }
+        } else {
+            PolicyDecision::Allow
+        }
+    }
+}
+
+#[test]
+fn commit_policy() {
+    let clients: Vec<MlsClient> = (0..3).map(|_| MlsClient::new_ephemeral()).collect();
+    for (c, n) in clients.iter().zip(["alice", "bob", "carol"]) {
+        c.create_identity(n.into()).unwrap();
+    }
+    let (a, b, c) = (&clients[0], &clients[1], &clients[2]);
+    let gid = a.create_group("g".into(), None).unwrap();
+    for (i, joiner) in [b, c].into_iter().enumerate() {
+        let out = a
+            .add_member(gid.clone(), joiner.generate_key_package(None).unwrap())
+            .unwrap();
+        for earlier in &clients[1..=i] {
+            earlier
+                .process_commit(gid.clone(), field(&out, "commit"))
+                .unwrap();
+        }
+        joiner.process_welcome(field(&out, "welcome")).unwrap();
+    }
+    for cl in [a, c] {
+        cl.set_commit_policy(Box::new(AdminOnlyRemovals), false);
+    }
+    let carol = b
+        .get_members(gid.clone())
+        .unwrap()
+        .iter()
+        .find(|m| m.identity == b"carol")
+        .unwrap()
+        .index;
+
+    // Bob's own client does not check its own commits.
+    let commit = field(&b.remove_member(gid.clone(), carol).unwrap(), "commit");
+    let epoch = a.get_epoch(gid.clone()).unwrap();
+    match a.process_commit(gid.clone(), commit.clone()) {
+        Err(MlsError::CommitRejectedByPolicy { reason }) => {
+            assert_eq!(reason, "only admins may remove")
+        }
+        o => panic!("{:?}", o),
+    }
+    assert_eq!(a.get_epoch(gid.clone()).unwrap(), epoch);
+    assert_eq!(a.get_members(gid.clone()).unwrap().len(), 3);
+    assert!(
+        matches!(c.stage_commit(gid.clone(), commit.clone()).unwrap(), StagedCommitDescription { ref sender_identity, .. } if sender_identity == b"bob")
+    );
+    assert!(matches!(
+        c.merge_staged_commit(gid.clone()),
+        Err(MlsError::CommitRejectedByPolicy { .. })
+    ));
+
+    // With own-commit checking, nothing is created.
+    let b2 = MlsClient::new_ephemeral();
+    b2.create_identity("bob2".into()).unwrap();
+    let g2 = b2.create_group("g2".into(), None).unwrap();
+    let out = b2
+        .add_member(g2.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    c.process_welcome(field(&out, "welcome")).unwrap();
+    b2.set_commit_policy(Box::new(AdminOnlyRemovals), true);
+    assert!(matches!(
+        b2.remove_member(g2.clone(), 1),
+        Err(MlsError::CommitRejectedByPolicy { .. })
+    ));
+    assert_eq!(b2.get_epoch(g2.clone()).unwrap(), 1);
+    b2.encrypt_message(g2.clone(), "still usable".into())
+        .unwrap();
+
+    // The admin's removal goes through; metadata changes are described.
+    let meta = field(
+        &a.set_group_metadata(gid.clone(), b"name".to_vec()).unwrap(),
+        "commit",
+    );
+    let desc = c.stage_commit(gid.clone(), meta).unwrap();
+    assert!(
+        desc.new_extensions.is_some_and(|e| !e.is_empty()) && desc.removed_identities.is_empty()
+    );
+    c.merge_staged_commit(gid.clone()).unwrap();
+    let commit = field(&a.remove_member(gid.clone(), carol).unwrap(), "commit");
+    assert!(matches!(
+        c.process_commit(gid.clone(), commit).unwrap(),
+        CommitOutcome::RemovedSelf
+    ));
+}
+
+#[test]
+fn error_kinds() {
+    let (a, b, gid) = pair();
+    assert!(matches!(
+        a.remove_member(gid.clone(), 7),
+        Err(MlsError::UnknownMember { .. })
+    ));
+    assert!(matches!(
+        a.propose_remove_member(gid.clone(), 7),
+        Err(MlsError::UnknownMember { .. })
+    ));
+
+    let mut kp = hex::decode(
+        {
+            let c = MlsClient::new_ephemeral();
+            c.create_identity("carol".into()).unwrap();
+            c
+        }
+        .generate_key_package(None)
+        .unwrap(),
+    )
+    .unwrap();
+    let last = kp.len() - 1;
+    kp[last] ^= 1;
+    assert!(matches!(
+        a.add_member(gid.clone(), hex::encode(kp)),
+        Err(MlsError::InvalidKeyPackage { .. })
+    ));
+
+    a.set_wire_format_policy(gid.clone(), true).unwrap();
+    b.set_wire_format_policy(gid.clone(), true).unwrap();
+    let mut p = hex::decode(field(
+        &a.propose_remove_member(gid.clone(), 1).unwrap(),
+        "proposal",
+    ))
+    .unwrap();
+    let last = p.len() - 1;
+    p[last] ^= 1;
+    assert!(matches!(
+        b.process_commit(gid.clone(), hex::encode(p)),
+        Err(MlsError::InvalidSignature { .. })
+    ));
+}
+
+#[test]
+fn group_pages() {
+    let a = MlsClient::new_ephemeral();
+    a.create_identity("alice".into()).unwrap();
+    for i in 0..5 {
+        a.create_group(format!("g{i}"), None).unwrap();
+    }
+    assert_eq!(a.count_active_groups(), 5);
+    let mut all = a.list_active_groups(None);
+    all.sort();
+    let pages: Vec<String> = (0..3)
+        .flat_map(|p| a.list_active_groups_page(p * 2, 2))
+        .collect();
+    assert_eq!(pages, all);
+    assert!(a.list_active_groups_page(5, 10).is_empty());
+}
+
+#[test]
+fn protocol_versions() {
+    let (a, b, gid) = pair();
+    assert_eq!(a.get_protocol_version(), "mls10");
+    assert_eq!(a.set_protocol_version(2), "mls10");
+    assert_eq!(
+        a.get_group_diagnostics(gid.clone())
+            .unwrap()
+            .protocol_version,
+        "mls10"
+    );
+    let kp = b.generate_key_package(None).unwrap();
+    assert_eq!(
+        a.get_key_package_info(kp.clone()).unwrap().protocol_version,
+        "mls10"
+    );
+
+    let mut bad = hex::decode(&kp).unwrap();
+    bad[0] = 0xff;
+    bad[1] = 0xfe;
+    match a.add_member(gid.clone(), hex::encode(&bad)) {
+        Err(MlsError::ProtocolVersionMismatch { ours, theirs }) => {
+            assert_eq!((ours.as_str(), theirs.as_str()), ("mls10", "0xfffe"))
+        }
+        o => panic!("{:?}", o),
+    }
+    let c = MlsClient::new_ephemeral();
+    c.create_identity("carol".into()).unwrap();
+    let out = a
+        .add_member(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    let mut welcome = hex::decode(field(&out, "welcome")).unwrap();
+    welcome[1] = 2;
+    assert!(matches!(
+        c.process_welcome(hex::encode(welcome)),
+        Err(MlsError::ProtocolVersionMismatch { .. })
+    ));
+    c.process_welcome(field(&out, "welcome")).unwrap();
+}
+
+#[test]
+fn group_export() {
+    let dir = tmp("gexa");
+    let a = MlsClient::new(dir.clone());
+    let b = MlsClient::new(tmp("gexb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    let ct = a.encrypt_message(gid.clone(), "before".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "before");
+
+    let blob = b.export_group(gid.clone()).unwrap();
+    assert!(b.import_group(blob.clone()).is_err());
+    // Another identity cannot take it over.
+    let c = MlsClient::new_ephemeral();
+    c.create_identity("carol".into()).unwrap();
+    assert!(c.import_group(blob.clone()).is_err());
+    assert!(c.list_active_groups(None).is_empty());
+
+    b.secure_wipe_group(gid.clone()).unwrap();
+    assert_eq!(b.import_group(blob).unwrap(), gid);
+    let ct = a.encrypt_message(gid.clone(), "after".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "after");
+    let ct = b.encrypt_message(gid.clone(), "back".into()).unwrap();
+    assert_eq!(a.decrypt_message(gid.clone(), ct).unwrap(), "back");
+}
+
+#[test]
+fn welcome_details() {
+    let a = MlsClient::new_ephemeral();
+    let b = MlsClient::new_ephemeral();
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    a.set_group_metadata(gid.clone(), b"Team chat".to_vec())
+        .unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    let joined = b.process_welcome_full(field(&out, "welcome")).unwrap();
+    assert_eq!(joined.group_id, gid);
+    assert_eq!(joined.metadata.as_deref(), Some(&b"Team chat"[..]));
+    assert_eq!(
+        joined.ciphersuite,
+        b.group_ciphersuite(gid.clone()).unwrap()
+    );
+    assert_eq!(joined.epoch, b.get_epoch(gid.clone()).unwrap());
+    let members = b.get_members(gid.clone()).unwrap();
+    assert_eq!(
+        joined
+            .members
+            .iter()
+            .map(|m| (m.index, m.identity.clone(), m.signature_key.clone()))
+            .collect::<Vec<_>>(),
+        members
+            .iter()
+            .map(|m| (m.index, m.identity.clone(), m.signature_key.clone()))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(members[joined.own_leaf_index as usize].identity, b"bob");
+}
+
+#[test]
+fn commit_txn() {
+    let (a, b, gid) = pair();
+    let c = MlsClient::new_ephemeral();
+    c.create_identity("carol".into()).unwrap();
+    let kp = c.generate_key_package(None).unwrap();
+    let out = a.add_member(gid.clone(), kp).unwrap();
+    b.process_commit(gid.clone(), field(&out, "commit"))
+        .unwrap();
+    c.process_welcome(field(&out, "welcome")).unwrap();
+    let d = MlsClient::new_ephemeral();
+    d.create_identity("dave".into()).unwrap();
+    let e = MlsClient::new_ephemeral();
+    e.create_identity("erin".into()).unwrap();
+
+    let epoch = b.get_epoch(gid.clone()).unwrap();
+    assert!(matches!(a.txn_self_update(), Err(MlsError::Generic { .. })));
+    a.begin_commit(gid.clone()).unwrap();
+    assert!(matches!(
+        a.begin_commit(gid.clone()),
+        Err(MlsError::CommitTransactionOpen { .. })
+    ));
+    assert!(matches!(
+        a.self_update(gid.clone()),
+        Err(MlsError::CommitTransactionOpen { .. })
+    ));
+    assert!(matches!(
+        a.txn_remove("zed".into()),
+        Err(MlsError::UnknownMembers { .. })
+    ));
+    assert!(matches!(
+        a.txn_remove("alice".into()),
+        Err(MlsError::CannotRemoveSelf { .. })
+    ));
+    a.txn_add(d.generate_key_package(None).unwrap()).unwrap();
+    a.txn_add(e.generate_key_package(None).unwrap()).unwrap();
+    a.txn_remove("carol".into()).unwrap();
+    a.txn_self_update().unwrap();
+    // Messages still flow while the transaction is open.
+    let ct = a.encrypt_message(gid.clone(), "x".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "x");
+    let bundle = a.finish_commit(gid.clone()).unwrap();
+    assert!(matches!(
+        a.finish_commit(gid.clone()),
+        Err(MlsError::Generic { .. })
+    ));
+
+    b.process_commit(gid.clone(), bundle.commit.clone())
+        .unwrap();
+    let welcome = bundle.welcome.unwrap();
+    d.process_welcome(welcome.clone()).unwrap();
+    e.process_welcome(welcome).unwrap();
+    let roster = |cl: &MlsClient| {
+        let mut ids: Vec<String> = cl
+            .get_members(gid.clone())
+            .unwrap()
+            .iter()
+            .map(|m| String::from_utf8(m.identity.clone()).unwrap())
+            .collect();
+        ids.sort();
+        ids
+    };
+    for cl in [&a, &b, &d, &e] {
+        assert_eq!(cl.get_epoch(gid.clone()).unwrap(), epoch + 1);
+        assert_eq!(roster(cl), vec!["alice", "bob", "dave", "erin"]);
+    }
+    let ct = a.encrypt_message(gid.clone(), "y".into()).unwrap();
+    assert_eq!(e.decrypt_message(gid.clone(), ct).unwrap(), "y");
+
+    a.begin_commit(gid.clone()).unwrap();
+    a.cancel_commit(gid.clone()).unwrap();
+    a.self_update(gid.clone()).unwrap();
+}
+
+#[test]
+fn add_if() {
+    let (a, _b, gid) = pair();
+    let c = MlsClient::new_ephemeral();
+    c.create_identity("carol".into()).unwrap();
+    let kp = c.generate_key_package(None).unwrap();
+    let epoch = a.get_epoch(gid.clone()).unwrap();
+    let err = a
+        .add_member_if(gid.clone(), kp.clone(), b"mallory".to_vec())
+        .err()
+        .unwrap();
+    assert!(
+        matches!(&err, MlsError::IdentityMismatch { expected, actual } if expected == "mallory" && actual == "carol"),
+        "{err:?}"
+    );
+    assert_eq!(a.get_epoch(gid.clone()).unwrap(), epoch);
+    let out = a.add_member_if(gid.clone(), kp, b"carol".to_vec()).unwrap();
+    c.process_welcome(out.welcome).unwrap();
+    assert_eq!(a.get_epoch(gid.clone()).unwrap(), epoch + 1);
+}
+
+#[test]
+fn verified_sender() {
+    let (a, b, gid) = pair();
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    let mut bytes = hex::decode(&ct).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    let err = b
+        .decrypt_message_with_sender(gid.clone(), hex::encode(&bytes))
+        .err()
+        .unwrap();
+    assert!(matches!(err, MlsError::DecryptionFailed { .. }), "{err:?}");
+    let ct = a.encrypt_message(gid.clone(), "hi again".into()).unwrap();
+    let m = b.decrypt_message_with_sender(gid.clone(), ct).unwrap();
+    assert!(m.verified);
+    assert_eq!(m.sender_index, 0);
+}
+
+#[test]
+fn binary_payloads() {
+    let (a, b, gid) = pair();
+    let mut seed = 0x2545f491u32;
+    let mut random = |n: usize| -> Vec<u8> {
+        (0..n)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as u8
+            })
+            .collect()
+    };
+    let mut payloads = vec![
+        Vec::new(),
+        vec![0],
+        vec![0, 0, 0],
+        vec![0xff, 0xfe, 0xc3, 0x28],
+        b"a\0b".to_vec(),
+        "h\u{e9}llo".as_bytes().to_vec(),
+    ];
+    for n in [1, 17, 300, 4096] {
+        payloads.push(random(n));
+    }
+    for p in payloads {
+        let ct = a.encrypt_message_bytes(gid.clone(), p.clone()).unwrap();
+        let m = b.decrypt_message_with_sender(gid.clone(), ct).unwrap();
+        assert_eq!(m.plaintext, p);
+        assert_eq!(m.as_utf8, String::from_utf8(p).ok());
+    }
+    let ct = a.encrypt_message(gid.clone(), String::new()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "");
+    let ct = a
+        .encrypt_message_bytes(gid.clone(), vec![0xc3, 0x28])
+        .unwrap();
+    assert!(matches!(
+        b.decrypt_message(gid.clone(), ct),
+        Err(MlsError::SerializationError { .. })
+    ));
+}
+
+#[test]
+fn past_epochs() {
+    let a = MlsClient::new(tmp("pea"));
+    let b = MlsClient::new(tmp("peb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    b.set_max_past_epochs(2).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    let e = b.get_epoch(gid.clone()).unwrap();
+    let oldest = || {
+        b.get_group_diagnostics(gid.clone())
+            .unwrap()
+            .oldest_retained_epoch
+    };
+    // Nothing from before we joined.
+    assert_eq!(oldest(), e);
+    let m0 = a.encrypt_message(gid.clone(), "m0".into()).unwrap();
+    let c = a.self_update(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+    let m1 = a.encrypt_message(gid.clone(), "m1".into()).unwrap();
+    let m2 = a.encrypt_message(gid.clone(), "m2".into()).unwrap();
+    let c = a.self_update(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+    assert_eq!(oldest(), e);
+    // Lowering the setting refuses the oldest retained epoch at once.
+    b.set_max_past_epochs(1).unwrap();
+    assert_eq!(oldest(), e + 1);
+    let err = b.decrypt_message(gid.clone(), m0).unwrap_err();
+    assert!(
+        matches!(err, MlsError::EpochTooOld { message_epoch, oldest_retained } if message_epoch == e && oldest_retained == e + 1),
+        "{err:?}"
+    );
+    assert_eq!(b.decrypt_message(gid.clone(), m1).unwrap(), "m1");
+    b.purge_past_epochs(gid.clone()).unwrap();
+    assert_eq!(oldest(), e + 2);
+    let err = b.decrypt_message(gid.clone(), m2).unwrap_err();
+    assert!(
+        matches!(err, MlsError::EpochTooOld { message_epoch, oldest_retained } if message_epoch == e + 1 && oldest_retained == e + 2),
+        "{err:?}"
+    );
+    let ct = a.encrypt_message(gid.clone(), "now".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "now");
+    // The setting survives reloading the group.
+    b.archive_group(gid.clone()).unwrap();
+    let m3 = a.encrypt_message(gid.clone(), "m3".into()).unwrap();
+    let c = a.self_update(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), m3).unwrap(), "m3");
+}
+
+#[test]
+fn pending_commit_flag() {
+    let (a, _b, gid) = pair();
+    assert!(matches!(
+        a.has_pending_commit("00".into()),
+        Err(MlsError::GroupNotFound { .. })
+    ));
+    assert!(!a.has_pending_commit(gid.clone()).unwrap());
+    let c = MlsClient::new_ephemeral();
+    c.create_identity("carol".into()).unwrap();
+    a.add_member_prepare(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    assert!(a.has_pending_commit(gid.clone()).unwrap());
+    a.confirm_commit(gid.clone()).unwrap();
+    assert!(!a.has_pending_commit(gid.clone()).unwrap());
+}
+
+#[test]
+fn multi_identity() {
+    let dir = tmp("multi");
+    let a = MlsClient::new(dir.clone());
+    let peer = MlsClient::new(tmp("multipeer"));
+    a.create_identity("work".into()).unwrap();
+    a.add_identity("personal".into()).unwrap();
+    assert!(a.add_identity("personal".into()).is_err());
+    peer.create_identity("peer".into()).unwrap();
+    assert_eq!(
+        a.list_identities(),
+        vec!["work".to_string(), "personal".to_string()]
+    );
+
+    let g_work = a.create_group("w".into(), None).unwrap();
+    let g_pers = a.create_group("p".into(), Some("personal".into())).unwrap();
+    assert!(matches!(
+        a.create_group("x".into(), Some("nope".into())),
+        Err(MlsError::UnknownIdentity { .. })
+    ));
+    for gid in [&g_work, &g_pers] {
+        let kp = peer.generate_key_package(None).unwrap();
+        let out: serde_json::Value =
+            serde_json::from_str(&a.add_member(gid.clone(), kp).unwrap()).unwrap();
+        peer.process_welcome(out["welcome"].as_str().unwrap().to_string())
+            .unwrap();
+        let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+        assert_eq!(peer.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+        let ct = peer.encrypt_message(gid.clone(), "yo".into()).unwrap();
+        assert_eq!(a.decrypt_message(gid.clone(), ct).unwrap(), "yo");
+    }
+    let names = |gid: &String| {
+        let mut v: Vec<String> = peer
+            .get_members(gid.clone())
+            .unwrap()
+            .into_iter()
+            .map(|m| String::from_utf8(m.identity).unwrap())
+            .collect();
+        v.sort();
+        v
+    };
+    assert_eq!(names(&g_work), vec!["peer", "work"]);
+    assert_eq!(names(&g_pers), vec!["peer", "personal"]);
+    assert_eq!(
+        a.list_active_groups(Some("personal".into())),
+        vec![g_pers.clone()]
+    );
+    assert_eq!(
+        a.list_active_groups(Some("work".into())),
+        vec![g_work.clone()]
+    );
+
+    // A self-update in the personal group is signed with its key.
+    let out: serde_json::Value =
+        serde_json::from_str(&a.self_update(g_pers.clone()).unwrap()).unwrap();
+    peer.process_commit(g_pers.clone(), out["commit"].as_str().unwrap().to_string())
+        .unwrap();
+
+    // Joining by Welcome picks the identity of the consumed key package.
+    let g_peer = peer.create_group("q".into(), None).unwrap();
+    let kp = a.generate_key_package(Some("personal".into())).unwrap();
+    let out: serde_json::Value =
+        serde_json::from_str(&peer.add_member(g_peer.clone(), kp).unwrap()).unwrap();
+    a.process_welcome(out["welcome"].as_str().unwrap().to_string())
+        .unwrap();
+    assert_eq!(names(&g_peer), vec!["peer", "personal"]);
+    let ct = a.encrypt_message(g_peer.clone(), "joined".into()).unwrap();
+    assert_eq!(peer.decrypt_message(g_peer.clone(), ct).unwrap(), "joined");
+
+    // Identities and group ownership survive a restart.
+    a.save_state().unwrap();
+    drop(a);
+    let a = MlsClient::new(dir);
+    a.load_state().unwrap();
+    assert_eq!(
+        a.list_identities(),
+        vec!["work".to_string(), "personal".to_string()]
+    );
+    let mut personal = a.list_active_groups(Some("personal".into()));
+    personal.sort();
+    let mut expected = vec![g_pers.clone(), g_peer.clone()];
+    expected.sort();
+    assert_eq!(personal, expected);
+    let ct = a.encrypt_message(g_pers.clone(), "again".into()).unwrap();
+    assert_eq!(peer.decrypt_message(g_pers.clone(), ct).unwrap(), "again");
+
+    // Rotating the default key leaves the personal groups alone.
+    let commits = a.rotate_signature_key().unwrap();
+    assert_eq!(commits.len(), 1);
+    assert_eq!(commits[0].group_id, g_work);
+}
+
+#[test]
+fn input_limits_and_garbage() {
+    let (a, b, gid) = pair();
+    let huge = "00".repeat(2 * 1024 * 1024);
+    assert!(matches!(
+        b.process_welcome(huge.clone()),
+        Err(MlsError::InputTooLarge { limit, actual }) if limit == 1024 * 1024 && actual == 2 * 1024 * 1024
+    ));
+    assert!(matches!(
+        a.add_member(gid.clone(), huge.clone()),
+        Err(MlsError::InputTooLarge { .. })
+    ));
+    assert!(matches!(
+        b.process_commit(gid.clone(), huge.clone()),
+        Err(MlsError::InputTooLarge { .. })
+    ));
+    // 2 MiB is fine for application messages, just not a valid one.
+    assert!(matches!(
+        b.decrypt_message(gid.clone(), huge.clone()),
+        Err(MlsError::SerializationError { .. })
+    ));
+    a.set_input_limits(InputLimits {
+        application_message: 16,
+        ..a.get_input_limits()
+    })
+    .unwrap();
+    let ct = b.encrypt_message(gid.clone(), "hello".into()).unwrap();
+    assert!(matches!(
+        a.decrypt_message(gid.clone(), ct),
+        Err(MlsError::InputTooLarge { limit: 16, .. })
+    ));
+    assert!(
+        matches!(a.decrypt_message(gid.clone(), "0g".into()), Err(MlsError::SerializationError { msg }) if msg.contains("position 1"))
+    );
+
+    let mut seed = 0x2545f4914f6cdd1du64;
+    let mut next = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+    for round in 0..300 {
+        let len = (next() % [8, 64, 600, 5000][round % 4]) as usize;
+        let bytes: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+        let text = if round % 3 == 0 {
+            String::from_utf8_lossy(&bytes).into_owned()
+        } else {
+            hex::encode(&bytes)
+        };
+        let _ = b.process_welcome(text.clone());
+        let _ = b.process_welcome_with_ratchet_tree(text.clone(), text.clone());
+        let _ = b.decrypt_message(gid.clone(), text.clone());
+        let _ = b.process_commit(gid.clone(), text.clone());
+        let _ = b.process_messages(gid.clone(), vec![text.clone()]);
+        let _ = b.inspect_message(text.clone());
+        let _ = b.rejoin(text.clone(), Some(text.clone()), None);
+        let _ = b.get_key_package_info(text.clone());
+        let _ = b.add_member(gid.clone(), text.clone());
+        let _ = b.decrypt_message_with_aad(gid.clone(), bytes.clone());
+        let _ = b.import_group(bytes.clone());
+    }
+    let ct = a.encrypt_message(gid.clone(), "still fine".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid, ct).unwrap(), "still fine");
+}
+
+#[test]
+fn welcome_sizes() {
+    let a = MlsClient::new_ephemeral();
+    a.create_identity("alice".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    for i in 0..48 {
+        let m = MlsClient::new_ephemeral();
+        m.create_identity(format!("member{}", i)).unwrap();
+        a.add_member(gid.clone(), m.generate_key_package(None).unwrap())
+            .unwrap();
+    }
+    let joiner = || {
+        let m = MlsClient::new_ephemeral();
+        m.create_identity("late".into()).unwrap();
+        let kp = m.generate_key_package(None).unwrap();
+        (m, kp)
+    };
+
+    let estimate = a.estimate_welcome_size(gid.clone(), 1).unwrap();
+    let (m, kp) = joiner();
+    let full = a
+        .add_member_with_welcome_limit(gid.clone(), kp, u64::MAX)
+        .unwrap();
+    assert!(!full.ratchet_tree_omitted);
+    assert!(full.commit_size > 0 && full.group_info_size > 0);
+    assert_eq!(
+        full.welcome_size,
+        hex::decode(&full.welcome).unwrap().len() as u64
+    );
+    assert!(estimate >= full.welcome_size && estimate < full.welcome_size * 11 / 10);
+    m.process_welcome(full.welcome).unwrap();
+
+    let (m, kp) = joiner();
+    let small = a
+        .add_member_with_welcome_limit(gid.clone(), kp, 4096)
+        .unwrap();
+    assert!(small.ratchet_tree_omitted);
+    assert!(
+        small.welcome_size < full.welcome_size / 2,
+        "{} vs {}",
+        small.welcome_size,
+        full.welcome_size
+    );
+    let tree = a.export_ratchet_tree(gid.clone()).unwrap();
+    assert_eq!(
+        m.process_welcome_with_ratchet_tree(small.welcome, tree)
+            .unwrap(),
+        gid
+    );
+    assert_eq!(m.get_members(gid.clone()).unwrap().len(), 51);
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(m.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+    // Later welcomes embed the tree again.
+    let (_m, kp) = joiner();
+    assert!(!a.add_member_prepare(gid, kp).unwrap().ratchet_tree_omitted);
+}
+
+#[test]
+fn in_sync() {
+    let (a, b, gid) = pair();
+    let auth = a.get_epoch_authenticator(gid.clone()).unwrap();
+    assert!(b.is_member_in_sync(gid.clone(), auth.clone()).unwrap());
+    let mut wrong = auth.clone();
+    wrong[0] ^= 1;
+    assert!(!b.is_member_in_sync(gid.clone(), wrong).unwrap());
+    assert!(!b
+        .is_member_in_sync(gid.clone(), auth[1..].to_vec())
+        .unwrap());
+    // A commit b has not seen yet puts them out of sync.
+    a.self_update(gid.clone()).unwrap();
+    let auth = a.get_epoch_authenticator(gid.clone()).unwrap();
+    assert!(!b.is_member_in_sync(gid.clone(), auth).unwrap());
+    assert!(matches!(
+        b.is_member_in_sync("00".into(), vec![]),
+        Err(MlsError::GroupNotFound { .. })
+    ));
+}
+
+#[test]
+fn identity_with_key_package() {
+    let a = MlsClient::new_ephemeral();
+    let b = MlsClient::new_ephemeral();
+    a.create_identity("alice".into()).unwrap();
+    for name in ["bob", "bob-work"] {
+        let created = b.create_identity_with_key_package(name.into()).unwrap();
+        assert_eq!(created.identity_id, name);
+        let gid = a.create_group(name.into(), None).unwrap();
+        let out: serde_json::Value =
+            serde_json::from_str(&a.add_member(gid.clone(), created.key_package).unwrap()).unwrap();
+        assert_eq!(
+            b.process_welcome(out["welcome"].as_str().unwrap().to_string())
+                .unwrap(),
+            gid
+        );
+        assert_eq!(b.list_active_groups(Some(name.into())), vec![gid.clone()]);
+        let ct = b.encrypt_message(gid.clone(), "hi".into()).unwrap();
+        assert_eq!(a.decrypt_message(gid, ct).unwrap(), "hi");
+    }
+    assert_eq!(
+        b.list_identities(),
+        vec!["bob".to_string(), "bob-work".to_string()]
+    );
+}
+
+#[test]
+fn metrics_counts() {
+    let (a, b, gid) = pair();
+    let op = |m: &MetricsSnapshot, name: &str| {
+        let o = m.operations.iter().find(|o| o.operation == name).unwrap();
+        assert_eq!(o.latency_histogram.iter().sum::<u64>(), o.calls);
+        (o.calls, o.failures)
+    };
+    let m = a.get_metrics();
+    assert!(m.enabled);
+    assert_eq!(op(&m, "create_group"), (1, 0));
+    assert_eq!(op(&m, "add_member"), (1, 0));
+    assert_eq!(op(&m, "encrypt_message"), (0, 0));
+    assert_eq!(op(&b.get_metrics(), "process_welcome"), (1, 0));
+    assert_eq!(op(&b.get_metrics(), "generate_key_package"), (1, 0));
+
+    a.reset_metrics().unwrap();
+    b.reset_metrics().unwrap();
+    for text in ["hi", "hello"] {
+        let ct = a.encrypt_message(gid.clone(), text.into()).unwrap();
+        b.decrypt_message(gid.clone(), ct.clone()).unwrap();
+        assert!(b.decrypt_message(gid.clone(), ct).is_err());
+    }
+    a.encrypt_message_bytes(gid.clone(), vec![0; 100]).unwrap();
+    assert!(a.encrypt_message("nope".into(), "x".into()).is_err());
+    let commit: serde_json::Value =
+        serde_json::from_str(&a.self_update(gid.clone()).unwrap()).unwrap();
+    b.process_commit(gid.clone(), commit["commit"].as_str().unwrap().into())
+        .unwrap();
+
+    let m = a.get_metrics();
+    assert_eq!(op(&m, "encrypt_message"), (4, 1));
+    assert_eq!(op(&m, "self_update"), (1, 0));
+    assert_eq!(op(&m, "create_group"), (0, 0));
+    assert_eq!(
+        m.failures,
+        HashMap::from([("GroupNotFound".to_string(), 1)])
+    );
+    assert_eq!(m.groups.len(), 1);
+    assert_eq!(
+        (m.groups[0].messages_encrypted, m.groups[0].bytes_encrypted),
+        (3, 107)
+    );
+    assert_eq!(
+        (m.groups[0].messages_decrypted, m.groups[0].bytes_decrypted),
+        (0, 0)
+    );
+
+    let m = b.get_metrics();
+    assert_eq!(op(&m, "decrypt_message"), (4, 2));
+    assert_eq!(op(&m, "process_commit"), (1, 0));
+    assert_eq!(
+        m.failures,
+        HashMap::from([("DuplicateMessage".to_string(), 2)])
+    );
+    assert_eq!(
+        (m.groups[0].messages_decrypted, m.groups[0].bytes_decrypted),
+        (2, 7)
+    );
+
+    b.reset_metrics().unwrap();
+    let m = b.get_metrics();
+    assert!(m
+        .operations
+        .iter()
+        .all(|o| o.calls == 0 && o.total_micros == 0));
+    assert!(m.failures.is_empty() && m.groups.is_empty());
+}
+
+#[test]
+fn validate_kp() {
+    let a = MlsClient::new_ephemeral();
+    let b = MlsClient::new_ephemeral();
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    let info = a.validate_key_package(kp.clone()).unwrap();
+    assert_eq!(info.identity, b"bob");
+    assert_eq!(info.ciphersuite, 1);
+    let mut bytes = hex::decode(&kp).unwrap();
+    let n = bytes.len();
+    bytes[n - 1] ^= 1;
+    assert!(matches!(
+        a.validate_key_package(hex::encode(&bytes)),
+        Err(MlsError::InvalidSignature { .. })
+    ));
+    let mut bytes = hex::decode(&kp).unwrap();
+    let off = if bytes[2..4] == [0, 5] { 4 } else { 2 };
+    assert_eq!(bytes[off..off + 2], [0, 1]);
+    bytes[off + 1] = 0x7f;
+    assert!(matches!(
+        a.validate_key_package(hex::encode(&bytes)),
+        Err(MlsError::UnsupportedCiphersuite { ciphersuite: 0x7f })
+    ));
+    let now = unix_time();
+    let expired = b
+        .generate_key_packages(1, now - 7200, now - 3600, None)
+        .unwrap();
+    assert!(matches!(
+        a.validate_key_package(expired[0].key_package.clone()),
+        Err(MlsError::KeyPackageExpired { .. })
+    ));
+    // Nothing was consumed: the key package still works.
+    let gid = a.create_group("g".into(), None).unwrap();
+    a.add_member(gid, kp).unwrap();
+}
+
+#[test]
+fn duplicate_adds() {
+    let a = MlsClient::new_ephemeral();
+    a.create_identity("alice".into()).unwrap();
+    let bob = |_| {
+        let c = MlsClient::new_ephemeral();
+        c.create_identity("bob".into()).unwrap();
+        c
+    };
+    let (b1, b2, b3) = (bob(1), bob(2), bob(3));
+    let gid = a.create_group("g".into(), None).unwrap();
+
+    // Same key package twice in one commit.
+    let kp = b1.generate_key_package(None).unwrap();
+    a.begin_commit(gid.clone()).unwrap();
+    assert!(!a.txn_add(kp.clone()).unwrap());
+    assert!(matches!(
+        a.txn_add(kp.clone()),
+        Err(MlsError::DuplicateSignatureKey { .. })
+    ));
+    // Same identity, other keys: flagged by default.
+    assert!(a.txn_add(b2.generate_key_package(None).unwrap()).unwrap());
+    a.set_duplicate_identity_policy(DuplicateIdentityPolicy::Reject)
+        .unwrap();
+    match a.txn_add(b3.generate_key_package(None).unwrap()) {
+        Err(MlsError::DuplicateIdentity { identity }) => assert_eq!(identity, "bob"),
+        o => panic!("{:?}", o),
+    }
+    a.finish_commit(gid.clone()).unwrap();
+    assert_eq!(a.get_members(gid.clone()).unwrap().len(), 3);
+
+    // Against current members.
+    let kp3 = b3.generate_key_package(None).unwrap();
+    assert!(matches!(
+        a.add_member(gid.clone(), kp3.clone()),
+        Err(MlsError::DuplicateIdentity { .. })
+    ));
+    assert!(matches!(
+        a.add_member(gid.clone(), kp),
+        Err(MlsError::AlreadyMember { .. })
+    ));
+    a.set_duplicate_identity_policy(DuplicateIdentityPolicy::Flag)
+        .unwrap();
+    let out: serde_json::Value =
+        serde_json::from_str(&a.add_member(gid.clone(), kp3).unwrap()).unwrap();
+    assert_eq!(out["duplicate_identity"], true);
+
+    // Removing the identity in the same commit makes room for it.
+    a.set_duplicate_identity_policy(DuplicateIdentityPolicy::Reject)
+        .unwrap();
+    a.begin_commit(gid.clone()).unwrap();
+    a.txn_remove("bob".into()).unwrap();
+    assert!(!a
+        .txn_add(bob(4).generate_key_package(None).unwrap())
+        .unwrap());
+    a.finish_commit(gid.clone()).unwrap();
+}
+
+#[test]
+fn group_times() {
+    let (a, b, gid) = pair();
+    let info = |c: &MlsClient| -> (u64, u64) {
+        let v: serde_json::Value =
+            serde_json::from_str(&c.get_group_info(gid.clone()).unwrap()).unwrap();
+        (
+            v["created_at"].as_u64().unwrap(),
+            v["updated_at"].as_u64().unwrap(),
+        )
+    };
+    let now = unix_time();
+    let (created, updated) = info(&a);
+    assert!(created <= now && created + 5 >= now);
+    // The add_member commit counts as a change.
+    assert!(updated >= created);
+    let (b_created, b_updated) = info(&b);
+    assert_eq!(b_created, b_updated);
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let c: serde_json::Value = serde_json::from_str(&a.self_update(gid.clone()).unwrap()).unwrap();
+    b.process_commit(gid.clone(), c["commit"].as_str().unwrap().into())
+        .unwrap();
+    let (c2, u2) = info(&a);
+    assert_eq!(c2, created);
+    assert!(u2 > updated);
+    let (bc, bu) = info(&b);
+    assert_eq!(bc, b_created);
+    assert!(bu > b_updated);
+    // Messages do not count.
+    let ct = a.encrypt_message(gid.clone(), "x".into()).unwrap();
+    b.decrypt_message(gid.clone(), ct).unwrap();
+    assert_eq!(info(&b), (bc, bu));
+    // Survives export/import of the whole state.
+    let blob = b.export_state("pw".into()).unwrap();
+    let b2 = MlsClient::new_ephemeral();
+    b2.import_state(blob, "pw".into()).unwrap();
+    assert_eq!(info(&b2), (bc, bu));
+    // And save/load.
+    let c = MlsClient::new(tmp("gt"));
+    c.create_identity("carol".into()).unwrap();
+    let g = c.create_group("g".into(), None).unwrap();
+    let before = c.get_group_info(g.clone()).unwrap();
+    c.save_state().unwrap();
+    drop(c);
+    let c = MlsClient::new(tmp_keep("gt"));
+    assert_eq!(c.get_group_info(g).unwrap(), before);
+}
+
+#[test]
+fn incremental_save() {
+    let path = tmp("incs");
+    let a = MlsClient::new(path.clone());
+    a.create_identity("alice".into()).unwrap();
+    let b = MlsClient::new_ephemeral();
+    b.create_identity("bob".into()).unwrap();
+    let g1 = a.create_group("g".into(), None).unwrap();
+    let g2 = a.create_group("g".into(), None).unwrap();
+    let file = |g: &str| std::path::Path::new(&path).join(format!("group_{}.json", g));
+    let contents = |g: &str| std::fs::read(file(g)).unwrap();
+    assert!(file(&g1).exists() && file(&g2).exists());
+
+    // Autosave rewrites only the group that changed.
+    let before = contents(&g1);
+    let out: serde_json::Value = serde_json::from_str(
+        &a.add_member(g2.clone(), b.generate_key_package(None).unwrap())
+            .unwrap(),
+    )
+    .unwrap();
+    b.process_welcome(out["welcome"].as_str().unwrap().into())
+        .unwrap();
+    std::fs::write(file(&g1), b"garbage").unwrap();
+    a.self_update(g2.clone()).unwrap();
+    assert_eq!(std::fs::read(file(&g1)).unwrap(), b"garbage");
+    assert_ne!(contents(&g2), before);
+    // A full save rewrites it.
+    assert_eq!(a.save_state().unwrap().saved_groups.len(), 2);
+    assert_eq!(contents(&g1), before);
+
+    // save_group() writes one group and leaves the others dirty.
+    a.set_autosave(false).unwrap();
+    a.self_update(g1.clone()).unwrap();
+    a.self_update(g2.clone()).unwrap();
+    let g2_before = contents(&g2);
+    assert_eq!(
+        a.save_group(g1.clone()).unwrap().saved_groups,
+        vec![g1.clone()]
+    );
+    assert_eq!(contents(&g2), g2_before);
+    assert!(matches!(
+        a.save_group("00".into()),
+        Err(MlsError::GroupNotFound { .. })
+    ));
+    a.set_autosave(true).unwrap();
+    assert_ne!(contents(&g2), g2_before);
+
+    // Leaving a group deletes its entry; everything loads back.
+    a.secure_wipe_group(g1.clone()).unwrap();
+    assert!(!file(&g1).exists());
+    let e2 = a.get_epoch(g2.clone()).unwrap();
+    drop(a);
+    let a = MlsClient::new(tmp_keep("incs"));
+    assert_eq!(a.list_all_groups(), vec![g2.clone()]);
+    assert_eq!(a.get_epoch(g2.clone()).unwrap(), e2);
+    a.encrypt_message(g2.clone(), "hi".into()).unwrap();
+}
+
+#[test]
+fn rejoin() {
+    let (a, b, gid) = pair();
+    a.self_update(gid.clone()).unwrap();
+    let observed = a.get_epoch(gid.clone()).unwrap();
+    assert_eq!(
+        b.check_epoch(gid.clone(), observed).unwrap(),
+        EpochStatus::Behind { by: 1 }
+    );
+    assert_eq!(
+        a.check_epoch(gid.clone(), observed).unwrap(),
+        EpochStatus::InSync
+    );
+    assert_eq!(
+        a.check_epoch(gid.clone(), 0).unwrap(),
+        EpochStatus::Ahead { by: observed }
+    );
+    b.mark_group_desynced(gid.clone()).unwrap();
+    assert!(matches!(
+        b.encrypt_message(gid.clone(), "x".into()),
+        Err(MlsError::GroupDesynced { .. })
+    ));
+    let info = b.request_rejoin_info(gid.clone()).unwrap();
+    assert_eq!(info.epoch, observed - 1);
+    a.remove_member(gid.clone(), 1).unwrap();
+    let out = a.add_member(gid.clone(), info.key_package).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(
+        b.process_welcome(v["welcome"].as_str().unwrap().to_string())
+            .unwrap(),
+        gid
+    );
+    assert_eq!(
+        b.check_epoch(gid.clone(), a.get_epoch(gid.clone()).unwrap())
+            .unwrap(),
+        EpochStatus::InSync
+    );
+    let ct = b.encrypt_message(gid.clone(), "back".into()).unwrap();
+    assert_eq!(a.decrypt_message(gid.clone(), ct).unwrap(), "back");
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+}
+
+#[test]
+fn signer_compat() {
+    let (private, public) = openmls_rust_crypto::RustCrypto::default()
+        .signature_key_gen(SignatureScheme::ED25519)
+        .unwrap();
+    let ours = SigningKeyPair::from_raw(SignatureScheme::ED25519, private, public);
+    let theirs: SignatureKeyPair =
+        serde_json::from_str(&serde_json::to_string(&ours).unwrap()).unwrap();
+    use openmls_traits::signatures::Signer;
+    assert_eq!(ours.sign(b"x").unwrap(), theirs.sign(b"x").unwrap());
+    let back: SigningKeyPair =
+        serde_json::from_str(&serde_json::to_string(&theirs).unwrap()).unwrap();
+    assert_eq!(back.public(), ours.public());
+    let st = openmls_rust_crypto::OpenMlsRustCrypto::default();
+    ours.store(st.storage()).unwrap();
+    assert!(
+        SignatureKeyPair::read(st.storage(), ours.public(), SignatureScheme::ED25519).is_some()
+    );
+}
+
+#[test]
+fn commit_journal() {
+    let dir = tmp("journal");
+    let a = MlsClient::new(dir.clone());
+    a.create_identity("alice".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let b = MlsClient::new(tmp("journal_b"));
+    b.create_identity("bob".into()).unwrap();
+    let c = MlsClient::new(tmp("journal_c"));
+    c.create_identity("carol".into()).unwrap();
+    let first = a
+        .add_member_if(
+            gid.clone(),
+            b.generate_key_package(None).unwrap(),
+            b"bob".to_vec(),
+        )
+        .unwrap();
+    let second = a
+        .add_member_if(
+            gid.clone(),
+            c.generate_key_package(None).unwrap(),
+            b"carol".to_vec(),
+        )
+        .unwrap();
+    assert_eq!((first.epoch, second.epoch), (1, 2));
+    assert!(second.sequence > first.sequence);
+    assert_ne!(first.commit_ref, second.commit_ref);
+    assert_eq!(a.get_unacked_commits(gid.clone()).unwrap().len(), 2);
+    a.ack_commit(gid.clone(), first.commit_ref.clone()).unwrap();
+    a.save_state().unwrap();
+    drop(a);
+    let a = MlsClient::new(dir);
+    let unacked = a.get_unacked_commits(gid.clone()).unwrap();
+    assert_eq!(unacked.len(), 1);
+    assert_eq!(unacked[0].commit_ref, second.commit_ref);
+    assert_eq!(unacked[0].welcome, second.welcome);
+    assert_eq!(unacked[0].key_package_ref, second.key_package_ref);
+    c.process_welcome(unacked[0].welcome.clone()).unwrap();
+
+    // An aborted commit leaves the journal.
+    let d = MlsClient::new(tmp("journal_d"));
+    d.create_identity("dave".into()).unwrap();
+    let third = a
+        .add_member_prepare(gid.clone(), d.generate_key_package(None).unwrap())
+        .unwrap();
+    assert!(third.sequence > second.sequence);
+    assert_eq!(a.get_unacked_commits(gid.clone()).unwrap().len(), 2);
+    a.abort_commit(gid.clone()).unwrap();
+    assert_eq!(a.get_unacked_commits(gid.clone()).unwrap().len(), 1);
+}
+
+#[test]
+fn epoch_debug_info() {
+    let (a, b, gid) = pair();
+    if cfg!(feature = "debug-secrets") {
+        let (x, y) = (
+            a.export_epoch_debug_info(gid.clone()).unwrap(),
+            b.export_epoch_debug_info(gid.clone()).unwrap(),
+        );
+        assert_eq!(
+            (x.epoch, &x.exporter_secret, &x.epoch_authenticator),
+            (y.epoch, &y.exporter_secret, &y.epoch_authenticator)
+        );
+        assert_eq!(x.confirmation_tag, y.confirmation_tag);
+        assert_eq!(
+            (&x.tree_hash, &x.confirmed_transcript_hash),
+            (&y.tree_hash, &y.confirmed_transcript_hash)
+        );
+        a.self_update(gid.clone()).unwrap();
+        assert_ne!(
+            a.export_epoch_debug_info(gid.clone())
+                .unwrap()
+                .exporter_secret,
+            x.exporter_secret
+        );
+    } else {
+        assert!(
+            matches!(a.export_epoch_debug_info(gid), Err(MlsError::Generic { msg }) if msg == "debug feature disabled")
+        );
+    }
+}
+
+#[test]
+fn restorable_groups() {
+    let path = tmp("restorable");
+    let a = MlsClient::new(path.clone());
+    a.create_identity("alice".into()).unwrap();
+    let g1 = a.create_group("one".into(), None).unwrap();
+    let g2 = a.create_group("two".into(), None).unwrap();
+    a.save_state().unwrap();
+    let listed = a.list_restorable_groups().unwrap();
+    assert_eq!(listed.len(), 2);
+    assert!(listed.iter().all(|g| g.restorable && g.reason.is_none()));
+    std::fs::remove_file(std::path::Path::new(&path).join(format!("group_{}.json", g2))).unwrap();
+    let listed = a.list_restorable_groups().unwrap();
+    let by_id = |id: &str| listed.iter().find(|g| g.group_id == id).unwrap();
+    assert!(by_id(&g1).restorable);
+    assert!(!by_id(&g2).restorable);
+    assert!(by_id(&g2).reason.is_some());
+    assert_eq!(a.list_saved_groups().unwrap().len(), 2);
+}
+
+#[test]
+fn replay_cache() {
+    let a = MlsClient::new(tmp("replay_a"));
+    let b = MlsClient::new_with_replay_cache(tmp("replay_b"), 2);
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct.clone()).unwrap(), "hi");
+    assert!(matches!(
+        b.decrypt_message(gid.clone(), ct.clone()),
+        Err(MlsError::DuplicateMessage { .. })
+    ));
+    // Pushed out of the two-entry cache, the replay is still caught by OpenMLS.
+    for i in 0..2 {
+        let m = a.encrypt_message(gid.clone(), format!("m{i}")).unwrap();
+        b.decrypt_message(gid.clone(), m).unwrap();
+    }
+    assert!(matches!(
+        b.decrypt_message(gid.clone(), ct.clone()),
+        Err(MlsError::DuplicateMessage { .. })
+    ));
+    {
+        let st = b.state.read().unwrap();
+        assert_eq!(st.seen_messages[&gid].digests.len(), 2);
+    }
+    // A commit's epoch change clears the cache once the next epoch's messages arrive.
+    let upd = a.self_update(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), commit_of(&upd)).unwrap();
+    let m = a.encrypt_message(gid.clone(), "new epoch".into()).unwrap();
+    b.decrypt_message(gid.clone(), m.clone()).unwrap();
+    {
+        let st = b.state.read().unwrap();
+        assert_eq!(st.seen_messages[&gid].digests.len(), 1);
+    }
+    assert!(matches!(
+        b.decrypt_message(gid.clone(), m),
+        Err(MlsError::DuplicateMessage { .. })
+    ));
+    // Our own sent messages and commits are held to the same size.
+    for i in 0..3 {
+        b.encrypt_message(gid.clone(), format!("b{i}")).unwrap();
+    }
+    b.self_update(gid.clone()).unwrap();
+    let st = b.state.read().unwrap();
+    assert_eq!(st.sent_messages[&gid].digests.len(), 2);
+}
+
+#[test]
+fn invite_bundle() {
+    let (a, b, gid) = pair();
+    let bundle = a.create_invite_bundle(gid.clone()).unwrap();
+    let c = MlsClient::new(tmp("invite_c"));
+    c.create_identity("carol".into()).unwrap();
+    let joined = c.join_from_invite_bundle(bundle.clone(), None).unwrap();
+    assert_eq!(joined.group_id, gid);
+    assert_eq!(joined.epoch, 2);
+    a.process_commit(gid.clone(), joined.commit.clone())
+        .unwrap();
+    b.process_commit(gid.clone(), joined.commit).unwrap();
+    let ct = c.encrypt_message(gid.clone(), "hello".into()).unwrap();
+    assert_eq!(a.decrypt_message(gid.clone(), ct.clone()).unwrap(), "hello");
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "hello");
+
+    // The group moved on since the bundle was made.
+    let d = MlsClient::new(tmp("invite_d"));
+    d.create_identity("dave".into()).unwrap();
+    let stale = d.join_from_invite_bundle(bundle.clone(), None).unwrap();
+    match a.process_commit(gid.clone(), stale.commit) {
+        Err(MlsError::InviteBundleStale {
+            bundle_epoch,
+            current_epoch,
+        }) => assert_eq!((bundle_epoch, current_epoch), (1, 2)),
+        r => panic!("{r:?}"),
+    }
+    let again = d.join_from_invite_bundle(a.create_invite_bundle(gid.clone()).unwrap(), None);
+    assert!(matches!(
+        again,
+        Err(MlsError::GroupAlreadyJoined {
+            current_epoch: 2,
+            ..
+        })
+    ));
+    d.mark_group_desynced(gid.clone()).unwrap();
+    let fresh = d
+        .join_from_invite_bundle(a.create_invite_bundle(gid.clone()).unwrap(), None)
+        .unwrap();
+    a.process_commit(gid.clone(), fresh.commit).unwrap();
+
+    let mut bad = bundle.clone();
+    bad.truncate(bad.len() - 1);
+    assert!(matches!(
+        d.join_from_invite_bundle(bad, None),
+        Err(MlsError::SerializationError { .. })
+    ));
+    assert!(matches!(
+        d.join_from_invite_bundle(b"nope".to_vec(), None),
+        Err(MlsError::SerializationError { .. })
+    ));
+}
+
+#[test]
+fn supported_versions_and_suites() {
+    let c = MlsClient::new(tmp("suites"));
+    c.create_identity("alice".into()).unwrap();
+    assert_eq!(c.supported_protocol_versions(), vec![1]);
+    let suites = c.supported_ciphersuites();
+    assert!(!suites.is_empty());
+    for cs in suites {
+        c.create_group_with_ciphersuite(String::new(), cs, None)
+            .unwrap();
+    }
+}
+
+#[test]
+fn groups_detailed() {
+    let (a, b, gid) = pair();
+    let g2 = b.create_group("h".into(), None).unwrap();
+    let before = b.list_groups_detailed(None);
+    assert_eq!(before.len(), 2);
+    assert!(before.iter().all(|s| !s.frozen));
+    b.freeze_group(g2.clone(), "x".into()).unwrap();
+    let frozen = b.list_groups_detailed(None);
+    assert!(frozen.iter().all(|s| s.frozen == (s.group_id == g2)));
+    b.unfreeze_group(g2.clone()).unwrap();
+    {
+        let mut s = b.state.write().unwrap();
+        s.group_activity.insert(gid.clone(), 1);
+        s.group_activity.insert(g2.clone(), 2);
+    }
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    b.decrypt_message(gid.clone(), ct).unwrap();
+    let newest = b.list_groups_detailed(Some(GroupFilter {
+        status: None,
+        min_members: None,
+        newest_first: true,
+    }));
+    assert_eq!(newest[0].group_id, gid);
+    assert!(newest[0].last_activity.unwrap() > 2);
+    assert_eq!(newest[0].member_count, 2);
+    let big = b.list_groups_detailed(Some(GroupFilter {
+        status: None,
+        min_members: Some(2),
+        newest_first: false,
+    }));
+    assert_eq!(big.len(), 1);
+
+    let bi = a
+        .get_members(gid.clone())
+        .unwrap()
+        .iter()
+        .find(|m| m.identity == b"bob")
+        .unwrap()
+        .index;
+    let r = a.remove_member(gid.clone(), bi).unwrap();
+    b.process_commit(gid.clone(), field(&r, "commit")).unwrap();
+    let removed = b.list_groups_detailed(Some(GroupFilter {
+        status: Some(MembershipStatus::Removed),
+        min_members: None,
+        newest_first: false,
+    }));
+    assert_eq!(removed.len(), 1);
+    assert_eq!(removed[0].group_id, gid);
+    assert!(removed[0].epoch.is_none() && removed[0].last_activity.is_some());
+    let active = b.list_groups_detailed(Some(GroupFilter {
+        status: Some(MembershipStatus::Active),
+        min_members: None,
+        newest_first: false,
+    }));
+    assert_eq!(active.len(), 1);
+    assert_eq!(active[0].group_id, g2);
+}
+
+#[test]
+fn swap_members() {
+    let (a, b, gid) = pair();
+    let c = MlsClient::new(tmp("swc"));
+    c.create_identity("carol".into()).unwrap();
+    let bi = a
+        .get_members(gid.clone())
+        .unwrap()
+        .iter()
+        .find(|m| m.identity == b"bob")
+        .unwrap()
+        .index;
+    let d = MlsClient::new(tmp("swd"));
+    d.create_identity("dave".into()).unwrap();
+    let out = a
+        .add_member(gid.clone(), d.generate_key_package(None).unwrap())
+        .unwrap();
+    d.process_welcome(field(&out, "welcome")).unwrap();
+    b.process_commit(gid.clone(), field(&out, "commit"))
+        .unwrap();
+    let epoch = a.get_epoch(gid.clone()).unwrap();
+
+    let r = a
+        .update_membership(
+            gid.clone(),
+            vec![c.generate_key_package(None).unwrap()],
+            vec![bi],
+        )
+        .unwrap();
+    assert_eq!(r.epoch, epoch + 1);
+    assert_eq!(a.get_epoch(gid.clone()).unwrap(), epoch + 1);
+    c.process_welcome(r.welcome.clone()).unwrap();
+    d.process_commit(gid.clone(), r.commit.clone()).unwrap();
+    let ids = |cl: &MlsClient| {
+        let mut v: Vec<Vec<u8>> = cl
+            .get_members(gid.clone())
+            .unwrap()
+            .into_iter()
+            .map(|m| m.identity)
+            .collect();
+        v.sort();
+        v
+    };
+    let want = vec![b"alice".to_vec(), b"carol".to_vec(), b"dave".to_vec()];
+    assert_eq!(ids(&a), want);
+    assert_eq!(ids(&c), want);
+    assert_eq!(ids(&d), want);
+    b.process_commit(gid.clone(), r.commit.clone()).unwrap();
+    assert!(b.list_active_groups(None).is_empty());
+    let ct = d.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(c.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+
+    assert!(matches!(
+        a.update_membership(gid.clone(), vec![], vec![]),
+        Err(MlsError::Generic { .. })
+    ));
+    let e = MlsClient::new(tmp("swe"));
+    e.create_identity("erin".into()).unwrap();
+    assert!(matches!(
+        a.update_membership(
+            gid.clone(),
+            vec![e.generate_key_package(None).unwrap()],
+            vec![99]
+        ),
+        Err(MlsError::UnknownMember { .. })
+    ));
+}
+
+#[test]
+fn trailing_bytes_modes() {
+    let (a, b, gid) = pair();
+    let c = MlsClient::new(tmp("tbc"));
+    c.create_identity("carol".into()).unwrap();
+    let kp = c.generate_key_package(None).unwrap() + "deadbeef";
+
+    a.set_deserialization_mode(DeserializationMode::Strict)
+        .unwrap();
+    match a.add_member(gid.clone(), kp.clone()) {
+        Err(MlsError::TrailingBytes {
+            structure,
+            consumed,
+            remaining,
+        }) => {
+            assert_eq!(structure, "KeyPackage");
+            assert_eq!(remaining, 4);
+            assert_eq!(consumed as usize, kp.len() / 2 - 4);
+        }
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+    b.set_deserialization_mode(DeserializationMode::Strict)
+        .unwrap();
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap() + "00";
+    assert!(matches!(
+        b.decrypt_message(gid.clone(), ct.clone()),
+        Err(MlsError::TrailingBytes { remaining: 1, .. })
+    ));
+
+    b.set_deserialization_mode(DeserializationMode::Permissive)
+        .unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+    a.set_deserialization_mode(DeserializationMode::Permissive)
+        .unwrap();
+    let out = a.add_member(gid.clone(), kp).unwrap();
+    b.process_commit(gid.clone(), field(&out, "commit") + "ab")
+        .unwrap();
+    c.process_welcome(field(&out, "welcome") + "abcd").unwrap();
+    let ignored = |cl: &MlsClient| cl.get_metrics().trailing_bytes_ignored;
+    assert_eq!(ignored(&a).get("KeyPackage"), Some(&1));
+    assert_eq!(ignored(&b).get("MLSMessage"), Some(&1));
+    assert_eq!(ignored(&b).get("handshake MLSMessage"), Some(&1));
+    assert_eq!(ignored(&c).get("Welcome MLSMessage"), Some(&1));
+}
+
+#[test]
+fn in_memory_flow() {
+    let listing = || {
+        let mut v: Vec<_> = std::fs::read_dir(".")
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        v.sort();
+        v
+    };
+    let before = listing();
+    let a = MlsClient::new_in_memory();
+    let b = MlsClient::new_in_memory();
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+
+    let report = b.save_state().unwrap();
+    assert_eq!(report.saved_groups, vec![gid.clone()]);
+    b.load_state().unwrap();
+    let ct = b.encrypt_message(gid.clone(), "back".into()).unwrap();
+    assert_eq!(a.decrypt_message(gid.clone(), ct).unwrap(), "back");
+    assert_eq!(listing(), before);
+}
+
+#[test]
+fn concurrent_groups() {
+    let a = Arc::new(MlsClient::new(tmp("cga")));
+    let b = Arc::new(MlsClient::new(tmp("cgb")));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let groups: Vec<String> = (0..8)
+        .map(|i| {
+            let gid = a.create_group(format!("g{}", i), None).unwrap();
+            let out = a
+                .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+                .unwrap();
+            b.process_welcome(field(&out, "welcome")).unwrap();
+            gid
+        })
+        .collect();
+    let threads: Vec<_> = groups
+        .iter()
+        .cloned()
+        .map(|gid| {
+            let a = a.clone().handle();
+            let b = b.clone().handle();
+            std::thread::spawn(move || {
+                for n in 0..25 {
+                    let msg = format!("{} {}", gid, n);
+                    let ct = a.encrypt_message(gid.clone(), msg.clone()).unwrap();
+                    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), msg);
+                    let ct = b.encrypt_message(gid.clone(), msg.clone()).unwrap();
+                    assert_eq!(a.decrypt_message(gid.clone(), ct).unwrap(), msg);
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    for gid in &groups {
+        assert_eq!(
+            a.get_epoch(gid.clone()).unwrap(),
+            b.get_epoch(gid.clone()).unwrap()
+        );
+        let ct = a.encrypt_message(gid.clone(), "done".into()).unwrap();
+        assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "done");
+    }
+}
+
+#[test]
+fn member_cap() {
+    let (a, _b, gid) = pair();
+    let kp = |n: &str| {
+        let c = MlsClient::new_ephemeral();
+        c.create_identity(n.into()).unwrap();
+        c.generate_key_package(None).unwrap()
+    };
+    a.set_max_members(gid.clone(), 2).unwrap();
+    assert_eq!(a.get_max_members(gid.clone()).unwrap(), Some(2));
+    let epoch = a.get_epoch(gid.clone()).unwrap();
+    assert!(matches!(
+        a.add_member(gid.clone(), kp("carol")),
+        Err(MlsError::GroupFull { max_members: 2, .. })
+    ));
+    assert!(matches!(
+        a.add_member_prepare(gid.clone(), kp("carol")),
+        Err(MlsError::GroupFull { .. })
+    ));
+    assert_eq!(a.get_epoch(gid.clone()).unwrap(), epoch);
+    assert_eq!(a.get_members(gid.clone()).unwrap().len(), 2);
+    assert!(!a.has_pending_commit(gid.clone()).unwrap());
+    assert!(a.get_unacked_commits(gid.clone()).unwrap().len() == 1);
+
+    a.set_max_members(gid.clone(), 3).unwrap();
+    a.begin_commit(gid.clone()).unwrap();
+    a.txn_add(kp("carol")).unwrap();
+    a.txn_add(kp("dave")).unwrap();
+    assert!(matches!(
+        a.finish_commit(gid.clone()),
+        Err(MlsError::GroupFull { .. })
+    ));
+    assert_eq!(a.get_epoch(gid.clone()).unwrap(), epoch);
+    let bi = a
+        .get_members(gid.clone())
+        .unwrap()
+        .iter()
+        .find(|m| m.identity == b"bob")
+        .unwrap()
+        .index;
+    a.update_membership(gid.clone(), vec![kp("carol"), kp("dave")], vec![bi])
+        .unwrap();
+    assert_eq!(a.get_members(gid.clone()).unwrap().len(), 3);
+    a.set_max_members(gid.clone(), 0).unwrap();
+    assert_eq!(a.get_max_members(gid.clone()).unwrap(), None);
+    a.add_member(gid.clone(), kp("erin")).unwrap();
+}
+
+#[test]
+fn rotate_all() {
+    let dir = tmp("rall");
+    let a = MlsClient::new(dir.clone());
+    let b = MlsClient::new(tmp("rallb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gs: Vec<String> = (0..3)
+        .map(|i| {
+            let gid = a.create_group(format!("g{}", i), None).unwrap();
+            let out = a
+                .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+                .unwrap();
+            b.process_welcome(field(&out, "welcome")).unwrap();
+            gid
+        })
+        .collect();
+    let old = a.get_own_member_info(gs[0].clone()).unwrap().signature_key;
+    let c = MlsClient::new_ephemeral();
+    c.create_identity("carol".into()).unwrap();
+    a.add_member_prepare(gs[1].clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+
+    // HPKE keys only.
+    let find = |r: &[GroupRotation], g: &String| {
+        r.iter()
+            .find(|rot| &rot.group_id == g)
+            .unwrap()
+            .commit
+            .is_none()
+    };
+    let r = a.rotate_all_groups(false).unwrap();
+    assert_eq!(r.len(), 3);
+    assert!(r.windows(2).all(|w| w[0].group_id < w[1].group_id));
+    let skipped = r.iter().find(|rot| rot.group_id == gs[1]).unwrap();
+    assert!(skipped
+        .skipped_reason
+        .as_ref()
+        .unwrap()
+        .contains("unconfirmed commit"));
+    assert!(!find(&r, &gs[0]) && find(&r, &gs[1]) && !find(&r, &gs[2]));
+    for rot in &r {
+        if let Some(commit) = &rot.commit {
+            b.process_commit(rot.group_id.clone(), commit.clone())
+                .unwrap();
+        }
+    }
+    assert_eq!(
+        a.get_own_member_info(gs[0].clone()).unwrap().signature_key,
+        old
+    );
+
+    let skipped_on_old_key = |r: &[GroupRotation], g: &String| {
+        r.iter()
+            .find(|rot| &rot.group_id == g)
+            .and_then(|rot| rot.skipped_reason.as_ref())
+            .is_some_and(|reason| reason.ends_with("still signed with the old key"))
+    };
+    let r = a.rotate_all_groups(true).unwrap();
+    assert!(find(&r, &gs[1]));
+    for rot in &r {
+        if let Some(commit) = &rot.commit {
+            b.process_commit(rot.group_id.clone(), commit.clone())
+                .unwrap();
+        }
+    }
+    let new = a.get_own_member_info(gs[0].clone()).unwrap().signature_key;
+    assert_ne!(new, old);
+    assert_eq!(
+        a.get_own_member_info(gs[2].clone()).unwrap().signature_key,
+        new
+    );
+    assert_eq!(
+        a.get_own_member_info(gs[1].clone()).unwrap().signature_key,
+        old
+    );
+    assert!(skipped_on_old_key(&r, &gs[1]));
+    assert_eq!(a.list_identities(), vec!["alice".to_string()]);
+
+    // Survives a restart; the left-behind group still works on the old key.
+    drop(a);
+    let a = MlsClient::new(dir);
+    a.abort_commit(gs[1].clone()).unwrap();
+    for g in &gs {
+        let ct = a.encrypt_message(g.clone(), "hi".into()).unwrap();
+        assert_eq!(b.decrypt_message(g.clone(), ct).unwrap(), "hi");
+    }
+
+    let r = a.rotate_all_groups(true).unwrap();
+    assert!(r.iter().all(|rot| rot.commit.is_some()));
+    for rot in &r {
+        b.process_commit(rot.group_id.clone(), rot.commit.clone().unwrap())
+            .unwrap();
+    }
+    let newest = a.get_own_member_info(gs[1].clone()).unwrap().signature_key;
+    assert_ne!(newest, old);
+    assert_eq!(a.list_identities(), vec!["alice".to_string()]);
+    for g in &gs {
+        assert_eq!(
+            a.get_own_member_info(g.clone()).unwrap().signature_key,
+            newest
+        );
+        let ct = a.encrypt_message(g.clone(), "again".into()).unwrap();
+        assert_eq!(b.decrypt_message(g.clone(), ct).unwrap(), "again");
+    }
+    let st = a.state.read().unwrap();
+    assert!(st.group_signers.is_empty());
+    assert!(SignatureKeyPair::read(st.crypto.storage(), &old, SignatureScheme::ED25519).is_none());
+}
+
+#[test]
+fn ctor_past_epochs() {
+    let a = MlsClient::new(tmp("cpea"));
+    let b = MlsClient::new_with_max_past_epochs(tmp("cpeb"), 1).unwrap();
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    let late = a.encrypt_message(gid.clone(), "late".into()).unwrap();
+    let c = a.self_update(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), late).unwrap(), "late");
+    // Without the option the same message is lost.
+    let (a, b, gid) = pair();
+    let late = a.encrypt_message(gid.clone(), "late".into()).unwrap();
+    let c = a.self_update(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+    assert!(matches!(
+        b.decrypt_message(gid.clone(), late),
+        Err(MlsError::EpochTooOld { .. })
+    ));
+    // Nor can setting it after joining retain more.
+    b.set_max_past_epochs(1).unwrap();
+    let late = a.encrypt_message(gid.clone(), "late".into()).unwrap();
+    let c = a.self_update(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+    let epoch = b.get_epoch(gid.clone()).unwrap();
+    assert!(
+        matches!(b.decrypt_message(gid.clone(), late), Err(MlsError::EpochTooOld { oldest_retained, .. }) if oldest_retained == epoch)
+    );
+}
+
+#[test]
+fn audit_log() {
+    // Own directories: `pair()`'s are shared with concurrent tests.
+    let a = MlsClient::new(tmp("audita"));
+    let b = MlsClient::new(tmp("auditb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    let c = MlsClient::new(tmp("auditc"));
+    c.create_identity("carol".into()).unwrap();
+    let out = a
+        .add_member(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_commit(gid.clone(), field(&out, "commit"))
+        .unwrap();
+    c.process_welcome(field(&out, "welcome")).unwrap();
+    let out = a.remove_member(gid.clone(), 2).unwrap();
+    b.process_commit(gid.clone(), field(&out, "commit"))
+        .unwrap();
+    c.process_commit(gid.clone(), field(&out, "commit"))
+        .unwrap();
+    b.set_autosave(false).unwrap();
+    let out = b.self_update(gid.clone()).unwrap();
+    a.process_commit(gid.clone(), field(&out, "commit"))
+        .unwrap();
+
+    let la = a.get_audit_log(gid.clone(), None).unwrap();
+    assert_eq!(la.len(), 4);
+    assert_eq!(
+        (la[0].epoch, la[0].added.clone(), la[0].committer.clone()),
+        (1, vec![b"bob".to_vec()], b"alice".to_vec())
+    );
+    let lb = b.get_audit_log(gid.clone(), None).unwrap();
+    assert_eq!(lb.len(), 3);
+    let strip = |e: &AuditEntry| {
+        (
+            e.epoch,
+            e.committer.clone(),
+            e.added.clone(),
+            e.removed.clone(),
+            e.commit_hash.clone(),
+        )
+    };
+    assert_eq!(
+        la[1..].iter().map(strip).collect::<Vec<_>>(),
+        lb.iter().map(strip).collect::<Vec<_>>()
+    );
+    assert_eq!(lb[0].added, vec![b"carol".to_vec()]);
+    assert_eq!(lb[1].removed, vec![b"carol".to_vec()]);
+    assert_eq!(lb[2].committer, b"bob".to_vec());
+    assert!(lb[2].added.is_empty() && lb[2].removed.is_empty());
+    assert_eq!(a.get_audit_log(gid.clone(), Some(3)).unwrap().len(), 2);
+    assert!(a.verify_audit_log(gid.clone()).unwrap() && b.verify_audit_log(gid.clone()).unwrap());
+    // Carol's log ends with her removal.
+    let lc = c.get_audit_log(gid.clone(), None).unwrap();
+    assert_eq!(lc.len(), 1);
+    assert_eq!(lc[0].removed, vec![b"carol".to_vec()]);
+    assert!(a.get_audit_log("00".into(), None).unwrap().is_empty());
+
+    // Written through: B never saved, yet the log survives.
+    drop(b);
+    let b = MlsClient::new(tmp_keep("auditb"));
+    assert_eq!(b.get_audit_log(gid.clone(), None).unwrap(), lb);
+
+    let path = std::path::Path::new(&tmp_keep("auditb")).join(format!("audit_{}.json", gid));
+    let json = std::fs::read_to_string(&path).unwrap();
+    std::fs::write(&path, json.replacen("\"epoch\": 3", "\"epoch\": 4", 1)).unwrap();
+    assert!(!b.verify_audit_log(gid.clone()).unwrap());
+    std::fs::write(&path, &json[..json.len() / 2]).unwrap();
+    assert!(!b.verify_audit_log(gid.clone()).unwrap());
+    assert!(matches!(
+        b.get_audit_log(gid.clone(), None),
+        Err(MlsError::SerializationError { .. })
+    ));
+}
+
+#[test]
+fn own_identity() {
+    let a = MlsClient::new(tmp("oida"));
+    a.create_identity("alice".into()).unwrap();
+    a.add_identity("work".into()).unwrap();
+    let g1 = a.create_group("g1".into(), None).unwrap();
+    let g2 = a.create_group("g2".into(), Some("work".into())).unwrap();
+    assert_eq!(a.get_own_identity(g1).unwrap(), b"alice".to_vec());
+    assert_eq!(a.get_own_identity(g2).unwrap(), b"work".to_vec());
+    assert!(matches!(
+        a.get_own_identity("00".into()),
+        Err(MlsError::GroupNotFound { .. })
+    ));
+}
+
+#[test]
+fn welcome_twice() {
+    let a = MlsClient::new(tmp("w2a"));
+    let b = MlsClient::new(tmp("w2b"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let welcome = field(
+        &a.add_member(gid.clone(), b.generate_key_package(None).unwrap())
+            .unwrap(),
+        "welcome",
+    );
+    b.process_welcome(welcome.clone()).unwrap();
+    let ct = a.encrypt_message(gid.clone(), "one".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "one");
+    let c = a.self_update(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+
+    let again = b.process_welcome(welcome.clone());
+    assert!(
+        matches!(&again, Err(MlsError::GroupAlreadyJoined { group_id, current_epoch: 2 }) if *group_id == gid),
+        "{again:?}"
+    );
+    b.set_autosave(false).unwrap();
+    drop(b);
+    let b = MlsClient::new(tmp_keep("w2b"));
+    assert!(matches!(
+        b.process_welcome(welcome.clone()),
+        Err(MlsError::GroupAlreadyJoined { .. })
+    ));
+    let ct = a.encrypt_message(gid.clone(), "two".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "two");
+
+    // Same with a last-resort key package, which survives the first join.
+    let c = MlsClient::new(tmp("w2c"));
+    c.create_identity("carol".into()).unwrap();
+    let welcome = field(
+        &a.add_member(
+            gid.clone(),
+            c.generate_last_resort_key_package("carol".into()).unwrap(),
+        )
+        .unwrap(),
+        "welcome",
+    );
+    c.process_welcome(welcome.clone()).unwrap();
+    assert!(matches!(
+        c.process_welcome(welcome.clone()),
+        Err(MlsError::GroupAlreadyJoined { .. })
+    ));
+    let ct = a.encrypt_message(gid.clone(), "three".into()).unwrap();
+    assert_eq!(c.decrypt_message(gid.clone(), ct).unwrap(), "three");
+
+    // Marked out of sync, the Welcome replaces the group.
+    c.mark_group_desynced(gid.clone()).unwrap();
+    assert_eq!(c.process_welcome(welcome).unwrap(), gid);
+}
+
+#[test]
+fn ephemeral() {
+    let (a, b, gid) = pair();
+    let blob = a
+        .encrypt_ephemeral(gid.clone(), b"typing".to_vec())
+        .unwrap();
+    let m = b.decrypt_ephemeral(gid.clone(), blob.clone()).unwrap();
+    assert_eq!(
+        (m.sender_leaf_index, m.sender_identity, m.payload),
+        (0, b"alice".to_vec(), b"typing".to_vec())
+    );
+    // Replays within the epoch decrypt again.
+    assert!(b.decrypt_ephemeral(gid.clone(), blob.clone()).is_ok());
+    assert!(b
+        .decrypt_message_with_aad(gid.clone(), blob.clone())
+        .is_err());
+
+    let mut forged = blob.clone();
+    forged[13..17].copy_from_slice(&1u32.to_be_bytes());
+    assert!(matches!(
+        b.decrypt_ephemeral(gid.clone(), forged),
+        Err(MlsError::InvalidSignature { .. })
+    ));
+    let mut tampered = blob.clone();
+    tampered[40] ^= 1;
+    assert!(matches!(
+        b.decrypt_ephemeral(gid.clone(), tampered),
+        Err(MlsError::InvalidSignature { .. })
+    ));
+    assert!(matches!(
+        b.decrypt_ephemeral(gid.clone(), blob[..20].to_vec()),
+        Err(MlsError::SerializationError { .. })
+    ));
+
+    let snapshot = |c: &MlsClient| {
+        serde_json::from_slice::<serde_json::Value>(&c.export_group(gid.clone()).unwrap()).unwrap()
+    };
+    let before = (snapshot(&a), snapshot(&b));
+    for i in 0..1000u32 {
+        let blob = a
+            .encrypt_ephemeral(gid.clone(), i.to_be_bytes().to_vec())
+            .unwrap();
+        assert_eq!(
+            b.decrypt_ephemeral(gid.clone(), blob).unwrap().payload,
+            i.to_be_bytes()
+        );
+    }
+    assert!(before == (snapshot(&a), snapshot(&b)));
+    let ct = a.encrypt_message(gid.clone(), "real".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "real");
+
+    let c = a.self_update(gid.clone()).unwrap();
+    b.process_commit(gid.clone(), field(&c, "commit")).unwrap();
+    match b.decrypt_ephemeral(gid.clone(), blob) {
+        Err(MlsError::EpochMismatch {
+            message_epoch,
+            current_epoch,
+            ..
+        }) => assert_eq!(message_epoch + 1, current_epoch),
+        r => panic!("{r:?}"),
+    }
+    let blob = b.encrypt_ephemeral(gid.clone(), vec![]).unwrap();
+    assert_eq!(
+        a.decrypt_ephemeral(gid.clone(), blob)
+            .unwrap()
+            .sender_leaf_index,
+        1
+    );
+}
+
+#[test]
+fn kp_refresh() {
+    let a = MlsClient::new(tmp("kpra"));
+    let b = MlsClient::new(tmp("kprb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let work = b.add_identity("bob-work".into()).unwrap();
+    let now = unix_time();
+    b.generate_key_packages(1, now - 7200, now - 3600, None)
+        .unwrap();
+    b.generate_key_packages(1, now - 7200, now + 3600, Some(work.clone()))
+        .unwrap();
+    let keep = b
+        .generate_key_packages(1, now - 60, now + 30 * 86400, None)
+        .unwrap();
+    let before = b.key_package_expirations().unwrap();
+    let stale: Vec<_> = before
+        .iter()
+        .filter(|e| e.needs_refresh)
+        .map(|e| e.key_package_ref.clone())
+        .collect();
+    assert_eq!(stale.len(), 2);
+    assert!(before[0].expired && !before[1].expired);
+    let fresh = b.refresh_key_packages().unwrap();
+    assert_eq!(fresh.len(), 2);
+    let after = b.key_package_expirations().unwrap();
+    assert!(after.iter().all(|e| !e.needs_refresh));
+    assert!(after.iter().all(|e| !stale.contains(&e.key_package_ref)));
+    assert_eq!(after.len(), before.len());
+    let ids: Vec<_> = after
+        .iter()
+        .map(|e| String::from_utf8_lossy(&e.identity).to_string())
+        .collect();
+    assert!(ids.iter().any(|i| i == "bob-work"));
+    assert!(b.refresh_key_packages().unwrap().is_empty());
+    // Refreshed packages are joinable.
+    let gid = a.create_group("g".into(), None).unwrap();
+    let add = a.add_member(gid.clone(), fresh[0].clone()).unwrap();
+    b.process_welcome(field(&add, "welcome")).unwrap();
+    let _ = keep;
+}
+
+#[test]
+fn envelope() {
+    let (a, b, gid) = pair();
+    let up = a.self_update(gid.clone()).unwrap();
+    let commit = field(&up, "commit");
+    let ct = a.encrypt_message(gid.clone(), "after".into()).unwrap();
+    assert!(b.decrypt_message(gid.clone(), ct.clone()).is_err());
+    let out = b.process_envelope(gid.clone(), vec![commit, ct]).unwrap();
+    assert_eq!(out.len(), 2);
+    assert!(matches!(out[0], IncomingMessage::Commit { .. }));
+    match &out[1] {
+        IncomingMessage::Application { plaintext, .. } => assert_eq!(plaintext, b"after"),
+        other => panic!("{other:?}"),
+    }
+    // A bad entry stops the envelope and reports what was applied.
+    let up = a.self_update(gid.clone()).unwrap();
+    let ct = a.encrypt_message(gid.clone(), "lost".into()).unwrap();
+    match b.process_envelope(gid.clone(), vec![field(&up, "commit"), "zz".into(), ct]) {
+        Err(MlsError::BatchFailed {
+            index, processed, ..
+        }) => {
+            assert_eq!(index, 1);
+            assert_eq!(processed.len(), 1);
+            assert!(matches!(processed[0], IncomingMessage::Commit { .. }));
+        }
+        other => panic!("{other:?}"),
+    }
+    assert_eq!(b.get_epoch(gid.clone()).unwrap(), a.get_epoch(gid).unwrap());
+}
+
+#[test]
+fn validate_proposals() {
+    let (a, b, gid) = pair();
+    let v = a.validate_pending_proposals(gid.clone()).unwrap();
+    assert_eq!(
+        (v.proposal_count, v.committable, v.conflicts.len()),
+        (0, true, 0)
+    );
+    // Bob proposes removing Alice: Alice cannot commit it.
+    let p = field(
+        &b.propose_remove_member(gid.clone(), 0).unwrap(),
+        "proposal",
+    );
+    a.process_commit(gid.clone(), p).unwrap();
+    let v = a.validate_pending_proposals(gid.clone()).unwrap();
+    assert_eq!(v.proposal_count, 1);
+    assert!(!v.committable);
+    assert!(v
+        .conflicts
+        .iter()
+        .any(|c| c.kind == ProposalConflictKind::RemovesCommitter));
+    let v = b.validate_pending_proposals(gid.clone()).unwrap();
+    assert!(v.committable && v.conflicts.is_empty());
+    // Bob queues his own removal twice more on his side.
+    b.propose_remove_member(gid.clone(), 0).unwrap();
+    let v = b.validate_pending_proposals(gid.clone()).unwrap();
+    assert!(v
+        .conflicts
+        .iter()
+        .any(|c| c.kind == ProposalConflictKind::DuplicateRemove && c.proposal_refs.len() == 2));
+    assert_eq!(a.get_pending_proposals(gid.clone()).unwrap().len(), 1);
+}
+
+#[test]
+fn last_resort_join() {
+    let a = MlsClient::new(tmp("lrja"));
+    let c = MlsClient::new(tmp("lrjc"));
+    let b = MlsClient::new(tmp("lrjb"));
+    a.create_identity("alice".into()).unwrap();
+    c.create_identity("carol".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    assert!(!b.was_last_resort_used());
+    let lr = b.generate_last_resort_key_package("bob".into()).unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    let welcome = |x: &MlsClient, kp: &str| {
+        let gid = x.create_group("g".into(), None).unwrap();
+        field(&x.add_member(gid, kp.to_string()).unwrap(), "welcome")
+    };
+    b.process_welcome(welcome(&a, &lr)).unwrap();
+    assert!(b.was_last_resort_used());
+    b.process_welcome(welcome(&c, &lr)).unwrap();
+    assert!(b.was_last_resort_used());
+    b.process_welcome(welcome(&a, &kp)).unwrap();
+    assert!(!b.was_last_resort_used());
+    let again = b.process_welcome(welcome(&c, &kp));
+    assert!(
+        matches!(again, Err(MlsError::NoMatchingKeyPackage { .. })),
+        "{again:?}"
+    );
+    assert_eq!(b.list_active_groups(None).len(), 3);
+}
+
+#[test]
+fn encrypt_detailed() {
+    let (a, b, gid) = enveloped_pair();
+    let m0 = a.encrypt_message_detailed(gid.clone(), "x".into()).unwrap();
+    let m1 = a.encrypt_message_detailed(gid.clone(), "y".into()).unwrap();
+    assert_eq!(
+        (m0.epoch, m0.generation, m1.epoch, m1.generation),
+        (1, 0, 1, 1)
+    );
+    let d = b
+        .decrypt_message_with_sender(gid.clone(), m1.ciphertext)
+        .unwrap();
+    assert_eq!(
+        d.generation,
+        Some(MessageGeneration {
+            epoch: 1,
+            generation: 1
+        })
+    );
+    a.self_update(gid.clone()).unwrap();
+    let m2 = a.encrypt_message_detailed(gid.clone(), "z".into()).unwrap();
+    assert_eq!((m2.epoch, m2.generation), (2, 0));
+}
+
+#[test]
+fn purge_orphans() {
+    let dir = tmp("purge");
+    let a = MlsClient::new(dir.clone());
+    a.create_identity("alice".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    a.self_update(gid.clone()).unwrap();
+    drop(a);
+    let good = format!("{dir}/group_{gid}.json");
+    std::fs::write(
+        format!("{dir}/group_abcdef.json"),
+        std::fs::read(&good).unwrap(),
+    )
+    .unwrap();
+    std::fs::write(format!("{dir}/group_0123.json"), b"{not json").unwrap();
+    std::fs::write(format!("{dir}/audit_0123.json"), b"[{").unwrap();
+    let a = MlsClient::new(dir.clone());
+    assert_eq!(a.list_active_groups(None), vec![gid.clone()]);
+    let mut moved: Vec<_> = std::fs::read_dir(format!("{dir}/.corrupt"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    moved.sort();
+    assert_eq!(
+        moved,
+        vec!["audit_0123.json", "group_0123.json", "group_abcdef.json"]
+    );
+    assert!(a.purge_orphaned_state().unwrap().is_empty());
+    assert!(std::path::Path::new(&good).exists());
+    // Out-of-band orphan while running.
+    std::fs::write(format!("{dir}/group_feed.json"), b"x").unwrap();
+    assert_eq!(
+        a.purge_orphaned_state().unwrap(),
+        vec!["group_feed.json".to_string()]
+    );
+    a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    drop(a);
+    // A corrupt file of a saved group loses that group, not the whole load.
+    std::fs::write(&good, b"garbage").unwrap();
+    let a = MlsClient::new(dir.clone());
+    assert!(a.has_identity());
+    assert!(a.list_active_groups(None).is_empty());
+    assert!(std::path::Path::new(&format!("{dir}/.corrupt/group_{gid}.json")).exists());
+}
+
+#[test]
+fn clear_logger_drops() {
+    struct L(Arc<Mutex<u32>>);
+    impl MlsLogger for L {
+        fn log(&self, _: LogLevel, _: String, _: String) {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+    let n = Arc::new(Mutex::new(0));
+    let a = MlsClient::new_ephemeral();
+    a.set_logger(Box::new(L(n.clone())));
+    log::warn!("one");
+    assert!(*n.lock().unwrap() >= 1);
+    a.clear_logger();
+    let before = *n.lock().unwrap();
+    log::warn!("two");
+    assert_eq!(*n.lock().unwrap(), before);
+}
+
+#[test]
+fn staged_welcome() {
+    let a = MlsClient::new(tmp("swa"));
+    let b = MlsClient::new(tmp("swb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    let w = field(&a.add_member(gid.clone(), kp).unwrap(), "welcome");
+    let kps = b.list_stored_key_packages().unwrap().len();
+    let p = b.stage_welcome(w.clone()).unwrap();
+    assert_eq!(p.group_id, gid);
+    assert_eq!(p.inviter_identity, b"alice");
+    assert_eq!((p.inviter_index, p.epoch, p.member_count), (0, 1, 2));
+    assert!(b.list_active_groups(None).is_empty());
+    assert_eq!(b.list_stored_key_packages().unwrap().len(), kps);
+    b.decline_welcome(gid.clone()).unwrap();
+    assert!(b.list_active_groups(None).is_empty());
+    assert!(b.commit_welcome(gid.clone()).is_err());
+    // Reload: nothing of the declined Welcome was kept.
+    drop(b);
+    let b = MlsClient::new(tmp_keep("swb"));
+    assert!(b.list_active_groups(None).is_empty());
+    assert_eq!(b.list_stored_key_packages().unwrap().len(), kps);
+    b.stage_welcome(w.clone()).unwrap();
+    let j = b.commit_welcome(gid.clone()).unwrap();
+    assert_eq!(
+        (j.inviter_index, j.inviter_identity.as_slice()),
+        (0, &b"alice"[..])
+    );
+    assert_eq!(b.list_active_groups(None), vec![gid.clone()]);
+    assert!(matches!(
+        b.stage_welcome(w),
+        Err(MlsError::GroupAlreadyJoined { .. })
+    ));
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid, ct).unwrap(), "hi");
+}
+
+#[test]
+fn bulk_encrypt() {
+    let a = MlsClient::new(tmp("bea"));
+    let b = MlsClient::new(tmp("beb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a
+        .create_group_with_config("g".into(), envelope_config(), None)
+        .unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    b.process_welcome(field(&a.add_member(gid.clone(), kp).unwrap(), "welcome"))
+        .unwrap();
+    let cts = a
+        .encrypt_messages(gid.clone(), vec![b"1".to_vec(), b"2".to_vec(), vec![]])
+        .unwrap();
+    let got: Vec<_> = cts
+        .into_iter()
+        .map(|c| b.decrypt_message_with_sender(gid.clone(), c).unwrap())
+        .collect();
+    assert_eq!(
+        got.iter().map(|d| d.plaintext.clone()).collect::<Vec<_>>(),
+        vec![b"1".to_vec(), b"2".to_vec(), vec![]]
+    );
+    assert_eq!(got[2].generation.unwrap().generation, 2);
+    let many = a
+        .encrypt_messages(gid.clone(), vec![b"typing".to_vec(); 100])
+        .unwrap();
+    assert_eq!(many.len(), 100);
+    let last = b
+        .decrypt_message_with_sender(gid.clone(), many[99].clone())
+        .unwrap();
+    assert_eq!(last.plaintext, b"typing");
+}
+
+#[test]
+fn resumption_psk() {
+    let a = MlsClient::new(tmp("rpa"));
+    let b = MlsClient::new(tmp("rpb"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    b.process_welcome(field(&a.add_member(gid.clone(), kp).unwrap(), "welcome"))
+        .unwrap();
+    let pa = a.export_resumption_psk(gid.clone()).unwrap();
+    assert_eq!(pa.len(), 32);
+    assert_eq!(pa, b.export_resumption_psk(gid.clone()).unwrap());
+    let c = field(&a.self_update(gid.clone()).unwrap(), "commit");
+    assert_ne!(pa, a.export_resumption_psk(gid.clone()).unwrap());
+    b.process_commit(gid.clone(), c).unwrap();
+    let psk = a.export_resumption_psk(gid.clone()).unwrap();
+    assert_eq!(psk, b.export_resumption_psk(gid.clone()).unwrap());
+    // Successor group bound to the old epoch.
+    let id = b"g-epoch-2".to_vec();
+    a.add_external_psk(id.clone(), psk.clone()).unwrap();
+    b.add_external_psk(id.clone(), psk).unwrap();
+    let h = a.create_group("h".into(), None).unwrap();
+    let kp = b.generate_key_package(None).unwrap();
+    b.process_welcome(field(&a.add_member(h.clone(), kp).unwrap(), "welcome"))
+        .unwrap();
+    let p = field(&a.propose_psk(h.clone(), id).unwrap(), "proposal");
+    b.process_commit(h.clone(), p).unwrap();
+    let c = field(&a.commit_pending_proposals(h.clone()).unwrap(), "commit");
+    b.process_commit(h.clone(), c).unwrap();
+    let ct = a.encrypt_message(h.clone(), "resumed".into()).unwrap();
+    assert_eq!(b.decrypt_message(h, ct).unwrap(), "resumed");
+}
+
+#[test]
+fn control_messages() {
+    for framing in [false, true] {
+        let a = MlsClient::new_with_message_framing(tmp(&format!("cma{framing}")), framing);
+        let b = MlsClient::new_with_message_framing(tmp(&format!("cmb{framing}")), framing);
+        a.create_identity("alice".into()).unwrap();
+        b.create_identity("bob".into()).unwrap();
+        let gid = a
+            .create_group_with_config("g".into(), envelope_config(), None)
+            .unwrap();
+        let kp = b.generate_key_package(None).unwrap();
+        b.process_welcome(field(&a.add_member(gid.clone(), kp).unwrap(), "welcome"))
+            .unwrap();
+        for kind in [
+            PayloadKind::Chat,
+            PayloadKind::ReadReceipt,
+            PayloadKind::Typing,
+            PayloadKind::Reaction,
+        ] {
+            let ct = a
+                .send_control_message(gid.clone(), kind, b"\xf0\x9f\x91\x8d".to_vec())
+                .unwrap();
+            let d = b.decrypt_message_with_sender(gid.clone(), ct).unwrap();
+            assert_eq!(
+                (d.payload_kind, d.plaintext.as_slice()),
+                (kind, &b"\xf0\x9f\x91\x8d"[..])
+            );
+            assert_eq!(d.header.is_some(), framing);
+        }
+        // Payloads that look like the old magic-prefixed framing arrive
+        // exactly as sent.
+        let lookalikes: [&[u8]; 4] = [
+            b"MLSc\x01read",
+            b"MLSc\x09odd",
+            b"MLSt\0\0\0\0\0\0\0\x01\0\0\0\0\0\0\0\x02body",
+            b"MLSt",
+        ];
+        for payload in lookalikes {
+            for kind in [PayloadKind::Chat, PayloadKind::Reaction] {
+                let ct = a
+                    .send_control_message(gid.clone(), kind, payload.to_vec())
+                    .unwrap();
+                let d = b.decrypt_message_with_sender(gid.clone(), ct).unwrap();
+                assert_eq!((d.payload_kind, d.plaintext.as_slice()), (kind, payload));
+                assert_eq!(d.header.is_some(), framing);
+            }
+        }
+        let ct = a
+            .send_control_message(gid.clone(), PayloadKind::Typing, vec![])
+            .unwrap();
+        let out = b.process_messages(gid.clone(), vec![ct]).unwrap();
+        assert!(matches!(
+            out[0],
+            IncomingMessage::Application {
+                payload_kind: PayloadKind::Typing,
+                ..
+            }
+        ));
+    }
+}
+
+#[test]
+fn create_full() {
+    let a = MlsClient::new(tmp("cgf"));
+    a.create_identity("alice".into()).unwrap();
+    let s = a.create_group_full("g".into(), None).unwrap();
+    assert_eq!(
+        (s.epoch, s.member_count, s.status, s.ciphersuite),
+        (Some(0), 1, MembershipStatus::Active, Some(1))
+    );
+    assert_eq!(a.list_groups_detailed(None)[0].group_id, s.group_id);
+    assert!(s.last_activity.is_some());
+}
+
+#[test]
+fn removed_by_commit() {
+    let (a, b, gid) = pair();
+    let r = a.remove_member(gid.clone(), 1).unwrap();
+    assert!(matches!(
+        b.process_commit(gid.clone(), field(&r, "commit")).unwrap(),
+        CommitOutcome::RemovedSelf
+    ));
+    assert!(b.list_active_groups(None).is_empty());
+    assert!(matches!(
+        b.encrypt_message(gid.clone(), "x".into()),
+        Err(MlsError::NoLongerMember { .. })
+    ));
+    assert_eq!(a.get_members(gid.clone()).unwrap().len(), 1);
+
+    // The same signal through the generic and batch entry points.
+    let c = MlsClient::new(tmp("rbc_c"));
+    c.create_identity("carol".into()).unwrap();
+    let d = MlsClient::new(tmp("rbc_d"));
+    d.create_identity("dave".into()).unwrap();
+    for member in [&c, &d] {
+        let out = a
+            .add_member(gid.clone(), member.generate_key_package(None).unwrap())
+            .unwrap();
+        for other in [&c, &d] {
+            if other.list_active_groups(None).contains(&gid) {
+                other.process_commit(gid.clone(), commit_of(&out)).unwrap();
+            }
+        }
+        member.process_welcome(field(&out, "welcome")).unwrap();
+    }
+    let index = |id: &[u8]| {
+        a.get_members(gid.clone())
+            .unwrap()
+            .into_iter()
+            .find(|m| m.identity == id)
+            .unwrap()
+            .index
+    };
+    let r = a.remove_member(gid.clone(), index(b"carol")).unwrap();
+    d.process_commit(gid.clone(), commit_of(&r)).unwrap();
+    match c
+        .process_messages(gid.clone(), vec![commit_of(&r)])
+        .unwrap()
+        .as_slice()
+    {
+        [IncomingMessage::Removed { removed_by }] => assert_eq!(removed_by, b"alice"),
+        other => panic!("{other:?}"),
+    }
+    assert!(!c.list_active_groups(None).contains(&gid));
+    let r = a.remove_member(gid.clone(), index(b"dave")).unwrap();
+    assert!(matches!(
+        d.decrypt_message(gid.clone(), commit_of(&r)),
+        Err(MlsError::SelfRemoved { .. })
+    ));
+    assert!(matches!(
+        d.decrypt_message(gid.clone(), commit_of(&r)),
+        Err(MlsError::NoLongerMember { .. })
+    ));
+}
+
+#[test]
+fn create_key_packages_batch() {
+    let b = MlsClient::new(tmp("ckp_b"));
+    b.create_identity("bob".into()).unwrap();
+    assert!(matches!(
+        b.create_key_packages("bob".into(), 101),
+        Err(MlsError::InputTooLarge { .. })
+    ));
+    let kps = b.create_key_packages("bob".into(), 3).unwrap();
+    assert_eq!(kps.len(), 3);
+    for (i, kp) in kps.into_iter().enumerate() {
+        let a = MlsClient::new(tmp(&format!("ckp_a{}", i)));
+        a.create_identity(format!("a{}", i)).unwrap();
+        let gid = a.create_group(format!("g{}", i), None).unwrap();
+        let out = a.add_member(gid.clone(), kp).unwrap();
+        let joined = b.process_welcome(field(&out, "welcome")).unwrap();
+        assert_eq!(joined, gid);
+    }
+
+    // Another identity's packages carry its credential and key.
+    assert!(matches!(
+        b.create_key_packages("nobody".into(), 1),
+        Err(MlsError::UnknownIdentity { .. })
+    ));
+    assert!(matches!(
+        b.generate_last_resort_key_package("nobody".into()),
+        Err(MlsError::UnknownIdentity { .. })
+    ));
+    b.add_identity("work".into()).unwrap();
+    let bob_key = b
+        .get_key_package_info(b.generate_key_package(None).unwrap())
+        .unwrap()
+        .signature_key;
+    let kp = b.create_key_packages("work".into(), 1).unwrap().remove(0);
+    let lr = b.generate_last_resort_key_package("work".into()).unwrap();
+    for kp in [&kp, &lr] {
+        let info = b.get_key_package_info(kp.clone()).unwrap();
+        assert_eq!(info.identity, b"work".to_vec());
+        assert_ne!(info.signature_key, bob_key);
+    }
+    let a = MlsClient::new(tmp("ckp_aw"));
+    a.create_identity("alice".into()).unwrap();
+    let gid = a.create_group("gw".into(), None).unwrap();
+    let out = a.add_member(gid.clone(), kp).unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    assert_eq!(
+        b.get_own_member_info(gid.clone()).unwrap().identity,
+        b"work".to_vec()
+    );
+    let ct = b.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(a.decrypt_message(gid, ct).unwrap(), "hi");
+}
+
+#[test]
+fn state_version_migration() {
+    let dir = tmp("statever");
+    let a = MlsClient::new(dir.clone());
+    a.create_identity("alice".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    drop(a);
+    let path = std::path::Path::new(&dir).join("state.json");
+    let mut v: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    assert_eq!(v["version"], 1);
+
+    // Unversioned and v1 files load.
+    for version in [None, Some(1)] {
+        match version {
+            None => {
+                v.as_object_mut().unwrap().remove("version");
+            }
+            Some(n) => v["version"] = n.into(),
+        }
+        std::fs::write(&path, serde_json::to_vec(&v).unwrap()).unwrap();
+        let a = MlsClient::new(dir.clone());
+        assert!(a.list_all_groups().contains(&gid));
+    }
+
+    v["version"] = 99.into();
+    let newer = serde_json::to_vec(&v).unwrap();
+    std::fs::write(&path, &newer).unwrap();
+    let a = MlsClient::new(dir.clone());
+    assert!(matches!(
+        a.load_state(),
+        Err(MlsError::UnsupportedStateVersion { version: 99 })
+    ));
+    assert!(matches!(
+        a.save_state(),
+        Err(MlsError::UnsupportedStateVersion { version: 99 })
+    ));
+    assert_eq!(std::fs::read(&path).unwrap(), newer);
+}
+
+#[test]
+fn signed_messages() {
+    let a = MlsClient::new(tmp("sgn_a"));
+    let b = MlsClient::new(tmp("sgn_b"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+
+    let signed = a
+        .encrypt_signed_message(gid.clone(), "hello".into())
+        .unwrap();
+    let plaintext = b
+        .decrypt_message(gid.clone(), signed.ciphertext.clone())
+        .unwrap();
+    assert_eq!(plaintext, "hello");
+    assert!(b.verify_signed_message(signed.clone(), plaintext).unwrap());
+    let alice = b
+        .get_members(gid.clone())
+        .unwrap()
+        .into_iter()
+        .find(|m| m.identity == b"alice")
+        .unwrap();
+    assert_eq!(alice.signature_key, signed.signature_key);
+
+    assert!(!b
+        .verify_signed_message(signed.clone(), "hellO".into())
+        .unwrap());
+    let mut other_epoch = signed.clone();
+    other_epoch.epoch += 1;
+    assert!(!b
+        .verify_signed_message(other_epoch, "hello".into())
+        .unwrap());
+    // Verifiable by a non-member too.
+    let c = MlsClient::new_ephemeral();
+    assert!(c.verify_signed_message(signed, "hello".into()).unwrap());
+
+    // A group of another identity is signed for with that identity's key.
+    a.add_identity("work".into()).unwrap();
+    let gw = a.create_group("gw".into(), Some("work".into())).unwrap();
+    let out = a
+        .add_member(gw.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    let signed = a.encrypt_signed_message(gw.clone(), "hi".into()).unwrap();
+    let work = b
+        .get_members(gw.clone())
+        .unwrap()
+        .into_iter()
+        .find(|m| m.identity == b"work")
+        .unwrap();
+    assert_eq!(work.signature_key, signed.signature_key);
+    assert_ne!(alice.signature_key, signed.signature_key);
+    assert_eq!(
+        b.decrypt_message(gw.clone(), signed.ciphertext.clone())
+            .unwrap(),
+        "hi"
+    );
+    assert!(b.verify_signed_message(signed, "hi".into()).unwrap());
+}
+
+#[test]
+fn diagnostics_report() {
+    let a = MlsClient::new(tmp("diag_a"));
+    let b = MlsClient::new(tmp("diag_b"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+
+    let ra = a.diagnostics(gid.clone()).unwrap();
+    let rb = b.diagnostics(gid.clone()).unwrap();
+    let va: serde_json::Value = serde_json::from_str(&ra).unwrap();
+    let vb: serde_json::Value = serde_json::from_str(&rb).unwrap();
+    assert_eq!(va["epoch"], 1);
+    assert_eq!(va["member_count"], 2);
+    assert_eq!(va["has_pending_commit"], false);
+    assert_eq!(va["pending_proposal_count"], 0);
+    assert_eq!(va["tree_hash"], vb["tree_hash"]);
+    assert_eq!(
+        va["epoch_authenticator_hash"],
+        vb["epoch_authenticator_hash"]
+    );
+    let auth = hex::encode(a.get_epoch_authenticator(gid.clone()).unwrap());
+    assert!(!ra.contains(&auth));
+}
+
+#[test]
+fn merge_failure_rolls_back() {
+    let a = MlsClient::new(tmp("mrb_a"));
+    let b = MlsClient::new(tmp("mrb_b"));
+    let c = MlsClient::new(tmp("mrb_c"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    c.create_identity("carol".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+    let epoch = a.get_epoch(gid.clone()).unwrap();
+    let members = a.get_members(gid.clone()).unwrap().len();
+
+    // The in-memory provider cannot be made to fail a merge, so stage an
+    // add and merge it with a merge that fails once written.
+    let mut state = a.state.write().unwrap();
+    state
+        .prepare_add_member(&gid, &c.generate_key_package(None).unwrap(), None, false)
+        .unwrap();
+    let st = &mut *state;
+    let group = st.groups.get_mut(&gid).unwrap();
+    let err = merge_or_roll_back(&gid, group, &st.crypto, |group| {
+        group.merge_pending_commit(&st.crypto).unwrap();
+        assert_eq!(group.epoch().as_u64(), epoch + 1);
+        Err("injected failure".into())
+    })
+    .unwrap_err();
+    assert!(matches!(err, MlsError::Generic { msg } if msg.contains("injected failure")));
+    assert!(group.pending_commit().is_none());
+    drop(state);
+
+    assert_eq!(a.get_epoch(gid.clone()).unwrap(), epoch);
+    assert_eq!(a.get_members(gid.clone()).unwrap().len(), members);
+    // Still usable: it can send and commit again.
+    let ct = a.encrypt_message(gid.clone(), "hi".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "hi");
+    let out = a
+        .add_member(gid.clone(), c.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_commit(gid.clone(), field(&out, "commit"))
+        .unwrap();
+    c.process_welcome(field(&out, "welcome")).unwrap();
+    assert_eq!(b.get_epoch(gid.clone()).unwrap(), epoch + 1);
+    let ct = a.encrypt_message(gid.clone(), "still".into()).unwrap();
+    assert_eq!(c.decrypt_message(gid.clone(), ct).unwrap(), "still");
+    // The rollback survives a reload.
+    drop(a);
+    let a = MlsClient::new(tmp_keep("mrb_a"));
+    assert_eq!(a.get_epoch(gid.clone()).unwrap(), epoch + 1);
+    let ct = a.encrypt_message(gid.clone(), "later".into()).unwrap();
+    assert_eq!(b.decrypt_message(gid.clone(), ct).unwrap(), "later");
+}
+
+#[test]
+fn export_leaf_node_matches_member() {
+    let a = MlsClient::new(tmp("eln_a"));
+    let b = MlsClient::new(tmp("eln_b"));
+    a.create_identity("alice".into()).unwrap();
+    b.create_identity("bob".into()).unwrap();
+    let gid = a.create_group("g".into(), None).unwrap();
+    let out = a
+        .add_member(gid.clone(), b.generate_key_package(None).unwrap())
+        .unwrap();
+    b.process_welcome(field(&out, "welcome")).unwrap();
+
+    for member in b.get_members(gid.clone()).unwrap() {
+        let bytes = b.export_leaf_node(gid.clone(), member.index).unwrap();
+        let has = |needle: &[u8]| bytes.windows(needle.len()).any(|w| w == needle);
+        assert!(has(&member.signature_key));
+        assert!(has(&member.identity));
+        assert_eq!(
+            bytes,
+            a.export_leaf_node(gid.clone(), member.index).unwrap()
+        );
+    }
+    assert!(matches!(
+        b.export_leaf_node(gid.clone(), 7),
+        Err(MlsError::UnknownMember { .. })
+    ));
+    assert!(matches!(
+        b.export_leaf_node("00".into(), 0),
+        Err(MlsError::GroupNotFound { .. })
+    ));
+}